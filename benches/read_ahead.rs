@@ -0,0 +1,99 @@
+//! Compares downloads with and without read-ahead, from a source
+//! that's slow to read (like a busy disk) over a link with latency.
+use std::env;
+use std::io;
+use std::io::Read;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use tftpeer::tftp::shared::data_channel::{DataChannel, DataChannelOwner};
+use tftpeer::tftp::shared::{parse_udp_packet, STRIDE_SIZE};
+
+const BLOCKS: usize = 64;
+const SIZE: usize = BLOCKS * STRIDE_SIZE + 100;
+/// Time a packet takes to reach the other end.
+const ONE_WAY: Duration = Duration::from_micros(500);
+/// Time each read from the source takes.
+const READ_LATENCY: Duration = Duration::from_micros(500);
+
+struct SlowSource {
+    left: usize,
+}
+
+impl Read for SlowSource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        thread::sleep(READ_LATENCY);
+        let n = buf.len().min(self.left);
+        buf[..n].iter_mut().for_each(|b| *b = 7);
+        self.left -= n;
+        Ok(n)
+    }
+}
+
+/// One end of a link that delivers packets `ONE_WAY` after they're sent.
+struct LinkEnd {
+    tx: Sender<(Instant, Vec<u8>)>,
+    rx: Receiver<(Instant, Vec<u8>)>,
+}
+
+impl LinkEnd {
+    fn send(&self, packet: Vec<u8>) {
+        self.tx.send((Instant::now() + ONE_WAY, packet)).unwrap();
+    }
+
+    fn recv(&self) -> Vec<u8> {
+        let (arrival, packet) = self.rx.recv().unwrap();
+        let now = Instant::now();
+        if arrival > now {
+            thread::sleep(arrival - now);
+        }
+        packet
+    }
+}
+
+fn link() -> (LinkEnd, LinkEnd) {
+    let (a_tx, b_rx) = channel();
+    let (b_tx, a_rx) = channel();
+    (LinkEnd { tx: a_tx, rx: a_rx }, LinkEnd { tx: b_tx, rx: b_rx })
+}
+
+fn download(read_ahead: bool) {
+    let (server_end, client_end) = link();
+    let client = thread::spawn(move || {
+        let dst = env::temp_dir().join("tftpeer_read_ahead.bin");
+        let mut rx = DataChannel::overwrite(dst.to_str().unwrap(), DataChannelOwner::Client).unwrap();
+        while !rx.is_done() {
+            rx.handle(parse_udp_packet(&client_end.recv()).unwrap()).unwrap();
+            client_end.send(rx.packet_at_hand().unwrap().to_vec());
+            rx.on_packet_sent();
+        }
+    });
+
+    let source = Box::new(SlowSource { left: SIZE });
+    let mut tx = DataChannel::from_source("bench", source, SIZE as u64, DataChannelOwner::Server);
+    while !tx.is_done() {
+        server_end.send(tx.packet_at_hand().unwrap().to_vec());
+        tx.on_packet_sent();
+        if read_ahead {
+            tx.read_ahead();
+        }
+        tx.handle(parse_udp_packet(&server_end.recv()).unwrap()).unwrap();
+    }
+
+    client.join().unwrap();
+}
+
+fn bench_read_ahead(c: &mut Criterion) {
+    let mut group = c.benchmark_group("download");
+    group.throughput(Throughput::Bytes(SIZE as u64));
+    group.sample_size(10);
+    group.bench_function("without read-ahead", |b| b.iter(|| download(false)));
+    group.bench_function("with read-ahead", |b| b.iter(|| download(true)));
+    group.finish();
+}
+
+criterion_group!(benches, bench_read_ahead);
+criterion_main!(benches);
@@ -0,0 +1,122 @@
+//! Compares serializing every block into a new buffer with
+//! reusing a single buffer, and with writing the block's bytes
+//! straight into it, and prints the allocations each approach
+//! makes per block. The blocks a sending data channel hands out
+//! are counted as well.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::Cursor;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+
+use tftpeer::tftp::shared::ack_packet::AckPacket;
+use tftpeer::tftp::shared::data_channel::{DataChannel, DataChannelOwner};
+use tftpeer::tftp::shared::data_packet::DataPacket;
+use tftpeer::tftp::shared::{Serializable, TFTPPacket, STRIDE_SIZE};
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const BLOCKS: u16 = 1000;
+
+/// Builds the DATA packet of a block, its payload is allocated
+/// the same way for both approaches.
+fn block(blk: u16) -> DataPacket {
+    DataPacket::new(blk, vec![blk as u8; STRIDE_SIZE])
+}
+
+fn serialize_blocks() {
+    for blk in 1..=BLOCKS {
+        black_box(block(blk).serialize());
+    }
+}
+
+fn serialize_blocks_into(buf: &mut Vec<u8>) {
+    for blk in 1..=BLOCKS {
+        buf.clear();
+        block(blk).serialize_into(buf);
+        black_box(&buf);
+    }
+}
+
+/// Serializes each block from the buffer it was read into, as a sending
+/// channel does, instead of copying it into a packet first.
+fn serialize_blocks_from_slice(buf: &mut Vec<u8>, block: &mut [u8]) {
+    for blk in 1..=BLOCKS {
+        block.iter_mut().for_each(|b| *b = blk as u8);
+        buf.clear();
+        DataPacket::serialize_from_slice(blk, block, buf);
+        black_box(&buf);
+    }
+}
+
+fn sending_channel() -> DataChannel {
+    let size = BLOCKS as usize * STRIDE_SIZE;
+    let source = Box::new(Cursor::new(vec![7; size]));
+    DataChannel::from_source("bench", source, size as u64, DataChannelOwner::Server)
+}
+
+/// Sends every block the way the server does: the packet at hand is
+/// copied into the buffer kept for resending, then acknowledged.
+fn send_blocks(channel: &mut DataChannel, last_sent: &mut Vec<u8>) {
+    for blk in 1..=BLOCKS {
+        last_sent.clear();
+        last_sent.extend_from_slice(channel.packet_at_hand().unwrap());
+        black_box(&last_sent);
+        channel.on_packet_sent();
+        channel.handle(TFTPPacket::ACK(AckPacket::new(blk))).unwrap();
+    }
+}
+
+fn allocations_per_block(transfer: impl FnOnce()) -> f64 {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    transfer();
+    let after = ALLOCATIONS.load(Ordering::Relaxed);
+    (after - before) as f64 / BLOCKS as f64
+}
+
+fn bench_serialize(c: &mut Criterion) {
+    let mut buf = Vec::new();
+    println!("serialize: {:.2} allocations per block", allocations_per_block(serialize_blocks));
+    println!(
+        "serialize_into: {:.2} allocations per block",
+        allocations_per_block(|| serialize_blocks_into(&mut buf))
+    );
+    let mut block = vec![0; STRIDE_SIZE];
+    println!(
+        "serialize_from_slice: {:.2} allocations per block",
+        allocations_per_block(|| serialize_blocks_from_slice(&mut buf, &mut block))
+    );
+
+    let mut last_sent = Vec::new();
+    let mut channel = sending_channel();
+    println!(
+        "channel send: {:.2} allocations per block",
+        allocations_per_block(|| send_blocks(&mut channel, &mut last_sent))
+    );
+
+    c.bench_function("serialize", |b| b.iter(serialize_blocks));
+    c.bench_function("serialize_into", |b| b.iter(|| serialize_blocks_into(&mut buf)));
+    c.bench_function("serialize_from_slice", |b| b.iter(|| serialize_blocks_from_slice(&mut buf, &mut block)));
+    c.bench_function("channel send", |b| {
+        b.iter_batched(sending_channel, |mut channel| send_blocks(&mut channel, &mut last_sent), BatchSize::SmallInput)
+    });
+}
+
+criterion_group!(benches, bench_serialize);
+criterion_main!(benches);
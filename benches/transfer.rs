@@ -0,0 +1,113 @@
+//! Times packets being serialized and parsed, and whole transfers of
+//! 10 MB through the client against a server data channel that answers
+//! in memory, so the numbers leave the network out.
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io;
+use std::io::Cursor;
+use std::net::SocketAddr;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+
+use tftpeer::tftp::client::{stream_with, upload_reader_with, ClientConfig};
+use tftpeer::tftp::shared::data_channel::{DataChannel, DataChannelOwner};
+use tftpeer::tftp::shared::data_packet::DataPacket;
+use tftpeer::tftp::shared::request_packet::ReadRequestPacket;
+use tftpeer::tftp::shared::transport::Transport;
+use tftpeer::tftp::shared::{parse_udp_packet, Serializable, TFTPPacket, STRIDE_SIZE};
+
+const SIZE: usize = 10 * 1024 * 1024;
+const SERVER: ([u8; 4], u16) = ([127, 0, 0, 1], 69);
+
+/// A server that answers each packet as it's sent, its replies
+/// are queued for the client to receive.
+struct InMemoryServer {
+    content: Vec<u8>,
+    channel: RefCell<Option<DataChannel>>,
+    replies: RefCell<VecDeque<Vec<u8>>>,
+}
+
+impl InMemoryServer {
+    fn new(content: Vec<u8>) -> Self {
+        InMemoryServer {
+            content,
+            channel: RefCell::new(None),
+            replies: RefCell::new(VecDeque::new()),
+        }
+    }
+}
+
+impl Transport for InMemoryServer {
+    fn send_to(&self, buf: &[u8], _peer: SocketAddr) -> io::Result<usize> {
+        let mut channel = self.channel.borrow_mut();
+        match parse_udp_packet(buf).unwrap() {
+            TFTPPacket::RRQ(_) => {
+                let source = Box::new(Cursor::new(self.content.clone()));
+                *channel = Some(DataChannel::from_source("bench", source, self.content.len() as u64, DataChannelOwner::Server));
+            }
+            TFTPPacket::WRQ(_) => *channel = Some(DataChannel::receive_blocks("bench", DataChannelOwner::Server)),
+            packet => {
+                let channel = channel.as_mut().unwrap();
+                channel.handle(packet).unwrap();
+                channel.take_block();
+            }
+        }
+
+        let channel = channel.as_mut().unwrap();
+        if let Some(reply) = channel.packet_at_hand() {
+            self.replies.borrow_mut().push_back(reply.to_vec());
+            channel.on_packet_sent();
+        }
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        match self.replies.borrow_mut().pop_front() {
+            Some(reply) => {
+                buf[..reply.len()].copy_from_slice(&reply);
+                Ok((reply.len(), SERVER.into()))
+            }
+            None => Err(io::Error::new(io::ErrorKind::WouldBlock, "Nothing was sent")),
+        }
+    }
+}
+
+fn download(content: &[u8], config: &ClientConfig) {
+    let server = InMemoryServer::new(content.to_vec());
+    let received: usize = stream_with(&server, SERVER.into(), "bench", config).unwrap()
+        .map(|block| block.unwrap().len())
+        .sum();
+    assert_eq!(received, SIZE);
+}
+
+fn upload(content: &[u8], config: &ClientConfig) {
+    let server = InMemoryServer::new(Vec::new());
+    let stats = upload_reader_with(&server, SERVER.into(), "bench", Cursor::new(content.to_vec()), config).unwrap();
+    assert_eq!(stats.bytes, SIZE as u64);
+}
+
+fn bench_packets(c: &mut Criterion) {
+    let data = DataPacket::new(7, vec![7; STRIDE_SIZE]).serialize();
+    let rrq = ReadRequestPacket::new("pxelinux.0", "octet").serialize();
+
+    let mut group = c.benchmark_group("packets");
+    group.bench_function("serialize DATA", |b| b.iter(|| DataPacket::new(7, black_box(vec![7; STRIDE_SIZE])).serialize()));
+    group.bench_function("parse DATA", |b| b.iter(|| parse_udp_packet(black_box(&data)).unwrap()));
+    group.bench_function("parse RRQ", |b| b.iter(|| parse_udp_packet(black_box(&rrq)).unwrap()));
+    group.finish();
+}
+
+fn bench_transfers(c: &mut Criterion) {
+    let content = vec![7; SIZE];
+    let config = ClientConfig::default();
+
+    let mut group = c.benchmark_group("10 MB in memory");
+    group.throughput(Throughput::Bytes(SIZE as u64));
+    group.sample_size(10);
+    group.bench_function("download", |b| b.iter(|| download(&content, &config)));
+    group.bench_function("upload", |b| b.iter(|| upload(&content, &config)));
+    group.finish();
+}
+
+criterion_group!(benches, bench_packets, bench_transfers);
+criterion_main!(benches);
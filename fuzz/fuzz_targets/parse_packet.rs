@@ -0,0 +1,16 @@
+//! Feeds arbitrary datagrams to the packet parser, run with
+//! `cargo fuzz run parse_packet` from the repository root.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use tftpeer::tftp::shared::parse_udp_packet;
+
+fuzz_target!(|data: &[u8]| {
+    // Whatever parses has to survive being sent again.
+    if let Ok(packet) = parse_udp_packet(data) {
+        let opcode = packet.opcode();
+        let reparsed = parse_udp_packet(&packet.serialize()).expect("Serialized packet doesn't parse");
+        assert_eq!(reparsed.opcode(), opcode);
+    }
+});
@@ -1,9 +1,21 @@
-use clap::Clap;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
 
-use crate::tftp::client::client_main;
-use crate::tftp::server::server_main;
+use clap::Clap;
+use log::{error, info};
 
-mod tftp;
+use tftpeer::tftp::client::{client_main, ClientConfig, TidPolicy};
+use tftpeer::tftp::logger;
+use tftpeer::tftp::logger::LogFormat;
+use tftpeer::tftp::server::{resolve_root, server_main, AccessLog, DiskQuota, Operation, ServerConfig};
+use tftpeer::tftp::shared::err_packet::TFTPError;
+use tftpeer::tftp::shared::transfer_mode::TransferMode;
+use tftpeer::tftp::shared::transport::MAX_DSCP;
 
 /// This doc string acts as a help message when the user runs '--help'
 /// as do all doc strings on fields
@@ -22,6 +34,21 @@ enum SubCommand {
     /// act as a TFTP server.
     #[clap(name = "server")]
     Server(ServerArgs),
+    /// serve a single download of a file, then exit.
+    #[clap(name = "serve-once")]
+    ServeOnce(ServeOnceArgs),
+}
+
+#[derive(Clap, Debug)]
+struct ServeOnceArgs {
+    /// File to serve, it's requested by its name alone.
+    file: PathBuf,
+    /// IP for the server to use.
+    #[clap(short = "a", long = "address", default_value = "127.0.0.1")]
+    address: String,
+    /// UDP port that the server will listen on.
+    #[clap(short = "p", long = "port", default_value = "69")]
+    port: u16,
 }
 
 #[derive(Clap, Debug)]
@@ -32,6 +59,123 @@ struct ServerArgs {
     /// UDP port that the server will listen on.
     #[clap(short = "p", long = "port", default_value = "69")]
     port: u16,
+    /// Directory to serve, defaults to $TFTPEER_ROOT then the current directory.
+    #[clap(short = "r", long = "root")]
+    root: Option<String>,
+    /// Allow uploads to create missing directories under the root.
+    #[clap(long = "create-dirs")]
+    create_dirs: bool,
+    /// Only serve and accept files with this extension, may be repeated.
+    #[clap(long = "allow-ext", number_of_values = 1)]
+    allow_ext: Vec<String>,
+    /// Refuse all uploads.
+    #[clap(long = "read-only", conflicts_with = "write_only")]
+    read_only: bool,
+    /// Refuse all downloads.
+    #[clap(long = "write-only")]
+    write_only: bool,
+    /// Clients that may be served at the same time.
+    #[clap(short = "w", long = "workers", default_value = "8", parse(try_from_str = parse_workers))]
+    workers: usize,
+    /// Seconds a client may stay silent before its transfer is dropped.
+    #[clap(long = "idle-timeout", default_value = "5", parse(try_from_str = parse_idle_timeout))]
+    idle_timeout: u64,
+    /// Milliseconds to stay around after acknowledging the last block of an
+    /// upload, to acknowledge it again if the client didn't get the ACK.
+    #[clap(long = "dally", default_value = "0")]
+    dally: u64,
+    /// Answer downloads of this name with a listing of the root.
+    #[clap(long = "index-name")]
+    index_name: Option<String>,
+    /// Answer downloads of this name with "OK", for liveness probes.
+    #[clap(long = "health-name")]
+    health_name: Option<String>,
+    /// Bind the sockets that serve clients within START:END.
+    #[clap(long = "port-range", parse(try_from_str = parse_port_range))]
+    port_range: Option<RangeInclusive<u16>>,
+    /// Only take packets arriving on this network interface (Linux only).
+    #[clap(long = "interface")]
+    interface: Option<String>,
+    /// Serve clients from this address instead of the bind address,
+    /// for hosts with several addresses listening on 0.0.0.0.
+    #[clap(long = "data-address")]
+    data_address: Option<IpAddr>,
+    /// Mark packets sent to clients with this DSCP (0-63), IPv4 only.
+    #[clap(long = "dscp", parse(try_from_str = parse_dscp))]
+    dscp: Option<u8>,
+    /// Bytes to ask for the receive buffer of transfer sockets.
+    #[clap(long = "recv-buffer")]
+    recv_buffer: Option<usize>,
+    /// Bytes to ask for the send buffer of transfer sockets.
+    #[clap(long = "send-buffer")]
+    send_buffer: Option<usize>,
+    /// MTU of the path to clients, larger block sizes aren't negotiated.
+    #[clap(long = "mtu")]
+    mtu: Option<usize>,
+    /// Print every packet sent and received, e.g. "<- [addr] ACK [3]".
+    #[clap(long = "verbose-packets")]
+    verbose_packets: bool,
+    /// How log records are printed: "text", or "json" for a JSON object a
+    /// line with level, timestamp, connection, target and message keys.
+    #[clap(long = "log-format", default_value = "text")]
+    log_format: LogFormat,
+    /// Only negotiate block sizes that are powers of two, for picky boot ROMs.
+    #[clap(long = "pow2-blksize")]
+    pow2_blksize: bool,
+    /// Smallest block size to negotiate for downloads, clients asking for
+    /// less get this one as far as --mtu and --pow2-blksize allow.
+    #[clap(long = "min-blksize")]
+    min_blksize: Option<usize>,
+    /// Ignore the options of requests, transfers go like in plain RFC 1350.
+    #[clap(long = "strict-rfc", conflicts_with = "min_blksize")]
+    strict_rfc: bool,
+    /// Refuse requests for file names longer than this many bytes.
+    #[clap(long = "max-filename-len", default_value = "255")]
+    max_filename_len: usize,
+    /// Refuse uploads once all of them together wrote this many bytes.
+    #[clap(long = "max-total-bytes")]
+    max_total_bytes: Option<u64>,
+    /// Let uploads replace existing files.
+    #[clap(long = "allow-overwrite", conflicts_with = "read_only")]
+    allow_overwrite: bool,
+    /// Let clients upload with --append to add to existing files.
+    #[clap(long = "append", conflicts_with = "read_only")]
+    append: bool,
+    /// (experimental) Receive uploads into .part files that clients
+    /// can continue with --resume after an interruption.
+    #[clap(long = "resumable-uploads", conflicts_with = "read_only")]
+    resumable_uploads: bool,
+    /// Read each block from disk while the previous one awaits its ACK.
+    #[clap(long = "read-ahead")]
+    read_ahead: bool,
+    /// Append a JSON line for each request to this file.
+    #[clap(long = "access-log")]
+    access_log: Option<String>,
+    /// Never open files outside the root, not even through symlinks.
+    /// Enforced by the kernel on Linux 5.6 and later.
+    #[clap(long = "chroot", conflicts_with = "create_dirs")]
+    chroot: bool,
+    /// Answer downloads of NAME.tar with the directory NAME packed on the fly.
+    #[clap(long = "tar-dirs", conflicts_with = "chroot")]
+    tar_dirs: bool,
+    /// Refuse downloads of empty files.
+    #[clap(long = "reject-empty")]
+    reject_empty: bool,
+    /// Don't answer requests for missing files, they're refused by default.
+    #[clap(long = "silent-notfound")]
+    silent_notfound: bool,
+    /// Log a panic while serving a client and go on serving the others.
+    #[clap(long = "catch-panics")]
+    catch_panics: bool,
+    /// Store uploads under unique names of the server's instead of the
+    /// requested ones, clients asking with the "filename" option are told
+    /// the name.
+    #[clap(long = "rename-uploads", conflicts_with_all = &["append", "resumable_uploads"])]
+    rename_uploads: bool,
+    /// Redirect clients to this server (IP:PORT) when all workers are
+    /// busy, clients started with --follow-redirect retry there.
+    #[clap(long = "redirect-busy")]
+    redirect_busy: Option<SocketAddr>,
 }
 
 /// A subcommand for controlling testing
@@ -42,35 +186,328 @@ struct ClientOperations {
     /// If specified tftpeer will attempt to upload the input file
     #[clap(short = "u", long = "upload")]
     upload: bool,
+    /// Upload the directory named by the input as a tar archive, the
+    /// server stores it as NAME.tar.
+    #[clap(long = "tar", requires = "upload")]
+    tar: bool,
+    /// Download NAME.tar and unpack it into the directory NAME.
+    #[clap(long = "untar", conflicts_with = "upload")]
+    untar: bool,
     /// Server bind address
     #[clap(short = "a", long = "address", default_value = "127.0.0.1")]
     address: String,
     /// Server bind port
     #[clap(short = "p", long = "port", default_value = "69")]
     port: u16,
+    /// (experimental) Keep the full blocks of an interrupted download,
+    /// they're still sent by the server but aren't written again. Uploads
+    /// continue from what a server with --resumable-uploads kept.
+    #[clap(long = "resume")]
+    resume: bool,
+    /// Permissions of the downloaded file in octal, e.g. 755 (Unix only).
+    #[clap(long = "mode-bits", conflicts_with = "upload", parse(try_from_str = parse_mode_bits))]
+    mode_bits: Option<u32>,
+    /// Save the download in this directory under the file name of the
+    /// remote path, e.g. DIR/boot.img for firmware/boot.img.
+    #[clap(long = "download-dir", conflicts_with_all = &["upload", "untar"])]
+    download_dir: Option<PathBuf>,
+    /// Keep the whole remote path within --download-dir,
+    /// e.g. DIR/firmware/boot.img.
+    #[clap(long = "preserve-path", requires = "download_dir")]
+    preserve_path: bool,
+    /// Mark packets sent to the server with this DSCP (0-63), IPv4 only.
+    #[clap(long = "dscp", parse(try_from_str = parse_dscp))]
+    dscp: Option<u8>,
+    /// Bytes to ask for the receive buffer of the socket.
+    #[clap(long = "recv-buffer")]
+    recv_buffer: Option<usize>,
+    /// Bytes to ask for the send buffer of the socket.
+    #[clap(long = "send-buffer")]
+    send_buffer: Option<usize>,
+    /// Transfer mode, netascii converts line endings.
+    #[clap(short = "m", long = "mode", default_value = "octet")]
+    mode: TransferMode,
+    /// Consecutive retransmissions before giving up on the server.
+    #[clap(short = "r", long = "retries", default_value = "5", parse(try_from_str = parse_retries))]
+    retries: u32,
+    /// Block size to ask the server for.
+    #[clap(long = "blksize")]
+    blksize: Option<usize>,
+    /// Round the block size asked for down to a power of two.
+    #[clap(long = "pow2-blksize", requires = "blksize")]
+    pow2_blksize: bool,
+    /// Add the upload to the end of the file on the server, if it allows that.
+    #[clap(long = "append", requires = "upload")]
+    append: bool,
+    /// Send no options, 512 byte blocks like a plain RFC 1350 client.
+    #[clap(long = "strict-rfc", conflicts_with_all = &["blksize", "append", "resume"])]
+    strict_rfc: bool,
+    /// Repeat the last block while the file being uploaded is slow to read.
+    #[clap(long = "keepalive", requires = "upload")]
+    keepalive: bool,
+    /// Seconds to wait for the server's first answer, it may take longer
+    /// to find the file than to send a block.
+    #[clap(long = "connect-timeout", default_value = "15", parse(try_from_str = parse_connect_timeout))]
+    connect_timeout: u64,
+    /// Double the wait after each retransmission instead of keeping it flat.
+    #[clap(long = "backoff")]
+    backoff: bool,
+    /// Milliseconds to stay around after acknowledging the last block of a
+    /// download, to acknowledge it again if the server didn't get the ACK.
+    /// Without it a lost final ACK leaves the server timing out on a
+    /// download that completed, 0 exits right away. The default outlasts a
+    /// couple of retransmissions of a server waiting a second between them.
+    #[clap(long = "dally", default_value = "3000")]
+    dally: u64,
+    /// How the server's answer is told from spoofed ones: "first-reply" takes
+    /// the first datagram that can answer the request, "strict" only the exact
+    /// answer from the address the request went to.
+    #[clap(long = "tid-policy", default_value = "first-reply")]
+    tid_policy: TidPolicy,
+    /// Retry once against the server a busy server redirects to.
+    #[clap(long = "follow-redirect")]
+    follow_redirect: bool,
+    /// Keep asking for a file the server doesn't have yet for this many seconds.
+    #[clap(long = "wait-for-file", conflicts_with = "upload")]
+    wait_for_file: Option<u64>,
+    /// Only print warnings and errors, nothing at all on success.
+    #[clap(short = "q", long = "quiet")]
+    quiet: bool,
+    /// Print every packet sent and received, e.g. "-> [addr] DATA [3]".
+    #[clap(long = "verbose-packets")]
+    verbose_packets: bool,
+    /// Print the number of transferred bytes on success, for scripts.
+    #[clap(long = "porcelain")]
+    porcelain: bool,
+    /// Print the outcome as a single JSON object.
+    #[clap(long = "json", conflicts_with = "porcelain")]
+    json: bool,
+}
+
+fn parse_retries(s: &str) -> Result<u32, String> {
+    match s.parse::<u32>() {
+        Ok(0) => Err(String::from("at least one retry is needed")),
+        Ok(retries) => Ok(retries),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn parse_idle_timeout(s: &str) -> Result<u64, String> {
+    match s.parse::<u64>() {
+        Ok(0) => Err(String::from("clients need at least a second")),
+        Ok(seconds) => Ok(seconds),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn parse_connect_timeout(s: &str) -> Result<u64, String> {
+    match s.parse::<u64>() {
+        Ok(0) => Err(String::from("the server needs at least a second to answer")),
+        Ok(seconds) => Ok(seconds),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn parse_workers(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(0) => Err(String::from("at least one worker is needed")),
+        Ok(workers) => Ok(workers),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn parse_dscp(s: &str) -> Result<u8, String> {
+    match s.parse::<u8>() {
+        Ok(dscp) if dscp <= MAX_DSCP => Ok(dscp),
+        Ok(_) => Err(format!("DSCP is a 6-bit value, at most {}", MAX_DSCP)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn parse_mode_bits(s: &str) -> Result<u32, String> {
+    match u32::from_str_radix(s, 8) {
+        Ok(bits) if bits <= 0o7777 => Ok(bits),
+        Ok(_) => Err(String::from("at most 7777")),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn parse_port_range(s: &str) -> Result<RangeInclusive<u16>, String> {
+    let (start, end) = match s.find(':') {
+        Some(i) => (&s[..i], &s[i + 1..]),
+        None => return Err(String::from("expected START:END")),
+    };
+
+    let start = start.parse::<u16>().map_err(|e| e.to_string())?;
+    let end = end.parse::<u16>().map_err(|e| e.to_string())?;
+    if start == 0 || start > end {
+        return Err(String::from("START must be a port no greater than END"));
+    }
+
+    Ok(start..=end)
 }
 
 fn main() {
     let opts: Opts = Opts::parse();
     match opts.subcmd {
         SubCommand::Client(client_args) => {
+            logger::init(client_args.quiet || client_args.json, client_args.verbose_packets, LogFormat::Text).expect("Failed to set up logging");
             let addr = format!("{}:{}", client_args.address, client_args.port);
             if client_args.upload {
-                println!(
+                info!(
                     "[UPLOAD] FILE: ({}) TO SERVER: {}",
                     client_args.filename, addr
                 );
             } else {
-                println!(
+                info!(
                     "[DOWNLOAD] FILE: ({}) SERVER: {}",
                     client_args.filename, addr
                 );
             }
 
-            client_main(&addr, &client_args.filename, client_args.upload).unwrap();
+            let config = ClientConfig {
+                mode: client_args.mode,
+                resume: client_args.resume,
+                mode_bits: client_args.mode_bits,
+                download_dir: client_args.download_dir,
+                preserve_path: client_args.preserve_path,
+                retries: client_args.retries,
+                connect_timeout: Duration::from_secs(client_args.connect_timeout),
+                backoff: client_args.backoff,
+                dally: Duration::from_millis(client_args.dally),
+                follow_redirect: client_args.follow_redirect,
+                tid_policy: client_args.tid_policy,
+                wait_for_file: client_args.wait_for_file.map(Duration::from_secs),
+                tar: client_args.tar || client_args.untar,
+                block_size: client_args.blksize,
+                pow2_blksize: client_args.pow2_blksize,
+                strict_rfc: client_args.strict_rfc,
+                append: client_args.append,
+                keepalive: client_args.keepalive,
+                dscp: client_args.dscp,
+                recv_buffer: client_args.recv_buffer,
+                send_buffer: client_args.send_buffer,
+                porcelain: client_args.porcelain,
+                json: client_args.json,
+                ..ClientConfig::default()
+            };
+            let abort = config.abort.clone();
+            ctrlc::set_handler(move || abort.store(true, Ordering::SeqCst))
+                .expect("Failed to install the interrupt handler");
+            client_main(&addr, &client_args.filename, client_args.upload, config);
         }
         SubCommand::Server(server_args) => {
-            server_main(&server_args.address, server_args.port);
+            logger::init(false, server_args.verbose_packets, server_args.log_format).expect("Failed to set up logging");
+            let access_log = match server_args.access_log.map(AccessLog::open).transpose() {
+                Ok(access_log) => access_log.map(Arc::new),
+                Err(e) => {
+                    error!("Failed to open the access log: {}", e);
+                    exit(1);
+                }
+            };
+            let config = ServerConfig {
+                root: resolve_root(server_args.root.as_deref()),
+                create_dirs: server_args.create_dirs,
+                allowed_extensions: server_args.allow_ext,
+                read_only: server_args.read_only,
+                write_only: server_args.write_only,
+                workers: server_args.workers,
+                idle_timeout: Duration::from_secs(server_args.idle_timeout),
+                dally: Duration::from_millis(server_args.dally),
+                index_name: server_args.index_name,
+                health_name: server_args.health_name,
+                port_range: server_args.port_range,
+                interface: server_args.interface,
+                data_address: server_args.data_address,
+                dscp: server_args.dscp,
+                recv_buffer: server_args.recv_buffer,
+                send_buffer: server_args.send_buffer,
+                mtu: server_args.mtu,
+                pow2_blksize: server_args.pow2_blksize,
+                min_blksize: server_args.min_blksize,
+                strict_rfc: server_args.strict_rfc,
+                max_filename_len: server_args.max_filename_len,
+                append: server_args.append,
+                allow_overwrite: server_args.allow_overwrite,
+                resumable_uploads: server_args.resumable_uploads,
+                read_ahead: server_args.read_ahead,
+                disk_quota: server_args.max_total_bytes.map(|limit| Arc::new(DiskQuota::new(limit))),
+                chroot: server_args.chroot,
+                tar_dirs: server_args.tar_dirs,
+                reject_empty: server_args.reject_empty,
+                silent_notfound: server_args.silent_notfound,
+                catch_panics: server_args.catch_panics,
+                rename_uploads: server_args.rename_uploads,
+                redirect_busy_to: server_args.redirect_busy,
+                access_log,
+                ..ServerConfig::default()
+            };
+            server_main(&server_args.address, server_args.port, config);
+        }
+        SubCommand::ServeOnce(args) => {
+            logger::init(false, false, LogFormat::Text).expect("Failed to set up logging");
+            let name = match args.file.file_name().and_then(|name| name.to_str()) {
+                Some(name) => name.to_string(),
+                None => {
+                    error!("{} isn't a file name", args.file.display());
+                    exit(2);
+                }
+            };
+            let root = match args.file.parent() {
+                Some(parent) if parent != Path::new("") => parent.to_path_buf(),
+                _ => PathBuf::from("."),
+            };
+            info!("[SERVE ONCE] FILE: ({})", name);
+
+            let config = ServerConfig {
+                root,
+                read_only: true,
+                workers: 1,
+                once: true,
+                authorize: Some(Arc::new(move |_: &SocketAddr, _: Operation, requested: &str| {
+                    if requested == name {
+                        Ok(())
+                    } else {
+                        Err(TFTPError::FileNotFound)
+                    }
+                })),
+                ..ServerConfig::default()
+            };
+            server_main(&args.address, args.port, config);
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use clap::Clap;
+
+    use crate::{Opts, SubCommand};
+
+    #[test]
+    fn downloads_dally_three_seconds_by_default() {
+        let opts = Opts::try_parse_from(&["tftpeer", "client", "a.txt"]).unwrap();
+        match opts.subcmd {
+            SubCommand::Client(args) => assert_eq!(args.dally, 3000),
+            _ => panic!("Expected the client subcommand"),
+        }
+    }
+
+    #[test]
+    fn clients_may_not_be_given_zero_idle_time() {
+        assert!(Opts::try_parse_from(&["tftpeer", "server", "--idle-timeout", "0"]).is_err());
+        assert!(Opts::try_parse_from(&["tftpeer", "server", "--idle-timeout", "1"]).is_ok());
+    }
+
+    #[test]
+    fn servers_need_a_worker() {
+        assert!(Opts::try_parse_from(&["tftpeer", "server", "--workers", "0"]).is_err());
+        assert!(Opts::try_parse_from(&["tftpeer", "server", "--workers", "1"]).is_ok());
+    }
+
+    #[test]
+    fn clients_wait_at_least_a_second_for_the_server() {
+        assert!(Opts::try_parse_from(&["tftpeer", "client", "a.txt", "--connect-timeout", "0"]).is_err());
+        assert!(Opts::try_parse_from(&["tftpeer", "client", "a.txt", "--connect-timeout", "1"]).is_ok());
+    }
+}
@@ -1,76 +1,513 @@
 extern crate pretty_bytes;
 
+use std::fmt;
+use std::fs;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::{ErrorKind, Read};
 use std::mem;
-use std::net::UdpSocket;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::path::{Component, Path, PathBuf};
 use std::process::exit;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
+use log::{debug, error, info, warn};
 use pretty_bytes::converter::convert;
+use serde_json::{json, Value};
 
-use crate::tftp::shared::{data_channel::{DataChannel, DataChannelMode}, err_packet::ErrorPacket, request_packet::{ReadRequestPacket, WriteRequestPacket}, Serializable, STRIDE_SIZE, TFTPPacket};
+use crate::tftp::shared::{data_channel::{DataChannel, DataChannelMode}, err_packet::ErrorPacket, parse_udp_packet, request_packet::{ReadRequestPacket, Request, WriteRequestPacket}, recv_buffer_len, Serializable, TFTPPacket, STRIDE_SIZE};
+use crate::tftp::shared::clock::{Clock, SystemClock};
 use crate::tftp::shared::data_channel::DataChannelOwner;
+use crate::tftp::shared::data_packet::{pow2_block_size, DATA_MAX_LEN};
+use crate::tftp::shared::err_packet::TFTPError;
+use crate::tftp::shared::oack_packet::OptionAckPacket;
+use crate::tftp::shared::rtt::{RttSamples, RttSummary};
+use crate::tftp::shared::tar_stream;
+use crate::tftp::shared::transfer_mode::TransferMode;
+use crate::tftp::shared::transport::{dally, send_retrying, set_buffer_sizes, set_dscp, Transport, UdpTransport};
+
+/// Longest time the transfer goes without checking whether it was aborted.
+const ABORT_POLL: Duration = Duration::from_millis(100);
+
+/// Time between requests for a file that isn't on the server yet.
+const WAIT_FOR_FILE_POLL: Duration = Duration::from_millis(250);
+
+/// Longest wait between retransmissions when backing off, unless the
+/// first wait is longer already.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Settings for a single client transfer.
+pub struct ClientConfig {
+    /// How the file is represented on the wire.
+    pub mode: TransferMode,
+    /// Keep the full blocks of a previously interrupted download, or
+    /// continue an upload from what the server kept of it.
+    pub resume: bool,
+    /// Permissions given to a completed download, ignored off Unix.
+    pub mode_bits: Option<u32>,
+    /// Directory downloads are saved in instead of the remote path,
+    /// see `local_path`.
+    pub download_dir: Option<PathBuf>,
+    /// Keep the directories of the remote path within `download_dir`
+    /// instead of only the file name.
+    pub preserve_path: bool,
+    /// Consecutive retransmissions before giving up on the server.
+    pub retries: u32,
+    /// Time to wait for the server before retransmitting.
+    pub timeout: Duration,
+    /// Time to wait for the first answer to the request before
+    /// retransmitting it, `timeout` applies from then on.
+    pub connect_timeout: Duration,
+    /// Double the timeout after each consecutive retransmission.
+    pub backoff: bool,
+    /// DSCP that packets sent to the server are marked with.
+    pub dscp: Option<u8>,
+    /// Bytes asked for the receive buffer of the socket.
+    pub recv_buffer: Option<usize>,
+    /// Bytes asked for the send buffer of the socket.
+    pub send_buffer: Option<usize>,
+    /// Ask the server to add the upload to the end of an existing file.
+    pub append: bool,
+    /// Block size asked of the server, it's only used if the server
+    /// accepts it.
+    pub block_size: Option<usize>,
+    /// Round the block size asked for down to a power of two.
+    pub pow2_blksize: bool,
+    /// Send requests without any options, like a plain RFC 1350 client.
+    pub strict_rfc: bool,
+    /// Repeat the last DATA of an upload when the source stalls for half
+    /// the timeout, so the server doesn't give up on a slow producer.
+    /// There's nothing to repeat before the first block.
+    pub keepalive: bool,
+    /// Print only the raw byte count on success.
+    pub porcelain: bool,
+    /// Print the outcome as a single JSON object.
+    pub json: bool,
+    /// Set to stop the transfer, the server is told about it and
+    /// a partial download is removed unless it's being resumed.
+    /// Other threads can set it through `cancellation_token`.
+    pub abort: Arc<AtomicBool>,
+    /// Times the retransmissions and the transfer.
+    pub clock: Arc<dyn Clock>,
+    /// Retry the transfer once against the server that a busy server
+    /// redirects to.
+    pub follow_redirect: bool,
+    /// Keep asking for a download the server doesn't have yet for this
+    /// long, other errors end the transfer right away.
+    pub wait_for_file: Option<Duration>,
+    /// Move a directory as a tar archive, see `upload_dir` and `download_dir`.
+    pub tar: bool,
+    /// Which datagram the transfer is settled on, see `TidPolicy`.
+    pub tid_policy: TidPolicy,
+    /// How long the socket of a download stays open after the final ACK,
+    /// the server is sent it again if it sends the last block again.
+    /// Nothing is waited for when it's zero.
+    pub dally: Duration,
+}
+
+/// How the client picks the server's answer among the datagrams that
+/// arrive before the transfer ID (the server's port) is known.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum TidPolicy {
+    /// The first datagram that can answer the request wins, wherever it
+    /// comes from. Works with servers that answer from another address
+    /// than the one asked, like some multi-homed ones, but anyone who can
+    /// guess the client's port can answer first. An OACK or an error is
+    /// still only taken from the IP the request went to.
+    FirstReply,
+    /// Only the exact answer to the request is taken, from the address the
+    /// request went to. An OACK is only taken if options were asked for.
+    /// Datagrams of off-path hosts are dropped, servers answering from
+    /// another address can't be used.
+    Strict,
+}
+
+impl Default for TidPolicy {
+    fn default() -> Self {
+        TidPolicy::FirstReply
+    }
+}
+
+impl FromStr for TidPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "first-reply" => Ok(TidPolicy::FirstReply),
+            "strict" => Ok(TidPolicy::Strict),
+            _ => Err(format!("Unknown TID policy [{}], expected first-reply or strict", s)),
+        }
+    }
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            mode: TransferMode::Octet,
+            resume: false,
+            mode_bits: None,
+            download_dir: None,
+            preserve_path: false,
+            retries: 5,
+            timeout: Duration::from_secs(5),
+            connect_timeout: Duration::from_secs(15),
+            backoff: false,
+            append: false,
+            block_size: None,
+            pow2_blksize: false,
+            strict_rfc: false,
+            keepalive: false,
+            dscp: None,
+            recv_buffer: None,
+            send_buffer: None,
+            porcelain: false,
+            json: false,
+            abort: Arc::new(AtomicBool::new(false)),
+            clock: Arc::new(SystemClock),
+            follow_redirect: false,
+            wait_for_file: None,
+            tar: false,
+            tid_policy: TidPolicy::FirstReply,
+            dally: Duration::from_secs(0),
+        }
+    }
+}
+
+impl ClientConfig {
+    /// Token that stops the transfers run with this config, it can be
+    /// sent to another thread. It sets `abort`, a stopped transfer ends
+    /// with `TransferError::Aborted`.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        CancellationToken(self.abort.clone())
+    }
+
+    /// Where a download of `remote` is saved, the remote path itself unless
+    /// there's a `download_dir`. Within it only the file name is kept, or
+    /// the whole path with `preserve_path`, which has to stay inside it.
+    pub fn local_path(&self, remote: &str) -> Result<PathBuf, TransferError> {
+        let dir = match &self.download_dir {
+            Some(dir) => dir,
+            None => return Ok(PathBuf::from(remote)),
+        };
+        let outside = || TransferError::Local(format!("cannot save {} in {}: the name leads out of it", remote, dir.display()));
+
+        if !self.preserve_path {
+            return Path::new(remote).file_name().map(|name| dir.join(name)).ok_or_else(outside);
+        }
+
+        let mut path = dir.clone();
+        for component in Path::new(remote).components() {
+            match component {
+                Component::Normal(part) => path.push(part),
+                Component::CurDir => {}
+                _ => return Err(outside()),
+            }
+        }
+        if path == *dir {
+            return Err(outside());
+        }
+        Ok(path)
+    }
+
+    /// Time to wait for the server after `retries` consecutive
+    /// retransmissions, longer until the server has `answered`.
+    fn retransmit_timeout(&self, answered: bool, retries: u32) -> Duration {
+        let timeout = if answered { self.timeout } else { self.connect_timeout };
+        if !self.backoff {
+            return timeout;
+        }
+
+        let cap = MAX_BACKOFF.max(timeout);
+        let factor = 1u32.checked_shl(retries).unwrap_or(u32::MAX);
+        timeout.checked_mul(factor).map_or(cap, |t| t.min(cap))
+    }
+
+    /// Value of the `blksize` option sent to the server, if any.
+    fn requested_block_size(&self) -> Option<usize> {
+        match self.block_size {
+            Some(block_size) if self.pow2_blksize => Some(pow2_block_size(block_size)),
+            block_size => block_size,
+        }
+    }
+}
+
+/// Summary of a finished transfer.
+#[derive(Debug, Clone, Copy)]
+pub struct TransferStats {
+    /// File bytes transferred.
+    pub bytes: u64,
+    /// Time from sending the request to the end of the transfer.
+    pub duration: Duration,
+    /// Round trip times of the blocks, a slow network shows here
+    /// while a slow disk on either end doesn't. Packets that were
+    /// retransmitted aren't timed.
+    pub rtt: Option<RttSummary>,
+}
+
+impl TransferStats {
+    /// Average rate in bits per second.
+    pub fn rate_bps(&self) -> u64 {
+        let secs = self.duration.as_secs_f64();
+        if secs > 0.0 {
+            (self.bytes as f64 * 8.0 / secs) as u64
+        } else {
+            0
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        json!({
+            "status": "ok",
+            "bytes": self.bytes,
+            "duration_ms": self.duration.as_millis() as u64,
+            "rate_bps": self.rate_bps(),
+            "rtt_ms": self.rtt.map(|rtt| json!({"min": ms(rtt.min), "median": ms(rtt.median), "p99": ms(rtt.p99)})),
+        })
+    }
+}
+
+/// Reasons a client transfer can fail.
+#[derive(Debug)]
+pub enum TransferError {
+    /// The local file to upload doesn't exist.
+    LocalNotFound(String),
+    /// The local file can't be used for the transfer.
+    Local(String),
+    /// The server answered the request with an error packet, nothing was transferred.
+    Rejected(DataChannelMode, ErrorPacket),
+    /// The server ended the transfer with an error packet.
+    Server(ErrorPacket),
+    /// The server broke the protocol, the reason was sent to it.
+    Protocol(String),
+    /// The server stopped answering while the given block was awaited.
+    Timeout(u16),
+    /// The socket failed.
+    Io(io::Error),
+    /// The transfer was stopped by the user or through a `CancellationToken`.
+    Aborted,
+}
+
+impl Display for TransferError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferError::LocalNotFound(msg) => write!(f, "{}", msg),
+            TransferError::Local(msg) => write!(f, "{}", msg),
+            TransferError::Rejected(DataChannelMode::Rx, ep) => write!(f, "server rejected download: {}", ep.err()),
+            TransferError::Rejected(DataChannelMode::Tx, ep) => write!(f, "server rejected upload: {}", ep.err()),
+            TransferError::Server(ep) => write!(f, "{}", ep.err()),
+            TransferError::Protocol(msg) => write!(f, "{}", msg),
+            TransferError::Timeout(blk) => write!(f, "Timed out waiting for block #{}", blk),
+            TransferError::Io(e) => write!(f, "{}", e),
+            TransferError::Aborted => write!(f, "Transfer aborted by user"),
+        }
+    }
+}
+
+impl TransferError {
+    /// Process exit code for the error, scripts can
+    /// tell local failures from server rejections by it.
+    /// A rejection with one of the codes of RFC 1350 and RFC 2347
+    /// exits with 10 plus the code, e.g. 11 for a missing file.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            TransferError::LocalNotFound(_) => 2,
+            TransferError::Local(_) => 3,
+            TransferError::Rejected(_, ep) if ep.code() <= 8 => 10 + i32::from(ep.code()),
+            TransferError::Rejected(..) | TransferError::Server(_) => 4,
+            TransferError::Timeout(_) => 5,
+            TransferError::Protocol(_) | TransferError::Io(_) => 6,
+            TransferError::Aborted => 130,
+        }
+    }
+
+    /// Code of the error packet the server sent, if it sent one.
+    pub fn tftp_code(&self) -> Option<u16> {
+        match self {
+            TransferError::Rejected(_, ep) | TransferError::Server(ep) => Some(ep.code()),
+            _ => None,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "status": "error",
+            "tftp_code": self.tftp_code(),
+            "message": self.to_string(),
+        })
+    }
+}
+
+impl From<io::Error> for TransferError {
+    fn from(e: io::Error) -> Self {
+        TransferError::Io(e)
+    }
+}
+
+/// Stops a transfer from another thread, e.g. when the user of an
+/// embedding application hits cancel. It's made by
+/// `ClientConfig::cancellation_token` and shares the config's `abort` flag.
+#[derive(Clone, Debug)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    /// Asks the transfer to stop, it tells the server and ends with
+    /// `TransferError::Aborted` the next time it checks.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
 
 struct TFTPClient {
     packet_buffer: Option<Vec<u8>>,
     data_channel: DataChannel,
-    error: Option<String>,
-    transfer_size: u64,
+    error: Option<ErrorPacket>,
+    /// Largest block an upload offered to send.
+    offered_block_size: usize,
 }
 
 impl TFTPClient {
     /// Constructs a new TFTPClient.
-    fn new(file_name: &str, mode: DataChannelMode) -> Self {
-        let data_channel = DataChannel::new(file_name, mode, DataChannelOwner::Client);
-
-        let data_channel = match data_channel {
-            Ok(channel) => channel,
-            Err(e) => {
-                eprintln!("[ERROR] {}", e.err());
-                exit(-2)
-            }
-        };
+    fn new(file_name: &str, mode: DataChannelMode) -> Result<Self, ErrorPacket> {
+        let data_channel = DataChannel::new(file_name, mode, DataChannelOwner::Client)?;
 
         // Keep the information we need to know
         // in the object and initialize them
         // to some default values.
-        TFTPClient {
+        Ok(TFTPClient {
             packet_buffer: None,
             data_channel,
             error: None,
-            transfer_size: 0,
-        }
+            offered_block_size: STRIDE_SIZE,
+        })
     }
 
     /// Places a RRQ in the packet buffer to be sent to the server.
-    pub fn download(file_name: &str) -> TFTPClient {
-        let mut client = TFTPClient::new(file_name, DataChannelMode::Rx);
+    pub fn download(file_name: &str, config: &ClientConfig) -> Result<TFTPClient, TransferError> {
+        let local = config.local_path(file_name)?;
+        let local = local.to_string_lossy();
+        let mut client = TFTPClient::new(&local, DataChannelMode::Rx)
+            .map_err(|e| TransferError::Local(format!("cannot save {}: {}", local, e.err())))?;
+        if config.resume && config.mode == TransferMode::Netascii {
+            return Err(TransferError::Local(String::from("Can't resume a netascii download")));
+        }
+        client.data_channel.set_transfer_mode(config.mode)
+            .map_err(|e| TransferError::Local(format!("cannot save {}: {}", local, e)))?;
+        if config.resume {
+            let blocks = client.data_channel.resume()
+                .map_err(|e| TransferError::Local(format!("Can't resume download: {}", e)))?;
+            info!("Resuming after {} blocks.", blocks);
+        }
 
-        let rrq = Box::new(ReadRequestPacket::new(file_name, "octet"));
-        client.packet_buffer = Some(rrq.serialize());
-        client
+        client.packet_buffer = Some(TFTPClient::read_request(file_name, config));
+        Ok(client)
+    }
+
+    /// RRQ for the file, with the options the config asks for.
+    fn read_request(file_name: &str, config: &ClientConfig) -> Vec<u8> {
+        let mut rrq = ReadRequestPacket::new(file_name, config.mode.as_str());
+        if let Some(block_size) = config.requested_block_size() {
+            rrq = rrq.with_option("blksize", &block_size.to_string());
+        }
+        // What was kept is checked against the size of the remote file.
+        if config.resume {
+            rrq = rrq.with_option("tsize", "0");
+        }
+        if config.strict_rfc {
+            rrq = rrq.without_options();
+        }
+        Box::new(rrq).serialize()
+    }
+
+    /// Places a RRQ in the packet buffer, the received
+    /// blocks are held for the caller instead of saved.
+    fn stream(file_name: &str, config: &ClientConfig) -> Result<TFTPClient, TransferError> {
+        if config.resume {
+            return Err(TransferError::Local(String::from("Can't resume a streamed download")));
+        }
+        let mut data_channel = DataChannel::receive_blocks(file_name, DataChannelOwner::Client);
+        data_channel.set_transfer_mode(config.mode)
+            .map_err(|e| TransferError::Local(format!("cannot stream {}: {}", file_name, e)))?;
+
+        Ok(TFTPClient {
+            packet_buffer: Some(TFTPClient::read_request(file_name, config)),
+            data_channel,
+            error: None,
+            offered_block_size: STRIDE_SIZE,
+        })
     }
 
     /// Places a WRQ in the packet buffer to be sent
     /// to the server, then opens the file to be read.
-    pub fn upload(file_name: &str) -> TFTPClient {
-        let mut client = TFTPClient::new(file_name, DataChannelMode::Tx);
+    pub fn upload(file_name: &str, config: &ClientConfig) -> Result<TFTPClient, TransferError> {
+        let data_channel = DataChannel::new(file_name, DataChannelMode::Tx, DataChannelOwner::Client).map_err(|e| {
+            if e.code() == TFTPError::FileNotFound.code() {
+                TransferError::LocalNotFound(format!("cannot open {} for upload: No such file", file_name))
+            } else {
+                TransferError::Local(format!("cannot open {} for upload: {}", file_name, e.err()))
+            }
+        })?;
 
-        let wrq = Box::new(WriteRequestPacket::new(file_name, "octet"));
-        client.packet_buffer = Some(wrq.serialize());
-        client
+        TFTPClient::upload_from(data_channel, config)
     }
 
-    /// Returns the first packet in the packet
-    /// buffer to be sent to the server.
-    pub fn get_next_packet(&mut self) -> Vec<u8> {
-        self.transfer_size += self.data_channel.transfer_size() as u64;
+    /// Places a WRQ for the channel's file name in the packet
+    /// buffer, the data is read from the channel's source.
+    fn upload_from(mut data_channel: DataChannel, config: &ClientConfig) -> Result<TFTPClient, TransferError> {
+        let file_name = data_channel.file_name().to_string();
+        if config.resume && config.mode == TransferMode::Netascii {
+            return Err(TransferError::Local(String::from("Can't resume a netascii upload")));
+        }
+        data_channel.set_transfer_mode(config.mode)
+            .map_err(|e| TransferError::Local(format!("cannot open {} for upload: {}", file_name, e)))?;
+        if config.keepalive {
+            data_channel.read_in_background(ABORT_POLL);
+        }
+
+        let mut wrq = WriteRequestPacket::new(&file_name, config.mode.as_str());
+        if let Some(block_size) = config.requested_block_size() {
+            wrq = wrq.with_option("blksize", &block_size.to_string());
+        }
+        if config.append {
+            wrq = wrq.with_option("append", "1");
+        }
+        if config.resume {
+            wrq = wrq.with_option("resume", "1");
+        }
+        if config.strict_rfc {
+            wrq = wrq.without_options();
+        }
+        let offered_block_size = match config.requested_block_size() {
+            Some(block_size) if !config.strict_rfc => block_size,
+            _ => STRIDE_SIZE,
+        };
+        let wrq = Box::new(wrq);
+        Ok(TFTPClient {
+            packet_buffer: Some(wrq.serialize()),
+            data_channel,
+            error: None,
+            offered_block_size,
+        })
+    }
 
-        let packet_at_hand = self.data_channel.packet_at_hand();
-        if packet_at_hand.is_none() {
+    /// Returns the next packet to be sent to the server, the
+    /// request goes first. Nothing is returned when the last
+    /// packet received needs no answer.
+    pub fn get_next_packet(&mut self) -> Option<&[u8]> {
+        if self.packet_buffer.is_some() {
             // RRQ / WRQ are managed here.
-            return mem::replace(&mut self.packet_buffer, None).unwrap();
+            return self.packet_buffer.as_deref();
         }
 
-        packet_at_hand.unwrap()
+        self.data_channel.packet_at_hand()
     }
 
     /// Tells whether the client's packet buffer
@@ -82,21 +519,67 @@ impl TFTPClient {
     /// Facade to client logic, parses the given buffer to a TFTP packet
     /// then acts accordingly.
     pub fn process_packet(&mut self, buf: &[u8]) {
-        let packet = crate::tftp::shared::parse_udp_packet(&buf);
+        let packet = match parse_udp_packet(&buf) {
+            Ok(packet) => packet,
+            Err(e) => {
+                // Whatever it was, it's retransmitted or times out.
+                warn!("Dropping malformed packet: {}", e);
+                return;
+            }
+        };
+
+        // On error the ERR packet is at hand, it's sent to the server next.
         match packet {
-            TFTPPacket::DATA(data) => {
-                self.data_channel.on_data(data);
+            TFTPPacket::ERR(err) => self.on_err(err),
+            // It's answered by DATA #1 for an upload and ACK #0 for a download.
+            TFTPPacket::OACK(oack) => {
+                // A repeated OACK that comes once blocks moved is too
+                // late to change anything, the transfer goes on.
+                if self.data_channel.transferred_bytes() > 0 {
+                    debug!("Ignoring a repeated OACK");
+                    return;
+                }
+                self.accept_options(&oack);
+                if !self.data_channel.is_err() {
+                    let _ = self.data_channel.handle(TFTPPacket::OACK(oack));
+                }
             }
-            TFTPPacket::ACK(ack) => {
-                self.data_channel.on_ack(ack);
+            packet => {
+                let _ = self.data_channel.handle(packet);
             }
-            TFTPPacket::ERR(err) => self.on_err(err),
-            t => panic!(format!("Unexpected packet type: [{:?}]", t)),
         };
     }
 
+    /// Applies the options the server accepted to the transfer.
+    fn accept_options(&mut self, oack: &OptionAckPacket) {
+        debug!("Server accepted {:?}", oack.options());
+        let block_size = oack.option("blksize")
+            .and_then(|size| size.parse::<usize>().ok())
+            .filter(|size| (1..=DATA_MAX_LEN).contains(size));
+        if let Some(block_size) = block_size {
+            // A server may only lower the block size of an upload (RFC 2348),
+            // downloads take larger blocks for servers with a minimum.
+            if self.data_channel.mode() == DataChannelMode::Tx && block_size > self.offered_block_size {
+                let msg = format!("Server asked for {} byte blocks, {} were offered", block_size, self.offered_block_size);
+                self.data_channel.refuse_options(&msg);
+                return;
+            }
+            self.data_channel.set_block_size(block_size);
+        }
+
+        // The server kept this much of an interrupted upload.
+        if self.data_channel.mode() == DataChannelMode::Tx {
+            if let Some(kept) = oack.option("resume").and_then(|kept| kept.parse().ok()) {
+                info!("Resuming after {} bytes.", kept);
+                self.data_channel.skip(kept);
+            }
+        }
+    }
+
     pub fn on_packet_sent(&mut self) {
-        self.data_channel.on_packet_sent();
+        if self.packet_buffer.take().is_none() {
+            self.data_channel.on_packet_sent();
+        }
     }
 
     /// Returns true if the client entered an error
@@ -107,70 +590,2055 @@ impl TFTPClient {
 
     /// Number of bytes transferred.
     fn transferred_bytes(&self) -> u64 {
-        self.transfer_size
+        self.data_channel.transferred_bytes()
     }
 
-    /// Extracts the error message from the client.
-    fn get_err(self) -> String {
-        self.error.unwrap()
+    /// Block number the client is waiting for.
+    fn blk(&self) -> u16 {
+        self.data_channel.blk()
+    }
+
+    /// Extracts the error from the client.
+    fn get_err(&mut self) -> TransferError {
+        if let Some(err) = self.data_channel.error() {
+            return TransferError::Protocol(err.to_string());
+        }
+
+        TransferError::Server(self.error.take().unwrap())
     }
 
     /// Set the error state for the client.
     fn on_err(&mut self, err: ErrorPacket) {
-        self.error = Some(String::from(err.err()));
+        self.error = Some(err);
     }
 }
 
-fn check_done(client: &TFTPClient) {
-    if client.is_done() {
-        let size = convert(client.transferred_bytes() as f64);
-        println!("{} bytes transferred successfully.", size);
-        exit(0);
-    }
+/// Runs a whole transfer against the server.
+///
+/// # Example
+///
+/// ```no_run
+/// use tftpeer::tftp::client::{transfer, ClientConfig};
+/// use tftpeer::tftp::shared::transfer_mode::TransferMode;
+///
+/// // Text files get the line endings of this host.
+/// let config = ClientConfig {
+///     mode: TransferMode::Netascii,
+///     ..ClientConfig::default()
+/// };
+/// let stats = transfer("10.0.0.1:69", "notes.txt", false, &config).unwrap();
+/// println!("{} bytes", stats.bytes);
+/// ```
+pub fn transfer(server_address: &str, filename: &str, upload: bool, config: &ClientConfig) -> Result<TransferStats, TransferError> {
+    let (transport, server_address) = connect(server_address, config)?;
+    transfer_with(&transport, server_address, filename, upload, config)
 }
 
-/// Entry point for TFTP client.
-pub fn client_main(server_address: &str, filename: &str, upload: bool) -> std::io::Result<()> {
+/// Makes the socket a transfer with the server goes through.
+fn connect(server_address: &str, config: &ClientConfig) -> Result<(UdpTransport, SocketAddr), TransferError> {
     // Make a UDPSocket on any port on localhost.
-    let sock = UdpSocket::bind("0.0.0.0:58955")?;
+    let sock = UdpSocket::bind("0.0.0.0:0")?;
+    sock.set_read_timeout(Some(config.timeout.min(ABORT_POLL)))?;
+    if let Some(dscp) = config.dscp {
+        set_dscp(&sock, dscp)?;
+    }
+    set_buffer_sizes(&sock, config.recv_buffer, config.send_buffer)?;
+    info!("[CLIENT_ADDRESS]: {}", sock.local_addr().unwrap());
+
+    let server_address: SocketAddr = server_address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "Invalid server address"))?;
 
-    let mut server_address = server_address.to_string();
+    Ok((UdpTransport::new(sock), server_address))
+}
 
-    let mut client = if upload {
-        println!("Uploading...");
-        TFTPClient::upload(filename)
+/// Runs a whole transfer over the given transport, its reads should
+/// time out often enough for aborts and retransmissions to be timely.
+pub fn transfer_with<T: Transport>(
+    transport: &T,
+    server_address: SocketAddr,
+    filename: &str,
+    upload: bool,
+    config: &ClientConfig,
+) -> Result<TransferStats, TransferError> {
+    let start = || if upload {
+        TFTPClient::upload(filename, config)
     } else {
-        println!("Downloading...");
-        TFTPClient::download(filename)
+        TFTPClient::download(filename, config)
+    };
+
+    let attempt = || match run(start()?, transport, server_address, config) {
+        Err(TransferError::Rejected(mode, ep)) if config.follow_redirect => match ep.redirect_target() {
+            Some(other) => {
+                info!("Redirected to [{}]", other);
+                run(start()?, transport, other, config)
+            }
+            None => Err(TransferError::Rejected(mode, ep)),
+        },
+        result => result,
     };
 
-    println!("[CLIENT_ADDRESS]: {}", sock.local_addr().unwrap());
+    let deadline = config.wait_for_file.map(|wait| config.clock.now() + wait);
+    let stats = loop {
+        match attempt() {
+            // Any other error is final, waiting won't change it.
+            Err(e) if !upload
+                && e.tftp_code() == Some(TFTPError::FileNotFound.code())
+                && deadline.map_or(false, |deadline| config.clock.now() < deadline) => {
+                debug!("{} isn't on the server yet, asking again", filename);
+                thread::sleep(WAIT_FOR_FILE_POLL);
+            }
+            result => break result?,
+        }
+    };
+    if let (false, Some(bits)) = (upload, config.mode_bits) {
+        let local = config.local_path(filename)?;
+        set_mode_bits(&local, bits)
+            .map_err(|e| TransferError::Local(format!("cannot set the mode of {}: {}", local.display(), e)))?;
+    }
+
+    Ok(stats)
+}
+
+/// Uploads whatever `source` yields as `filename`, no local file is needed.
+/// The size isn't known upfront, the first short block ends the upload.
+/// It can't be retried against a server that redirects it.
+///
+/// # Example
+///
+/// ```no_run
+/// use tftpeer::tftp::client::{upload_reader, ClientConfig};
+///
+/// let config = ClientConfig::default();
+/// let report = std::io::Cursor::new(b"uptime: 42 days\n".to_vec());
+/// upload_reader("10.0.0.1:69", "report.txt", report, &config).unwrap();
+/// ```
+pub fn upload_reader<R: Read + Send + 'static>(server_address: &str, filename: &str, source: R, config: &ClientConfig) -> Result<TransferStats, TransferError> {
+    let (transport, server_address) = connect(server_address, config)?;
+    upload_reader_with(&transport, server_address, filename, source, config)
+}
+
+/// Uploads from a reader over the given transport, see `upload_reader`.
+pub fn upload_reader_with<T: Transport, R: Read + Send + 'static>(
+    transport: &T,
+    server_address: SocketAddr,
+    filename: &str,
+    source: R,
+    config: &ClientConfig,
+) -> Result<TransferStats, TransferError> {
+    let data_channel = DataChannel::from_reader(filename, Box::new(source), DataChannelOwner::Client);
+    let client = TFTPClient::upload_from(data_channel, config)?;
+    run(client, transport, server_address, config)
+}
+
+/// Downloads a file block by block instead of saving it, each block is
+/// yielded once it's acknowledged. The transfer moves along as the
+/// iterator is driven, it has to be driven to the end. It can be stopped
+/// from another thread through `ClientConfig::cancellation_token`.
+///
+/// # Example
+///
+/// ```no_run
+/// use tftpeer::tftp::client::{stream, ClientConfig};
+///
+/// let config = ClientConfig::default();
+/// let mut content = Vec::new();
+/// for block in stream("10.0.0.1:69", "router.cfg", &config).unwrap() {
+///     content.extend(block.unwrap());
+/// }
+/// ```
+pub fn stream<'a>(server_address: &str, filename: &str, config: &'a ClientConfig) -> Result<BlockStream<'a, UdpTransport>, TransferError> {
+    let (transport, server_address) = connect(server_address, config)?;
+    stream_with(transport, server_address, filename, config)
+}
+
+/// Downloads a file block by block over the given transport, see `stream`.
+pub fn stream_with<'a, T: Transport>(
+    transport: T,
+    server_address: SocketAddr,
+    filename: &str,
+    config: &'a ClientConfig,
+) -> Result<BlockStream<'a, T>, TransferError> {
+    let client = TFTPClient::stream(filename, config)?;
+    let session = Session::start(client, transport, server_address, config)?;
+    Ok(BlockStream {
+        session: Some(session),
+    })
+}
+
+/// Blocks of a download in the order they're received, made by `stream`.
+/// Empty blocks aren't yielded, the iterator ends with the transfer or
+/// after the first error.
+pub struct BlockStream<'a, T: Transport> {
+    session: Option<Session<'a, T>>,
+}
+
+impl<'a, T: Transport> Iterator for BlockStream<'a, T> {
+    type Item = Result<Vec<u8>, TransferError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let session = self.session.as_mut()?;
+        loop {
+            let result = session.step();
+            let block = session.client.data_channel.take_block();
+            match result {
+                Ok(None) => match block {
+                    Some(block) if !block.is_empty() => return Some(Ok(block)),
+                    _ => continue,
+                },
+                Ok(Some(_)) => {
+                    self.session = None;
+                    return block.filter(|block| !block.is_empty()).map(Ok);
+                }
+                Err(e @ TransferError::Aborted) => {
+                    let session = self.session.take().unwrap();
+                    return session.abort(e).err().map(Err);
+                }
+                Err(e) => {
+                    self.session = None;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// Uploads the directory `dir` packed as a tar archive, which is made as
+/// it's sent. The server stores it as a single file named `dir.tar`.
+///
+/// # Example
+///
+/// ```no_run
+/// use tftpeer::tftp::client::{upload_dir, ClientConfig};
+///
+/// upload_dir("10.0.0.1:69", "boot", &ClientConfig::default()).unwrap();
+/// ```
+pub fn upload_dir(server_address: &str, dir: &str, config: &ClientConfig) -> Result<TransferStats, TransferError> {
+    let source = tar_stream::pack(Path::new(dir)).map_err(|e| match e.kind() {
+        ErrorKind::NotFound => TransferError::LocalNotFound(e.to_string()),
+        _ => TransferError::Local(format!("cannot pack {}: {}", dir, e)),
+    })?;
+    upload_reader(server_address, &tar_stream::archive_name(dir), source, config)
+}
+
+/// Downloads `dir.tar` and unpacks it into the directory `dir` as it comes
+/// in, the server may have it as a file or pack the directory on the fly.
+pub fn download_dir(server_address: &str, dir: &str, config: &ClientConfig) -> Result<TransferStats, TransferError> {
+    let started = config.clock.now();
+    let mut reader = BlockReader {
+        blocks: stream(server_address, &tar_stream::archive_name(dir), config)?,
+        block: io::Cursor::new(Vec::new()),
+        bytes: 0,
+        error: None,
+    };
+
+    // The archive may end before the stream does, the rest is padding.
+    let unpacked = tar_stream::unpack(&mut reader, Path::new(dir))
+        .and_then(|_| io::copy(&mut reader, &mut io::sink()));
+    if let Some(e) = reader.error.take() {
+        return Err(e);
+    }
+    if let Err(e) = unpacked {
+        let e = TransferError::Local(format!("cannot unpack into {}: {}", dir, e));
+        return match reader.blocks.session.take() {
+            Some(session) => session.abort(e),
+            None => Err(e),
+        };
+    }
+
+    Ok(TransferStats {
+        bytes: reader.bytes,
+        duration: config.clock.now() - started,
+        rtt: None,
+    })
+}
 
+/// Reads the blocks of a download as they're received.
+struct BlockReader<'a, T: Transport> {
+    blocks: BlockStream<'a, T>,
+    block: io::Cursor<Vec<u8>>,
+    bytes: u64,
+    /// Why the download failed, reads only report it as an I/O error.
+    error: Option<TransferError>,
+}
+
+impl<'a, T: Transport> Read for BlockReader<'a, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.block.position() == self.block.get_ref().len() as u64 {
+            match self.blocks.next() {
+                Some(Ok(block)) => {
+                    self.bytes += block.len() as u64;
+                    self.block = io::Cursor::new(block);
+                }
+                Some(Err(e)) => {
+                    let err = io::Error::new(ErrorKind::Other, e.to_string());
+                    self.error = Some(e);
+                    return Err(err);
+                }
+                None => return Ok(0),
+            }
+        }
+
+        self.block.read(buf)
+    }
+}
+
+#[cfg(unix)]
+fn set_mode_bits(file_name: &Path, bits: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::set_permissions(file_name, fs::Permissions::from_mode(bits))
+}
+
+#[cfg(not(unix))]
+fn set_mode_bits(file_name: &Path, bits: u32) -> io::Result<()> {
+    warn!("Mode bits {:o} aren't supported here, {} keeps its permissions", bits, file_name.display());
+    Ok(())
+}
+
+fn run<T: Transport>(
+    client: TFTPClient,
+    transport: &T,
+    server_address: SocketAddr,
+    config: &ClientConfig,
+) -> Result<TransferStats, TransferError> {
+    let mut session = Session::start(client, transport, server_address, config)?;
     loop {
-        let mut buf = [0; 1024];
+        match session.step() {
+            Ok(None) => continue,
+            Ok(Some(stats)) => return Ok(stats),
+            Err(e @ TransferError::Aborted) => return session.abort(e),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// A transfer under way, it's moved along one read of the transport at a time.
+struct Session<'a, T: Transport> {
+    client: TFTPClient,
+    transport: T,
+    server_address: SocketAddr,
+    config: &'a ClientConfig,
+    started: Instant,
+    last_packet: Vec<u8>,
+    sent_at: Instant,
+    /// Whether the packet sent last awaits its answer, it's timed
+    /// unless it had to be sent again.
+    unanswered: bool,
+    rtt: RttSamples,
+    retries: u32,
+    /// Whether anything was heard from the server yet.
+    answered: bool,
+    /// Address the server answered from, the transfer ID it's known by.
+    /// It's taken from the first answer, others are turned away after.
+    tid: Option<SocketAddr>,
+    /// Whether the request carried options, only then may an OACK answer it.
+    asked_options: bool,
+    buf: Vec<u8>,
+}
+
+impl<'a, T: Transport> Session<'a, T> {
+    /// Sends the request of the client to the server.
+    fn start(mut client: TFTPClient, transport: T, server_address: SocketAddr, config: &'a ClientConfig) -> Result<Self, TransferError> {
+        let started = config.clock.now();
+        let last_packet = client.get_next_packet().unwrap().to_vec();
+        send_retrying(&transport, &last_packet, server_address)?;
+        client.on_packet_sent();
+        let asked_options = match parse_udp_packet(&last_packet) {
+            Ok(TFTPPacket::RRQ(rrq)) => !rrq.options().is_empty(),
+            Ok(TFTPPacket::WRQ(wrq)) => !wrq.options().is_empty(),
+            _ => false,
+        };
+
+        Ok(Session {
+            client,
+            transport,
+            server_address,
+            config,
+            started,
+            last_packet,
+            sent_at: config.clock.now(),
+            unanswered: true,
+            rtt: RttSamples::default(),
+            retries: 0,
+            answered: false,
+            tid: None,
+            asked_options,
+            buf: Vec::new(),
+        })
+    }
+
+    /// Handles the next packet from the server, or the lack of one when the
+    /// read times out. Returns the stats once the transfer is over, an abort
+    /// is reported as an error and carried out by `abort`.
+    fn step(&mut self) -> Result<Option<TransferStats>, TransferError> {
+        let clock = &self.config.clock;
+        let config = self.config;
+        let client = &mut self.client;
+
+        self.buf.resize(recv_buffer_len(client.data_channel.block_size()), 0);
+        let (count, addr) = match self.transport.recv_from(&mut self.buf) {
+            Ok(received) => received,
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                check_stopped(config)?;
+
+                // The server has answered, it's the source that's slow.
+                if client.data_channel.awaits_source() {
+                    if let Some(next_packet) = client.get_next_packet() {
+                        self.last_packet.clear();
+                        self.last_packet.extend_from_slice(next_packet);
+                        send_retrying(&self.transport, &self.last_packet, self.server_address)?;
+                        self.sent_at = clock.now();
+                        self.unanswered = true;
+                        client.on_packet_sent();
+                    } else if client.data_channel.is_err() {
+                        return Err(client.get_err());
+                    } else if client.blk() > 1 && clock.now() - self.sent_at >= config.timeout / 2 {
+                        debug!("Source is stalled, repeating block #{}", client.blk() - 1);
+                        send_retrying(&self.transport, &self.last_packet, self.server_address)?;
+                        self.sent_at = clock.now();
+                        self.unanswered = false;
+                    }
+                    return Ok(None);
+                }
+
+                if clock.now() - self.sent_at < config.retransmit_timeout(self.answered, self.retries) {
+                    return Ok(None);
+                }
+
+                if self.retries == config.retries {
+                    return Err(TransferError::Timeout(client.blk()));
+                }
+
+                self.retries += 1;
+                send_retrying(&self.transport, &self.last_packet, self.server_address)?;
+                self.sent_at = clock.now();
+                self.unanswered = false;
+                return Ok(None);
+            }
+            Err(e) => return Err(e.into()),
+        };
 
+        let opening = self.tid.is_none();
+        match self.tid {
+            // Someone else is told off, their packet has no say in the transfer.
+            Some(tid) if addr != tid => {
+                warn!("Dropping a packet from unknown [{}]", addr);
+                send_retrying(&self.transport, &ErrorPacket::new(TFTPError::UnknownTID).serialize(), addr)?;
+                return Ok(None);
+            }
+            Some(_) => {}
+            None => {
+                let answers = match config.tid_policy {
+                    TidPolicy::FirstReply => {
+                        opens_transfer(&self.buf[..count], client.data_channel.mode(), addr.ip() == self.server_address.ip())
+                    }
+                    TidPolicy::Strict => addr.ip() == self.server_address.ip()
+                        && answers_request(&self.buf[..count], client.data_channel.mode(), self.asked_options),
+                };
+                if !answers {
+                    warn!("Dropping a packet from [{}] that doesn't answer the request", addr);
+                    return Ok(None);
+                }
+
+                // The server opens a UDP socket for each new client.
+                // that's why we need to change the address to send
+                // data to, otherwise we'll get an error from the
+                // server. I didn't notice that on the first time I
+                // tried and was getting an error, inspecting src/dst
+                // port revealed that. (and it's mentioned in the RFC)
+                // Single port servers keep answering from where the
+                // request went, either way the first answer settles it.
+                if addr != self.server_address {
+                    debug!("Server moved the transfer to [{}]", addr);
+                }
+                self.tid = Some(addr);
+                self.server_address = addr;
+            }
+        }
+        self.retries = 0;
+        self.answered = true;
+        if mem::take(&mut self.unanswered) {
+            self.rtt.record(clock.now() - self.sent_at);
+        }
+
+        // A server's error is reported even if its message overflows the
+        // buffer, the cut off message still tells why the transfer ended.
+        let is_err = matches!(parse_udp_packet(&self.buf[..count]), Ok(TFTPPacket::ERR(_)));
+        if count == self.buf.len() && !is_err {
+            let ep = ErrorPacket::with_message(TFTPError::IllegalOperation, "Packet is larger than the block size.");
+            send_retrying(&self.transport, &ep.serialize(), self.server_address)?;
+            return Err(TransferError::Protocol(String::from("Received a packet larger than the block size")));
+        }
+
+        client.process_packet(&self.buf[..count]);
         if client.is_err() {
-            eprintln!("[ERROR] {}", client.get_err());
-            exit(-3);
+            return Err(match client.get_err() {
+                // The server turned the request down, nothing was transferred.
+                TransferError::Server(ep) if opening => TransferError::Rejected(client.data_channel.mode(), ep),
+                e => e,
+            });
         }
 
-        let next_packet = &client.get_next_packet();
+        check_stopped(config)?;
 
-        sock.send_to(next_packet, server_address)?;
-        client.on_packet_sent();
+        if let Some(next_packet) = client.get_next_packet() {
+            self.last_packet.clear();
+            self.last_packet.extend_from_slice(next_packet);
+            send_retrying(&self.transport, &self.last_packet, self.server_address)?;
+            self.sent_at = clock.now();
+            self.unanswered = true;
+            client.on_packet_sent();
+        }
+
+        if client.data_channel.is_err() {
+            return Err(client.get_err());
+        }
+
+        // Download ends when sending the last ACK,
+        // upload ends when receiving the last ACK.
+        if client.is_done() {
+            let stats = TransferStats {
+                bytes: client.transferred_bytes(),
+                duration: clock.now() - self.started,
+                rtt: self.rtt.summary(),
+            };
+            if client.data_channel.mode() == DataChannelMode::Rx && config.dally > Duration::from_secs(0) {
+                dally(&self.transport, self.server_address, &self.last_packet, config.dally);
+            }
+            return Ok(Some(stats));
+        }
+
+        Ok(None)
+    }
+
+    /// Tells the server the transfer is over and drops what was downloaded
+    /// so far, then returns `reason`.
+    fn abort(self, reason: TransferError) -> Result<TransferStats, TransferError> {
+        let message = match reason {
+            TransferError::Aborted => "transfer aborted by user",
+            _ => "transfer cancelled",
+        };
+        let ep = ErrorPacket::with_message(TFTPError::UndefinedError, message);
+        send_retrying(&self.transport, &ep.serialize(), self.server_address)?;
+
+        let client = self.client;
+        let partial_download = match client.data_channel.mode() {
+            DataChannelMode::Rx if client.transferred_bytes() > 0 && !self.config.resume && !client.data_channel.holds_blocks() => {
+                Some(client.data_channel.file_name().to_string())
+            }
+            _ => None,
+        };
+        drop(client);
+
+        if let Some(file_name) = partial_download {
+            fs::remove_file(file_name)?;
+        }
+
+        Err(reason)
+    }
+}
+
+/// Whether a packet is one the server answers a request with: an OACK,
+/// an error, DATA #1 for a download or ACK #0 for an upload. An OACK or
+/// an error would end or reshape the transfer, they're only taken from
+/// the IP the request went to.
+fn opens_transfer(buf: &[u8], mode: DataChannelMode, from_server_ip: bool) -> bool {
+    match (parse_udp_packet(buf), mode) {
+        (Ok(TFTPPacket::OACK(_)), _) | (Ok(TFTPPacket::ERR(_)), _) => from_server_ip,
+        (Ok(TFTPPacket::DATA(dp)), DataChannelMode::Rx) => dp.blk() == 1,
+        (Ok(TFTPPacket::ACK(ap)), DataChannelMode::Tx) => ap.blk() == 0,
+        _ => false,
+    }
+}
+
+/// Whether a packet is the exact answer to the request, like `opens_transfer`
+/// but an OACK only answers a request with options. The packet is taken
+/// to come from the server's IP.
+fn answers_request(buf: &[u8], mode: DataChannelMode, asked_options: bool) -> bool {
+    match parse_udp_packet(buf) {
+        Ok(TFTPPacket::OACK(_)) => asked_options,
+        _ => opens_transfer(buf, mode, true),
+    }
+}
+
+/// Whether the user aborted the transfer or it was cancelled through its token.
+fn check_stopped(config: &ClientConfig) -> Result<(), TransferError> {
+    if config.abort.load(Ordering::SeqCst) {
+        return Err(TransferError::Aborted);
+    }
+
+    Ok(())
+}
+
+/// Entry point for TFTP client.
+pub fn client_main(server_address: &str, filename: &str, upload: bool, config: ClientConfig) {
+    if upload {
+        info!("Uploading...");
+    } else {
+        info!("Downloading...");
+    }
+
+    let result = match (config.tar, upload) {
+        (true, true) => upload_dir(server_address, filename, &config),
+        (true, false) => download_dir(server_address, filename, &config),
+        (false, _) => transfer(server_address, filename, upload, &config),
+    };
+    match result {
+        Ok(stats) if config.json => println!("{}", stats.to_json()),
+        Ok(stats) if config.porcelain => println!("{}", stats.bytes),
+        Ok(stats) => {
+            let size = convert(stats.bytes as f64);
+            info!("{} bytes transferred successfully.", size);
+            if let Some(rtt) = stats.rtt {
+                debug!("Block round trips: min {:?}, median {:?}, p99 {:?}", rtt.min, rtt.median, rtt.p99);
+            }
+        }
+        Err(e) => {
+            if config.json {
+                println!("{}", e.to_json());
+            } else {
+                error!("[ERROR] {}", e);
+            }
+            exit(e.exit_code())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::collections::VecDeque;
+    use std::fs;
+    use std::io;
+    use std::io::Read;
+    use std::net::{SocketAddr, UdpSocket};
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use serde_json::Value;
+
+    use crate::tftp::client::{answers_request, download_dir, run, stream, transfer, transfer_with, upload_dir, upload_reader, upload_reader_with, ClientConfig, TFTPClient, TidPolicy, TransferError, TransferStats, ABORT_POLL};
+    use crate::tftp::shared::clock::Clock;
+    use crate::tftp::shared::{parse_udp_packet, Serializable, TFTPPacket};
+    use crate::tftp::shared::data_channel::{DataChannel, DataChannelMode, DataChannelOwner, STALL_LIMIT};
+    use crate::tftp::shared::ack_packet::AckPacket;
+    use crate::tftp::shared::data_packet::DataPacket;
+    use crate::tftp::shared::oack_packet::OptionAckPacket;
+    use crate::tftp::server::{ServerConfig, TFTPListener};
+    use crate::tftp::shared::err_packet::{ErrorPacket, TFTPError};
+    use crate::tftp::shared::test_util::{scratch_dir, MockClock, ScriptedServer};
+    use crate::tftp::shared::request_packet::{ReadRequestPacket, Request};
+    use crate::tftp::shared::transfer_mode::TransferMode;
+    use crate::tftp::shared::transport::{Transport, UdpTransport};
+
+    #[test]
+    fn custom_server_error_is_surfaced() {
+        let dir = scratch_dir("client_custom_error");
+        let file_name = dir.join("a.txt");
+        let mut client = TFTPClient::download(file_name.to_str().unwrap(), &ClientConfig::default()).unwrap();
+
+        let ep = ErrorPacket::new_custom(String::from("Quota exceeded for guests."));
+        client.process_packet(&ep.serialize());
+
+        assert!(client.is_err());
+        assert_eq!(client.get_err().to_string(), "Quota exceeded for guests.");
+    }
+
+    /// Serves a two block download, ignoring the first `drops`
+    /// ACKs of block #1 as if they were lost.
+    fn spawn_lossy_server(drops: usize) -> SocketAddr {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = sock.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let mut buf = [0; 1024];
+            let (_, client) = sock.recv_from(&mut buf).unwrap();
+            sock.send_to(&DataPacket::new(1, vec![1; 512]).serialize(), client).unwrap();
+
+            let mut acks = 0;
+            loop {
+                let (count, _) = sock.recv_from(&mut buf).unwrap();
+                match parse_udp_packet(&buf[..count]).unwrap() {
+                    TFTPPacket::ACK(ap) if ap.blk() == 1 => acks += 1,
+                    _ => return,
+                }
+
+                if acks > drops {
+                    sock.send_to(&DataPacket::new(2, vec![2; 10]).serialize(), client).unwrap();
+                    return;
+                }
+            }
+        });
+
+        addr
+    }
+
+    /// Serves a two block download from the socket the request came to,
+    /// or from a new one like RFC 1350 asks. A stranger sends a bogus block
+    /// #2 once block #1 is acknowledged, the reply it gets is returned.
+    fn spawn_tid_server(switch_port: bool) -> (SocketAddr, thread::JoinHandle<TFTPPacket>) {
+        let listen = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = listen.local_addr().unwrap();
+
+        let stranger = thread::spawn(move || {
+            let mut buf = [0; 1024];
+            let (_, client) = listen.recv_from(&mut buf).unwrap();
+            let sock = if switch_port {
+                UdpSocket::bind("127.0.0.1:0").unwrap()
+            } else {
+                listen
+            };
+            sock.send_to(&DataPacket::new(1, vec![1; 512]).serialize(), client).unwrap();
+            let (count, _) = sock.recv_from(&mut buf).unwrap();
+            assert!(matches!(parse_udp_packet(&buf[..count]).unwrap(), TFTPPacket::ACK(ap) if ap.blk() == 1));
+
+            let stranger = UdpSocket::bind("127.0.0.1:0").unwrap();
+            stranger.send_to(&DataPacket::new(2, vec![9; 5]).serialize(), client).unwrap();
+            let (count, _) = stranger.recv_from(&mut buf).unwrap();
+
+            sock.send_to(&DataPacket::new(2, vec![2; 10]).serialize(), client).unwrap();
+            parse_udp_packet(&buf[..count]).unwrap()
+        });
+
+        (addr, stranger)
+    }
+
+    /// Downloads from a scripted server into `dir`, checking the file.
+    fn download_from(dir: &str, server: ScriptedServer, content: &[u8], config: &ClientConfig) -> Vec<u16> {
+        let (addr, run) = server.spawn();
+        let file_name = scratch_dir(dir).join("a.bin");
+        transfer(&addr.to_string(), file_name.to_str().unwrap(), false, config).unwrap();
+
+        let run = run.join().unwrap();
+        assert_eq!(fs::read(&file_name).unwrap(), content);
+        assert_eq!(run.timeouts, 0);
+        run.acks
+    }
+
+    #[test]
+    fn blocks_older_than_the_last_one_are_ignored() {
+        let content: Vec<u8> = (0..2148).map(|i| i as u8).collect();
+        let server = ScriptedServer::new(content.clone()).replay_after(3, 1).replay_after(4, 2);
+
+        let acks = download_from("scripted_stale", server, &content, &ClientConfig::default());
+        assert_eq!(acks, vec![1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn duplicated_blocks_are_acknowledged_again() {
+        let content = vec![7; 1536];
+        let server = ScriptedServer::new(content.clone()).replay_after(3, 3);
+
+        let acks = download_from("scripted_repeat", server, &content, &ClientConfig::default());
+        assert_eq!(acks, vec![1, 2, 3, 3, 4]);
+    }
+
+    #[test]
+    fn late_blocks_are_waited_for() {
+        let content = vec![3; 1000];
+        let server = ScriptedServer::new(content.clone()).delay(2, Duration::from_millis(350));
+        let config = ClientConfig {
+            timeout: Duration::from_millis(100),
+            ..ClientConfig::default()
+        };
+
+        let acks = download_from("scripted_late", server, &content, &config);
+        // ACK #1 is repeated while the block is held back, then the transfer goes on.
+        assert!(acks.iter().filter(|&&blk| blk == 1).count() > 1);
+        assert_eq!(acks.last(), Some(&2));
+    }
+
+    #[test]
+    fn block_round_trips_are_timed() {
+        let latency = Duration::from_millis(50);
+        let content = vec![5; 2000];
+        let (addr, run) = (1..=4)
+            .fold(ScriptedServer::new(content), |server, blk| server.delay(blk, latency))
+            .spawn();
+
+        let file_name = scratch_dir("block_rtt").join("a.bin");
+        let stats = transfer(&addr.to_string(), file_name.to_str().unwrap(), false, &ClientConfig::default()).unwrap();
+        run.join().unwrap();
+
+        let rtt = stats.rtt.unwrap();
+        assert!(rtt.min >= latency, "{:?}", rtt);
+        assert!(rtt.median < latency * 3, "{:?}", rtt);
+        assert!(stats.to_json()["rtt_ms"]["median"].as_f64().unwrap() >= 50.0);
+    }
+
+    /// Serves a one block download, a host off the path slips in `spoofed`
+    /// before the server answers. Returns what was downloaded.
+    // Linux routes all of 127.0.0.0/8 to the loopback interface.
+    #[cfg(target_os = "linux")]
+    fn spoofed_download(dir: &str, tid_policy: TidPolicy, spoofed: Vec<u8>) -> Vec<u8> {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = sock.local_addr().unwrap();
+        thread::spawn(move || {
+            let (_, client) = sock.recv_from(&mut [0; 1024]).unwrap();
+            let spoofer = UdpSocket::bind("127.0.0.2:0").unwrap();
+            spoofer.send_to(&spoofed, client).unwrap();
+            thread::sleep(Duration::from_millis(50));
+            sock.send_to(&DataPacket::new(1, b"genuine".to_vec()).serialize(), client).unwrap();
+        });
+
+        let file_name = scratch_dir(dir).join("a.txt");
+        let config = ClientConfig {
+            tid_policy,
+            ..ClientConfig::default()
+        };
+        transfer(&addr.to_string(), file_name.to_str().unwrap(), false, &config).unwrap();
+        fs::read(&file_name).unwrap()
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn first_reply_takes_a_spoofed_early_answer() {
+        let spoofed = DataPacket::new(1, b"spoofed".to_vec()).serialize();
+        assert_eq!(spoofed_download("tid_first_reply", TidPolicy::FirstReply, spoofed), b"spoofed");
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn first_reply_drops_a_spoofed_early_error() {
+        let spoofed = ErrorPacket::new(TFTPError::FileNotFound).serialize();
+        assert_eq!(spoofed_download("tid_spoofed_error", TidPolicy::FirstReply, spoofed), b"genuine");
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn strict_tid_drops_a_spoofed_early_answer() {
+        let spoofed = DataPacket::new(1, b"spoofed".to_vec()).serialize();
+        assert_eq!(spoofed_download("tid_strict", TidPolicy::Strict, spoofed), b"genuine");
+    }
+
+    #[test]
+    fn strict_tid_takes_an_oack_only_for_options() {
+        let oack = OptionAckPacket::new(vec![(String::from("blksize"), String::from("1024"))]).serialize();
+        assert!(answers_request(&oack, DataChannelMode::Rx, true));
+        assert!(!answers_request(&oack, DataChannelMode::Rx, false));
+        assert!(answers_request(&DataPacket::new(1, vec![1]).serialize(), DataChannelMode::Rx, false));
+        assert!(!answers_request(&DataPacket::new(2, vec![1]).serialize(), DataChannelMode::Rx, false));
+    }
+
+    #[test]
+    fn single_port_servers_keep_the_transfer() {
+        let file_name = scratch_dir("tid_single_port").join("a.bin");
+        let (addr, stranger) = spawn_tid_server(false);
+
+        transfer(&addr.to_string(), file_name.to_str().unwrap(), false, &ClientConfig::default()).unwrap();
+
+        assert_eq!(fs::read(&file_name).unwrap(), [vec![1; 512], vec![2; 10]].concat());
+        match stranger.join().unwrap() {
+            TFTPPacket::ERR(ep) => assert_eq!(ep.code(), TFTPError::UnknownTID.code()),
+            p => panic!("Expected an error got opcode {}", p.opcode()),
+        }
+    }
+
+    #[test]
+    fn port_switching_servers_move_the_transfer() {
+        let file_name = scratch_dir("tid_switch_port").join("a.bin");
+        let (addr, stranger) = spawn_tid_server(true);
+
+        transfer(&addr.to_string(), file_name.to_str().unwrap(), false, &ClientConfig::default()).unwrap();
+
+        assert_eq!(fs::read(&file_name).unwrap(), [vec![1; 512], vec![2; 10]].concat());
+        match stranger.join().unwrap() {
+            TFTPPacket::ERR(ep) => assert_eq!(ep.code(), TFTPError::UnknownTID.code()),
+            p => panic!("Expected an error got opcode {}", p.opcode()),
+        }
+    }
+
+    /// The server of `spawn_lossy_server` answering right away, while reads
+    /// that find nothing take their whole timeout on the mock clock.
+    struct LossyScript {
+        clock: Arc<MockClock>,
+        drops: usize,
+        acks: Cell<usize>,
+        replies: RefCell<VecDeque<Vec<u8>>>,
+        /// When each packet was sent by the client.
+        sent_at: RefCell<Vec<Instant>>,
+    }
+
+    const SCRIPT_ADDR: ([u8; 4], u16) = ([127, 0, 0, 1], 6969);
+
+    impl Transport for LossyScript {
+        fn send_to(&self, buf: &[u8], _peer: SocketAddr) -> io::Result<usize> {
+            self.sent_at.borrow_mut().push(self.clock.now());
+            let reply = match parse_udp_packet(buf).unwrap() {
+                TFTPPacket::RRQ(_) => DataPacket::new(1, vec![1; 512]),
+                TFTPPacket::ACK(ap) if ap.blk() == 1 => {
+                    self.acks.set(self.acks.get() + 1);
+                    if self.acks.get() <= self.drops {
+                        return Ok(buf.len());
+                    }
+                    DataPacket::new(2, vec![2; 10])
+                }
+                _ => return Ok(buf.len()),
+            };
+
+            self.replies.borrow_mut().push_back(reply.serialize());
+            Ok(buf.len())
+        }
+
+        fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+            match self.replies.borrow_mut().pop_front() {
+                Some(reply) => {
+                    buf[..reply.len()].copy_from_slice(&reply);
+                    Ok((reply.len(), SocketAddr::from(SCRIPT_ADDR)))
+                }
+                None => {
+                    self.clock.advance(ABORT_POLL);
+                    Err(io::Error::new(io::ErrorKind::WouldBlock, "read timed out"))
+                }
+            }
+        }
+    }
+
+    /// Downloads from a `LossyScript`, returns the outcome along
+    /// with how long after the RRQ each packet was sent.
+    fn scripted_download(name: &str, drops: usize, config: ClientConfig) -> (Result<TransferStats, TransferError>, Vec<Duration>) {
+        let file_name = scratch_dir(name).join("a.txt");
+        let clock = Arc::new(MockClock::new());
+        let script = LossyScript {
+            clock: clock.clone(),
+            drops,
+            acks: Cell::new(0),
+            replies: RefCell::new(VecDeque::new()),
+            sent_at: RefCell::new(Vec::new()),
+        };
+        let config = ClientConfig { clock, ..config };
+
+        let result = transfer_with(&script, SocketAddr::from(SCRIPT_ADDR), file_name.to_str().unwrap(), false, &config);
+        let sent_at = script.sent_at.into_inner();
+        let offsets = sent_at.iter().map(|t| *t - sent_at[0]).collect();
+        (result, offsets)
+    }
+
+    #[test]
+    fn retransmits_within_retry_budget() {
+        let config = ClientConfig { retries: 3, ..ClientConfig::default() };
+        let (result, sent_at) = scripted_download("retries_recover", 2, config);
+
+        assert_eq!(result.unwrap().bytes, 522);
+        let secs = sent_at.iter().map(|t| t.as_secs()).collect::<Vec<_>>();
+        // RRQ, ACK #1 and its two retransmissions, then ACK #2.
+        assert_eq!(secs, vec![0, 0, 5, 10, 10]);
+    }
+
+    #[test]
+    fn gives_up_when_retries_are_exhausted() {
+        let config = ClientConfig { retries: 2, ..ClientConfig::default() };
+        match scripted_download("retries_exhausted", 3, config).0 {
+            Err(TransferError::Timeout(blk)) => assert_eq!(blk, 2),
+            r => panic!("Expected a timeout got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn backoff_spaces_out_retransmissions() {
+        let config = ClientConfig { retries: 4, backoff: true, ..ClientConfig::default() };
+        let (result, sent_at) = scripted_download("backoff_spacing", 4, config);
+
+        assert_eq!(result.unwrap().bytes, 522);
+        let secs = sent_at.iter().map(|t| t.as_secs()).collect::<Vec<_>>();
+        assert_eq!(secs, vec![0, 0, 5, 15, 35, 75, 75]);
+    }
+
+    #[test]
+    fn upload_of_missing_file_is_a_local_error() {
+        let file_name = scratch_dir("upload_missing").join("missing.txt");
+        let file_name = file_name.to_str().unwrap();
+
+        match transfer("127.0.0.1:69", file_name, true, &ClientConfig::default()) {
+            Err(e @ TransferError::LocalNotFound(_)) => {
+                assert_eq!(e.to_string(), format!("cannot open {} for upload: No such file", file_name));
+                assert_eq!(e.exit_code(), 2);
+            }
+            r => panic!("Expected a local error got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn json_output_for_a_finished_transfer() {
+        let stats = scripted_download("json_ok", 0, ClientConfig::default()).0.unwrap();
+
+        let out: Value = serde_json::from_str(&stats.to_json().to_string()).unwrap();
+        assert_eq!(out["status"], "ok");
+        assert_eq!(out["bytes"], 522);
+        assert!(out["duration_ms"].as_u64().is_some());
+        assert!(out["rate_bps"].as_u64().is_some());
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn netascii_download_uses_host_line_endings() {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = sock.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut buf = [0; 1024];
+            let (count, client) = sock.recv_from(&mut buf).unwrap();
+            match parse_udp_packet(&buf[..count]).unwrap() {
+                TFTPPacket::RRQ(rrq) => assert_eq!(rrq.mode(), "netascii"),
+                p => panic!("Expected RRQ got {:?}", p),
+            }
+
+            let text = b"one\r\ntwo\r\0three\r\n".to_vec();
+            sock.send_to(&DataPacket::new(1, text).serialize(), client).unwrap();
+            sock.recv_from(&mut buf).unwrap();
+        });
+
+        let file_name = scratch_dir("netascii_download").join("a.txt");
+        let config = ClientConfig {
+            mode: TransferMode::Netascii,
+            ..ClientConfig::default()
+        };
+        let stats = transfer(&addr.to_string(), file_name.to_str().unwrap(), false, &config).unwrap();
+        server.join().unwrap();
+
+        assert_eq!(stats.bytes, 17);
+        assert_eq!(fs::read(&file_name).unwrap(), b"one\ntwo\rthree\n");
+    }
+
+    #[test]
+    fn dallying_acknowledges_the_last_block_again() {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let addr = sock.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut buf = [0; 1024];
+            let (_, client) = sock.recv_from(&mut buf).unwrap();
+
+            // The final ACK is dropped, so the last block is sent again.
+            let last = DataPacket::new(1, b"hello".to_vec()).serialize();
+            let mut acks = Vec::new();
+            for _ in 0..2 {
+                sock.send_to(&last, client).unwrap();
+                let (count, _) = sock.recv_from(&mut buf).unwrap();
+                match parse_udp_packet(&buf[..count]).unwrap() {
+                    TFTPPacket::ACK(ap) => acks.push(ap.blk()),
+                    p => panic!("Expected ACK got {:?}", p),
+                }
+            }
+            acks
+        });
+
+        let file_name = scratch_dir("dally_download").join("a.txt");
+        let config = ClientConfig {
+            dally: Duration::from_millis(500),
+            ..ClientConfig::default()
+        };
+        let stats = transfer(&addr.to_string(), file_name.to_str().unwrap(), false, &config).unwrap();
+
+        assert_eq!(server.join().unwrap(), vec![1, 1]);
+        assert_eq!(stats.bytes, 5);
+        assert_eq!(fs::read(&file_name).unwrap(), b"hello");
+    }
+
+    /// Loses the first ACK of block `lost_ack`.
+    struct LostFinalAck {
+        socket: UdpSocket,
+        lost_ack: u16,
+        lost: Cell<bool>,
+    }
+
+    impl Transport for LostFinalAck {
+        fn send_to(&self, buf: &[u8], peer: SocketAddr) -> io::Result<usize> {
+            if let TFTPPacket::ACK(ap) = parse_udp_packet(buf).unwrap() {
+                if ap.blk() == self.lost_ack && !self.lost.get() {
+                    self.lost.set(true);
+                    return Ok(buf.len());
+                }
+            }
+            self.socket.send_to(buf, peer)
+        }
+
+        fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+            self.socket.recv_from(buf)
+        }
+    }
+
+    #[test]
+    fn servers_see_the_download_complete_when_the_final_ack_is_lost() {
+        let file_name = scratch_dir("lost_final_ack").join("a.txt");
+        let file_name = file_name.to_str().unwrap();
+
+        let root = scratch_dir("lost_final_ack_remote");
+        let remote = root.join(file_name);
+        fs::create_dir_all(remote.parent().unwrap()).unwrap();
+        fs::write(&remote, vec![4; 700]).unwrap();
+
+        let listener = TFTPListener::bind("127.0.0.1", 0, ServerConfig {
+            root,
+            ..ServerConfig::default()
+        }).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let metrics = listener.metrics();
+        thread::spawn(move || listener.serve());
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+        let transport = LostFinalAck { socket, lost_ack: 2, lost: Cell::new(false) };
+        // Nothing is negotiated, the server sends the last block again after
+        // its default timeout, within the command line's default dally.
+        let config = ClientConfig {
+            dally: Duration::from_secs(3),
+            ..ClientConfig::default()
+        };
+
+        let stats = transfer_with(&transport, addr, file_name, false, &config).unwrap();
+        assert!(transport.lost.get());
+        assert_eq!(stats.bytes, 700);
+        assert_eq!(fs::read(file_name).unwrap(), vec![4; 700]);
+
+        // The server got the ACK again while the client dallied.
+        assert_eq!(metrics.downloads(), 1);
+        assert_eq!(metrics.errors(TFTPError::UndefinedError.code()), 0);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn mode_bits_are_applied_to_downloads() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let file_name = scratch_dir("mode_bits").join("run.sh");
+        let addr = spawn_lossy_server(0);
+        let config = ClientConfig {
+            mode_bits: Some(0o751),
+            ..ClientConfig::default()
+        };
+
+        transfer(&addr.to_string(), file_name.to_str().unwrap(), false, &config).unwrap();
+        let mode = fs::metadata(&file_name).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o751);
+    }
+
+    #[test]
+    fn downloads_make_the_local_directories_of_the_path() {
+        let root = scratch_dir("nested_download_server");
+        let local = scratch_dir("nested_download");
+        let file_name = local.join("images").join("boot.img");
+        fs::create_dir_all(root.join(&file_name).parent().unwrap()).unwrap();
+        fs::write(root.join(&file_name), b"kernel").unwrap();
+        let config = ServerConfig {
+            root,
+            ..ServerConfig::default()
+        };
+        let listener = TFTPListener::bind("127.0.0.1", 0, config).unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || listener.serve());
+
+        transfer(&addr.to_string(), file_name.to_str().unwrap(), false, &ClientConfig::default()).unwrap();
+        assert_eq!(fs::read(&file_name).unwrap(), b"kernel");
+
+        // Nothing is made for a file the server doesn't have.
+        let missing = local.join("missing").join("boot.img");
+        assert!(transfer(&addr.to_string(), missing.to_str().unwrap(), false, &ClientConfig::default()).is_err());
+        assert!(!local.join("missing").exists());
+    }
+
+    /// Serves `firmware/boot.img` from a fresh root.
+    fn spawn_firmware_server(name: &str) -> String {
+        let root = scratch_dir(name);
+        fs::create_dir_all(root.join("firmware")).unwrap();
+        fs::write(root.join("firmware").join("boot.img"), b"kernel").unwrap();
+        let listener = TFTPListener::bind("127.0.0.1", 0, ServerConfig {
+            root,
+            ..ServerConfig::default()
+        }).unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || listener.serve());
+        addr
+    }
+
+    #[test]
+    fn downloads_keep_the_file_name_in_the_download_dir() {
+        let addr = spawn_firmware_server("download_dir_server");
+        let dir = scratch_dir("download_dir");
+        let config = ClientConfig {
+            download_dir: Some(dir.clone()),
+            ..ClientConfig::default()
+        };
+
+        transfer(&addr, "firmware/boot.img", false, &config).unwrap();
+        assert_eq!(fs::read(dir.join("boot.img")).unwrap(), b"kernel");
+        assert!(!dir.join("firmware").exists());
+    }
+
+    #[test]
+    fn downloads_preserve_the_remote_path_when_asked() {
+        let addr = spawn_firmware_server("preserve_path_server");
+        let dir = scratch_dir("preserve_path");
+        let config = ClientConfig {
+            download_dir: Some(dir.clone()),
+            preserve_path: true,
+            ..ClientConfig::default()
+        };
+
+        transfer(&addr, "firmware/boot.img", false, &config).unwrap();
+        assert_eq!(fs::read(dir.join("firmware").join("boot.img")).unwrap(), b"kernel");
+    }
+
+    #[test]
+    fn download_dir_paths_stay_inside_it() {
+        let dir = Path::new("downloads").to_path_buf();
+        let basename = ClientConfig {
+            download_dir: Some(dir.clone()),
+            ..ClientConfig::default()
+        };
+        assert_eq!(basename.local_path("../../etc/passwd").unwrap(), dir.join("passwd"));
+        assert!(basename.local_path("firmware/..").is_err());
+
+        let preserved = ClientConfig {
+            preserve_path: true,
+            ..basename
+        };
+        assert_eq!(preserved.local_path("./a/b.img").unwrap(), dir.join("a").join("b.img"));
+        for remote in &["../etc/passwd", "a/../../b", "/etc/passwd", "."] {
+            assert!(preserved.local_path(remote).is_err(), "{} was let through", remote);
+        }
+
+        assert_eq!(ClientConfig::default().local_path("a/b.img").unwrap(), Path::new("a/b.img"));
+    }
+
+    /// Downloads from a server that turns every request down with `ep`.
+    fn rejected_download(dir: &str, ep: ErrorPacket) -> TransferError {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = sock.local_addr().unwrap();
+        thread::spawn(move || {
+            let (_, client) = sock.recv_from(&mut [0; 1024]).unwrap();
+            sock.send_to(&ep.serialize(), client).unwrap();
+        });
+
+        let file_name = scratch_dir(dir).join("a.txt");
+        match transfer(&addr.to_string(), file_name.to_str().unwrap(), false, &ClientConfig::default()) {
+            Err(e) => e,
+            r => panic!("Expected an error got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn missing_files_are_rejections() {
+        let e = rejected_download("rejected_not_found", ErrorPacket::new(TFTPError::FileNotFound));
+        assert!(matches!(e, TransferError::Rejected(DataChannelMode::Rx, _)));
+        assert_eq!(e.to_string(), "server rejected download: File not found.");
+        assert_eq!(e.exit_code(), 11);
+    }
+
+    #[test]
+    fn oversized_errors_keep_the_servers_message() {
+        let msg = "x".repeat(600);
+        let e = rejected_download("rejected_oversized", ErrorPacket::with_message(TFTPError::AccessViolation, &msg));
+        match e {
+            TransferError::Rejected(DataChannelMode::Rx, ep) => {
+                assert_eq!(ep.code(), TFTPError::AccessViolation.code());
+                assert!(msg.starts_with(ep.err()));
+            }
+            e => panic!("Expected a rejection got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn access_violations_keep_the_servers_message() {
+        let ep = ErrorPacket::with_message(TFTPError::AccessViolation, "only /pub is readable");
+        let e = rejected_download("rejected_access", ep);
+        assert_eq!(e.to_string(), "server rejected download: only /pub is readable");
+        assert_eq!(e.tftp_code(), Some(TFTPError::AccessViolation.code()));
+        assert_eq!(e.exit_code(), 12);
+    }
+
+    #[test]
+    fn json_output_for_a_missing_file() {
+        let root = scratch_dir("json_not_found");
+        let config = ServerConfig {
+            root: root.clone(),
+            ..ServerConfig::default()
+        };
+        let listener = TFTPListener::bind("127.0.0.1", 0, config).unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || listener.serve());
+
+        let file_name = root.join("local.txt");
+        let e = match transfer(&addr.to_string(), file_name.to_str().unwrap(), false, &ClientConfig::default()) {
+            Err(e) => e,
+            r => panic!("Expected an error got {:?}", r),
+        };
+
+        let out: Value = serde_json::from_str(&e.to_json().to_string()).unwrap();
+        assert_eq!(out["status"], "error");
+        assert_eq!(out["tftp_code"], TFTPError::FileNotFound.code());
+        assert_eq!(out["message"], "server rejected download: File not found.");
+    }
+
+    #[test]
+    fn busy_server_redirects_to_another() {
+        let file_name = scratch_dir("redirect").join("a.txt");
+        let file_name = file_name.to_str().unwrap();
+        let root = scratch_dir("redirect_remote");
+        let remote = root.join(file_name);
+        fs::create_dir_all(remote.parent().unwrap()).unwrap();
+        fs::write(&remote, vec![5; 700]).unwrap();
+
+        let other = TFTPListener::bind("127.0.0.1", 0, ServerConfig {
+            root: root.clone(),
+            ..ServerConfig::default()
+        }).unwrap();
+        let other_addr = other.local_addr().unwrap();
+        thread::spawn(move || other.serve());
+
+        let busy = TFTPListener::bind("127.0.0.1", 0, ServerConfig {
+            root,
+            workers: 1,
+            queue_timeout: Duration::from_millis(100),
+            redirect_busy_to: Some(other_addr),
+            ..ServerConfig::default()
+        }).unwrap();
+        let busy_addr = busy.local_addr().unwrap().to_string();
+        thread::spawn(move || busy.serve());
+
+        // Takes the only worker and keeps it.
+        let hog = UdpSocket::bind("127.0.0.1:0").unwrap();
+        hog.send_to(&ReadRequestPacket::new(file_name, "octet").serialize(), &busy_addr).unwrap();
+        hog.recv_from(&mut [0; 1024]).unwrap();
+
+        match transfer(&busy_addr, file_name, false, &ClientConfig::default()) {
+            Err(TransferError::Rejected(_, ep)) => assert_eq!(ep.redirect_target(), Some(other_addr)),
+            r => panic!("Expected a redirect got {:?}", r),
+        }
+
+        let config = ClientConfig {
+            follow_redirect: true,
+            ..ClientConfig::default()
+        };
+        let stats = transfer(&busy_addr, file_name, false, &config).unwrap();
+        assert_eq!(stats.bytes, 700);
+        assert_eq!(fs::read(file_name).unwrap(), vec![5; 700]);
+    }
+
+    #[test]
+    fn streamed_blocks_reassemble_the_file() {
+        let root = scratch_dir("stream_remote");
+        let content = (0..1300).map(|i| i as u8).collect::<Vec<_>>();
+        fs::write(root.join("streamed.bin"), &content).unwrap();
+        let server = TFTPListener::bind("127.0.0.1", 0, ServerConfig {
+            root,
+            ..ServerConfig::default()
+        }).unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+        thread::spawn(move || server.serve());
+
+        let config = ClientConfig::default();
+        let blocks = stream(&addr, "streamed.bin", &config).unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(blocks.iter().map(Vec::len).collect::<Vec<_>>(), vec![512, 512, 276]);
+        assert_eq!(blocks.concat(), content);
+        assert!(!Path::new("streamed.bin").exists());
+    }
+
+    #[test]
+    fn cancelled_streams_tell_the_server() {
+        let root = scratch_dir("cancel_remote");
+        fs::write(root.join("long.bin"), vec![7; 5000]).unwrap();
+
+        // A single worker that's only freed quickly if the server stops on the cancellation.
+        let server = TFTPListener::bind("127.0.0.1", 0, ServerConfig {
+            root,
+            workers: 1,
+            queue_timeout: Duration::from_secs(2),
+            ..ServerConfig::default()
+        }).unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+        thread::spawn(move || server.serve());
+
+        let config = ClientConfig::default();
+        let mut blocks = stream(&addr, "long.bin", &config).unwrap();
+        assert_eq!(blocks.next().unwrap().unwrap().len(), 512);
+
+        let token = config.cancellation_token();
+        thread::spawn(move || token.cancel()).join().unwrap();
+        match blocks.next() {
+            Some(Err(e @ TransferError::Aborted)) => assert_eq!(e.exit_code(), 130),
+            r => panic!("Expected a cancellation got {:?}", r),
+        }
+        assert!(blocks.next().is_none());
+
+        let config = ClientConfig::default();
+        let blocks = stream(&addr, "long.bin", &config).unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(blocks.concat().len(), 5000);
+    }
+
+    #[test]
+    fn directories_round_trip_as_tar_archives() {
+        let tree = scratch_dir("tar_client").join("tree");
+        fs::create_dir_all(tree.join("sub")).unwrap();
+        fs::write(tree.join("a.txt"), b"alpha").unwrap();
+        fs::write(tree.join("sub").join("b.bin"), vec![7; 1300]).unwrap();
+
+        let root = scratch_dir("tar_client_remote");
+        let server = TFTPListener::bind("127.0.0.1", 0, ServerConfig {
+            root: root.clone(),
+            create_dirs: true,
+            ..ServerConfig::default()
+        }).unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+        thread::spawn(move || server.serve());
+
+        let tree_name = tree.to_str().unwrap();
+        upload_dir(&addr, tree_name, &ClientConfig::default()).unwrap();
+        assert!(root.join(format!("{}.tar", tree_name)).is_file());
+
+        fs::remove_dir_all(&tree).unwrap();
+        download_dir(&addr, tree_name, &ClientConfig::default()).unwrap();
+        assert_eq!(fs::read(tree.join("a.txt")).unwrap(), b"alpha");
+        assert_eq!(fs::read(tree.join("sub").join("b.bin")).unwrap(), vec![7; 1300]);
+    }
+
+    #[test]
+    fn servers_pack_directories_on_the_fly() {
+        let boot = scratch_dir("tar_dirs").join("boot");
+        let root = scratch_dir("tar_dirs_remote");
+        fs::create_dir_all(root.join(&boot)).unwrap();
+        fs::write(root.join(&boot).join("kernel"), vec![3; 2000]).unwrap();
+
+        let server = TFTPListener::bind("127.0.0.1", 0, ServerConfig {
+            root,
+            tar_dirs: true,
+            ..ServerConfig::default()
+        }).unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+        thread::spawn(move || server.serve());
+
+        download_dir(&addr, boot.to_str().unwrap(), &ClientConfig::default()).unwrap();
+        assert_eq!(fs::read(boot.join("kernel")).unwrap(), vec![3; 2000]);
+    }
+
+    #[test]
+    fn uploads_from_a_reader_end_with_its_data() {
+        let root = scratch_dir("reader_upload");
+        let server = TFTPListener::bind("127.0.0.1", 0, ServerConfig {
+            root: root.clone(),
+            ..ServerConfig::default()
+        }).unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+        thread::spawn(move || server.serve());
+
+        // A multiple of the block size ends with an empty block.
+        for &size in &[1100, 1024] {
+            let content = (0..size).map(|i| i as u8).collect::<Vec<_>>();
+            let name = format!("piped_{}.bin", size);
+            let stats = upload_reader(&addr, &name, io::Cursor::new(content.clone()), &ClientConfig::default()).unwrap();
+
+            assert_eq!(stats.bytes, size as u64);
+            assert_eq!(fs::read(root.join(&name)).unwrap(), content);
+        }
+    }
+
+    #[test]
+    fn download_waits_for_the_file_to_appear() {
+        let root = scratch_dir("wait_for_file_remote");
+        let listener = TFTPListener::bind("127.0.0.1", 0, ServerConfig {
+            root: root.clone(),
+            ..ServerConfig::default()
+        }).unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let metrics = listener.metrics();
+        thread::spawn(move || listener.serve());
+
+        let file_name = scratch_dir("wait_for_file").join("late.txt");
+        let file_name = file_name.to_str().unwrap();
+        match transfer(&addr, file_name, false, &ClientConfig::default()) {
+            Err(e) => assert_eq!(e.tftp_code(), Some(TFTPError::FileNotFound.code())),
+            r => panic!("Expected FileNotFound got {:?}", r),
+        }
+
+        let remote = root.join(file_name);
+        fs::create_dir_all(remote.parent().unwrap()).unwrap();
+        let writer = thread::spawn(move || {
+            thread::sleep(ABORT_POLL);
+            fs::write(remote, "finally").unwrap();
+        });
+        let config = ClientConfig {
+            wait_for_file: Some(Duration::from_secs(5)),
+            ..ClientConfig::default()
+        };
+        transfer(&addr, file_name, false, &config).unwrap();
+        writer.join().unwrap();
+
+        assert_eq!(fs::read(file_name).unwrap(), b"finally");
+        // The first download and at least one try of the second, how many
+        // tries fit before the file appears is up to the scheduler.
+        assert!(metrics.errors(TFTPError::FileNotFound.code()) >= 2);
+    }
+
+    #[test]
+    fn abort_tells_the_server_and_drops_the_partial_file() {
+        let file_name = scratch_dir("abort").join("a.txt");
+        let file_name = file_name.to_str().unwrap();
+
+        // The server sees the same relative name under its own root.
+        let root = scratch_dir("abort_remote");
+        let remote = root.join(file_name);
+        fs::create_dir_all(remote.parent().unwrap()).unwrap();
+        fs::write(&remote, vec![1; 700]).unwrap();
+
+        // A single worker that's only freed quickly if the server stops on the abort.
+        let listener = TFTPListener::bind("127.0.0.1", 0, ServerConfig {
+            root,
+            workers: 1,
+            queue_timeout: Duration::from_secs(2),
+            ..ServerConfig::default()
+        }).unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || listener.serve());
+
+        let config = ClientConfig::default();
+        let token = config.cancellation_token();
+        thread::spawn(move || token.cancel()).join().unwrap();
+        match transfer(&addr, file_name, false, &config) {
+            Err(e @ TransferError::Aborted) => assert_eq!(e.exit_code(), 130),
+            r => panic!("Expected an abort got {:?}", r),
+        }
+        assert!(!Path::new(file_name).exists());
+
+        let stats = transfer(&addr, file_name, false, &ClientConfig::default()).unwrap();
+        assert_eq!(stats.bytes, 700);
+    }
+
+    #[test]
+    fn backoff_doubles_the_timeout_up_to_a_cap() {
+        let flat = ClientConfig::default();
+        assert_eq!(flat.retransmit_timeout(true, 0), Duration::from_secs(5));
+        assert_eq!(flat.retransmit_timeout(true, 3), Duration::from_secs(5));
+        assert_eq!(flat.retransmit_timeout(false, 3), Duration::from_secs(15));
+
+        let config = ClientConfig {
+            backoff: true,
+            ..ClientConfig::default()
+        };
+        let delays = (0..6).map(|r| config.retransmit_timeout(true, r).as_secs()).collect::<Vec<_>>();
+        assert_eq!(delays, vec![5, 10, 20, 40, 60, 60]);
+        assert_eq!(config.retransmit_timeout(true, 40), Duration::from_secs(60));
+
+        // A first wait over the cap is kept, not shortened.
+        let patient = ClientConfig {
+            backoff: true,
+            connect_timeout: Duration::from_secs(120),
+            ..ClientConfig::default()
+        };
+        assert_eq!(patient.retransmit_timeout(false, 0), Duration::from_secs(120));
+        assert_eq!(patient.retransmit_timeout(false, 5), Duration::from_secs(120));
+    }
+
+    /// Serves a two block download, the first block only after `delay`.
+    fn spawn_slow_starting_server(delay: Duration) -> SocketAddr {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = sock.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let mut buf = [0; 1024];
+            let (_, client) = sock.recv_from(&mut buf).unwrap();
+            thread::sleep(delay);
+            sock.send_to(&DataPacket::new(1, vec![1; 512]).serialize(), client).unwrap();
+            sock.recv_from(&mut buf).unwrap();
+            sock.send_to(&DataPacket::new(2, vec![2; 10]).serialize(), client).unwrap();
+        });
+
+        addr
+    }
+
+    #[test]
+    fn first_answer_gets_the_connect_timeout() {
+        let dir = scratch_dir("connect_timeout");
+        let config = ClientConfig {
+            retries: 0,
+            timeout: Duration::from_millis(100),
+            connect_timeout: Duration::from_secs(2),
+            ..ClientConfig::default()
+        };
+        let addr = spawn_slow_starting_server(Duration::from_millis(300)).to_string();
+        let stats = transfer(&addr, dir.join("a.txt").to_str().unwrap(), false, &config).unwrap();
+        assert_eq!(stats.bytes, 522);
+
+        let config = ClientConfig {
+            connect_timeout: Duration::from_millis(100),
+            ..config
+        };
+        let addr = spawn_slow_starting_server(Duration::from_millis(300)).to_string();
+        match transfer(&addr, dir.join("b.txt").to_str().unwrap(), false, &config) {
+            Err(TransferError::Timeout(blk)) => assert_eq!(blk, 1),
+            r => panic!("Expected a timeout got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn peers_repeating_the_same_ack_stall_the_upload() {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = sock.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let mut buf = [0; 1024];
+            let (_, client) = sock.recv_from(&mut buf).unwrap();
+            sock.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+
+            // Block 1 is never acknowledged, only the request is, over and over.
+            loop {
+                sock.send_to(&AckPacket::new(0).serialize(), client).unwrap();
+                if let Ok((count, _)) = sock.recv_from(&mut buf) {
+                    if let TFTPPacket::ERR(ep) = parse_udp_packet(&buf[..count]).unwrap() {
+                        return ep;
+                    }
+                }
+            }
+        });
+
+        let config = ClientConfig::default();
+        let started = Instant::now();
+        match upload_reader(&addr, "a.bin", io::Cursor::new(vec![7; 1000]), &config) {
+            Err(TransferError::Protocol(msg)) => assert!(msg.starts_with("Transfer stalled at block #1"), "{}", msg),
+            r => panic!("Expected a stall got {:?}", r),
+        }
+        assert!(started.elapsed() < config.timeout);
+        assert_eq!(server.join().unwrap().err(), "transfer stalled");
+    }
+
+    #[test]
+    fn uploads_ask_for_a_pow2_block_size() {
+        let config = ClientConfig {
+            block_size: Some(1400),
+            pow2_blksize: true,
+            ..ClientConfig::default()
+        };
+        assert_eq!(config.requested_block_size(), Some(1024));
+        assert_eq!(ClientConfig { pow2_blksize: false, ..config }.requested_block_size(), Some(1400));
+
+        let root = scratch_dir("pow2_upload");
+        let listener = TFTPListener::bind("127.0.0.1", 0, ServerConfig {
+            root: root.clone(),
+            pow2_blksize: true,
+            ..ServerConfig::default()
+        }).unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || listener.serve());
+
+        let content = (0..3000).map(|i| i as u8).collect::<Vec<_>>();
+        let config = ClientConfig {
+            block_size: Some(1400),
+            ..ClientConfig::default()
+        };
+        upload_reader(&addr, "blocks.bin", io::Cursor::new(content.clone()), &config).unwrap();
+        assert_eq!(fs::read(root.join("blocks.bin")).unwrap(), content);
+    }
+
+    /// Answers a request with an OACK of the asked block size, returns the
+    /// server's socket and the client's address once the OACK is sent.
+    fn accept_block_size(sock: &UdpSocket, expected: &str) -> SocketAddr {
+        let mut buf = [0; 1024];
+        let (count, client) = sock.recv_from(&mut buf).unwrap();
+        let options = match parse_udp_packet(&buf[..count]).unwrap() {
+            TFTPPacket::RRQ(rrq) => rrq.options().to_vec(),
+            TFTPPacket::WRQ(wrq) => wrq.options().to_vec(),
+            p => panic!("Expected a request got {:?}", p),
+        };
+        assert_eq!(options, vec![(String::from("blksize"), String::from(expected))]);
+
+        let oack = OptionAckPacket::new(options);
+        sock.send_to(&oack.serialize(), client).unwrap();
+        client
+    }
+
+    #[test]
+    fn download_confirms_the_oack_with_ack_0() {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = sock.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let client = accept_block_size(&sock, "1024");
+            let mut buf = [0; 2048];
+            for (blk, len) in vec![(1, 1024), (2, 10)] {
+                let (count, _) = sock.recv_from(&mut buf).unwrap();
+                assert_eq!(&buf[..count], &AckPacket::new(blk - 1).serialize()[..]);
+                sock.send_to(&DataPacket::new(blk, vec![blk as u8; len]).serialize(), client).unwrap();
+            }
+            let (count, _) = sock.recv_from(&mut buf).unwrap();
+            assert_eq!(&buf[..count], &AckPacket::new(2).serialize()[..]);
+        });
+
+        let file_name = scratch_dir("oack_download").join("a.txt");
+        let config = ClientConfig {
+            block_size: Some(1024),
+            ..ClientConfig::default()
+        };
+        let stats = transfer(&addr.to_string(), file_name.to_str().unwrap(), false, &config).unwrap();
+        server.join().unwrap();
+        assert_eq!(stats.bytes, 1034);
+    }
+
+    #[test]
+    fn strict_requests_carry_no_options() {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = sock.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut buf = [0; 1024];
+            let (count, client) = sock.recv_from(&mut buf).unwrap();
+            let wrq = match parse_udp_packet(&buf[..count]).unwrap() {
+                TFTPPacket::WRQ(wrq) => wrq,
+                p => panic!("Expected a WRQ got {:?}", p),
+            };
+            sock.send_to(&ErrorPacket::new(TFTPError::AccessViolation).serialize(), client).unwrap();
+            wrq.options().to_vec()
+        });
+
+        let file_name = scratch_dir("strict_rfc").join("a.bin");
+        fs::write(&file_name, vec![1; 700]).unwrap();
+        let config = ClientConfig {
+            block_size: Some(1024),
+            strict_rfc: true,
+            ..ClientConfig::default()
+        };
+        assert!(transfer(&addr.to_string(), file_name.to_str().unwrap(), true, &config).is_err());
+        assert_eq!(server.join().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn download_takes_blocks_larger_than_asked_for() {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = sock.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut buf = [0; 2048];
+            let (_, client) = sock.recv_from(&mut buf).unwrap();
+            // The server has a minimum above the size asked for.
+            let oack = OptionAckPacket::new(vec![(String::from("blksize"), String::from("1400"))]);
+            sock.send_to(&oack.serialize(), client).unwrap();
+            for (blk, len) in vec![(1, 1400), (2, 10)] {
+                let (count, _) = sock.recv_from(&mut buf).unwrap();
+                assert_eq!(&buf[..count], &AckPacket::new(blk - 1).serialize()[..]);
+                sock.send_to(&DataPacket::new(blk, vec![blk as u8; len]).serialize(), client).unwrap();
+            }
+            let (count, _) = sock.recv_from(&mut buf).unwrap();
+            assert_eq!(&buf[..count], &AckPacket::new(2).serialize()[..]);
+        });
+
+        let file_name = scratch_dir("oack_larger").join("a.txt");
+        let config = ClientConfig {
+            block_size: Some(512),
+            ..ClientConfig::default()
+        };
+        let stats = transfer(&addr.to_string(), file_name.to_str().unwrap(), false, &config).unwrap();
+        server.join().unwrap();
+        assert_eq!(stats.bytes, 1410);
+    }
+
+    #[test]
+    fn file_uploads_ask_for_no_options_by_default() {
+        let file_name = scratch_dir("wrq_options").join("a.txt");
+        fs::write(&file_name, vec![1; 700]).unwrap();
+        let mut client = TFTPClient::upload(file_name.to_str().unwrap(), &ClientConfig::default()).unwrap();
+        match parse_udp_packet(client.get_next_packet().unwrap()).unwrap() {
+            TFTPPacket::WRQ(wrq) => assert_eq!(wrq.options(), &[]),
+            p => panic!("Expected a WRQ got {:?}", p),
+        }
+    }
+
+    #[test]
+    fn late_repeated_oacks_are_ignored() {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = sock.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut buf = [0; 1024];
+            let (_, client) = sock.recv_from(&mut buf).unwrap();
+            let mut acks = Vec::new();
+            let mut answer = |packet: &[u8]| {
+                sock.send_to(packet, client).unwrap();
+                let (count, _) = sock.recv_from(&mut buf).unwrap();
+                match parse_udp_packet(&buf[..count]).unwrap() {
+                    TFTPPacket::ACK(ap) => acks.push(ap.blk()),
+                    p => panic!("Expected an ACK got {:?}", p),
+                }
+            };
+
+            let oack = OptionAckPacket::new(vec![(String::from("blksize"), String::from("512"))]).serialize();
+            answer(&oack);
+            answer(&DataPacket::new(1, vec![1; 512]).serialize());
+            // The OACK shows up again after the first block.
+            sock.send_to(&oack, client).unwrap();
+            answer(&DataPacket::new(2, vec![2; 10]).serialize());
+            acks
+        });
+
+        let file_name = scratch_dir("late_oack").join("a.txt");
+        let config = ClientConfig {
+            block_size: Some(512),
+            ..ClientConfig::default()
+        };
+        let stats = transfer(&addr.to_string(), file_name.to_str().unwrap(), false, &config).unwrap();
+        assert_eq!(stats.bytes, 522);
+        assert_eq!(server.join().unwrap(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn upload_refuses_blocks_larger_than_offered() {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = sock.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut buf = [0; 2048];
+            let (_, client) = sock.recv_from(&mut buf).unwrap();
+            let oack = OptionAckPacket::new(vec![(String::from("blksize"), String::from("1400"))]);
+            sock.send_to(&oack.serialize(), client).unwrap();
+            let (count, _) = sock.recv_from(&mut buf).unwrap();
+            match parse_udp_packet(&buf[..count]).unwrap() {
+                TFTPPacket::ERR(ep) => ep.code(),
+                p => panic!("Expected an error got {:?}", p),
+            }
+        });
+
+        let file_name = scratch_dir("oack_larger_upload").join("a.txt");
+        fs::write(&file_name, vec![1; 2000]).unwrap();
+        let config = ClientConfig {
+            block_size: Some(512),
+            ..ClientConfig::default()
+        };
+        assert!(transfer(&addr.to_string(), file_name.to_str().unwrap(), true, &config).is_err());
+        assert_eq!(server.join().unwrap(), TFTPError::OptionNegotiation.code());
+    }
+
+    #[test]
+    fn upload_answers_the_oack_with_negotiated_blocks() {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = sock.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let client = accept_block_size(&sock, "1024");
+            let mut buf = [0; 2048];
+            let mut lens = Vec::new();
+            loop {
+                let (count, _) = sock.recv_from(&mut buf).unwrap();
+                let dp = match parse_udp_packet(&buf[..count]).unwrap() {
+                    TFTPPacket::DATA(dp) => dp,
+                    p => panic!("Expected DATA got {:?}", p),
+                };
+                let blk = dp.blk();
+                lens.push(dp.data().len());
+                sock.send_to(&AckPacket::new(blk).serialize(), client).unwrap();
+                if lens[lens.len() - 1] < 1024 {
+                    return lens;
+                }
+            }
+        });
+
+        let config = ClientConfig {
+            block_size: Some(1024),
+            ..ClientConfig::default()
+        };
+        upload_reader(&addr.to_string(), "a.bin", io::Cursor::new(vec![3; 1500]), &config).unwrap();
+        assert_eq!(server.join().unwrap(), vec![1024, 476]);
+    }
+
+    /// Loses every `nth` packet that's sent through it.
+    struct LossyTransport {
+        socket: UdpSocket,
+        nth: usize,
+        sent: Cell<usize>,
+    }
+
+    impl Transport for LossyTransport {
+        fn send_to(&self, buf: &[u8], peer: SocketAddr) -> io::Result<usize> {
+            self.sent.set(self.sent.get() + 1);
+            if self.sent.get() % self.nth == 0 {
+                return Ok(buf.len());
+            }
+            self.socket.send_to(buf, peer)
+        }
+
+        fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+            self.socket.recv_from(buf)
+        }
+    }
+
+    #[test]
+    fn transfers_over_a_lossy_transport_complete() {
+        let file_name = scratch_dir("lossy_transport").join("a.txt");
+        let file_name = file_name.to_str().unwrap();
+
+        let root = scratch_dir("lossy_transport_remote");
+        let remote = root.join(file_name);
+        fs::create_dir_all(remote.parent().unwrap()).unwrap();
+        fs::write(&remote, vec![7; 2000]).unwrap();
+
+        let listener = TFTPListener::bind("127.0.0.1", 0, ServerConfig {
+            root,
+            ..ServerConfig::default()
+        }).unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || listener.serve());
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+        let transport = LossyTransport { socket, nth: 3, sent: Cell::new(0) };
+        let config = ClientConfig {
+            timeout: Duration::from_millis(50),
+            ..ClientConfig::default()
+        };
+
+        let stats = transfer_with(&transport, addr, file_name, false, &config).unwrap();
+        assert_eq!(stats.bytes, 2000);
+        assert_eq!(fs::read(file_name).unwrap(), vec![7; 2000]);
+        assert!(transport.sent.get() > 5);
+    }
+
+    /// Fails every `nth` send with a full buffer of the network interface, twice.
+    struct CongestedTransport {
+        socket: UdpSocket,
+        nth: usize,
+        sent: Cell<usize>,
+        failed: Cell<usize>,
+    }
+
+    impl Transport for CongestedTransport {
+        fn send_to(&self, buf: &[u8], peer: SocketAddr) -> io::Result<usize> {
+            if self.sent.get() % self.nth == 0 && self.failed.get() < 2 {
+                self.failed.set(self.failed.get() + 1);
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            self.failed.set(0);
+            self.sent.set(self.sent.get() + 1);
+            self.socket.send_to(buf, peer)
+        }
+
+        fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+            self.socket.recv_from(buf)
+        }
+    }
+
+    #[test]
+    fn transfers_outlast_passing_send_errors() {
+        let root = scratch_dir("congested_transport");
+        let listener = TFTPListener::bind("127.0.0.1", 0, ServerConfig {
+            root: root.clone(),
+            ..ServerConfig::default()
+        }).unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || listener.serve());
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let transport = CongestedTransport { socket, nth: 2, sent: Cell::new(0), failed: Cell::new(0) };
+        let source = io::Cursor::new(vec![5; 3000]);
+        let stats = upload_reader_with(&transport, addr, "a.bin", source, &ClientConfig::default()).unwrap();
+
+        assert_eq!(stats.bytes, 3000);
+        assert_eq!(fs::read(root.join("a.bin")).unwrap(), vec![5; 3000]);
+    }
+
+    /// Hands out a block, then stalls for a while before the rest.
+    struct SlowSource {
+        content: io::Cursor<Vec<u8>>,
+        stall: Option<Duration>,
+    }
+
+    impl Read for SlowSource {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.content.position() == 512 {
+                if let Some(stall) = self.stall.take() {
+                    thread::sleep(stall);
+                }
+            }
+            let end = buf.len().min(512);
+            self.content.read(&mut buf[..end])
+        }
+    }
+
+    #[test]
+    fn keepalive_outlasts_a_stalled_source() {
+        let root = scratch_dir("keepalive");
+        let listener = TFTPListener::bind("127.0.0.1", 0, ServerConfig {
+            root: root.clone(),
+            idle_timeout: Duration::from_millis(300),
+            ..ServerConfig::default()
+        }).unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || listener.serve());
+
+        let content: Vec<u8> = (0..1200).map(|i| i as u8).collect();
+        let source = SlowSource {
+            content: io::Cursor::new(content.clone()),
+            stall: Some(Duration::from_millis(1000)),
+        };
+        let data_channel = DataChannel::from_source("slow.bin", Box::new(source), 1200, DataChannelOwner::Client);
+        let config = ClientConfig {
+            timeout: Duration::from_millis(200),
+            keepalive: true,
+            ..ClientConfig::default()
+        };
+        let client = TFTPClient::upload_from(data_channel, &config).unwrap();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+        let stats = run(client, &UdpTransport::new(socket), addr, &config).unwrap();
+
+        assert_eq!(stats.bytes, 1200);
+        assert_eq!(fs::read(root.join("slow.bin")).unwrap(), content);
+    }
+
+    /// Counts the sends of DATA #1 after the first one.
+    struct RepeatCounter {
+        socket: UdpSocket,
+        repeats: Cell<u32>,
+    }
+
+    impl Transport for RepeatCounter {
+        fn send_to(&self, buf: &[u8], peer: SocketAddr) -> io::Result<usize> {
+            if let Ok(TFTPPacket::DATA(dp)) = parse_udp_packet(buf) {
+                if dp.blk() == 1 {
+                    self.repeats.set(self.repeats.get() + 1);
+                }
+            }
+            self.socket.send_to(buf, peer)
+        }
+
+        fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+            self.socket.recv_from(buf)
+        }
+    }
+
+    #[test]
+    fn keepalive_outlasts_more_rounds_than_the_stall_limit() {
+        let root = scratch_dir("keepalive_rounds");
+        let listener = TFTPListener::bind("127.0.0.1", 0, ServerConfig {
+            root: root.clone(),
+            idle_timeout: Duration::from_millis(300),
+            ..ServerConfig::default()
+        }).unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || listener.serve());
+
+        let config = ClientConfig {
+            timeout: Duration::from_millis(40),
+            ..ClientConfig::default()
+        };
+        // Well over STALL_LIMIT keepalives are sent while the source stalls.
+        let stall = config.timeout / 2 * STALL_LIMIT * 2;
+        let content: Vec<u8> = (0..1200).map(|i| i as u8).collect();
+        let source = SlowSource {
+            content: io::Cursor::new(content.clone()),
+            stall: Some(stall),
+        };
+        let mut data_channel = DataChannel::from_source("slower.bin", Box::new(source), 1200, DataChannelOwner::Client);
+        // Like `keepalive` does, but the source is polled often enough
+        // for a keepalive every half timeout.
+        data_channel.read_in_background(Duration::from_millis(5));
+        let client = TFTPClient::upload_from(data_channel, &config).unwrap();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        let transport = RepeatCounter { socket, repeats: Cell::new(0) };
+        let stats = run(client, &transport, addr, &config).unwrap();
 
-        check_done(&client);    // Download ends here, when sending the last ACK.
-        let (count, addr) = sock.recv_from(&mut buf)?;
-        // The server opens a UDP socket for each new client.
-        // that's why we need to change the address to send
-        // data to, otherwise we'll get an error from the
-        // server. I didn't notice that on the first time I
-        // tried and was getting an error, inspecting src/dst
-        // port revealed that. (and it's mentioned in the RFC)
-        server_address = addr.to_string();
-
-        let raw_packet = &buf[..count];
-        client.process_packet(raw_packet);
-        check_done(&client);    // Upload ends here, when receiving the last ACK.
+        assert!(transport.repeats.get() > STALL_LIMIT);
+        assert_eq!(stats.bytes, 1200);
+        assert_eq!(fs::read(root.join("slower.bin")).unwrap(), content);
     }
 }
@@ -0,0 +1,286 @@
+use std::cell::Cell;
+use std::io;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{Mutex, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use serde_json::json;
+
+use crate::tftp::shared::transport::PACKET_TARGET;
+
+thread_local! {
+    /// Client the records of this thread are about.
+    static CONNECTION: Cell<Option<SocketAddr>> = Cell::new(None);
+}
+
+/// Tags the records logged on this thread with the client they're about,
+/// `None` clears it. Each client is served on a thread of its own.
+pub fn set_connection(client: Option<SocketAddr>) {
+    CONNECTION.with(|connection| connection.set(client));
+}
+
+/// How each record is printed.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum LogFormat {
+    /// The message alone.
+    Text,
+    /// A JSON object a line with the level, the time, the client the
+    /// record is about if any, the target and the message, for log
+    /// pipelines. The target stands in for fields, records carry no
+    /// key-values.
+    Json,
+}
+
+impl Default for LogFormat {
+    fn default() -> Self {
+        LogFormat::Text
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format [{}], expected text or json", s)),
+        }
+    }
+}
+
+/// Prints informational records to stdout and
+/// warnings and errors to stderr.
+pub struct Logger {
+    level: LevelFilter,
+    /// Whether the packet trace is printed.
+    packets: bool,
+    format: LogFormat,
+    out: Mutex<Box<dyn Write + Send>>,
+    err: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Logger {
+    pub fn new(quiet: bool, packets: bool) -> Self {
+        let level = if quiet { LevelFilter::Warn } else { LevelFilter::Info };
+        Logger {
+            level,
+            packets,
+            format: LogFormat::Text,
+            out: Mutex::new(Box::new(io::stdout())),
+            err: Mutex::new(Box::new(io::stderr())),
+        }
+    }
+
+    pub fn with_format(mut self, format: LogFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Prints to `out` and `err` instead of stdout and stderr.
+    pub fn with_writers<O, E>(mut self, out: O, err: E) -> Self
+    where
+        O: Write + Send + 'static,
+        E: Write + Send + 'static,
+    {
+        self.out = Mutex::new(Box::new(out));
+        self.err = Mutex::new(Box::new(err));
+        self
+    }
+
+    /// The record as it's printed.
+    fn line(&self, record: &Record) -> String {
+        match self.format {
+            LogFormat::Text => record.args().to_string(),
+            LogFormat::Json => {
+                let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+                let connection = CONNECTION.with(Cell::get).map(|client| client.to_string());
+                json!({
+                    "level": record.level().as_str(),
+                    "timestamp": time.as_secs_f64(),
+                    "connection": connection,
+                    "target": record.target(),
+                    "message": record.args().to_string(),
+                })
+                .to_string()
+            }
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        if metadata.target() == PACKET_TARGET {
+            return self.packets;
+        }
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        // The record is formatted before the lock is taken, a panicking
+        // `Display` leaves the writer usable for the records after it.
+        let line = self.line(record);
+        let writer = if record.level() <= Level::Warn { &self.err } else { &self.out };
+        // There's nowhere left to tell about a failure to print.
+        let _ = writeln!(writer.lock().unwrap_or_else(PoisonError::into_inner), "{}", line);
+    }
+
+    fn flush(&self) {
+        let _ = self.out.lock().unwrap_or_else(PoisonError::into_inner).flush();
+        let _ = self.err.lock().unwrap_or_else(PoisonError::into_inner).flush();
+    }
+}
+
+/// Installs the logger, quiet mode only lets warnings and errors through.
+/// The packet trace is printed regardless when `packets` is set.
+pub fn init(quiet: bool, packets: bool, format: LogFormat) -> Result<(), SetLoggerError> {
+    let logger = Logger::new(quiet, packets).with_format(format);
+    let level = if packets { LevelFilter::Trace } else { logger.level };
+    log::set_boxed_logger(Box::new(logger))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt;
+    use std::fmt::{Display, Formatter};
+    use std::io;
+    use std::io::Write;
+    use std::panic;
+    use std::panic::AssertUnwindSafe;
+    use std::sync::{Arc, Mutex};
+
+    use log::{Level, Log, Metadata, Record};
+    use serde_json::Value;
+
+    use crate::tftp::logger::{set_connection, LogFormat, Logger};
+    use crate::tftp::shared::transport::PACKET_TARGET;
+
+    /// Keeps what's printed to it for the test to read.
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Captured {
+        fn lines(&self) -> Vec<String> {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap().lines().map(String::from).collect()
+        }
+    }
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A logger printing to captured stdout and stderr, in that order.
+    fn captured(logger: Logger) -> (Logger, Captured, Captured) {
+        let (out, err) = (Captured::default(), Captured::default());
+        (logger.with_writers(out.clone(), err.clone()), out, err)
+    }
+
+    fn metadata(level: Level) -> Metadata<'static> {
+        Metadata::builder().level(level).build()
+    }
+
+    #[test]
+    fn quiet_logger_drops_info() {
+        let logger = Logger::new(true, false);
+        assert!(!logger.enabled(&metadata(Level::Info)));
+        assert!(logger.enabled(&metadata(Level::Warn)));
+        assert!(logger.enabled(&metadata(Level::Error)));
+    }
+
+    #[test]
+    fn quiet_logger_leaves_stdout_empty() {
+        let (logger, out, err) = captured(Logger::new(true, false));
+        for level in [Level::Info, Level::Warn, Level::Error].iter() {
+            logger.log(&Record::builder().args(format_args!("{}", level)).level(*level).build());
+        }
+
+        assert!(out.lines().is_empty());
+        assert_eq!(err.lines(), vec!["WARN", "ERROR"]);
+    }
+
+    #[test]
+    fn default_logger_keeps_info() {
+        let logger = Logger::new(false, false);
+        assert!(logger.enabled(&metadata(Level::Info)));
+        assert!(!logger.enabled(&metadata(Level::Debug)));
+    }
+
+    #[test]
+    fn packet_trace_is_only_printed_when_asked() {
+        let packets = Metadata::builder().level(Level::Trace).target(PACKET_TARGET).build();
+        assert!(!Logger::new(false, false).enabled(&packets));
+        assert!(Logger::new(true, true).enabled(&packets));
+        assert!(!Logger::new(false, true).enabled(&metadata(Level::Trace)));
+    }
+
+    #[test]
+    fn json_records_are_objects_a_line() {
+        let (logger, out, err) = captured(Logger::new(false, false).with_format(LogFormat::Json));
+        let log = |logger: &Logger, level: Level| {
+            logger.log(&Record::builder().args(format_args!("Sent {} blocks", 3)).level(level).target("tftpeer::server").build())
+        };
+
+        log(&logger, Level::Warn);
+        set_connection(Some("127.0.0.1:4000".parse().unwrap()));
+        log(&logger, Level::Info);
+        set_connection(None);
+
+        let warnings = err.lines();
+        assert_eq!(warnings.len(), 1);
+        let warning: Value = serde_json::from_str(&warnings[0]).unwrap();
+        assert_eq!(warning["level"], "WARN");
+        assert_eq!(warning["target"], "tftpeer::server");
+        assert_eq!(warning["message"], "Sent 3 blocks");
+        assert!(warning["connection"].is_null());
+        assert!(warning["timestamp"].as_f64().unwrap() > 0.0);
+
+        let infos = out.lines();
+        assert_eq!(infos.len(), 1);
+        let info: Value = serde_json::from_str(&infos[0]).unwrap();
+        assert_eq!(info["level"], "INFO");
+        assert_eq!(info["connection"], "127.0.0.1:4000");
+
+        let (text, out, _) = captured(Logger::new(false, false));
+        log(&text, Level::Info);
+        assert_eq!(out.lines(), vec!["Sent 3 blocks"]);
+        assert_eq!("json".parse(), Ok(LogFormat::Json));
+        assert!("yaml".parse::<LogFormat>().is_err());
+    }
+
+    /// Stands for a value whose `Display` has a bug.
+    struct Panicking;
+
+    impl Display for Panicking {
+        fn fmt(&self, _: &mut Formatter<'_>) -> fmt::Result {
+            panic!("broken Display")
+        }
+    }
+
+    #[test]
+    fn panicking_records_leave_the_logger_usable() {
+        let (logger, out, _) = captured(Logger::new(false, false));
+        let logged = panic::catch_unwind(AssertUnwindSafe(|| {
+            logger.log(&Record::builder().args(format_args!("{}", Panicking)).level(Level::Info).build())
+        }));
+        assert!(logged.is_err());
+
+        logger.log(&Record::builder().args(format_args!("still here")).level(Level::Info).build());
+        logger.flush();
+        assert_eq!(out.lines(), vec!["still here"]);
+    }
+}
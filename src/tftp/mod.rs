@@ -1,3 +1,5 @@
 pub mod client;
+pub mod logger;
+pub mod sandbox;
 pub mod server;
 pub mod shared;
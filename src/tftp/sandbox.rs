@@ -0,0 +1,222 @@
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+
+/// How a file beneath the root is opened.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Access {
+    Read,
+    /// Creates the file, it mustn't exist yet.
+    CreateNew,
+    /// Adds to the end of the file, it's created if it doesn't exist yet.
+    Append,
+    /// Replaces the content of the file, it's created if it doesn't exist yet.
+    Truncate,
+}
+
+/// Opens `name` relative to `root`, refusing any path that resolves
+/// outside of it. On Linux the kernel resolves the path beneath an open
+/// handle of the root (`openat2` with `RESOLVE_BENEATH`), so a symlink
+/// swapped in after the name was checked can't lead out of the root.
+/// Elsewhere, or on kernels older than 5.6, the file is opened by path
+/// and the name checks done before are all there is.
+pub fn open_beneath(root: &Path, name: &Path, access: Access) -> io::Result<File> {
+    #[cfg(target_os = "linux")]
+    {
+        if let Some(result) = linux::open_beneath(root, name, access) {
+            return result;
+        }
+    }
+
+    open_options(access).open(root.join(name))
+}
+
+/// Renames `from` to `to`, both relative to `root`. Like `open_beneath`,
+/// on Linux their directories are resolved beneath the root and the
+/// rename is done relative to them (`renameat`).
+pub fn rename_beneath(root: &Path, from: &Path, to: &Path) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        if let Some(result) = linux::rename_beneath(root, from, to) {
+            return result;
+        }
+    }
+
+    fs::rename(root.join(from), root.join(to))
+}
+
+/// Removes the file `name` relative to `root`, see `rename_beneath`.
+pub fn remove_beneath(root: &Path, name: &Path) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        if let Some(result) = linux::remove_beneath(root, name) {
+            return result;
+        }
+    }
+
+    fs::remove_file(root.join(name))
+}
+
+fn open_options(access: Access) -> OpenOptions {
+    let mut options = OpenOptions::new();
+    match access {
+        Access::Read => options.read(true),
+        Access::CreateNew => options.write(true).create_new(true),
+        Access::Append => options.append(true).create(true),
+        Access::Truncate => options.write(true).truncate(true).create(true),
+    };
+    options
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::ffi::OsStr;
+    use std::fs::File;
+    use std::io;
+    use std::os::fd::OwnedFd;
+    use std::path::Path;
+
+    use log::warn;
+    use rustix::fs::{openat2, renameat, unlinkat, AtFlags, Mode, OFlags, ResolveFlags};
+    use rustix::io::Errno;
+
+    use crate::tftp::sandbox::Access;
+
+    /// Returns nothing when the kernel can't resolve paths beneath the root.
+    pub fn open_beneath(root: &Path, name: &Path, access: Access) -> Option<io::Result<File>> {
+        let flags = OFlags::CLOEXEC | match access {
+            Access::Read => OFlags::RDONLY,
+            Access::CreateNew => OFlags::WRONLY | OFlags::CREATE | OFlags::EXCL,
+            Access::Append => OFlags::WRONLY | OFlags::APPEND | OFlags::CREATE,
+            Access::Truncate => OFlags::WRONLY | OFlags::TRUNC | OFlags::CREATE,
+        };
+        open_at(root, name, flags, Mode::from_raw_mode(0o666)).map(|fd| fd.map(File::from))
+    }
+
+    pub fn rename_beneath(root: &Path, from: &Path, to: &Path) -> Option<io::Result<()>> {
+        let (from_dir, from_name) = match parent_of(root, from)? {
+            Ok(parent) => parent,
+            Err(e) => return Some(Err(e)),
+        };
+        let (to_dir, to_name) = match parent_of(root, to)? {
+            Ok(parent) => parent,
+            Err(e) => return Some(Err(e)),
+        };
+        Some(renameat(&from_dir, from_name, &to_dir, to_name).map_err(io::Error::from))
+    }
+
+    pub fn remove_beneath(root: &Path, name: &Path) -> Option<io::Result<()>> {
+        let (dir, file_name) = match parent_of(root, name)? {
+            Ok(parent) => parent,
+            Err(e) => return Some(Err(e)),
+        };
+        Some(unlinkat(&dir, file_name, AtFlags::empty()).map_err(io::Error::from))
+    }
+
+    /// Opens the directory `name` is in beneath the root, for the calls
+    /// relative to it, along with the last component of `name`.
+    fn parent_of<'a>(root: &Path, name: &'a Path) -> Option<io::Result<(OwnedFd, &'a OsStr)>> {
+        let file_name = match name.file_name() {
+            Some(file_name) => file_name,
+            None => return Some(Err(io::Error::new(io::ErrorKind::InvalidInput, "File path has no file name."))),
+        };
+        let parent = match name.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let flags = OFlags::PATH | OFlags::DIRECTORY | OFlags::CLOEXEC;
+        open_at(root, parent, flags, Mode::from_raw_mode(0)).map(|dir| dir.map(|dir| (dir, file_name)))
+    }
+
+    fn open_at(root: &Path, name: &Path, flags: OFlags, mode: Mode) -> Option<io::Result<OwnedFd>> {
+        let root = match File::open(root) {
+            Ok(root) => root,
+            Err(e) => return Some(Err(e)),
+        };
+        let resolve = ResolveFlags::BENEATH | ResolveFlags::NO_MAGICLINKS;
+
+        match openat2(&root, name, flags, mode, resolve) {
+            Ok(fd) => Some(Ok(fd)),
+            Err(Errno::XDEV) => Some(Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "File path leads outside of the root.",
+            ))),
+            Err(Errno::NOSYS) => {
+                warn!("openat2 isn't supported by this kernel, files are opened by path");
+                None
+            }
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{ErrorKind, Read};
+    use std::path::Path;
+
+    use crate::tftp::sandbox::{open_beneath, remove_beneath, rename_beneath, Access};
+    use crate::tftp::shared::test_util::scratch_dir;
+
+    #[test]
+    fn files_beneath_the_root_are_opened() {
+        let root = scratch_dir("sandbox_open");
+        fs::create_dir(root.join("sub")).unwrap();
+        fs::write(root.join("sub").join("a.txt"), "hello").unwrap();
+
+        let mut content = String::new();
+        open_beneath(&root, Path::new("sub/a.txt"), Access::Read).unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "hello");
+
+        open_beneath(&root, Path::new("b.txt"), Access::CreateNew).unwrap();
+        let e = open_beneath(&root, Path::new("b.txt"), Access::CreateNew).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::AlreadyExists);
+    }
+
+    #[test]
+    fn files_beneath_the_root_are_renamed_and_removed() {
+        let root = scratch_dir("sandbox_rename");
+        fs::create_dir(root.join("sub")).unwrap();
+        fs::write(root.join("sub").join("a.tmp"), "new").unwrap();
+
+        rename_beneath(&root, Path::new("sub/a.tmp"), Path::new("sub/a.txt")).unwrap();
+        assert_eq!(fs::read_to_string(root.join("sub").join("a.txt")).unwrap(), "new");
+        assert!(!root.join("sub").join("a.tmp").exists());
+
+        rename_beneath(&root, Path::new("sub/a.txt"), Path::new("a.txt")).unwrap();
+        remove_beneath(&root, Path::new("a.txt")).unwrap();
+        assert!(!root.join("a.txt").exists());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn symlinks_out_of_the_root_are_refused() {
+        use std::os::unix::fs::symlink;
+
+        let dir = scratch_dir("sandbox_escape");
+        let root = dir.join("root");
+        fs::create_dir(&root).unwrap();
+        fs::write(dir.join("secret.txt"), "secret").unwrap();
+        // The names hold up to any check, only resolving them leads out.
+        symlink("../secret.txt", root.join("link.txt")).unwrap();
+        symlink("..", root.join("out")).unwrap();
+
+        for name in &["link.txt", "out/secret.txt"] {
+            let e = open_beneath(&root, Path::new(name), Access::Read).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::PermissionDenied, "{}", name);
+        }
+        let e = open_beneath(&root, Path::new("out/new.txt"), Access::CreateNew).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::PermissionDenied);
+        assert!(!dir.join("new.txt").exists());
+
+        fs::write(root.join("a.txt"), "a").unwrap();
+        let e = rename_beneath(&root, Path::new("a.txt"), Path::new("out/moved.txt")).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::PermissionDenied);
+        assert!(!dir.join("moved.txt").exists());
+        let e = remove_beneath(&root, Path::new("out/secret.txt")).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::PermissionDenied);
+        assert!(dir.join("secret.txt").exists());
+    }
+}
@@ -1,162 +1,3527 @@
 extern crate pretty_bytes;
 
-use std::net::{SocketAddr, UdpSocket};
-use std::time::Duration;
+use std::collections::hash_map::RandomState;
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::fmt::{Display, Formatter};
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::io::{Cursor, Write};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::ops::{Deref, DerefMut, RangeInclusive};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Component, Path, PathBuf};
+use std::process::exit;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+#[cfg(feature = "async-server")]
 use async_std::task as asyncstd_task;
+use log::{debug, error, info, warn};
 use pretty_bytes::converter::convert;
+use serde_json::json;
 
-use crate::tftp::shared::{parse_udp_packet, Serializable, TFTPPacket};
+use crate::tftp::logger;
+use crate::tftp::sandbox::{open_beneath, remove_beneath, rename_beneath, Access};
+use crate::tftp::shared::{parse_udp_packet, recv_buffer_len, Serializable, TFTPPacket};
+use crate::tftp::shared::clock::{Clock, SystemClock};
 use crate::tftp::shared::data_channel::{DataChannel, DataChannelMode, DataChannelOwner};
+use crate::tftp::shared::data_packet::{pow2_block_size, DATA_MAX_LEN};
 use crate::tftp::shared::err_packet::{ErrorPacket, TFTPError};
-use crate::tftp::shared::request_packet::{ReadRequestPacket, Request, WriteRequestPacket};
+use crate::tftp::shared::oack_packet::OptionAckPacket;
+use crate::tftp::shared::request_packet::{ReadRequestPacket, Request, WriteRequestPacket, MAX_REQUEST_LEN};
+use crate::tftp::shared::tar_stream::{archived_dir, pack};
+use crate::tftp::shared::transfer_mode::TransferMode;
+use crate::tftp::shared::transport::{bind_to_interface, dally, log_packet, send_retrying, set_buffer_sizes, set_dscp, Transport, UdpTransport};
 
-const sock_dur: Option<Duration> = Some(Duration::from_secs(5));
+#[cfg(not(any(feature = "sync-server", feature = "async-server")))]
+compile_error!("The server needs either the sync-server or the async-server feature");
+
+/// Environment variable that names the server root when `--root` isn't given.
+pub const ROOT_ENV_VAR: &str = "TFTPEER_ROOT";
+/// Smallest block size a client may ask for (RFC 2348).
+const MIN_BLOCK_SIZE: usize = 8;
+/// What a download of the health name gets.
+const HEALTH_PAYLOAD: &[u8] = b"OK\n";
+/// IPv4 and UDP headers plus the opcode and block number of a DATA packet.
+const DATA_OVERHEAD: usize = 20 + 8 + 4;
+/// Shortest retransmission timeout a client may negotiate, shorter
+/// ones would have the server spinning on its socket.
+const MIN_TIMEOUT: Duration = Duration::from_millis(10);
+/// Wait before the last packet is sent again when the client didn't
+/// negotiate one with the `timeout` or `utimeout` option.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+/// Longest timeout of the `timeout` option (RFC 2349).
+const MAX_TIMEOUT: Duration = Duration::from_secs(255);
+/// Longest a one-shot listener keeps listening after its download completed.
+const ONCE_POLL: Duration = Duration::from_millis(100);
+
+/// What a client asks the server to do with a file.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Operation {
+    Read,
+    Write,
+}
+
+/// Approves or denies a request given the client, the operation
+/// and the requested file name, denied requests get the error.
+pub type AuthorizeHook = Arc<dyn Fn(&SocketAddr, Operation, &str) -> Result<(), TFTPError> + Send + Sync>;
+
+/// Shown a transfer each time a packet of its client was handled, its
+/// progress and error can be read then without ending it.
+pub type ProgressHook = Arc<dyn Fn(&TFTPServer) + Send + Sync>;
+
+/// Caps the bytes written by all the uploads to a server.
+#[derive(Debug)]
+pub struct DiskQuota {
+    limit: u64,
+    used: AtomicU64,
+}
+
+impl DiskQuota {
+    pub fn new(limit: u64) -> Self {
+        DiskQuota { limit, used: AtomicU64::new(0) }
+    }
+
+    /// Bytes written by uploads so far.
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::SeqCst)
+    }
+
+    /// Counts the bytes against the quota, nothing is counted
+    /// and false is returned if they don't fit.
+    fn reserve(&self, bytes: u64) -> bool {
+        let limit = self.limit;
+        self.used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                used.checked_add(bytes).filter(|&total| total <= limit)
+            })
+            .is_ok()
+    }
+
+    fn release(&self, bytes: u64) {
+        self.used.fetch_sub(bytes, Ordering::SeqCst);
+    }
+}
+
+/// Appends a JSON object per line for each request a server handles,
+/// telling when it ended, who made it, what it asked for and how it went:
+///
+/// `{"time":1700000000.25,"client":"10.0.0.7:4312","op":"R","file":"boot.img","mode":"octet","result":"ok","bytes":4096}`
+///
+/// `result` is `ok` or the code of the error that ended the request.
+/// Each line is written as soon as it's complete.
+#[derive(Debug)]
+pub struct AccessLog {
+    file: Mutex<File>,
+}
+
+impl AccessLog {
+    /// Opens the log for appending, it's created if it doesn't exist yet.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        Ok(AccessLog { file: Mutex::new(file) })
+    }
+
+    fn record(&self, client_addr: &SocketAddr, rq_packet: &[u8], outcome: &Outcome) {
+        let (op, file_name, mode) = match parse_udp_packet(rq_packet) {
+            Ok(TFTPPacket::RRQ(rrq)) => ("R", rrq.filename().to_string(), rrq.mode().to_ascii_lowercase()),
+            Ok(TFTPPacket::WRQ(wrq)) => ("W", wrq.filename().to_string(), wrq.mode().to_ascii_lowercase()),
+            _ => return,
+        };
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let result = match outcome.error {
+            Some(code) => json!(code),
+            None => json!("ok"),
+        };
+
+        let mut line = json!({
+            "time": time.as_secs_f64(),
+            "client": client_addr.to_string(),
+            "op": op,
+            "file": file_name,
+            "mode": mode,
+            "result": result,
+            "bytes": outcome.bytes,
+        })
+        .to_string();
+        line.push('\n');
+
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = file.write_all(line.as_bytes()).and_then(|_| file.flush()) {
+            error!("Failed to write the access log: {}", e);
+        }
+    }
+}
+
+/// Settings shared by all the clients of a server.
+pub struct ServerConfig {
+    /// Directory that requested file names are resolved against.
+    pub root: PathBuf,
+    /// Whether uploads may create missing directories under the root.
+    pub create_dirs: bool,
+    /// Extensions of the files that may be read or written, all
+    /// extensions are allowed when empty.
+    pub allowed_extensions: Vec<String>,
+    /// Refuse all write requests.
+    pub read_only: bool,
+    /// Refuse all read requests.
+    pub write_only: bool,
+    /// Transfers that may run at the same time.
+    pub workers: usize,
+    /// How long a request may wait for a free worker before it's refused.
+    pub queue_timeout: Duration,
+    /// How long a client may stay silent before it's told the
+    /// transfer timed out and its worker is freed.
+    pub idle_timeout: Duration,
+    /// How long the socket of an upload stays open after the final ACK, a
+    /// client that sends the last block again didn't get it and is sent
+    /// it again. Nothing is waited for when it's zero.
+    pub dally: Duration,
+    /// File name that's answered with a listing of the root.
+    pub index_name: Option<String>,
+    /// File name that's answered with a fixed payload for liveness probes,
+    /// it's served even when downloads are refused.
+    pub health_name: Option<String>,
+    /// Ports that data sockets are bound to, any free port when empty.
+    pub port_range: Option<RangeInclusive<u16>>,
+    /// Network interface the listener and data sockets are bound to, packets
+    /// arriving on others aren't taken. Only Linux supports it.
+    pub interface: Option<String>,
+    /// Address that data sockets are bound to, the listener's by default.
+    /// A listener on a wildcard address can't tell which of the host's
+    /// addresses a client contacted, multi-homed hosts should set it so
+    /// replies come from the address clients know.
+    pub data_address: Option<IpAddr>,
+    /// DSCP that packets sent to clients are marked with.
+    pub dscp: Option<u8>,
+    /// Bytes asked for the receive buffer of each transfer socket.
+    pub recv_buffer: Option<usize>,
+    /// Bytes asked for the send buffer of each transfer socket.
+    pub send_buffer: Option<usize>,
+    /// MTU of the path to clients, negotiated blocks are kept within it.
+    pub mtu: Option<usize>,
+    /// Only negotiate block sizes that are powers of two.
+    pub pow2_blksize: bool,
+    /// Smallest block size negotiated for downloads, clients asking for
+    /// less get it as far as `mtu` and `pow2_blksize` allow.
+    pub min_blksize: Option<usize>,
+    /// Ignore the options of requests, for testing against plain RFC 1350 clients.
+    pub strict_rfc: bool,
+    /// Longest file name a request may carry in bytes, longer ones
+    /// are refused before the name is used.
+    pub max_filename_len: usize,
+    /// Called for each request before any file is opened.
+    pub authorize: Option<AuthorizeHook>,
+    /// Called as each transfer goes on, see `ProgressHook`.
+    pub on_progress: Option<ProgressHook>,
+    /// Let uploads with the `append` option add to existing files.
+    pub append: bool,
+    /// Let uploads replace existing files instead of being refused.
+    pub allow_overwrite: bool,
+    /// Read each DATA block while the previous one awaits its ACK.
+    pub read_ahead: bool,
+    /// Receive uploads into a `.part` file that's only renamed once
+    /// complete. An interrupted upload is continued by a request
+    /// with the `resume` option, the OACK tells how much was kept.
+    pub resumable_uploads: bool,
+    /// Shared by all uploads, the one that goes over it is refused
+    /// and what it wrote is rolled back.
+    pub disk_quota: Option<Arc<DiskQuota>>,
+    /// Where a line is written for each request once it's handled.
+    pub access_log: Option<Arc<AccessLog>>,
+    /// Open files relative to the root in a way that can't lead outside
+    /// of it, even through symlinks changed after the name was checked.
+    /// Only Linux enforces it, see `open_beneath`. Missing directories
+    /// aren't created.
+    pub chroot: bool,
+    /// Answer a download of `name.tar` that isn't a file with the directory
+    /// `name` packed as a tar archive on the fly. It's off with `chroot`,
+    /// whose confinement doesn't extend to packing a directory.
+    pub tar_dirs: bool,
+    /// Refuse downloads of empty files, they're served by default.
+    pub reject_empty: bool,
+    /// Drop requests for files that don't exist instead of answering
+    /// them, so scanners can't tell which names exist.
+    pub silent_notfound: bool,
+    /// Catch a panic while serving a client, it's logged and counted as
+    /// an error. Without it the panic only ends the client's thread.
+    pub catch_panics: bool,
+    /// Store uploads under a name of the server's, see `unique_upload_name`,
+    /// the requested one is only checked against `allowed_extensions`. A
+    /// request with the `filename` option is told the name in the OACK.
+    pub rename_uploads: bool,
+    /// Stop listening once a download completed, requests that
+    /// arrive meanwhile are dropped.
+    pub once: bool,
+    /// Server that requests are redirected to when no worker frees up in
+    /// time, clients that don't follow redirects just see an error.
+    pub redirect_busy_to: Option<SocketAddr>,
+    /// Times how long clients stay silent.
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            root: PathBuf::from("."),
+            create_dirs: false,
+            allowed_extensions: Vec::new(),
+            read_only: false,
+            write_only: false,
+            workers: 8,
+            queue_timeout: Duration::from_secs(5),
+            idle_timeout: Duration::from_secs(5),
+            dally: Duration::from_secs(0),
+            index_name: None,
+            health_name: None,
+            port_range: None,
+            interface: None,
+            data_address: None,
+            dscp: None,
+            recv_buffer: None,
+            send_buffer: None,
+            mtu: None,
+            pow2_blksize: false,
+            min_blksize: None,
+            strict_rfc: false,
+            max_filename_len: 255,
+            authorize: None,
+            on_progress: None,
+            append: false,
+            allow_overwrite: false,
+            resumable_uploads: false,
+            read_ahead: false,
+            disk_quota: None,
+            access_log: None,
+            chroot: false,
+            tar_dirs: false,
+            reject_empty: false,
+            silent_notfound: false,
+            catch_panics: false,
+            rename_uploads: false,
+            once: false,
+            redirect_busy_to: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+/// Counters describing the transfers handled by a server, they're
+/// updated as clients are served so they can be read at any time.
+#[derive(Debug, Default)]
+pub struct ServerMetrics {
+    downloads: AtomicU64,
+    uploads: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    /// Error packets sent, indexed by their TFTP error code.
+    errors: [AtomicU64; 9],
+    dropped: AtomicU64,
+}
+
+impl ServerMetrics {
+    /// Number of completed downloads (RRQs).
+    pub fn downloads(&self) -> u64 {
+        self.downloads.load(Ordering::Relaxed)
+    }
+
+    /// Number of completed uploads (WRQs).
+    pub fn uploads(&self) -> u64 {
+        self.uploads.load(Ordering::Relaxed)
+    }
+
+    /// File bytes sent to clients.
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    /// File bytes received from clients.
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received.load(Ordering::Relaxed)
+    }
+
+    /// Number of error packets sent with the given TFTP error code.
+    pub fn errors(&self, code: u16) -> u64 {
+        self.errors
+            .get(code as usize)
+            .map_or(0, |count| count.load(Ordering::Relaxed))
+    }
+
+    /// Number of requests dropped without an answer.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn on_transfer_done(&self, mode: DataChannelMode, bytes: u64) {
+        match mode {
+            DataChannelMode::Tx => {
+                self.downloads.fetch_add(1, Ordering::Relaxed);
+                self.bytes_sent.fetch_add(bytes, Ordering::Relaxed);
+            }
+            DataChannelMode::Rx => {
+                self.uploads.fetch_add(1, Ordering::Relaxed);
+                self.bytes_received.fetch_add(bytes, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn on_error(&self, code: u16) {
+        if let Some(count) = self.errors.get(code as usize) {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn on_dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Display for ServerMetrics {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let errors: u64 = (0..self.errors.len() as u16).map(|code| self.errors(code)).sum();
+        write!(
+            f,
+            "downloads: {} uploads: {} sent: {} received: {} errors: {} dropped: {}",
+            self.downloads(),
+            self.uploads(),
+            convert(self.bytes_sent() as f64),
+            convert(self.bytes_received() as f64),
+            errors,
+            self.dropped()
+        )
+    }
+}
+
+/// Picks the directory to serve, the `--root` flag takes precedence
+/// over `TFTPEER_ROOT` which takes precedence over the current directory.
+pub fn resolve_root(flag: Option<&str>) -> PathBuf {
+    resolve_root_from(flag, env::var_os(ROOT_ENV_VAR))
+}
+
+/// `resolve_root` given the value of `TFTPEER_ROOT`, if it's set.
+fn resolve_root_from(flag: Option<&str>, env_root: Option<OsString>) -> PathBuf {
+    flag.map(PathBuf::from)
+        .or_else(|| env_root.map(PathBuf::from))
+        .unwrap_or_else(|| PathBuf::from("."))
+}
+
+/// Resolves a requested file name against the server root, names that
+/// would escape the root are refused.
+fn resolve_path(root: &Path, file_name: &str) -> Result<PathBuf, ErrorPacket> {
+    for component in Path::new(file_name).components() {
+        match component {
+            Component::Normal(_) | Component::CurDir => {}
+            // Client isn't allowed to traverse the TFTP directory upwards
+            // in any case.
+            Component::ParentDir => {
+                let err = String::from("File path must not refer to a parent directory.");
+                return Err(ErrorPacket::new_custom(err));
+            }
+            // Client needn't know anything about the server's host.
+            Component::RootDir | Component::Prefix(_) => {
+                let err = String::from("File path must not start with root.");
+                return Err(ErrorPacket::new_custom(err));
+            }
+        }
+    }
+
+    Ok(root.join(file_name))
+}
+
+/// Refuses transfer modes the server can't handle with an illegal
+/// operation (RFC 1350), `mail` is obsolete and gets a message of its own.
+fn check_mode(mode: &str) -> Result<TransferMode, ErrorPacket> {
+    if mode.eq_ignore_ascii_case("mail") {
+        let msg = "mail mode is not supported";
+        return Err(ErrorPacket::with_message(TFTPError::IllegalOperation, msg));
+    }
+
+    mode.parse().map_err(|e: String| ErrorPacket::with_message(TFTPError::IllegalOperation, &e))
+}
+
+/// Opens a file by its requested name, which mustn't lead outside of the root.
+fn open_in_root(config: &ServerConfig, file_name: &str, access: Access) -> Result<File, ErrorPacket> {
+    open_beneath(&config.root, Path::new(file_name), access).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => ErrorPacket::new(TFTPError::FileNotFound),
+        io::ErrorKind::AlreadyExists => ErrorPacket::new(TFTPError::FileExists),
+        io::ErrorKind::PermissionDenied => ErrorPacket::new(TFTPError::AccessViolation),
+        _ => ErrorPacket::new_custom(e.to_string()),
+    })
+}
+
+fn set_transfer_mode(data_channel: &mut DataChannel, mode: TransferMode) -> Result<(), ErrorPacket> {
+    data_channel.set_transfer_mode(mode).map_err(|e| ErrorPacket::new_custom(e.to_string()))
+}
+
+/// A name for an upload that no other one gets: the time it started and a
+/// random suffix, e.g. `1697371234123456-9f86d081884c7d65.bin`. Only a
+/// plain extension of the requested name is kept.
+fn unique_upload_name(requested: &str) -> String {
+    static UPLOADS: AtomicU64 = AtomicU64::new(0);
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(UPLOADS.fetch_add(1, Ordering::Relaxed));
+
+    let name = format!("{}-{:016x}", time.as_micros(), hasher.finish());
+    match Path::new(requested).extension().and_then(OsStr::to_str) {
+        Some(ext) if ext.bytes().all(|b| b.is_ascii_alphanumeric()) => format!("{}.{}", name, ext),
+        _ => name,
+    }
+}
+
+/// Tells whether the extension of a requested file is in the allow-list.
+fn is_extension_allowed(allowed: &[String], file_name: &str) -> bool {
+    if allowed.is_empty() {
+        return true;
+    }
+
+    match Path::new(file_name).extension().and_then(OsStr::to_str) {
+        Some(ext) => allowed
+            .iter()
+            .any(|a| a.trim_start_matches('.').eq_ignore_ascii_case(ext)),
+        None => false,
+    }
+}
+
+/// Lists the files in the root that may be downloaded, one per line.
+fn list_root(config: &ServerConfig) -> io::Result<Vec<u8>> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(&config.root)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if is_extension_allowed(&config.allowed_extensions, &name) {
+            names.push(name);
+        }
+    }
+
+    names.sort();
+    let mut listing = String::new();
+    for name in names {
+        listing.push_str(&name);
+        listing.push('\n');
+    }
+
+    Ok(listing.into_bytes())
+}
+
+fn authorize(config: &ServerConfig, client_addr: &SocketAddr, operation: Operation, file_name: &str) -> Result<(), ErrorPacket> {
+    match &config.authorize {
+        Some(authorize) => authorize(client_addr, operation, file_name).map_err(ErrorPacket::new),
+        None => Ok(()),
+    }
+}
+
+/// Block size to use for the one a client asked for, DATA packets are
+/// kept within the MTU so they aren't fragmented. Invalid sizes are refused,
+/// with `pow2` the size is rounded down to a power of two. Sizes below
+/// `min` are raised to it, the MTU and `pow2` still have the last word.
+fn negotiate_block_size(requested: &str, mtu: Option<usize>, pow2: bool, min: Option<usize>) -> Option<usize> {
+    let requested = requested.parse::<usize>().ok()?;
+    if requested < MIN_BLOCK_SIZE {
+        return None;
+    }
+
+    let largest = match mtu {
+        Some(mtu) => mtu.saturating_sub(DATA_OVERHEAD).max(MIN_BLOCK_SIZE),
+        None => DATA_MAX_LEN,
+    };
+    let mut block_size = requested.max(min.unwrap_or(0)).min(largest).min(DATA_MAX_LEN);
+    if pow2 {
+        block_size = pow2_block_size(block_size);
+    }
+    Some(block_size)
+}
+
+/// Timeout to use for the `timeout` option, in seconds (RFC 2349), or the
+/// `utimeout` option, in microseconds. Short ones are raised to a minimum,
+/// invalid ones are refused.
+fn negotiate_timeout(name: &str, requested: &str) -> Option<Duration> {
+    let requested = requested.parse::<u64>().ok()?;
+    let timeout = match name {
+        "timeout" => Duration::from_secs(requested),
+        "utimeout" => Duration::from_micros(requested),
+        _ => return None,
+    };
+
+    if requested == 0 || timeout > MAX_TIMEOUT {
+        return None;
+    }
+    Some(timeout.max(MIN_TIMEOUT))
+}
 
 /// A TFTP server that supports a single client.
-struct TFTPServer {
-    data_channel: DataChannel
+pub struct TFTPServer {
+    data_channel: DataChannel,
+    /// Address of the client being served.
+    peer: SocketAddr,
+    /// Quota that received data counts against.
+    quota: Option<Arc<DiskQuota>>,
+    /// Bytes counted against the quota by this transfer.
+    charged: u64,
+    /// Length of an appended file before the upload.
+    kept_len: u64,
+    /// Name the upload gets once complete, it's received into a `.part` file.
+    complete_as: Option<PathBuf>,
+    /// Keeps other uploads out of the `.part` file while it's received into.
+    part_claim: Option<PartClaim>,
+    /// File an overwriting upload is received into, see `TempFile`.
+    temp_file: Option<TempFile>,
+    /// Root that the files of an upload are renamed and removed beneath,
+    /// with `chroot`.
+    chroot: Option<PathBuf>,
+    /// Whether blocks are read while the previous one awaits its ACK.
+    read_ahead: bool,
+    /// Negotiated wait before the last packet is sent again.
+    timeout: Option<Duration>,
+    /// How long the client may stay silent, retransmissions or not.
+    idle_timeout: Duration,
+    /// How long the final ACK of an upload is sent again when the last block is.
+    dally: Duration,
+    on_progress: Option<ProgressHook>,
+    clock: Arc<dyn Clock>,
 }
 
 impl TFTPServer {
-    pub fn new(rq_packet: &[u8]) -> Result<Self, ErrorPacket> {
-        match parse_udp_packet(rq_packet) {
-            TFTPPacket::RRQ(rrq) => TFTPServer::init_rrq_response(rrq),
-            TFTPPacket::WRQ(wrq) => TFTPServer::init_wrq_response(wrq),
+    pub fn new(rq_packet: &[u8], client_addr: &SocketAddr, config: &ServerConfig) -> Result<Self, ErrorPacket> {
+        let packet = match parse_udp_packet(rq_packet).map_err(|_| ErrorPacket::new(TFTPError::IllegalOperation))? {
+            // Nothing is negotiated, the transfer is the one RFC 1350 describes.
+            TFTPPacket::RRQ(rrq) if config.strict_rfc => TFTPPacket::RRQ(rrq.without_options()),
+            TFTPPacket::WRQ(wrq) if config.strict_rfc => TFTPPacket::WRQ(wrq.without_options()),
+            packet => packet,
+        };
+
+        if packet.filename().map_or(false, |name| name.len() > config.max_filename_len) {
+            return Err(ErrorPacket::with_message(TFTPError::IllegalOperation, "File name is too long."));
+        }
+
+        match packet {
+            TFTPPacket::RRQ(rrq) if config.health_name.as_deref() == Some(rrq.filename()) => {
+                Ok(TFTPServer::init_health_response(rrq, *client_addr, config))
+            }
+            TFTPPacket::RRQ(_) if config.write_only => Err(ErrorPacket::new(TFTPError::AccessViolation)),
+            TFTPPacket::RRQ(rrq) => {
+                authorize(config, client_addr, Operation::Read, rrq.filename())?;
+                TFTPServer::init_rrq_response(rrq, *client_addr, config)
+            }
+            TFTPPacket::WRQ(_) if config.read_only => Err(ErrorPacket::new(TFTPError::AccessViolation)),
+            TFTPPacket::WRQ(wrq) => {
+                authorize(config, client_addr, Operation::Write, wrq.filename())?;
+                let wrq = if config.rename_uploads {
+                    let name = unique_upload_name(wrq.filename());
+                    info!("Storing the upload of [{}] as [{}]", wrq.filename(), name);
+                    wrq.renamed(&name)
+                } else {
+                    wrq
+                };
+                TFTPServer::init_wrq_response(wrq, *client_addr, config)
+            }
             _ => panic!(),
         }
     }
 
-
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
     pub fn is_err(&self) -> bool {
         self.data_channel.is_err()
     }
-    pub fn err(self) -> String {
-        self.data_channel.err()
+    /// What went wrong with the transfer, if anything did.
+    pub fn error(&self) -> Option<&str> {
+        self.data_channel.error()
     }
     pub fn blk(&self) -> u16 {
         self.data_channel.blk()
     }
+    pub fn mode(&self) -> DataChannelMode {
+        self.data_channel.mode()
+    }
+    pub fn transferred_bytes(&self) -> u64 {
+        self.data_channel.transferred_bytes()
+    }
+    /// File bytes moved so far, see `DataChannel::transfer_size`.
+    pub fn transfer_size(&self) -> u64 {
+        self.data_channel.transfer_size()
+    }
+    pub fn block_size(&self) -> usize {
+        self.data_channel.block_size()
+    }
+    /// Time to wait for the client before the last packet is sent again,
+    /// the negotiated timeout if any, within the idle timeout. It's never
+    /// zero, a socket can't be given a zero timeout.
+    pub fn read_timeout(&self) -> Duration {
+        self.timeout.unwrap_or(DEFAULT_TIMEOUT).min(self.idle_timeout).max(MIN_TIMEOUT)
+    }
 
-    pub fn run(&mut self, raw_packet: &[u8]) {
-        let p = parse_udp_packet(raw_packet);
-        match p {
-            TFTPPacket::ERR(ep) => panic!("Terminating client: {}", ep.err()),
-            TFTPPacket::ACK(ack) => self.data_channel.on_ack(ack),
-            TFTPPacket::DATA(data) => self.data_channel.on_data(data),
-            p => panic!("Illegal packet {:?}", p),
-        };
+    pub fn run(&mut self, packet: TFTPPacket) {
+        // On error the ERR packet is at hand, it's sent to the client next.
+        let _ = self.data_channel.handle(packet);
     }
 
     pub fn on_packet_send(&mut self) {
         self.data_channel.on_packet_sent();
     }
 
-    fn init_rrq_response(rrq: ReadRequestPacket) -> Result<TFTPServer, ErrorPacket> {
-        DataChannel::new(rrq.filename(), DataChannelMode::Tx, DataChannelOwner::Server)
-            .and_then(|data_channel| {
-                let server = TFTPServer { data_channel };
-                Ok(server)
-            })
+    /// The ERR telling the client an unfinished transfer was given up on.
+    pub fn take_abort_packet(&mut self) -> Option<&[u8]> {
+        self.data_channel.take_abort_packet()
     }
 
-    fn init_wrq_response(wrq: WriteRequestPacket) -> Result<TFTPServer, ErrorPacket> {
-        DataChannel::new(wrq.filename(), DataChannelMode::Rx, DataChannelOwner::Server)
-            .and_then(|data_channel| {
-                let server = TFTPServer { data_channel };
-                Ok(server)
-            })
+    fn init_rrq_response(rrq: ReadRequestPacket, peer: SocketAddr, config: &ServerConfig) -> Result<TFTPServer, ErrorPacket> {
+        let mode = check_mode(rrq.mode())?;
+        if config.index_name.as_deref() == Some(rrq.filename()) {
+            return TFTPServer::init_index_response(rrq, mode, peer, config);
+        }
+
+        let path = resolve_path(&config.root, rrq.filename())?;
+        if !is_extension_allowed(&config.allowed_extensions, rrq.filename()) {
+            return Err(ErrorPacket::new(TFTPError::AccessViolation));
+        }
+        if config.tar_dirs && !config.chroot {
+            if let Some(dir) = archived_dir(&path) {
+                return TFTPServer::init_tar_response(rrq, &dir, mode, peer, config);
+            }
+        }
+
+        let mut data_channel = if config.chroot {
+            let fd = open_in_root(config, rrq.filename(), Access::Read)?;
+            DataChannel::send_file(&path.to_string_lossy(), fd, DataChannelOwner::Server)?
+        } else {
+            DataChannel::new(&path.to_string_lossy(), DataChannelMode::Tx, DataChannelOwner::Server)?
+        };
+        if config.reject_empty {
+            data_channel.refuse_empty()?;
+        }
+        set_transfer_mode(&mut data_channel, mode)?;
+        Ok(TFTPServer::negotiate(data_channel, peer, rrq.options(), Vec::new(), config))
     }
 
-    fn get_next_packet(&mut self) -> Vec<u8> {
-        self.data_channel.packet_at_hand().unwrap()
+    fn init_index_response(rrq: ReadRequestPacket, mode: TransferMode, peer: SocketAddr, config: &ServerConfig) -> Result<TFTPServer, ErrorPacket> {
+        let listing = list_root(config).map_err(|e| ErrorPacket::new_custom(e.to_string()))?;
+        let size = listing.len() as u64;
+        let source = Box::new(Cursor::new(listing));
+
+        let mut data_channel = DataChannel::from_source(rrq.filename(), source, size, DataChannelOwner::Server);
+        set_transfer_mode(&mut data_channel, mode)?;
+        Ok(TFTPServer::negotiate(data_channel, peer, rrq.options(), Vec::new(), config))
     }
 
-    fn done(&self) -> bool {
-        self.data_channel.is_done()
+    fn init_tar_response(rrq: ReadRequestPacket, dir: &Path, mode: TransferMode, peer: SocketAddr, config: &ServerConfig) -> Result<TFTPServer, ErrorPacket> {
+        let source = pack(dir).map_err(|e| ErrorPacket::new_custom(e.to_string()))?;
+        let mut data_channel = DataChannel::from_reader(rrq.filename(), Box::new(source), DataChannelOwner::Server);
+        set_transfer_mode(&mut data_channel, mode)?;
+        Ok(TFTPServer::negotiate(data_channel, peer, rrq.options(), Vec::new(), config))
     }
-}
 
-fn handle_client(socket: UdpSocket, mut server: TFTPServer, client_addr: SocketAddr) {
-    // asyncstd_task::spawn(async move {
-    loop {
-        if server.is_err() {
-            eprintln!("Fatal error: {}", server.err());
-            panic!();
+    fn init_health_response(rrq: ReadRequestPacket, peer: SocketAddr, config: &ServerConfig) -> TFTPServer {
+        let source = Box::new(Cursor::new(HEALTH_PAYLOAD));
+        let data_channel = DataChannel::from_source(rrq.filename(), source, HEALTH_PAYLOAD.len() as u64, DataChannelOwner::Server);
+        TFTPServer::negotiate(data_channel, peer, rrq.options(), Vec::new(), config)
+    }
+
+    fn init_wrq_response(wrq: WriteRequestPacket, peer: SocketAddr, config: &ServerConfig) -> Result<TFTPServer, ErrorPacket> {
+        let mode = check_mode(wrq.mode())?;
+        let path = resolve_path(&config.root, wrq.filename())?;
+        if !is_extension_allowed(&config.allowed_extensions, wrq.filename()) {
+            return Err(ErrorPacket::new(TFTPError::AccessViolation));
         }
 
-        if server.done() {
-            break;  // If we sent the last data packet in the previous loop
+        if (!config.create_dirs || config.chroot) && !path.parent().map_or(true, Path::is_dir) {
+            let err = String::from("Target directory doesn't exist.");
+            return Err(ErrorPacket::new_custom(err));
         }
 
-        let p = server.get_next_packet();
-        println!("Sending #{} [{}]", server.blk(), convert(p.len() as f64));
-        socket.send_to(&p, client_addr).unwrap();
-        server.on_packet_send();
-        if server.done() {
-            break;  // If we've just sent the last ack
+        let mut accepted = Vec::new();
+        if config.rename_uploads && wrq.options().iter().any(|(name, _)| name == "filename") {
+            accepted.push((String::from("filename"), wrq.filename().to_string()));
         }
 
-        let mut buf = [0 as u8; 1024];
-        match socket.recv_from(&mut buf){
-            Ok((count, addr)) => {
-                let raw_msg = &buf[..count];
+        let appends = config.append && wrq.options().iter().any(|(name, _)| name == "append");
+        let kept_len = match fs::metadata(&path) {
+            Ok(meta) if appends => meta.len(),
+            _ => 0,
+        };
+        if config.resumable_uploads && !appends {
+            return TFTPServer::init_resumable_upload(wrq, path, mode, peer, accepted, config);
+        }
 
-                if addr != client_addr {
-                    let error_packet = ErrorPacket::new(TFTPError::UnknownTID);
-                    socket.send_to(&error_packet.serialize(), addr).unwrap();
+        let overwrites = !appends && config.allow_overwrite && path.is_file();
+        if overwrites {
+            warn!("Overwriting [{}]", path.display());
+        }
+        // The old file is kept until the new one is complete, a failed
+        // upload only drops what it received.
+        let (file_name, received_into) = if overwrites {
+            let suffix = temp_suffix();
+            (format!("{}{}", wrq.filename(), suffix), temp_path(&path, &suffix))
+        } else {
+            (wrq.filename().to_string(), path.clone())
+        };
+        let access = if appends { Access::Append } else { Access::CreateNew };
+        let mut data_channel = match access {
+            _ if config.chroot => {
+                let fd = open_in_root(config, &file_name, access)?;
+                DataChannel::receive_file(&received_into.to_string_lossy(), fd, DataChannelOwner::Server)
+            }
+            Access::Append => DataChannel::append_to(&path.to_string_lossy(), DataChannelOwner::Server)?,
+            _ => DataChannel::new(&received_into.to_string_lossy(), DataChannelMode::Rx, DataChannelOwner::Server)?,
+        };
+        let temp_file = if overwrites { Some(TempFile::new(received_into, config)) } else { None };
+        set_transfer_mode(&mut data_channel, mode)?;
+        let mut server = TFTPServer::negotiate(data_channel, peer, wrq.options(), accepted, config);
+        server.kept_len = kept_len;
+        if overwrites {
+            server.complete_as = Some(path);
+            server.temp_file = temp_file;
+        }
+        Ok(server)
+    }
+
+    /// Receives the upload into `<path>.part`, a request with the `resume`
+    /// option adds to what's left there by an interrupted upload.
+    fn init_resumable_upload(wrq: WriteRequestPacket, path: PathBuf, mode: TransferMode, peer: SocketAddr, mut accepted: Vec<(String, String)>, config: &ServerConfig) -> Result<TFTPServer, ErrorPacket> {
+        if path.exists() && !config.allow_overwrite {
+            return Err(ErrorPacket::new(TFTPError::FileExists));
+        }
+
+        let part = part_path(&path);
+        let part_name = part.to_string_lossy();
+        // Another client is still sending the file, truncating or adding
+        // to its `.part` would mix the two uploads.
+        let part_claim = PartClaim::claim(&part)
+            .ok_or_else(|| ErrorPacket::with_message(TFTPError::FileExists, "File is being uploaded."))?;
+        // Netascii offsets don't match the client's file, those start over.
+        let resumes = mode == TransferMode::Octet && wrq.options().iter().any(|(name, _)| name == "resume");
+        let kept_len = match fs::metadata(&part) {
+            Ok(meta) if resumes && meta.is_file() => meta.len(),
+            _ => 0,
+        };
+
+        let access = if kept_len > 0 {
+            info!("Resuming [{}] after {} bytes", path.display(), kept_len);
+            Access::Append
+        } else {
+            Access::Truncate
+        };
+        let mut data_channel = match access {
+            _ if config.chroot => {
+                let fd = open_in_root(config, &format!("{}.part", wrq.filename()), access)?;
+                DataChannel::receive_file(&part_name, fd, DataChannelOwner::Server)
+            }
+            Access::Append => DataChannel::append_to(&part_name, DataChannelOwner::Server)?,
+            _ => DataChannel::overwrite(&part_name, DataChannelOwner::Server)?,
+        };
+        if resumes {
+            accepted.push((String::from("resume"), kept_len.to_string()));
+        }
+
+        set_transfer_mode(&mut data_channel, mode)?;
+        let mut server = TFTPServer::negotiate(data_channel, peer, wrq.options(), accepted, config);
+        server.kept_len = kept_len;
+        server.complete_as = Some(path);
+        server.part_claim = Some(part_claim);
+        Ok(server)
+    }
+
+    /// Applies the options of the request the server supports, the client
+    /// is answered with an OACK when any of them, or of those already
+    /// `accepted`, is accepted.
+    fn negotiate(mut data_channel: DataChannel, peer: SocketAddr, options: &[(String, String)], mut accepted: Vec<(String, String)>, config: &ServerConfig) -> TFTPServer {
+        let mut timeout = None;
+        // The finer grained option wins when a client sends both.
+        let has_utimeout = options
+            .iter()
+            .any(|(name, value)| name == "utimeout" && negotiate_timeout(name, value).is_some());
+
+        // Only a download's blocks are raised to the minimum, a client
+        // sending smaller blocks would take the first one for the last.
+        let min_blksize = match data_channel.mode() {
+            DataChannelMode::Tx => config.min_blksize,
+            DataChannelMode::Rx => None,
+        };
+
+        for (name, value) in options {
+            // Unknown options are ignored (RFC 2347).
+            if name == "blksize" {
+                if let Some(block_size) = negotiate_block_size(value, config.mtu, config.pow2_blksize, min_blksize) {
+                    data_channel.set_block_size(block_size);
+                    accepted.push((name.clone(), block_size.to_string()));
+                }
+            } else if name == "append" && config.append && data_channel.mode() == DataChannelMode::Rx {
+                accepted.push((name.clone(), value.clone()));
+            } else if name == "tsize" && data_channel.mode() == DataChannelMode::Tx {
+                // The size of a download, if it's known upfront (RFC 2349).
+                if let Some(size) = data_channel.size() {
+                    accepted.push((name.clone(), size.to_string()));
+                }
+            } else if name == "utimeout" || (name == "timeout" && !has_utimeout) {
+                if let Some(negotiated) = negotiate_timeout(name, value) {
+                    let value = match name.as_str() {
+                        "utimeout" => negotiated.as_micros().to_string(),
+                        _ => negotiated.as_secs().to_string(),
+                    };
+                    timeout = Some(negotiated);
+                    accepted.push((name.clone(), value));
                 }
+            }
+        }
 
-                server.run(raw_msg);
-            },
-            Err(e) => {
-                eprintln!("Client connection error: {}", e);
-                break;
+        if !accepted.is_empty() {
+            data_channel.acknowledge_options(OptionAckPacket::new(accepted));
+        }
+
+        let quota = match data_channel.mode() {
+            DataChannelMode::Rx => config.disk_quota.clone(),
+            DataChannelMode::Tx => None,
+        };
+
+        TFTPServer {
+            data_channel,
+            peer,
+            quota,
+            charged: 0,
+            kept_len: 0,
+            complete_as: None,
+            part_claim: None,
+            temp_file: None,
+            chroot: if config.chroot { Some(config.root.clone()) } else { None },
+            read_ahead: config.read_ahead,
+            timeout,
+            idle_timeout: config.idle_timeout,
+            dally: config.dally,
+            on_progress: config.on_progress.clone(),
+            clock: Arc::clone(&config.clock),
+        }
+    }
+
+    fn report_progress(&self) {
+        if let Some(on_progress) = &self.on_progress {
+            on_progress(self);
+        }
+    }
+
+    fn get_next_packet(&mut self) -> Option<&[u8]> {
+        self.data_channel.packet_at_hand()
+    }
+
+    fn read_ahead(&mut self) {
+        if self.read_ahead {
+            self.data_channel.read_ahead();
+        }
+    }
+
+    /// Counts newly received bytes against the disk quota.
+    fn charge_quota(&mut self, bytes: u64) -> Result<(), ErrorPacket> {
+        match &self.quota {
+            Some(quota) if bytes > 0 => {
+                if !quota.reserve(bytes) {
+                    return Err(ErrorPacket::new(TFTPError::DiskFull));
+                }
+                self.charged += bytes;
+                Ok(())
             }
+            _ => Ok(()),
+        }
+    }
+
+    /// Undoes an upload, what it wrote is dropped and given back to the quota.
+    fn roll_back(self) -> io::Result<()> {
+        if let Some(quota) = &self.quota {
+            quota.release(self.charged);
+        }
+
+        let file_name = PathBuf::from(self.data_channel.file_name());
+        drop(self.data_channel);
+        if let Some(temp_file) = self.temp_file {
+            return temp_file.remove();
+        }
+
+        let chroot = self.chroot.as_deref();
+        match (chroot, self.kept_len) {
+            (_, 0) => remove_upload_file(chroot, &file_name),
+            (Some(root), kept_len) => open_beneath(root, beneath(root, &file_name), Access::Append)?.set_len(kept_len),
+            (None, kept_len) => fs::OpenOptions::new().write(true).open(file_name)?.set_len(kept_len),
         }
     }
+
+    /// Gives a complete upload the name it was sent with.
+    fn complete(mut self) -> io::Result<()> {
+        let file_name = PathBuf::from(self.data_channel.file_name());
+        drop(self.data_channel);
+        let path = match self.complete_as {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        rename_upload_file(self.chroot.as_deref(), &file_name, &path)?;
+        if let Some(temp_file) = &mut self.temp_file {
+            temp_file.kept = true;
+        }
+        Ok(())
+    }
+
+    fn done(&self) -> bool {
+        self.data_channel.is_done()
+    }
 }
 
-pub fn handle_new_client(client_addr: SocketAddr, rq_packet: &[u8]) {
-    println!("New connection: {}", client_addr);
-    let socket = UdpSocket::bind("0.0.0.0:0").expect("Failed to bind UDP socket");
-    socket.set_read_timeout(sock_dur);
+/// File that a resumable upload of `path` is received into.
+fn part_path(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+    PathBuf::from(part)
+}
 
-    match TFTPServer::new(rq_packet) {
-        Ok(server) => {
-            handle_client(socket, server, client_addr);
+/// Suffix of a file an overwriting upload is received into, no other
+/// upload gets the same one.
+fn temp_suffix() -> String {
+    format!(".{}.tmp", unique_upload_name(""))
+}
+
+fn temp_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(suffix);
+    PathBuf::from(temp)
+}
+
+/// The file an overwriting upload is received into, the old file is
+/// kept until it takes its place. It's removed if the upload fails.
+struct TempFile {
+    path: PathBuf,
+    chroot: Option<PathBuf>,
+    /// Whether the file took the place of the old one.
+    kept: bool,
+}
+
+impl TempFile {
+    fn new(path: PathBuf, config: &ServerConfig) -> Self {
+        let chroot = if config.chroot { Some(config.root.clone()) } else { None };
+        TempFile { path, chroot, kept: false }
+    }
+
+    fn remove(mut self) -> io::Result<()> {
+        self.kept = true;
+        remove_upload_file(self.chroot.as_deref(), &self.path)
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if self.kept {
+            return;
         }
-        Err(error_packet) => {
-            eprintln!("Terminating client [{}]", error_packet.err());
-            socket
-                .send_to(&error_packet.serialize(), client_addr)
-                .unwrap();
-            drop(socket);
+        if let Err(e) = remove_upload_file(self.chroot.as_deref(), &self.path) {
+            warn!("Failed to remove [{}]: {}", self.path.display(), e);
         }
     }
 }
 
-pub fn server_main(address: &str, port: u16) {
-    let addr = format!("{}:{}", address, port);
-    let sock = UdpSocket::bind(addr).expect("Failed to bind UDP socket");
-    println!("[SERVER_ADDRESS]: {}", sock.local_addr().unwrap());
+/// Renames a file of an upload, beneath `chroot` when it's set.
+fn rename_upload_file(chroot: Option<&Path>, from: &Path, to: &Path) -> io::Result<()> {
+    match chroot {
+        Some(root) => rename_beneath(root, beneath(root, from), beneath(root, to)),
+        None => fs::rename(from, to),
+    }
+}
 
-    let f = async {
-        loop {
-            let mut buf = [0; 1024];
-            let (count, addr) = sock.recv_from(&mut buf).unwrap();
+/// Removes a file of an upload, beneath `chroot` when it's set.
+fn remove_upload_file(chroot: Option<&Path>, path: &Path) -> io::Result<()> {
+    match chroot {
+        Some(root) => remove_beneath(root, beneath(root, path)),
+        None => fs::remove_file(path),
+    }
+}
 
-            let raw_packet = &buf[..count];
-            match parse_udp_packet(raw_packet) {
-                TFTPPacket::RRQ(_) | TFTPPacket::WRQ(_) => {
-                    handle_new_client(addr, raw_packet);
-                }
-                _ => {
-                    let err = ErrorPacket::new(TFTPError::IllegalOperation);
-                    sock.send_to(&err.serialize(), addr).unwrap();
-                }
-            }
+/// `path` relative to the `root` it was resolved in.
+fn beneath<'a>(root: &Path, path: &'a Path) -> &'a Path {
+    path.strip_prefix(root).unwrap_or(path)
+}
+
+/// `.part` files that uploads are being received into.
+static PARTS_IN_USE: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// An upload's claim on its `.part` file, given up when dropped.
+struct PartClaim(PathBuf);
+
+impl PartClaim {
+    /// Claims `part`, unless another upload holds it.
+    fn claim(part: &Path) -> Option<PartClaim> {
+        let mut parts = PARTS_IN_USE.lock().unwrap();
+        if parts.iter().any(|p| p == part) {
+            return None;
         }
-    };
-    asyncstd_task::block_on(f);
+        parts.push(part.to_path_buf());
+        Some(PartClaim(part.to_path_buf()))
+    }
+}
+
+impl Drop for PartClaim {
+    fn drop(&mut self) {
+        PARTS_IN_USE.lock().unwrap().retain(|p| *p != self.0);
+    }
+}
+
+/// How a request ended, as told by the access log.
+struct Outcome {
+    /// Code of the error that ended the transfer.
+    error: Option<u16>,
+    bytes: u64,
+}
+
+impl Outcome {
+    fn failed(code: u16, bytes: u64) -> Self {
+        Outcome { error: Some(code), bytes }
+    }
+}
+
+/// Ends a transfer that went through.
+fn finish_transfer(server: TFTPServer, metrics: &ServerMetrics) -> Outcome {
+    let bytes = server.transfer_size();
+    metrics.on_transfer_done(server.mode(), bytes);
+    if let Err(e) = server.complete() {
+        error!("Failed to complete an upload: {}", e);
+    }
+
+    Outcome { error: None, bytes }
+}
+
+/// A transfer being served, the client is told it was aborted if it's
+/// dropped before it's over. Like when serving it panics.
+struct AbortOnDrop<'a, T: Transport> {
+    transport: &'a T,
+    server: Option<TFTPServer>,
+}
+
+impl<'a, T: Transport> AbortOnDrop<'a, T> {
+    fn new(transport: &'a T, server: TFTPServer) -> Self {
+        AbortOnDrop { transport, server: Some(server) }
+    }
+
+    /// Hands back the transfer, the client hears nothing more of it.
+    fn into_inner(mut self) -> TFTPServer {
+        self.server.take().unwrap()
+    }
+}
+
+impl<T: Transport> Deref for AbortOnDrop<'_, T> {
+    type Target = TFTPServer;
+
+    fn deref(&self) -> &TFTPServer {
+        self.server.as_ref().unwrap()
+    }
+}
+
+impl<T: Transport> DerefMut for AbortOnDrop<'_, T> {
+    fn deref_mut(&mut self) -> &mut TFTPServer {
+        self.server.as_mut().unwrap()
+    }
+}
+
+impl<T: Transport> Drop for AbortOnDrop<'_, T> {
+    fn drop(&mut self) {
+        let server = match &mut self.server {
+            Some(server) => server,
+            None => return,
+        };
+
+        let peer = server.peer();
+        if let Some(p) = server.take_abort_packet() {
+            warn!("Aborting the transfer of [{}]", peer);
+            let _ = send_retrying(self.transport, p, peer);
+        }
+    }
+}
+
+fn handle_client<T: Transport>(transport: &T, server: TFTPServer, metrics: &ServerMetrics) -> Outcome {
+    let mut server = AbortOnDrop::new(transport, server);
+    let client_addr = server.peer();
+    // The packets sent and received go through the same two buffers.
+    let mut last_sent = Vec::new();
+    let mut buf = Vec::new();
+    let mut last_heard = server.clock.now();
+    // asyncstd_task::spawn(async move {
+    loop {
+        if server.done() {
+            return finish_transfer(server.into_inner(), metrics);  // If we sent the last data packet in the previous loop
+        }
+
+        // Nothing is at hand when a duplicate packet was ignored. Reading
+        // the next block may fail, that's what's at hand then.
+        let has_next = match server.get_next_packet() {
+            Some(p) => {
+                last_sent.clear();
+                last_sent.extend_from_slice(p);
+                true
+            }
+            None => false,
+        };
+        if server.is_err() {
+            // The error packet is at hand, the client is told why before we stop.
+            let mut code = TFTPError::IllegalOperation.code();
+            if has_next {
+                if let Ok(TFTPPacket::ERR(ep)) = parse_udp_packet(&last_sent) {
+                    code = ep.code();
+                }
+                let _ = send_retrying(transport, &last_sent, client_addr);
+            }
+            metrics.on_error(code);
+            error!("Fatal error with [{}]: {}", client_addr, server.error().unwrap_or_default());
+            return Outcome::failed(code, server.transfer_size());
+        }
+
+        if has_next {
+            info!("Sending #{} [{}] to [{}]", server.blk(), convert(last_sent.len() as f64), client_addr);
+            if let Err(e) = send_retrying(transport, &last_sent, client_addr) {
+                return send_failed(e, &server, metrics);
+            }
+            server.on_packet_send();
+            if server.done() {
+                let period = server.dally;
+                let outcome = finish_transfer(server.into_inner(), metrics);  // If we've just sent the last ack
+                if period > Duration::from_secs(0) {
+                    dally(transport, client_addr, &last_sent, period);
+                }
+                return outcome;
+            }
+            server.read_ahead();
+        }
+
+        buf.resize(recv_buffer_len(server.block_size()), 0);
+        match transport.recv_from(&mut buf){
+            Ok((count, addr)) if count == buf.len() && addr == client_addr => {
+                let error_packet = ErrorPacket::with_message(TFTPError::IllegalOperation, "Packet is larger than the block size.");
+                metrics.on_error(error_packet.code());
+                warn!("Terminating client [{}]: {}", client_addr, error_packet.err());
+                let outcome = Outcome::failed(error_packet.code(), server.into_inner().transfer_size());
+                let _ = send_retrying(transport, &error_packet.serialize(), client_addr);
+                return outcome;
+            }
+            Ok((count, addr)) => {
+                let raw_msg = &buf[..count];
+
+                // Someone else is told off, their packet has no say in the transfer.
+                if addr != client_addr {
+                    let error_packet = ErrorPacket::new(TFTPError::UnknownTID);
+                    metrics.on_error(error_packet.code());
+                    let _ = send_retrying(transport, &error_packet.serialize(), addr);
+                    continue;
+                }
+                last_heard = server.clock.now();
+
+                let packet = match parse_udp_packet(raw_msg) {
+                    Ok(packet) => packet,
+                    Err(e) => {
+                        warn!("Dropping malformed packet from [{}]: {}", addr, e);
+                        continue;
+                    }
+                };
+
+                // The client gave up, there's nobody left to answer.
+                if let TFTPPacket::ERR(ep) = packet {
+                    info!("Client [{}] aborted: {}", client_addr, ep.err());
+                    return Outcome::failed(ep.code(), server.into_inner().transfer_size());
+                }
+
+                let received = server.transferred_bytes();
+                server.run(packet);
+                server.report_progress();
+                let charge = server.transferred_bytes() - received;
+                if let Err(error_packet) = server.charge_quota(charge) {
+                    metrics.on_error(error_packet.code());
+                    warn!("Terminating client [{}]: {}", client_addr, error_packet.err());
+                    let outcome = Outcome::failed(error_packet.code(), server.transfer_size());
+                    // Rolled back first, the client may try again once told.
+                    if let Err(e) = server.into_inner().roll_back() {
+                        error!("Failed to roll back an upload: {}", e);
+                    }
+                    let _ = send_retrying(transport, &error_packet.serialize(), client_addr);
+                    return outcome;
+                }
+            },
+            // The client isn't idle yet, what it may have missed is sent again.
+            Err(e) if (e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut)
+                && server.clock.now() - last_heard < server.idle_timeout => {
+                if !last_sent.is_empty() {
+                    debug!("Resending #{} to [{}]", server.blk(), client_addr);
+                    if let Err(e) = send_retrying(transport, &last_sent, client_addr) {
+                        return send_failed(e, &server, metrics);
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+                let error_packet = ErrorPacket::with_message(TFTPError::UndefinedError, "Transfer timed out.");
+                metrics.on_error(error_packet.code());
+                warn!("Client [{}] went idle, dropping it", client_addr);
+                let outcome = Outcome::failed(error_packet.code(), server.into_inner().transfer_size());
+                let _ = send_retrying(transport, &error_packet.serialize(), client_addr);
+                return outcome;
+            }
+            Err(e) => {
+                warn!("Client connection error: {}", e);
+                return Outcome::failed(TFTPError::UndefinedError.code(), server.transfer_size());
+            }
+        }
+    }
+}
+
+/// Ends a transfer whose packet couldn't be sent, even after trying again.
+fn send_failed(e: io::Error, server: &TFTPServer, metrics: &ServerMetrics) -> Outcome {
+    let code = TFTPError::UndefinedError.code();
+    metrics.on_error(code);
+    error!("Failed to send #{} to [{}]: {}", server.blk(), server.peer(), e);
+    Outcome::failed(code, server.transfer_size())
+}
+
+/// Tells whether the packet has the op code of a RRQ or a WRQ.
+fn is_request(raw_packet: &[u8]) -> bool {
+    raw_packet.len() >= 2 && raw_packet[0] == 0 && (raw_packet[1] == 1 || raw_packet[1] == 2)
+}
+
+/// Binds the socket a client is served on to the first
+/// free port of the range, or any free port without one.
+fn bind_data_socket(ip: IpAddr, port_range: &Option<RangeInclusive<u16>>) -> io::Result<UdpSocket> {
+    let range = match port_range {
+        Some(range) => range.clone(),
+        None => return UdpSocket::bind((ip, 0)),
+    };
+
+    for port in range {
+        match UdpSocket::bind((ip, port)) {
+            Ok(socket) => return Ok(socket),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Err(io::Error::new(io::ErrorKind::AddrInUse, "No free data port, try again later."))
+}
+
+/// Serves the request on a new socket bound to `local_ip`.
+pub fn handle_new_client(client_addr: SocketAddr, local_ip: IpAddr, rq_packet: &[u8], config: &ServerConfig, metrics: &ServerMetrics) {
+    info!("New connection: {}", client_addr);
+    let socket = bind_data_socket(local_ip, &config.port_range).and_then(|socket| {
+        if let Some(interface) = &config.interface {
+            bind_to_interface(&socket, interface)?;
+        }
+        Ok(socket)
+    });
+    let socket = match socket {
+        Ok(socket) => socket,
+        Err(e) => {
+            // The client still has to hear why, from outside the range.
+            let error_packet = ErrorPacket::with_message(TFTPError::UndefinedError, &e.to_string());
+            metrics.on_error(error_packet.code());
+            warn!("Terminating client [{}]", error_packet.err());
+            let socket = UdpTransport::new(UdpSocket::bind((local_ip, 0)).expect("Failed to bind UDP socket"));
+            let outcome = Outcome::failed(error_packet.code(), 0);
+            if let Err(e) = send_retrying(&socket, &error_packet.serialize(), client_addr) {
+                warn!("Failed to tell [{}] off: {}", client_addr, e);
+            }
+            log_access(config, &client_addr, rq_packet, &outcome);
+            return;
+        }
+    };
+    // A zero timeout would block forever, std refuses it.
+    socket.set_read_timeout(Some(config.idle_timeout.max(MIN_TIMEOUT))).expect("Failed to set the idle timeout");
+    if let Some(dscp) = config.dscp {
+        if let Err(e) = set_dscp(&socket, dscp) {
+            warn!("Failed to set DSCP {}: {}", dscp, e);
+        }
+    }
+    if let Err(e) = set_buffer_sizes(&socket, config.recv_buffer, config.send_buffer) {
+        warn!("Failed to size the socket buffers: {}", e);
+    }
+
+    let outcome = match TFTPServer::new(rq_packet, &client_addr, config) {
+        Ok(server) => {
+            socket.set_read_timeout(Some(server.read_timeout())).expect("Failed to set the read timeout");
+            handle_client(&UdpTransport::new(socket), server, metrics)
+        }
+        Err(error_packet) if config.silent_notfound && error_packet.code() == TFTPError::FileNotFound.code() => {
+            metrics.on_dropped();
+            info!("Dropping request of [{}] for a missing file", client_addr);
+            Outcome::failed(error_packet.code(), 0)
+        }
+        Err(error_packet) => {
+            metrics.on_error(error_packet.code());
+            warn!("Terminating client [{}]", error_packet.err());
+            let outcome = Outcome::failed(error_packet.code(), 0);
+            if let Err(e) = send_retrying(&UdpTransport::new(socket), &error_packet.serialize(), client_addr) {
+                warn!("Failed to tell [{}] off: {}", client_addr, e);
+            }
+            outcome
+        }
+    };
+    log_access(config, &client_addr, rq_packet, &outcome);
+}
+
+/// Serves the request like `handle_new_client`, a panic on the way is
+/// logged with the client's address and counted as an error.
+fn handle_new_client_catching_panics(client_addr: SocketAddr, local_ip: IpAddr, rq_packet: &[u8], config: &ServerConfig, metrics: &ServerMetrics) {
+    let served = panic::catch_unwind(AssertUnwindSafe(|| {
+        handle_new_client(client_addr, local_ip, rq_packet, config, metrics);
+    }));
+
+    if let Err(payload) = served {
+        let msg = payload
+            .downcast_ref::<&str>()
+            .map(|msg| msg.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        error!("Serving [{}] panicked: {}", client_addr, msg);
+        let code = TFTPError::UndefinedError.code();
+        metrics.on_error(code);
+        log_access(config, &client_addr, rq_packet, &Outcome::failed(code, 0));
+    }
+}
+
+fn log_access(config: &ServerConfig, client_addr: &SocketAddr, rq_packet: &[u8], outcome: &Outcome) {
+    if let Some(access_log) = &config.access_log {
+        access_log.record(client_addr, rq_packet, outcome);
+    }
+}
+
+/// Counts the workers that are free to serve a client.
+struct WorkerPool {
+    workers: usize,
+    state: Mutex<PoolState>,
+    freed: Condvar,
+}
+
+struct PoolState {
+    free: usize,
+    /// Requests waiting for a worker to be freed.
+    waiting: usize,
+}
+
+/// A worker taken from the pool, it's given back when dropped.
+struct Worker {
+    pool: Arc<WorkerPool>,
+}
+
+impl WorkerPool {
+    /// A pool of at least one worker, no request would be served otherwise.
+    fn new(workers: usize) -> Self {
+        let workers = workers.max(1);
+        WorkerPool {
+            workers,
+            state: Mutex::new(PoolState { free: workers, waiting: 0 }),
+            freed: Condvar::new(),
+        }
+    }
+
+    /// Waits up to `timeout` for a free worker. As many requests may
+    /// wait as there are workers, the rest are refused right away.
+    fn acquire(pool: &Arc<WorkerPool>, timeout: Duration) -> Option<Worker> {
+        let mut state = pool.state.lock().unwrap();
+        if state.free == 0 && state.waiting >= pool.workers {
+            return None;
+        }
+
+        state.waiting += 1;
+        let (mut state, _) = pool
+            .freed
+            .wait_timeout_while(state, timeout, |state| state.free == 0)
+            .unwrap();
+        state.waiting -= 1;
+
+        if state.free == 0 {
+            return None;
+        }
+
+        state.free -= 1;
+        Some(Worker { pool: Arc::clone(pool) })
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.pool.state.lock().unwrap().free += 1;
+        self.pool.freed.notify_one();
+    }
+}
+
+/// Listens for requests and serves each client on its own
+/// thread, at most `workers` clients are served at once.
+pub struct TFTPListener {
+    sock: Arc<UdpSocket>,
+    config: Arc<ServerConfig>,
+    metrics: Arc<ServerMetrics>,
+    workers: Arc<WorkerPool>,
+}
+
+impl TFTPListener {
+    pub fn bind(address: &str, port: u16, config: ServerConfig) -> io::Result<Self> {
+        let addr = format!("{}:{}", address, port);
+        let sock = UdpSocket::bind(addr)?;
+        if let Some(interface) = &config.interface {
+            bind_to_interface(&sock, interface)?;
+        }
+
+        Ok(TFTPListener {
+            sock: Arc::new(sock),
+            workers: Arc::new(WorkerPool::new(config.workers)),
+            config: Arc::new(config),
+            metrics: Arc::new(ServerMetrics::default()),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.sock.local_addr()
+    }
+
+    /// Counters for the clients served so far, they keep
+    /// being updated while the listener is serving.
+    pub fn metrics(&self) -> Arc<ServerMetrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    /// Whether a one-shot listener has served its download.
+    fn is_done(&self) -> bool {
+        self.config.once && self.metrics.downloads() > 0
+    }
+
+    /// Answers requests until the socket fails for good, errors
+    /// a client can cause (see `is_transient`) are only logged.
+    /// A one-shot listener returns once its download completed.
+    pub fn serve(&self) -> io::Result<()> {
+        let sock = &self.sock;
+        let local_ip = match self.config.data_address {
+            Some(ip) => ip,
+            None => sock.local_addr().expect("Listener has no address").ip(),
+        };
+        if self.config.once {
+            sock.set_read_timeout(Some(ONCE_POLL))?;
+        }
+
+        #[cfg(feature = "async-server")]
+        return asyncstd_task::block_on(async { self.listen(local_ip) });
+        #[cfg(not(feature = "async-server"))]
+        self.listen(local_ip)
+    }
+
+    /// Takes requests until the listener is done, each client is handed
+    /// to a worker of its own.
+    fn listen(&self, local_ip: IpAddr) -> io::Result<()> {
+        let sock = &self.sock;
+        loop {
+            if self.is_done() {
+                info!("Served the download, no longer listening");
+                return Ok(());
+            }
+
+            // The spare byte tells requests that are too long apart.
+            let mut buf = [0; MAX_REQUEST_LEN + 1];
+            let (count, addr) = match sock.recv_from(&mut buf) {
+                Ok(received) => received,
+                // Only a one-shot listener has a read timeout.
+                Err(e) if self.config.once && (e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut) => {
+                    continue;
+                }
+                Err(e) if is_transient(&e) => {
+                    warn!("Failed to receive a request: {}", e);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            let raw_packet = &buf[..count];
+            log_packet(false, addr, raw_packet);
+            match parse_udp_packet(raw_packet) {
+                Ok(TFTPPacket::RRQ(_)) | Ok(TFTPPacket::WRQ(_)) => {
+                    let sock = Arc::clone(&self.sock);
+                    let config = Arc::clone(&self.config);
+                    let metrics = Arc::clone(&self.metrics);
+                    let workers = Arc::clone(&self.workers);
+                    let raw_packet = raw_packet.to_vec();
+                    // The client waits for a worker on its own thread, the
+                    // listener goes on taking requests meanwhile.
+                    thread::spawn(move || {
+                        logger::set_connection(Some(addr));
+                        let worker = match WorkerPool::acquire(&workers, config.queue_timeout) {
+                            Some(worker) => worker,
+                            None => return refuse_busy(&sock, addr, &raw_packet, &config, &metrics),
+                        };
+                        // The download completed while the request was queued.
+                        if config.once && metrics.downloads() > 0 {
+                            return;
+                        }
+
+                        if config.catch_panics {
+                            handle_new_client_catching_panics(addr, local_ip, &raw_packet, &config, &metrics);
+                        } else {
+                            handle_new_client(addr, local_ip, &raw_packet, &config, &metrics);
+                        }
+                        drop(worker);
+                    });
+                }
+                // Requests that are too long or carry too many options aren't
+                // answered, a flood of them shouldn't be met with a flood of errors.
+                Err(e) if count > MAX_REQUEST_LEN || is_request(raw_packet) => {
+                    debug!("Dropping malformed request from [{}]: {}", addr, e);
+                    self.metrics.on_dropped();
+                }
+                _ => {
+                    let err = ErrorPacket::new(TFTPError::IllegalOperation);
+                    self.metrics.on_error(err.code());
+                    let err = err.serialize();
+                    log_packet(true, addr, &err);
+                    if let Err(e) = sock.send_to(&err, addr) {
+                        warn!("Failed to answer [{}]: {}", addr, e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Tells a client that no worker was freed in time to serve it, or where
+/// to go instead.
+fn refuse_busy(sock: &UdpSocket, addr: SocketAddr, raw_packet: &[u8], config: &ServerConfig, metrics: &ServerMetrics) {
+    let err = match config.redirect_busy_to {
+        Some(other) => ErrorPacket::redirect(other),
+        None => ErrorPacket::with_message(TFTPError::UndefinedError, "Server is busy, try again later."),
+    };
+    metrics.on_error(err.code());
+    let outcome = Outcome::failed(err.code(), 0);
+    let err = err.serialize();
+    log_packet(true, addr, &err);
+    if let Err(e) = sock.send_to(&err, addr) {
+        warn!("Failed to answer [{}]: {}", addr, e);
+    }
+    log_access(config, &addr, raw_packet, &outcome);
+}
+
+/// Tells errors the listening socket recovers from. Some platforms report
+/// an ICMP port unreachable, caused by an answer to a client that's gone,
+/// on the next read as a reset or refused connection.
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+    )
+}
+
+pub fn server_main(address: &str, port: u16, config: ServerConfig) {
+    let listener = match TFTPListener::bind(address, port, config) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to bind UDP socket: {}", e);
+            exit(1);
+        }
+    };
+    info!("[SERVER_ADDRESS]: {}", listener.local_addr().unwrap());
+    if let Err(e) = listener.serve() {
+        error!("Stopped listening: {}", e);
+        exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::fs;
+    use std::io;
+    use std::net::{SocketAddr, UdpSocket};
+    use std::panic::{self, AssertUnwindSafe};
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use serde_json::Value;
+
+    use crate::tftp::client::{transfer, transfer_with, ClientConfig, TransferError, TransferStats};
+    use crate::tftp::server::{handle_client, is_request, is_transient, AccessLog, DiskQuota, resolve_path, resolve_root_from, Operation, ServerConfig, ServerMetrics, TFTPListener, TFTPServer, MIN_TIMEOUT};
+    use crate::tftp::shared::{parse_udp_packet, Serializable, STRIDE_SIZE, TFTPPacket};
+    use crate::tftp::shared::ack_packet::AckPacket;
+    use crate::tftp::shared::data_packet::DataPacket;
+    use crate::tftp::shared::err_packet::{ErrorPacket, TFTPError};
+    use crate::tftp::shared::request_packet::{ReadRequestPacket, WriteRequestPacket};
+    use crate::tftp::shared::test_util::{memory_link, scratch_dir, Faults, MockClock};
+    use crate::tftp::shared::transport::Transport;
+
+    #[test]
+    fn download_resolves_against_env_root() {
+        let dir = scratch_dir("env_root");
+        fs::write(dir.join("a.txt"), "hello").unwrap();
+
+        let env_root = Some(dir.clone().into_os_string());
+        assert_eq!(resolve_root_from(Some("flag"), env_root.clone()), PathBuf::from("flag"));
+        let config = ServerConfig {
+            root: resolve_root_from(None, env_root),
+            ..ServerConfig::default()
+        };
+        assert_eq!(resolve_root_from(None, None), PathBuf::from("."));
+        assert_eq!(config.root, dir);
+
+        let rrq = ReadRequestPacket::new("a.txt", "octet").serialize();
+        let mut server = TFTPServer::new(&rrq, &client(), &config).ok().unwrap();
+        match parse_udp_packet(server.get_next_packet().unwrap()).unwrap() {
+            TFTPPacket::DATA(dp) => assert_eq!(dp.data(), b"hello"),
+            p => panic!("Expected DATA got {:?}", p),
+        }
+    }
+
+    #[test]
+    fn embedders_are_shown_the_progress_of_transfers() {
+        let dir = scratch_dir("progress_hook");
+        fs::write(dir.join("a.txt"), vec![1; 1300]).unwrap();
+        let progress = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&progress);
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root: dir,
+            on_progress: Some(Arc::new(move |server: &TFTPServer| {
+                seen.lock().unwrap().push((server.transfer_size(), server.error().map(String::from)));
+            })),
+            ..ServerConfig::default()
+        });
+        thread::spawn(move || listener.serve());
+
+        assert_eq!(download(addr, "a.txt").unwrap().len(), 1300);
+        wait_until(|| progress.lock().unwrap().len() == 3);
+        assert_eq!(*progress.lock().unwrap(), vec![(512, None), (1024, None), (1300, None)]);
+    }
+
+    #[test]
+    fn progress_and_errors_are_read_mid_transfer() {
+        let dir = scratch_dir("server_progress");
+        fs::write(dir.join("a.txt"), vec![1; 1300]).unwrap();
+        let config = ServerConfig {
+            root: dir,
+            ..ServerConfig::default()
+        };
+
+        let rrq = ReadRequestPacket::new("a.txt", "octet").serialize();
+        let mut server = TFTPServer::new(&rrq, &client(), &config).ok().unwrap();
+        server.get_next_packet().unwrap();
+        server.on_packet_send();
+        server.run(TFTPPacket::ACK(AckPacket::new(1)));
+        assert_eq!(server.blk(), 2);
+        assert_eq!(server.transfer_size(), 512);
+        assert_eq!(server.error(), None);
+
+        server.get_next_packet().unwrap();
+        server.on_packet_send();
+        server.run(TFTPPacket::ACK(AckPacket::new(7)));
+        assert!(server.error().is_some());
+        // Reading the error leaves the server to tell the client about it.
+        assert_eq!(server.transfer_size(), 1024);
+        match parse_udp_packet(server.get_next_packet().unwrap()).unwrap() {
+            TFTPPacket::ERR(_) => {}
+            p => panic!("Expected ERR got {:?}", p),
+        }
+    }
+
+    #[test]
+    fn dots_inside_names_are_allowed() {
+        let root = PathBuf::from("root");
+        assert_eq!(resolve_path(&root, "my..file.txt").unwrap(), root.join("my..file.txt"));
+        assert_eq!(resolve_path(&root, "a/b..c").unwrap(), root.join("a/b..c"));
+    }
+
+    #[test]
+    fn paths_escaping_root_are_refused() {
+        let root = PathBuf::from("root");
+        assert!(resolve_path(&root, "../etc/passwd").is_err());
+        assert!(resolve_path(&root, "a/../../b").is_err());
+        assert!(resolve_path(&root, "/etc/passwd").is_err());
+
+        let rrq = ReadRequestPacket::new("../a.txt", "octet").serialize();
+        assert!(TFTPServer::new(&rrq, &client(), &ServerConfig::default()).is_err());
+    }
+
+    #[test]
+    fn uploads_create_dirs_only_when_allowed() {
+        let dir = scratch_dir("create_dirs");
+        let wrq = WriteRequestPacket::new("sub/a.txt", "octet").serialize();
+
+        let mut config = ServerConfig {
+            root: dir.clone(),
+            ..ServerConfig::default()
+        };
+        assert!(TFTPServer::new(&wrq, &client(), &config).is_err());
+        assert!(!dir.join("sub").exists());
+
+        config.create_dirs = true;
+        assert!(TFTPServer::new(&wrq, &client(), &config).is_ok());
+        assert!(dir.join("sub").is_dir());
+    }
+
+    fn ext_config(dir: PathBuf) -> ServerConfig {
+        ServerConfig {
+            root: dir,
+            allowed_extensions: vec![String::from("cfg"), String::from(".bin")],
+            ..ServerConfig::default()
+        }
+    }
+
+    #[test]
+    fn allowed_extensions_are_served() {
+        let dir = scratch_dir("allowed_ext");
+        fs::write(dir.join("boot.bin"), "image").unwrap();
+        let config = ext_config(dir);
+
+        let rrq = ReadRequestPacket::new("boot.bin", "octet").serialize();
+        assert!(TFTPServer::new(&rrq, &client(), &config).is_ok());
+
+        let wrq = WriteRequestPacket::new("switch.CFG", "octet").serialize();
+        assert!(TFTPServer::new(&wrq, &client(), &config).is_ok());
+    }
+
+    #[test]
+    fn other_extensions_are_refused() {
+        let dir = scratch_dir("disallowed_ext");
+        fs::write(dir.join("secret.key"), "key").unwrap();
+        let config = ext_config(dir);
+        let violation = ErrorPacket::new(TFTPError::AccessViolation);
+
+        let rrq = ReadRequestPacket::new("secret.key", "octet").serialize();
+        assert_eq!(TFTPServer::new(&rrq, &client(), &config).err(), Some(violation));
+
+        let violation = ErrorPacket::new(TFTPError::AccessViolation);
+        let wrq = WriteRequestPacket::new("upload", "octet").serialize();
+        assert_eq!(TFTPServer::new(&wrq, &client(), &config).err(), Some(violation));
+    }
+
+    #[test]
+    fn read_only_server_refuses_uploads() {
+        let dir = scratch_dir("read_only");
+        let config = ServerConfig {
+            root: dir.clone(),
+            create_dirs: true,
+            read_only: true,
+            ..ServerConfig::default()
+        };
+
+        let wrq = WriteRequestPacket::new("sub/a.txt", "octet").serialize();
+        let violation = ErrorPacket::new(TFTPError::AccessViolation);
+        assert_eq!(TFTPServer::new(&wrq, &client(), &config).err(), Some(violation));
+        assert!(!dir.join("sub").exists());
+    }
+
+    #[test]
+    fn write_only_server_refuses_downloads() {
+        let dir = scratch_dir("write_only");
+        fs::write(dir.join("a.txt"), "hello").unwrap();
+        let config = ServerConfig {
+            root: dir,
+            write_only: true,
+            ..ServerConfig::default()
+        };
+
+        let rrq = ReadRequestPacket::new("a.txt", "octet").serialize();
+        let violation = ErrorPacket::new(TFTPError::AccessViolation);
+        assert_eq!(TFTPServer::new(&rrq, &client(), &config).err(), Some(violation));
+
+        let wrq = WriteRequestPacket::new("b.txt", "octet").serialize();
+        assert!(TFTPServer::new(&wrq, &client(), &config).is_ok());
+    }
+
+    fn client() -> SocketAddr {
+        "127.0.0.1:6900".parse().unwrap()
+    }
+
+    /// Starts a listener on a free local port in the background.
+    fn spawn_listener(config: ServerConfig) -> (SocketAddr, TFTPListener) {
+        let listener = TFTPListener::bind("127.0.0.1", 0, config).unwrap();
+        (listener.local_addr().unwrap(), listener)
+    }
+
+    fn client_socket() -> UdpSocket {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        sock
+    }
+
+    fn recv_packet(sock: &UdpSocket) -> (TFTPPacket, SocketAddr) {
+        let mut buf = [0; 4096];
+        let (count, addr) = sock.recv_from(&mut buf).unwrap();
+        (parse_udp_packet(&buf[..count]).unwrap(), addr)
+    }
+
+    /// Downloads a file with raw packets, returns the content or the error packet.
+    fn download(server: SocketAddr, name: &str) -> Result<Vec<u8>, ErrorPacket> {
+        let sock = client_socket();
+        sock.send_to(&ReadRequestPacket::new(name, "octet").serialize(), server).unwrap();
+
+        let mut content = Vec::new();
+        loop {
+            match recv_packet(&sock) {
+                (TFTPPacket::DATA(dp), addr) => {
+                    let blk = dp.blk();
+                    let data = dp.data();
+                    content.extend_from_slice(&data);
+                    sock.send_to(&AckPacket::new(blk).serialize(), addr).unwrap();
+                    if data.len() < STRIDE_SIZE {
+                        return Ok(content);
+                    }
+                }
+                (TFTPPacket::ERR(ep), _) => return Err(ep),
+                (p, _) => panic!("Unexpected packet {:?}", p),
+            }
+        }
+    }
+
+    /// Uploads the given content with raw packets.
+    fn upload(server: SocketAddr, name: &str, content: &[u8]) {
+        let sock = client_socket();
+        sock.send_to(&WriteRequestPacket::new(name, "octet").serialize(), server).unwrap();
+
+        let mut blocks = content.chunks(STRIDE_SIZE).map(Vec::from).collect::<Vec<_>>();
+        if content.len() % STRIDE_SIZE == 0 {
+            blocks.push(Vec::new());
+        }
+
+        for (i, block) in blocks.into_iter().enumerate() {
+            match recv_packet(&sock) {
+                (TFTPPacket::ACK(ap), addr) => {
+                    assert_eq!(ap.blk(), i as u16);
+                    let dp = DataPacket::new(i as u16 + 1, block);
+                    sock.send_to(&dp.serialize(), addr).unwrap();
+                }
+                (p, _) => panic!("Unexpected packet {:?}", p),
+            }
+        }
+
+        match recv_packet(&sock) {
+            (TFTPPacket::ACK(_), _) => {}
+            (p, _) => panic!("Unexpected packet {:?}", p),
+        }
+    }
+
+    /// Waits for a condition that's met by a server thread.
+    fn wait_until<F: Fn() -> bool>(condition: F) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() {
+            assert!(Instant::now() < deadline, "Timed out waiting for the server");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn panics_while_serving_a_client_are_caught() {
+        let dir = scratch_dir("catch_panics");
+        fs::write(dir.join("a.txt"), vec![7; 700]).unwrap();
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root: dir,
+            workers: 1,
+            catch_panics: true,
+            authorize: Some(Arc::new(|_: &SocketAddr, _: Operation, name: &str| {
+                if name == "boom.txt" {
+                    panic!("Bug triggered by a client");
+                }
+                Ok(())
+            })),
+            ..ServerConfig::default()
+        });
+        let metrics = listener.metrics();
+        thread::spawn(move || listener.serve());
+
+        let sock = client_socket();
+        sock.send_to(&ReadRequestPacket::new("boom.txt", "octet").serialize(), addr).unwrap();
+        wait_until(|| metrics.errors(TFTPError::UndefinedError.code()) == 1);
+
+        // The listener and its only worker are still there for others.
+        assert_eq!(download(addr, "a.txt").unwrap().len(), 700);
+    }
+
+    #[test]
+    fn metrics_count_transfers() {
+        let dir = scratch_dir("metrics");
+        fs::write(dir.join("a.txt"), vec![7; 700]).unwrap();
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root: dir,
+            ..ServerConfig::default()
+        });
+        let metrics = listener.metrics();
+        thread::spawn(move || listener.serve());
+
+        assert_eq!(download(addr, "a.txt").unwrap().len(), 700);
+        assert_eq!(download(addr, "a.txt").unwrap().len(), 700);
+        upload(addr, "b.txt", &[1; 1024]);
+        assert_eq!(download(addr, "missing.txt").unwrap_err().code(), 1);
+
+        wait_until(|| metrics.errors(1) == 1);
+        assert_eq!(metrics.downloads(), 2);
+        assert_eq!(metrics.bytes_sent(), 1400);
+        assert_eq!(metrics.uploads(), 1);
+        assert_eq!(metrics.bytes_received(), 1024);
+    }
+
+    #[test]
+    fn requests_are_written_to_the_access_log() {
+        let dir = scratch_dir("access_log");
+        let root = dir.join("root");
+        fs::create_dir(&root).unwrap();
+        fs::write(root.join("a.txt"), vec![7; 700]).unwrap();
+        let log_path = dir.join("access.log");
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root,
+            access_log: Some(Arc::new(AccessLog::open(&log_path).unwrap())),
+            ..ServerConfig::default()
+        });
+        thread::spawn(move || listener.serve());
+
+        assert_eq!(download(addr, "a.txt").unwrap().len(), 700);
+        assert_eq!(download(addr, "missing.txt").unwrap_err().code(), 1);
+
+        let lines = || fs::read_to_string(&log_path).unwrap().lines().map(String::from).collect::<Vec<_>>();
+        wait_until(|| lines().len() == 2);
+        let mut entries = lines()
+            .iter()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        // The download may still be logging its last ACK when the error is logged.
+        entries.sort_by_key(|entry| entry["file"].as_str().unwrap().to_string());
+
+        assert!(entries[0]["time"].as_f64().unwrap() > 0.0);
+        assert!(entries[0]["client"].as_str().unwrap().starts_with("127.0.0.1:"));
+        assert_eq!(entries[0]["op"], "R");
+        assert_eq!(entries[0]["file"], "a.txt");
+        assert_eq!(entries[0]["mode"], "octet");
+        assert_eq!(entries[0]["result"], "ok");
+        assert_eq!(entries[0]["bytes"], 700);
+
+        assert_eq!(entries[1]["file"], "missing.txt");
+        assert_eq!(entries[1]["result"], 1);
+        assert_eq!(entries[1]["bytes"], 0);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn chroot_refuses_symlinks_out_of_the_root() {
+        use std::os::unix::fs::symlink;
+
+        let dir = scratch_dir("chroot");
+        let root = dir.join("root");
+        fs::create_dir(&root).unwrap();
+        fs::write(dir.join("secret.txt"), "secret").unwrap();
+        fs::write(root.join("a.txt"), "hello").unwrap();
+        symlink("../secret.txt", root.join("link.txt")).unwrap();
+        symlink("..", root.join("out")).unwrap();
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root: root.clone(),
+            chroot: true,
+            ..ServerConfig::default()
+        });
+        thread::spawn(move || listener.serve());
+
+        assert_eq!(download(addr, "a.txt").unwrap(), b"hello");
+        upload(addr, "b.txt", &[1; 700]);
+        wait_until(|| fs::read(root.join("b.txt")).map_or(false, |c| c == vec![1; 700]));
+        assert_eq!(download(addr, "link.txt").unwrap_err().code(), TFTPError::AccessViolation.code());
+        assert_eq!(download(addr, "out/secret.txt").unwrap_err().code(), TFTPError::AccessViolation.code());
+    }
+
+    #[test]
+    fn empty_files_are_served_unless_rejected() {
+        let dir = scratch_dir("empty_files");
+        fs::write(dir.join("empty.txt"), "").unwrap();
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root: dir.clone(),
+            ..ServerConfig::default()
+        });
+        thread::spawn(move || listener.serve());
+        assert_eq!(download(addr, "empty.txt").unwrap(), b"");
+
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root: dir,
+            reject_empty: true,
+            ..ServerConfig::default()
+        });
+        thread::spawn(move || listener.serve());
+        let e = download(addr, "empty.txt").unwrap_err();
+        assert_eq!(e.code(), TFTPError::UndefinedError.code());
+        assert_eq!(e.err(), "Requested file is empty.");
+    }
+
+    #[test]
+    fn missing_files_are_silently_dropped_when_asked() {
+        let dir = scratch_dir("silent_notfound");
+        fs::write(dir.join("a.txt"), "hello").unwrap();
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root: dir,
+            silent_notfound: true,
+            ..ServerConfig::default()
+        });
+        let metrics = listener.metrics();
+        thread::spawn(move || listener.serve());
+
+        let sock = client_socket();
+        sock.send_to(&ReadRequestPacket::new("missing.txt", "octet").serialize(), addr).unwrap();
+        wait_until(|| metrics.dropped() == 1);
+        sock.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        assert!(sock.recv_from(&mut [0; 16]).is_err());
+        assert_eq!(metrics.errors(TFTPError::FileNotFound.code()), 0);
+
+        assert_eq!(download(addr, "a.txt").unwrap(), b"hello");
+    }
+
+    #[test]
+    fn one_shot_listener_serves_a_single_download() {
+        let dir = scratch_dir("serve_once");
+        fs::write(dir.join("boot.img"), vec![9; 700]).unwrap();
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root: dir,
+            once: true,
+            ..ServerConfig::default()
+        });
+        // Kept alive past serve() so the port stays bound, a late request
+        // then lands on the listener's socket instead of a closed port.
+        let listener = Arc::new(listener);
+        let serving = {
+            let listener = Arc::clone(&listener);
+            thread::spawn(move || listener.serve())
+        };
+
+        assert_eq!(download(addr, "boot.img").unwrap(), vec![9; 700]);
+        serving.join().unwrap().unwrap();
+
+        let sock = client_socket();
+        sock.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        sock.send_to(&ReadRequestPacket::new("boot.img", "octet").serialize(), addr).unwrap();
+        assert!(sock.recv_from(&mut [0; 16]).is_err());
+        assert_eq!(listener.metrics().downloads(), 1);
+    }
+
+    #[test]
+    fn long_file_names_are_refused() {
+        let dir = scratch_dir("long_names");
+        let config = ServerConfig {
+            root: dir.clone(),
+            create_dirs: true,
+            ..ServerConfig::default()
+        };
+        let too_long = || ErrorPacket::with_message(TFTPError::IllegalOperation, "File name is too long.");
+
+        let name = format!("sub/{}", "a".repeat(252));
+        let wrq = WriteRequestPacket::new(&name, "octet").serialize();
+        assert_eq!(TFTPServer::new(&wrq, &client(), &config).err(), Some(too_long()));
+        assert!(!dir.join("sub").exists());
+        let rrq = ReadRequestPacket::new(&name, "octet").serialize();
+        assert_eq!(TFTPServer::new(&rrq, &client(), &config).err(), Some(too_long()));
+
+        // Names up to the limit are looked for as usual.
+        let rrq = ReadRequestPacket::new(&name[..255], "octet").serialize();
+        assert_eq!(TFTPServer::new(&rrq, &client(), &config).err(), Some(ErrorPacket::new(TFTPError::FileNotFound)));
+    }
+
+    #[test]
+    fn mail_mode_is_refused() {
+        let config = ServerConfig {
+            root: scratch_dir("mail_mode"),
+            ..ServerConfig::default()
+        };
+
+        let wrq = WriteRequestPacket::new("a.txt", "mail").serialize();
+        let ep = TFTPServer::new(&wrq, &client(), &config).err().unwrap();
+        assert_eq!(ep.code(), TFTPError::IllegalOperation.code());
+        assert_eq!(ep.err(), "mail mode is not supported");
+
+        let rrq = ReadRequestPacket::new("a.txt", "binary").serialize();
+        let ep = TFTPServer::new(&rrq, &client(), &config).err().unwrap();
+        assert_eq!(ep.code(), TFTPError::IllegalOperation.code());
+        assert_eq!(ep.err(), "Unsupported transfer mode [binary]");
+    }
+
+    #[test]
+    fn transfers_beyond_the_worker_count_wait() {
+        let dir = scratch_dir("workers_wait");
+        fs::write(dir.join("a.txt"), vec![1; 700]).unwrap();
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root: dir,
+            workers: 1,
+            ..ServerConfig::default()
+        });
+        thread::spawn(move || listener.serve());
+
+        let first = client_socket();
+        first.send_to(&ReadRequestPacket::new("a.txt", "octet").serialize(), addr).unwrap();
+        let (_, first_tid) = recv_packet(&first);
+
+        // The only worker is busy with the first client.
+        let second = client_socket();
+        second.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        second.send_to(&ReadRequestPacket::new("a.txt", "octet").serialize(), addr).unwrap();
+        assert!(second.recv_from(&mut [0; 1024]).is_err());
+
+        first.send_to(&AckPacket::new(1).serialize(), first_tid).unwrap();
+        recv_packet(&first);
+        first.send_to(&AckPacket::new(2).serialize(), first_tid).unwrap();
+
+        second.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        match recv_packet(&second) {
+            (TFTPPacket::DATA(dp), _) => assert_eq!(dp.blk(), 1),
+            (p, _) => panic!("Expected data got {:?}", p),
+        }
+    }
+
+    #[test]
+    fn requests_are_taken_while_others_wait_for_a_worker() {
+        let dir = scratch_dir("workers_queue");
+        fs::write(dir.join("a.txt"), vec![1; 700]).unwrap();
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root: dir,
+            workers: 1,
+            queue_timeout: Duration::from_secs(10),
+            ..ServerConfig::default()
+        });
+        thread::spawn(move || listener.serve());
+
+        let first = client_socket();
+        first.send_to(&ReadRequestPacket::new("a.txt", "octet").serialize(), addr).unwrap();
+        recv_packet(&first);
+        let second = client_socket();
+        second.send_to(&ReadRequestPacket::new("a.txt", "octet").serialize(), addr).unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        // The second client waits for the only worker, a third one would
+        // make the queue longer than the pool and is refused right away.
+        let third = client_socket();
+        third.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        third.send_to(&ReadRequestPacket::new("a.txt", "octet").serialize(), addr).unwrap();
+        match recv_packet(&third) {
+            (TFTPPacket::ERR(ep), from) => {
+                assert_eq!(ep.err(), "Server is busy, try again later.");
+                assert_eq!(from, addr);
+            }
+            (p, _) => panic!("Unexpected packet {:?}", p),
+        }
+    }
+
+    #[test]
+    fn queued_request_times_out() {
+        let dir = scratch_dir("workers_busy");
+        fs::write(dir.join("a.txt"), vec![1; 700]).unwrap();
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root: dir,
+            workers: 1,
+            queue_timeout: Duration::from_millis(100),
+            ..ServerConfig::default()
+        });
+        thread::spawn(move || listener.serve());
+
+        let first = client_socket();
+        first.send_to(&ReadRequestPacket::new("a.txt", "octet").serialize(), addr).unwrap();
+        recv_packet(&first);
+
+        let busy = download(addr, "a.txt").unwrap_err();
+        assert_eq!(busy.code(), 0);
+        assert_eq!(busy.err(), "Server is busy, try again later.");
+    }
+
+    #[test]
+    fn index_lists_the_root() {
+        let dir = scratch_dir("index");
+        fs::write(dir.join("b.txt"), "b").unwrap();
+        fs::write(dir.join("a.txt"), "a").unwrap();
+        fs::write(dir.join("c.bin"), "c").unwrap();
+        fs::create_dir(dir.join("sub.txt")).unwrap();
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root: dir,
+            allowed_extensions: vec![String::from("txt")],
+            index_name: Some(String::from(".index")),
+            ..ServerConfig::default()
+        });
+        thread::spawn(move || listener.serve());
+
+        let listing = String::from_utf8(download(addr, ".index").unwrap()).unwrap();
+        assert_eq!(listing.lines().collect::<Vec<_>>(), vec!["a.txt", "b.txt"]);
+    }
+
+    #[test]
+    fn wrong_block_is_answered_with_an_error() {
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root: scratch_dir("wrong_block"),
+            ..ServerConfig::default()
+        });
+        let metrics = listener.metrics();
+        thread::spawn(move || listener.serve());
+
+        let sock = client_socket();
+        sock.send_to(&WriteRequestPacket::new("a.txt", "octet").serialize(), addr).unwrap();
+        let data_addr = match recv_packet(&sock) {
+            (TFTPPacket::ACK(ap), data_addr) if ap.blk() == 0 => data_addr,
+            (p, _) => panic!("Unexpected packet {:?}", p),
+        };
+
+        sock.send_to(&DataPacket::new(5, vec![1; 10]).serialize(), data_addr).unwrap();
+        match recv_packet(&sock) {
+            (TFTPPacket::ERR(ep), from) => {
+                assert_eq!(ep.code(), TFTPError::IllegalOperation.code());
+                assert_eq!(from, data_addr);
+            }
+            (p, _) => panic!("Unexpected packet {:?}", p),
+        }
+
+        // It's sent once, the server stops serving the client after it.
+        wait_until(|| metrics.errors(TFTPError::IllegalOperation.code()) == 1);
+        sock.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        assert!(sock.recv_from(&mut [0; 16]).is_err());
+    }
+
+    #[test]
+    fn dallying_acknowledges_the_last_block_again() {
+        let dir = scratch_dir("dally_upload");
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root: dir.clone(),
+            dally: Duration::from_secs(2),
+            ..ServerConfig::default()
+        });
+        thread::spawn(move || listener.serve());
+
+        let sock = client_socket();
+        sock.send_to(&WriteRequestPacket::new("a.txt", "octet").serialize(), addr).unwrap();
+        let data_addr = match recv_packet(&sock) {
+            (TFTPPacket::ACK(ap), data_addr) if ap.blk() == 0 => data_addr,
+            (p, _) => panic!("Unexpected packet {:?}", p),
+        };
+
+        // The client didn't get the final ACK and sends the last block again.
+        let last = DataPacket::new(1, b"hello".to_vec()).serialize();
+        for _ in 0..2 {
+            sock.send_to(&last, data_addr).unwrap();
+            match recv_packet(&sock) {
+                (TFTPPacket::ACK(ap), from) => {
+                    assert_eq!(ap.blk(), 1);
+                    assert_eq!(from, data_addr);
+                }
+                (p, _) => panic!("Unexpected packet {:?}", p),
+            }
+        }
+        assert_eq!(fs::read(dir.join("a.txt")).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn renamed_uploads_of_the_same_name_are_kept_apart() {
+        let dir = scratch_dir("rename_uploads");
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root: dir.clone(),
+            rename_uploads: true,
+            ..ServerConfig::default()
+        });
+        thread::spawn(move || listener.serve());
+
+        let mut names = Vec::new();
+        for content in &[b"first", b"other"] {
+            let sock = client_socket();
+            let wrq = WriteRequestPacket::new("../drop.txt", "octet").with_option("filename", "0");
+            sock.send_to(&wrq.serialize(), addr).unwrap();
+            let (name, tid) = match recv_packet(&sock) {
+                (TFTPPacket::OACK(oack), tid) => (oack.option("filename").unwrap().to_string(), tid),
+                (p, _) => panic!("Unexpected packet {:?}", p),
+            };
+            sock.send_to(&DataPacket::new(1, content.to_vec()).serialize(), tid).unwrap();
+            match recv_packet(&sock) {
+                (TFTPPacket::ACK(ap), _) => assert_eq!(ap.blk(), 1),
+                (p, _) => panic!("Unexpected packet {:?}", p),
+            }
+
+            assert!(name.ends_with(".txt"));
+            assert_eq!(fs::read(dir.join(&name)).unwrap(), *content);
+            names.push(name);
+        }
+        assert_ne!(names[0], names[1]);
+
+        // A client that didn't ask isn't sent an OACK.
+        upload(addr, "drop.txt", b"third");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+        assert!(!dir.join("drop.txt").exists());
+    }
+
+    #[test]
+    fn files_shrunk_while_downloaded_are_an_error() {
+        let dir = scratch_dir("shrunk_download");
+        fs::write(dir.join("a.bin"), vec![1; 2000]).unwrap();
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root: dir.clone(),
+            ..ServerConfig::default()
+        });
+        let metrics = listener.metrics();
+        thread::spawn(move || listener.serve());
+
+        let sock = client_socket();
+        sock.send_to(&ReadRequestPacket::new("a.bin", "octet").serialize(), addr).unwrap();
+        let tid = match recv_packet(&sock) {
+            (TFTPPacket::DATA(dp), tid) if dp.blk() == 1 => tid,
+            (p, _) => panic!("Unexpected packet {:?}", p),
+        };
+
+        fs::write(dir.join("a.bin"), vec![1; 600]).unwrap();
+        sock.send_to(&AckPacket::new(1).serialize(), tid).unwrap();
+        match recv_packet(&sock) {
+            (TFTPPacket::ERR(ep), _) => assert_eq!(ep.err(), "File changed during the transfer."),
+            (p, _) => panic!("Unexpected packet {:?}", p),
+        }
+
+        // Counted under the code the client was sent.
+        wait_until(|| metrics.errors(TFTPError::UndefinedError.code()) == 1);
+        assert_eq!(metrics.errors(TFTPError::IllegalOperation.code()), 0);
+    }
+
+    #[test]
+    fn idle_clients_are_dropped() {
+        let dir = scratch_dir("idle_client");
+        fs::write(dir.join("a.txt"), vec![1; 700]).unwrap();
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root: dir,
+            workers: 1,
+            idle_timeout: Duration::from_millis(200),
+            ..ServerConfig::default()
+        });
+        let metrics = listener.metrics();
+        thread::spawn(move || listener.serve());
+
+        // Block #1 is never acknowledged.
+        let sock = client_socket();
+        sock.send_to(&ReadRequestPacket::new("a.txt", "octet").serialize(), addr).unwrap();
+        let data_addr = match recv_packet(&sock) {
+            (TFTPPacket::DATA(dp), data_addr) if dp.blk() == 1 => data_addr,
+            (p, _) => panic!("Unexpected packet {:?}", p),
+        };
+
+        let started = Instant::now();
+        match recv_packet(&sock) {
+            (TFTPPacket::ERR(ep), from) => {
+                assert_eq!(ep.err(), "Transfer timed out.");
+                assert_eq!(from, data_addr);
+            }
+            (p, _) => panic!("Unexpected packet {:?}", p),
+        }
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(metrics.errors(0), 1);
+
+        // The only worker is free again.
+        assert_eq!(download(addr, "a.txt").unwrap().len(), 700);
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn netascii_downloads_are_sent_with_cr_lf() {
+        let dir = scratch_dir("netascii_server");
+        fs::write(dir.join("a.txt"), "one\ntwo\r").unwrap();
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root: dir,
+            ..ServerConfig::default()
+        });
+        thread::spawn(move || listener.serve());
+
+        let sock = client_socket();
+        sock.send_to(&ReadRequestPacket::new("a.txt", "NETASCII").serialize(), addr).unwrap();
+        match recv_packet(&sock) {
+            (TFTPPacket::DATA(dp), _) => assert_eq!(dp.data(), b"one\r\ntwo\r\0"),
+            (p, _) => panic!("Unexpected packet {:?}", p),
+        }
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn netascii_uploads_round_trip_through_the_listener() {
+        let dir = scratch_dir("netascii_round_trip");
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root: dir.clone(),
+            ..ServerConfig::default()
+        });
+        thread::spawn(move || listener.serve());
+        let wire = b"one\r\ntwo\r\0";
+
+        let sock = client_socket();
+        sock.send_to(&WriteRequestPacket::new("a.txt", "Netascii").serialize(), addr).unwrap();
+        let data_addr = match recv_packet(&sock) {
+            (TFTPPacket::ACK(ap), data_addr) if ap.blk() == 0 => data_addr,
+            (p, _) => panic!("Unexpected packet {:?}", p),
+        };
+        sock.send_to(&DataPacket::new(1, wire.to_vec()).serialize(), data_addr).unwrap();
+        match recv_packet(&sock) {
+            (TFTPPacket::ACK(ap), _) => assert_eq!(ap.blk(), 1),
+            (p, _) => panic!("Unexpected packet {:?}", p),
+        }
+        wait_until(|| fs::read(dir.join("a.txt")).ok() == Some(b"one\ntwo\r".to_vec()));
+
+        let sock = client_socket();
+        sock.send_to(&ReadRequestPacket::new("a.txt", "netascii").serialize(), addr).unwrap();
+        match recv_packet(&sock) {
+            (TFTPPacket::DATA(dp), _) => assert_eq!(dp.data(), wire),
+            (p, _) => panic!("Unexpected packet {:?}", p),
+        }
+    }
+
+    #[test]
+    fn octet_is_matched_in_any_case() {
+        let dir = scratch_dir("octet_case");
+        fs::write(dir.join("a.txt"), "one\ntwo\r").unwrap();
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root: dir,
+            ..ServerConfig::default()
+        });
+        thread::spawn(move || listener.serve());
+
+        for mode in &["OCTET", "Octet"] {
+            let sock = client_socket();
+            sock.send_to(&ReadRequestPacket::new("a.txt", mode).serialize(), addr).unwrap();
+            match recv_packet(&sock) {
+                (TFTPPacket::DATA(dp), _) => assert_eq!(dp.data(), b"one\ntwo\r", "{}", mode),
+                (p, _) => panic!("Unexpected packet {:?}", p),
+            }
+        }
+    }
+
+    #[test]
+    fn packets_from_another_port_are_refused() {
+        let dir = scratch_dir("unknown_tid");
+        let content: Vec<u8> = (0..1200).map(|i| i as u8).collect();
+        fs::write(dir.join("a.bin"), &content).unwrap();
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root: dir,
+            ..ServerConfig::default()
+        });
+        thread::spawn(move || listener.serve());
+
+        let sock = client_socket();
+        sock.send_to(&ReadRequestPacket::new("a.bin", "octet").serialize(), addr).unwrap();
+        let (first, data_addr) = match recv_packet(&sock) {
+            (TFTPPacket::DATA(dp), data_addr) => (dp, data_addr),
+            (p, _) => panic!("Unexpected packet {:?}", p),
+        };
+
+        let intruder = client_socket();
+        intruder.send_to(&AckPacket::new(1).serialize(), data_addr).unwrap();
+        match recv_packet(&intruder) {
+            (TFTPPacket::ERR(ep), _) => assert_eq!(ep.code(), TFTPError::UnknownTID.code()),
+            (p, _) => panic!("Unexpected packet {:?}", p),
+        }
+
+        // The intruder's ACK didn't move the transfer along.
+        sock.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        assert!(sock.recv_from(&mut [0; 16]).is_err());
+        sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let mut blk = first.blk();
+        let mut received = first.data();
+        loop {
+            sock.send_to(&AckPacket::new(blk).serialize(), data_addr).unwrap();
+            if received.len() == content.len() && blk > 1 {
+                break;
+            }
+            match recv_packet(&sock) {
+                (TFTPPacket::DATA(dp), _) if dp.blk() == blk + 1 => {
+                    blk = dp.blk();
+                    received.extend(dp.data());
+                }
+                (p, _) => panic!("Unexpected packet {:?}", p),
+            }
+        }
+        assert_eq!(received, content);
+    }
+
+    #[test]
+    fn health_name_is_served_without_the_root() {
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root: PathBuf::from("missing_root"),
+            allowed_extensions: vec![String::from("txt")],
+            write_only: true,
+            health_name: Some(String::from(".health")),
+            ..ServerConfig::default()
+        });
+        let metrics = listener.metrics();
+        thread::spawn(move || listener.serve());
+
+        assert_eq!(download(addr, ".health").unwrap(), b"OK\n");
+        wait_until(|| metrics.downloads() == 1);
+        assert_eq!(download(addr, "a.txt").unwrap_err().code(), TFTPError::AccessViolation.code());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn uploads_into_a_read_only_directory_are_access_violations() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = scratch_dir("read_only_upload");
+        fs::create_dir(dir.join("locked")).unwrap();
+        fs::set_permissions(dir.join("locked"), fs::Permissions::from_mode(0o555)).unwrap();
+        // Permissions don't hold back root, there's nothing to test then.
+        if fs::write(dir.join("locked").join("probe"), b"").is_ok() {
+            return;
+        }
+        fs::write(dir.join("a.txt"), b"hello").unwrap();
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root: dir,
+            ..ServerConfig::default()
+        });
+        thread::spawn(move || listener.serve());
+
+        let sock = client_socket();
+        sock.send_to(&WriteRequestPacket::new("locked/a.txt", "octet").serialize(), addr).unwrap();
+        let (_, data_addr) = recv_packet(&sock);
+        sock.send_to(&DataPacket::new(1, b"hello".to_vec()).serialize(), data_addr).unwrap();
+        match recv_packet(&sock).0 {
+            TFTPPacket::ERR(ep) => assert_eq!(ep.code(), TFTPError::AccessViolation.code()),
+            p => panic!("Expected an error got {:?}", p),
+        }
+
+        // The server is still up.
+        assert_eq!(download(addr, "a.txt").unwrap(), b"hello");
+    }
+
+    #[test]
+    fn data_socket_is_bound_within_the_port_range() {
+        let dir = scratch_dir("port_range");
+        fs::write(dir.join("a.txt"), "hello").unwrap();
+        // A port the OS just handed out is free, unlike any fixed one.
+        let port = UdpSocket::bind("0.0.0.0:0").unwrap().local_addr().unwrap().port();
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root: dir,
+            port_range: Some(port..=port),
+            ..ServerConfig::default()
+        });
+        thread::spawn(move || listener.serve());
+
+        let sock = client_socket();
+        sock.send_to(&ReadRequestPacket::new("a.txt", "octet").serialize(), addr).unwrap();
+        let (_, data_addr) = recv_packet(&sock);
+        assert_eq!(data_addr.port(), port);
+    }
+
+    #[test]
+    fn exhausted_port_range_is_refused() {
+        let dir = scratch_dir("port_range_exhausted");
+        fs::write(dir.join("a.txt"), "hello").unwrap();
+        let taken = UdpSocket::bind("0.0.0.0:0").unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root: dir,
+            port_range: Some(port..=port),
+            ..ServerConfig::default()
+        });
+        thread::spawn(move || listener.serve());
+
+        let err = download(addr, "a.txt").unwrap_err();
+        assert_eq!(err.err(), "No free data port, try again later.");
+    }
+
+    #[test]
+    fn answers_to_vanished_clients_dont_stop_the_listener() {
+        let dir = scratch_dir("vanished_clients");
+        fs::write(dir.join("a.txt"), "hello").unwrap();
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root: dir,
+            ..ServerConfig::default()
+        });
+        thread::spawn(move || listener.serve());
+
+        // Each one is answered with an error after its port was closed,
+        // some platforms report that on the listener's next read.
+        for _ in 0..3 {
+            let gone = UdpSocket::bind("127.0.0.1:0").unwrap();
+            gone.send_to(&AckPacket::new(1).serialize(), addr).unwrap();
+        }
+        thread::sleep(Duration::from_millis(50));
+
+        assert_eq!(download(addr, "a.txt").unwrap(), b"hello");
+    }
+
+    #[test]
+    fn is_transient_keeps_the_listener_going() {
+        for kind in &[io::ErrorKind::ConnectionReset, io::ErrorKind::ConnectionRefused, io::ErrorKind::Interrupted] {
+            assert!(is_transient(&io::Error::from(*kind)), "{:?}", kind);
+        }
+        assert!(!is_transient(&io::Error::from(io::ErrorKind::PermissionDenied)));
+    }
+
+    #[test]
+    fn pow2_block_sizes_are_clamped_down() {
+        let dir = scratch_dir("blksize_pow2");
+        fs::write(dir.join("a.txt"), "hello").unwrap();
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root: dir,
+            mtu: Some(1500),
+            pow2_blksize: true,
+            ..ServerConfig::default()
+        });
+        thread::spawn(move || listener.serve());
+
+        let sock = client_socket();
+        for &(requested, negotiated) in &[("9000", "1024"), ("1000", "512"), ("256", "256")] {
+            let rrq = ReadRequestPacket::new("a.txt", "octet").with_option("blksize", requested);
+            sock.send_to(&rrq.serialize(), addr).unwrap();
+            match recv_packet(&sock) {
+                (TFTPPacket::OACK(oack), _) => assert_eq!(oack.option("blksize"), Some(negotiated)),
+                (p, _) => panic!("Expected OACK got {:?}", p),
+            }
+        }
+    }
+
+    #[test]
+    fn strict_servers_negotiate_nothing() {
+        let dir = scratch_dir("strict_rfc_server");
+        fs::write(dir.join("a.txt"), vec![2; 700]).unwrap();
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root: dir,
+            strict_rfc: true,
+            ..ServerConfig::default()
+        });
+        thread::spawn(move || listener.serve());
+
+        let sock = client_socket();
+        let rrq = ReadRequestPacket::new("a.txt", "octet")
+            .with_option("blksize", "1024")
+            .with_option("tsize", "0");
+        sock.send_to(&rrq.serialize(), addr).unwrap();
+        match recv_packet(&sock) {
+            (TFTPPacket::DATA(dp), _) => {
+                assert_eq!(dp.blk(), 1);
+                assert_eq!(dp.data().len(), 512);
+            }
+            (p, _) => panic!("Expected DATA got {:?}", p),
+        }
+    }
+
+    #[test]
+    fn small_block_sizes_are_clamped_up() {
+        let dir = scratch_dir("blksize_min");
+        fs::write(dir.join("a.txt"), vec![4; 1500]).unwrap();
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root: dir,
+            min_blksize: Some(1024),
+            ..ServerConfig::default()
+        });
+        thread::spawn(move || listener.serve());
+
+        let sock = client_socket();
+        for &(requested, negotiated) in &[("64", "1024"), ("1024", "1024"), ("1400", "1400")] {
+            let rrq = ReadRequestPacket::new("a.txt", "octet").with_option("blksize", requested);
+            sock.send_to(&rrq.serialize(), addr).unwrap();
+            match recv_packet(&sock) {
+                (TFTPPacket::OACK(oack), _) => assert_eq!(oack.option("blksize"), Some(negotiated)),
+                (p, _) => panic!("Expected OACK got {:?}", p),
+            }
+        }
+    }
+
+    #[test]
+    fn minimum_block_size_only_raises_downloads_within_the_limits() {
+        let dir = scratch_dir("blksize_min_limits");
+        fs::write(dir.join("a.txt"), vec![4; 1500]).unwrap();
+        let oack_block_size = |rq: &[u8], config: &ServerConfig| {
+            let mut server = TFTPServer::new(rq, &client(), config).unwrap();
+            match parse_udp_packet(server.get_next_packet().unwrap()).unwrap() {
+                TFTPPacket::OACK(oack) => oack.option("blksize").unwrap().to_string(),
+                p => panic!("Expected OACK got {:?}", p),
+            }
+        };
+        let config = |mtu, pow2_blksize| ServerConfig {
+            root: dir.clone(),
+            min_blksize: Some(1024),
+            mtu,
+            pow2_blksize,
+            ..ServerConfig::default()
+        };
+
+        let rrq = ReadRequestPacket::new("a.txt", "octet").with_option("blksize", "64").serialize();
+        assert_eq!(oack_block_size(&rrq, &config(None, false)), "1024");
+        assert_eq!(oack_block_size(&rrq, &config(Some(600), false)), "568");
+        assert_eq!(oack_block_size(&rrq, &config(Some(1000), true)), "512");
+
+        // An upload keeps the blocks its client sends.
+        let wrq = WriteRequestPacket::new("b.txt", "octet").with_option("blksize", "512").serialize();
+        assert_eq!(oack_block_size(&wrq, &config(None, false)), "512");
+    }
+
+    #[test]
+    fn block_size_is_clamped_to_the_mtu() {
+        let dir = scratch_dir("blksize_mtu");
+        fs::write(dir.join("a.txt"), vec![3; 2000]).unwrap();
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root: dir,
+            mtu: Some(1500),
+            ..ServerConfig::default()
+        });
+        thread::spawn(move || listener.serve());
+
+        let sock = client_socket();
+        let rrq = ReadRequestPacket::new("a.txt", "octet").with_option("blksize", "9000");
+        sock.send_to(&rrq.serialize(), addr).unwrap();
+        let tid = match recv_packet(&sock) {
+            (TFTPPacket::OACK(oack), tid) => {
+                assert_eq!(oack.option("blksize"), Some("1468"));
+                tid
+            }
+            (p, _) => panic!("Expected OACK got {:?}", p),
+        };
+
+        sock.send_to(&AckPacket::new(0).serialize(), tid).unwrap();
+        match recv_packet(&sock) {
+            (TFTPPacket::DATA(dp), _) => {
+                assert_eq!(dp.blk(), 1);
+                assert_eq!(dp.data().len(), 1468);
+            }
+            (p, _) => panic!("Expected DATA got {:?}", p),
+        }
+    }
+
+    #[test]
+    fn utimeout_is_negotiated_and_clamped() {
+        let dir = scratch_dir("utimeout");
+        fs::write(dir.join("a.txt"), "hello").unwrap();
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root: dir,
+            ..ServerConfig::default()
+        });
+        thread::spawn(move || listener.serve());
+
+        let sock = client_socket();
+        let rrq = ReadRequestPacket::new("a.txt", "octet")
+            .with_option("utimeout", "50000")
+            .with_option("timeout", "3");
+        sock.send_to(&rrq.serialize(), addr).unwrap();
+        let (oack, tid) = match recv_packet(&sock) {
+            (TFTPPacket::OACK(oack), tid) => (oack, tid),
+            (p, _) => panic!("Expected OACK got {:?}", p),
+        };
+        assert_eq!(oack.option("utimeout"), Some("50000"));
+        assert_eq!(oack.option("timeout"), None);
+
+        // Without an ACK the OACK is sent again once the timeout passes.
+        let started = Instant::now();
+        match recv_packet(&sock) {
+            (TFTPPacket::OACK(again), from) => {
+                assert_eq!(from, tid);
+                assert_eq!(again.option("utimeout"), Some("50000"));
+            }
+            (p, _) => panic!("Expected OACK got {:?}", p),
+        }
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        let rrq = ReadRequestPacket::new("a.txt", "octet").with_option("utimeout", "1");
+        sock.send_to(&rrq.serialize(), addr).unwrap();
+        match recv_packet(&sock) {
+            (TFTPPacket::OACK(oack), _) => assert_eq!(oack.option("utimeout"), Some("10000")),
+            (p, _) => panic!("Expected OACK got {:?}", p),
+        }
+    }
+
+    #[test]
+    fn downloads_tell_their_size() {
+        let config = ServerConfig {
+            root: scratch_dir("tsize"),
+            ..ServerConfig::default()
+        };
+        fs::write(config.root.join("a.bin"), vec![3; 1300]).unwrap();
+
+        let rrq = ReadRequestPacket::new("a.bin", "octet").with_option("tsize", "0").serialize();
+        let mut server = TFTPServer::new(&rrq, &client(), &config).unwrap();
+        match parse_udp_packet(server.get_next_packet().unwrap()).unwrap() {
+            TFTPPacket::OACK(oack) => assert_eq!(oack.option("tsize"), Some("1300")),
+            p => panic!("Expected OACK got {:?}", p),
+        }
+    }
+
+    #[test]
+    fn read_timeouts_are_never_zero() {
+        let dir = scratch_dir("zero_timeout");
+        fs::write(dir.join("a.txt"), "hello").unwrap();
+        let config = ServerConfig {
+            root: dir,
+            idle_timeout: Duration::from_secs(0),
+            ..ServerConfig::default()
+        };
+
+        for utimeout in &["0", "1", "50000"] {
+            let rrq = ReadRequestPacket::new("a.txt", "octet").with_option("utimeout", utimeout).serialize();
+            let server = TFTPServer::new(&rrq, &client(), &config).unwrap();
+            assert_eq!(server.read_timeout(), MIN_TIMEOUT);
+        }
+    }
+
+    #[test]
+    fn large_negotiated_blocks_are_received_whole() {
+        let dir = scratch_dir("blksize_upload");
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root: dir.clone(),
+            ..ServerConfig::default()
+        });
+        thread::spawn(move || listener.serve());
+
+        let sock = client_socket();
+        let wrq = WriteRequestPacket::new("a.bin", "octet").with_option("blksize", "2048");
+        sock.send_to(&wrq.serialize(), addr).unwrap();
+        let tid = match recv_packet(&sock) {
+            (TFTPPacket::OACK(oack), tid) => {
+                assert_eq!(oack.option("blksize"), Some("2048"));
+                tid
+            }
+            (p, _) => panic!("Expected OACK got {:?}", p),
+        };
+
+        let content: Vec<u8> = (0..2100).map(|i| (i % 251) as u8).collect();
+        for (i, block) in content.chunks(2048).enumerate() {
+            let blk = i as u16 + 1;
+            sock.send_to(&DataPacket::new(blk, block.to_vec()).serialize(), tid).unwrap();
+            match recv_packet(&sock) {
+                (TFTPPacket::ACK(ap), _) => assert_eq!(ap.blk(), blk),
+                (p, _) => panic!("Expected ACK got {:?}", p),
+            }
+        }
+
+        wait_until(|| fs::read(dir.join("a.bin")).map_or(false, |c| c.len() == content.len()));
+        assert_eq!(fs::read(dir.join("a.bin")).unwrap(), content);
+    }
+
+    #[test]
+    fn oversized_blocks_are_refused() {
+        let dir = scratch_dir("blksize_oversized");
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root: dir,
+            ..ServerConfig::default()
+        });
+        thread::spawn(move || listener.serve());
+
+        let sock = client_socket();
+        sock.send_to(&WriteRequestPacket::new("a.bin", "octet").serialize(), addr).unwrap();
+        let (_, tid) = recv_packet(&sock);
+
+        sock.send_to(&DataPacket::new(1, vec![1; 600]).serialize(), tid).unwrap();
+        match recv_packet(&sock) {
+            (TFTPPacket::ERR(ep), _) => assert_eq!(ep.err(), "Packet is larger than the block size."),
+            (p, _) => panic!("Expected ERR got {:?}", p),
+        }
+    }
+
+    /// Asks for a file and tells the address the answer came from.
+    fn data_address_of(listener: &TFTPListener) -> SocketAddr {
+        let sock = client_socket();
+        let rrq = ReadRequestPacket::new("a.txt", "octet").serialize();
+        sock.send_to(&rrq, listener.local_addr().unwrap()).unwrap();
+        let (_, data_addr) = recv_packet(&sock);
+        data_addr
+    }
+
+    // Linux routes all of 127.0.0.0/8 to the loopback interface.
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn clients_are_served_from_the_address_they_contacted() {
+        let dir = scratch_dir("data_address");
+        fs::write(dir.join("a.txt"), b"hello").unwrap();
+        let listener = Arc::new(TFTPListener::bind("127.0.0.2", 0, ServerConfig {
+            root: dir,
+            ..ServerConfig::default()
+        }).unwrap());
+        let serving = Arc::clone(&listener);
+        thread::spawn(move || serving.serve());
+
+        assert_eq!(data_address_of(&listener).ip().to_string(), "127.0.0.2");
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn data_address_overrides_the_listener_address() {
+        let dir = scratch_dir("data_address_override");
+        fs::write(dir.join("a.txt"), b"hello").unwrap();
+        let listener = Arc::new(TFTPListener::bind("127.0.0.1", 0, ServerConfig {
+            root: dir,
+            data_address: Some("127.0.0.3".parse().unwrap()),
+            ..ServerConfig::default()
+        }).unwrap());
+        let serving = Arc::clone(&listener);
+        thread::spawn(move || serving.serve());
+
+        assert_eq!(data_address_of(&listener).ip().to_string(), "127.0.0.3");
+    }
+
+    /// Serves `dir` on all addresses but only through `interface`,
+    /// returns where to reach it over the loopback interface.
+    /// Nothing is spawned when binding to a device isn't permitted, it
+    /// takes CAP_NET_RAW before Linux 5.7.
+    #[cfg(target_os = "linux")]
+    fn spawn_interface_listener(dir: PathBuf, interface: &str) -> Option<SocketAddr> {
+        let listener = match TFTPListener::bind("0.0.0.0", 0, ServerConfig {
+            root: dir,
+            interface: Some(interface.to_string()),
+            ..ServerConfig::default()
+        }) {
+            Ok(listener) => listener,
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => return None,
+            Err(e) => panic!("Failed to bind to {}: {}", interface, e),
+        };
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || listener.serve());
+        Some(SocketAddr::from(([127, 0, 0, 1], port)))
+    }
+
+    // Packets between local addresses arrive on the loopback interface.
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn packets_from_other_interfaces_are_not_taken() {
+        let dir = scratch_dir("interface");
+        fs::write(dir.join("a.txt"), b"hello").unwrap();
+        let rrq = ReadRequestPacket::new("a.txt", "octet").serialize();
+
+        let addr = match spawn_interface_listener(dir.clone(), "lo") {
+            Some(addr) => addr,
+            None => return,
+        };
+        let sock = client_socket();
+        sock.send_to(&rrq, addr).unwrap();
+        match recv_packet(&sock).0 {
+            TFTPPacket::DATA(dp) => assert_eq!(dp.data(), b"hello"),
+            p => panic!("Expected DATA got opcode {}", p.opcode()),
+        }
+
+        let other = fs::read_dir("/sys/class/net").unwrap()
+            .filter_map(|entry| entry.unwrap().file_name().into_string().ok())
+            .find(|name| name != "lo");
+        // Nothing to tell apart on a host with loopback alone.
+        if let Some(addr) = other.and_then(|other| spawn_interface_listener(dir, &other)) {
+            let sock = client_socket();
+            sock.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+            sock.send_to(&rrq, addr).unwrap();
+            assert!(sock.recv_from(&mut [0; 16]).is_err());
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn unknown_interfaces_are_refused() {
+        let err = TFTPListener::bind("127.0.0.1", 0, ServerConfig {
+            interface: Some(String::from("nosuchnic0")),
+            ..ServerConfig::default()
+        }).err().unwrap();
+        assert!(err.to_string().contains("nosuchnic0"));
+    }
+
+    #[test]
+    fn server_knows_its_peer() {
+        let dir = scratch_dir("peer");
+        fs::write(dir.join("a.txt"), b"hello").unwrap();
+        let config = ServerConfig {
+            root: dir,
+            ..ServerConfig::default()
+        };
+
+        let peer: SocketAddr = "192.0.2.7:4242".parse().unwrap();
+        let rrq = ReadRequestPacket::new("a.txt", "octet").serialize();
+        assert_eq!(TFTPServer::new(&rrq, &peer, &config).unwrap().peer(), peer);
+        let wrq = WriteRequestPacket::new("b.txt", "octet").serialize();
+        assert_eq!(TFTPServer::new(&wrq, &client(), &config).unwrap().peer(), client());
+    }
+
+    #[test]
+    fn authorize_hook_denies_writes_from_an_address() {
+        let dir = scratch_dir("authorize");
+        fs::write(dir.join("a.txt"), "hello").unwrap();
+        let denied: SocketAddr = "10.0.0.7:6900".parse().unwrap();
+        let config = ServerConfig {
+            root: dir,
+            authorize: Some(Arc::new(move |addr: &SocketAddr, operation: Operation, _: &str| {
+                if *addr == denied && operation == Operation::Write {
+                    Err(TFTPError::AccessViolation)
+                } else {
+                    Ok(())
+                }
+            })),
+            ..ServerConfig::default()
+        };
+
+        let wrq = WriteRequestPacket::new("b.txt", "octet").serialize();
+        let violation = ErrorPacket::new(TFTPError::AccessViolation);
+        assert_eq!(TFTPServer::new(&wrq, &denied, &config).err(), Some(violation));
+        assert!(TFTPServer::new(&wrq, &client(), &config).is_ok());
+
+        let rrq = ReadRequestPacket::new("a.txt", "octet").serialize();
+        assert!(TFTPServer::new(&rrq, &denied, &config).is_ok());
+    }
+
+    /// The data port side of a link, requests the client
+    /// retransmits are for the listener and never reach it.
+    struct DataPort<T: Transport>(T);
+
+    impl<T: Transport> Transport for DataPort<T> {
+        fn send_to(&self, buf: &[u8], peer: SocketAddr) -> io::Result<usize> {
+            self.0.send_to(buf, peer)
+        }
+
+        fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+            loop {
+                let (count, addr) = self.0.recv_from(buf)?;
+                if !is_request(&buf[..count]) {
+                    return Ok((count, addr));
+                }
+            }
+        }
+    }
+
+    /// A link that fails once a packet was sent over it, by an error or a panic.
+    struct BrokenLink {
+        panics: bool,
+        sent: RefCell<Vec<Vec<u8>>>,
+    }
+
+    impl Transport for BrokenLink {
+        fn send_to(&self, buf: &[u8], _peer: SocketAddr) -> io::Result<usize> {
+            self.sent.borrow_mut().push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn recv_from(&self, _buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+            if self.panics {
+                panic!("Serving the client failed");
+            }
+            Err(io::Error::new(io::ErrorKind::ConnectionReset, "Link is down"))
+        }
+    }
+
+    #[test]
+    fn unfinished_transfers_are_aborted() {
+        let dir = scratch_dir("aborted_transfer");
+        fs::write(dir.join("a.bin"), vec![1; 1000]).unwrap();
+        let config = ServerConfig {
+            root: dir,
+            ..ServerConfig::default()
+        };
+        let rrq = ReadRequestPacket::new("a.bin", "octet").serialize();
+
+        for &panics in &[false, true] {
+            let link = BrokenLink { panics, sent: RefCell::new(Vec::new()) };
+            let server = TFTPServer::new(&rrq, &client(), &config).unwrap();
+            let served = panic::catch_unwind(AssertUnwindSafe(|| {
+                handle_client(&link, server, &ServerMetrics::default());
+            }));
+            assert_eq!(served.is_err(), panics);
+
+            // DATA #1, then word that nothing follows.
+            let sent = link.sent.into_inner();
+            assert_eq!(sent.len(), 2);
+            match parse_udp_packet(&sent[1]).unwrap() {
+                TFTPPacket::ERR(ep) => assert_eq!(ep.err(), "transfer aborted"),
+                p => panic!("Expected an ERR got {:?}", p),
+            }
+        }
+    }
+
+    /// A client that never answers, each read takes the server's default
+    /// timeout on the mock clock.
+    struct SilentClient {
+        clock: Arc<MockClock>,
+        sent: RefCell<Vec<Vec<u8>>>,
+    }
+
+    impl Transport for SilentClient {
+        fn send_to(&self, buf: &[u8], _peer: SocketAddr) -> io::Result<usize> {
+            self.sent.borrow_mut().push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn recv_from(&self, _buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+            self.clock.advance(Duration::from_secs(1));
+            Err(io::Error::new(io::ErrorKind::WouldBlock, "read timed out"))
+        }
+    }
+
+    #[test]
+    fn silent_clients_are_dropped_on_the_clock() {
+        let dir = scratch_dir("silent_client");
+        fs::write(dir.join("a.bin"), vec![1; 1000]).unwrap();
+        let clock = Arc::new(MockClock::new());
+        let config = ServerConfig {
+            root: dir,
+            clock: clock.clone(),
+            ..ServerConfig::default()
+        };
+
+        let link = SilentClient { clock, sent: RefCell::new(Vec::new()) };
+        let rrq = ReadRequestPacket::new("a.bin", "octet").serialize();
+        let server = TFTPServer::new(&rrq, &client(), &config).unwrap();
+        handle_client(&link, server, &ServerMetrics::default());
+
+        // DATA #1, sent again each second until the client was silent for
+        // the five of the idle timeout, then word that it timed out.
+        let sent = link.sent.into_inner();
+        assert_eq!(sent.len(), 6);
+        assert!(sent[..5].iter().all(|p| *p == sent[0]));
+        match parse_udp_packet(&sent[5]).unwrap() {
+            TFTPPacket::ERR(ep) => assert_eq!(ep.err(), "Transfer timed out."),
+            p => panic!("Expected an ERR got {:?}", p),
+        }
+    }
+
+    /// Runs a transfer between the client and a server over an in-memory link
+    /// that loses packets both ways, neither side negotiates a timeout.
+    fn faulty_transfer(seed: u64, root: PathBuf, local: &str, upload: bool) -> TransferStats {
+        let client_faults = Faults { drop: 20, delay: 20, ..Faults::default() };
+        let server_faults = Faults { drop: 20, duplicate: 20, reorder: 50, delay: 20, ..Faults::default() };
+        let (mut client_end, mut server_end) = memory_link(
+            seed,
+            (client(), client_faults),
+            ("127.0.0.1:6901".parse().unwrap(), server_faults),
+        );
+        client_end.set_read_timeout(Duration::from_millis(20));
+
+        // A lost final ACK of a download is given up on sooner, one of an
+        // upload is sent again when the client sends the last block again.
+        let config = ServerConfig {
+            root,
+            idle_timeout: Duration::from_millis(300),
+            dally: Duration::from_millis(300),
+            ..ServerConfig::default()
+        };
+        let server = thread::spawn(move || {
+            let mut buf = vec![0; 1024];
+            let (count, client_addr) = server_end.recv_from(&mut buf).unwrap();
+            let server = TFTPServer::new(&buf[..count], &client_addr, &config).unwrap();
+            // Stands in for the server's default timeout, the link has its own.
+            server_end.set_read_timeout(Duration::from_millis(30));
+            handle_client(&DataPort(server_end), server, &ServerMetrics::default());
+        });
+
+        let config = ClientConfig {
+            retries: 20,
+            timeout: Duration::from_millis(50),
+            connect_timeout: Duration::from_millis(50),
+            ..ClientConfig::default()
+        };
+        let server_addr = "127.0.0.1:6901".parse().unwrap();
+        let stats = transfer_with(&client_end, server_addr, local, upload, &config).unwrap();
+        server.join().unwrap();
+        stats
+    }
+
+    #[test]
+    fn downloads_over_a_faulty_link_complete() {
+        let local = scratch_dir("faulty_download").join("a.txt");
+        let local = local.to_str().unwrap();
+        let content: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+
+        for seed in 1..=8 {
+            let root = scratch_dir("faulty_download_remote");
+            let remote = root.join(local);
+            fs::create_dir_all(remote.parent().unwrap()).unwrap();
+            fs::write(&remote, &content).unwrap();
+
+            let stats = faulty_transfer(seed, root, local, false);
+            assert_eq!(stats.bytes, 3000, "seed {}", seed);
+            assert_eq!(fs::read(local).unwrap(), content, "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn uploads_over_a_faulty_link_complete() {
+        let local = scratch_dir("faulty_upload").join("a.txt");
+        let local = local.to_str().unwrap();
+        let content: Vec<u8> = (0..3000).map(|i| (i * 7) as u8).collect();
+        fs::write(local, &content).unwrap();
+
+        for seed in 1..=8 {
+            let root = scratch_dir("faulty_upload_remote");
+            fs::create_dir_all(root.join(local).parent().unwrap()).unwrap();
+
+            faulty_transfer(seed, root.clone(), local, true);
+            assert_eq!(fs::read(root.join(local)).unwrap(), content, "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn append_adds_to_the_existing_file() {
+        let local = scratch_dir("append").join("log.txt");
+        let local = local.to_str().unwrap();
+        fs::write(local, "world\n").unwrap();
+
+        let root = scratch_dir("append_remote");
+        let remote = root.join(local);
+        fs::create_dir_all(remote.parent().unwrap()).unwrap();
+        fs::write(&remote, "hello\n").unwrap();
+
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root,
+            append: true,
+            ..ServerConfig::default()
+        });
+        thread::spawn(move || listener.serve());
+
+        let config = ClientConfig { append: true, ..ClientConfig::default() };
+        assert_eq!(transfer(&addr.to_string(), local, true, &config).unwrap().bytes, 6);
+        assert_eq!(fs::read_to_string(&remote).unwrap(), "hello\nworld\n");
+
+        // Without the option the existing file is still refused.
+        match transfer(&addr.to_string(), local, true, &ClientConfig::default()) {
+            Err(TransferError::Rejected(_, ep)) => assert_eq!(ep.code(), TFTPError::FileExists.code()),
+            r => panic!("Expected an error got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn overwrite_replaces_existing_files_when_allowed() {
+        let dir = scratch_dir("overwrite");
+        fs::write(dir.join("a.txt"), vec![1; 2000]).unwrap();
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root: dir.clone(),
+            allow_overwrite: true,
+            ..ServerConfig::default()
+        });
+        thread::spawn(move || listener.serve());
+
+        upload(addr, "a.txt", &[2; 700]);
+        wait_until(|| fs::read(dir.join("a.txt")).map_or(false, |c| c == vec![2; 700]));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn uploads_over_the_disk_quota_are_rolled_back() {
+        let dir = scratch_dir("disk_quota");
+        let quota = Arc::new(DiskQuota::new(3000));
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root: dir.clone(),
+            disk_quota: Some(quota.clone()),
+            ..ServerConfig::default()
+        });
+        thread::spawn(move || listener.serve());
+
+        // Four 1000 byte uploads in flight at once, only three fit.
+        let clients: Vec<_> = (0..4)
+            .map(|i| {
+                let sock = client_socket();
+                sock.send_to(&WriteRequestPacket::new(&format!("{}.bin", i), "octet").serialize(), addr).unwrap();
+                match recv_packet(&sock) {
+                    (TFTPPacket::ACK(ap), data_addr) if ap.blk() == 0 => (sock, data_addr),
+                    (p, _) => panic!("Unexpected packet {:?}", p),
+                }
+            })
+            .collect();
+        for (i, (sock, data_addr)) in clients.iter().enumerate() {
+            sock.send_to(&DataPacket::new(1, vec![i as u8; STRIDE_SIZE]).serialize(), data_addr).unwrap();
+            match recv_packet(sock) {
+                (TFTPPacket::ACK(ap), _) => assert_eq!(ap.blk(), 1),
+                (p, _) => panic!("Unexpected packet {:?}", p),
+            }
+        }
+        let mut refused = Vec::new();
+        for (i, (sock, data_addr)) in clients.iter().enumerate() {
+            sock.send_to(&DataPacket::new(2, vec![i as u8; 1000 - STRIDE_SIZE]).serialize(), data_addr).unwrap();
+            match recv_packet(sock) {
+                (TFTPPacket::ACK(ap), _) => assert_eq!(ap.blk(), 2),
+                (TFTPPacket::ERR(ep), _) => {
+                    assert_eq!(ep.code(), TFTPError::DiskFull.code());
+                    refused.push(i);
+                }
+                (p, _) => panic!("Unexpected packet {:?}", p),
+            }
+        }
+        assert_eq!(refused.len(), 1);
+
+        let name = |i: usize| dir.join(format!("{}.bin", i));
+        assert!(!name(refused[0]).exists());
+        assert_eq!(quota.used(), 3000);
+        for i in (0..4).filter(|i| *i != refused[0]) {
+            assert_eq!(fs::read(name(i)).unwrap(), vec![i as u8; 1000]);
+        }
+    }
+
+    #[test]
+    fn failed_overwrites_keep_the_old_file() {
+        let dir = scratch_dir("failed_overwrite");
+        fs::write(dir.join("a.txt"), "old").unwrap();
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root: dir.clone(),
+            allow_overwrite: true,
+            disk_quota: Some(Arc::new(DiskQuota::new(100))),
+            ..ServerConfig::default()
+        });
+        thread::spawn(move || listener.serve());
+
+        let sock = client_socket();
+        sock.send_to(&WriteRequestPacket::new("a.txt", "octet").serialize(), addr).unwrap();
+        let data_addr = match recv_packet(&sock) {
+            (TFTPPacket::ACK(ap), data_addr) if ap.blk() == 0 => data_addr,
+            (p, _) => panic!("Unexpected packet {:?}", p),
+        };
+        sock.send_to(&DataPacket::new(1, vec![1; 200]).serialize(), data_addr).unwrap();
+        match recv_packet(&sock) {
+            (TFTPPacket::ERR(ep), _) => assert_eq!(ep.code(), TFTPError::DiskFull.code()),
+            (p, _) => panic!("Unexpected packet {:?}", p),
+        }
+
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        assert_eq!(fs::read(dir.join("a.txt")).unwrap(), b"old");
+    }
+
+    #[test]
+    fn aborted_overwrites_leave_no_temp_file() {
+        for &chroot in &[false, true] {
+            let dir = scratch_dir(if chroot { "aborted_overwrite_chroot" } else { "aborted_overwrite" });
+            fs::write(dir.join("a.txt"), "old").unwrap();
+            let (addr, listener) = spawn_listener(ServerConfig {
+                root: dir.clone(),
+                allow_overwrite: true,
+                chroot,
+                ..ServerConfig::default()
+            });
+            thread::spawn(move || listener.serve());
+
+            let sock = client_socket();
+            sock.send_to(&WriteRequestPacket::new("a.txt", "octet").serialize(), addr).unwrap();
+            let data_addr = match recv_packet(&sock) {
+                (TFTPPacket::ACK(ap), data_addr) if ap.blk() == 0 => data_addr,
+                (p, _) => panic!("Unexpected packet {:?}", p),
+            };
+            sock.send_to(&DataPacket::new(1, vec![1; 512]).serialize(), data_addr).unwrap();
+            match recv_packet(&sock) {
+                (TFTPPacket::ACK(ap), _) => assert_eq!(ap.blk(), 1),
+                (p, _) => panic!("Unexpected packet {:?}", p),
+            }
+            assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+            sock.send_to(&ErrorPacket::new(TFTPError::UndefinedError).serialize(), data_addr).unwrap();
+            wait_until(|| fs::read_dir(&dir).unwrap().count() == 1);
+            assert_eq!(fs::read(dir.join("a.txt")).unwrap(), b"old");
+
+            // A complete upload takes the old file's place.
+            upload(addr, "a.txt", b"new");
+            wait_until(|| fs::read(dir.join("a.txt")).unwrap() == b"new");
+            assert_eq!(fs::read_dir(&dir).unwrap().count(), 1, "chroot {}", chroot);
+        }
+    }
+
+    #[test]
+    fn overwrite_is_refused_by_default() {
+        let dir = scratch_dir("overwrite_refused");
+        fs::write(dir.join("a.txt"), "old").unwrap();
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root: dir.clone(),
+            ..ServerConfig::default()
+        });
+        thread::spawn(move || listener.serve());
+
+        let sock = client_socket();
+        sock.send_to(&WriteRequestPacket::new("a.txt", "octet").serialize(), addr).unwrap();
+        match recv_packet(&sock) {
+            (TFTPPacket::ERR(ep), _) => assert_eq!(ep.code(), TFTPError::FileExists.code()),
+            (p, _) => panic!("Unexpected packet {:?}", p),
+        }
+        assert_eq!(fs::read_to_string(dir.join("a.txt")).unwrap(), "old");
+    }
+
+    #[test]
+    fn interrupted_uploads_are_resumed() {
+        let local = scratch_dir("resume_upload").join("a.bin");
+        let local = local.to_str().unwrap();
+        let content: Vec<u8> = (0..1500).map(|i| (i * 3) as u8).collect();
+        fs::write(local, &content).unwrap();
+
+        let root = scratch_dir("resume_upload_remote");
+        let remote = root.join(local);
+        fs::create_dir_all(remote.parent().unwrap()).unwrap();
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root,
+            resumable_uploads: true,
+            ..ServerConfig::default()
+        });
+        thread::spawn(move || listener.serve());
+
+        // The first attempt gives up after a block.
+        let sock = client_socket();
+        sock.send_to(&WriteRequestPacket::new(local, "octet").serialize(), addr).unwrap();
+        let (_, data_addr) = recv_packet(&sock);
+        sock.send_to(&DataPacket::new(1, content[..512].to_vec()).serialize(), data_addr).unwrap();
+        recv_packet(&sock);
+        sock.send_to(&ErrorPacket::new_custom(String::from("bye")).serialize(), data_addr).unwrap();
+
+        let part = PathBuf::from(format!("{}.part", remote.display()));
+        wait_until(|| fs::metadata(&part).map(|m| m.len() == 512).unwrap_or(false));
+        assert!(!remote.exists());
+
+        let config = ClientConfig {
+            resume: true,
+            ..ClientConfig::default()
+        };
+        let stats = transfer(&addr.to_string(), local, true, &config).unwrap();
+        assert_eq!(stats.bytes, 1500 - 512);
+        wait_until(|| remote.exists());
+        assert_eq!(fs::read(&remote).unwrap(), content);
+        assert!(!part.exists());
+    }
+
+    #[test]
+    fn uploads_in_progress_keep_their_part_file() {
+        let root = scratch_dir("part_in_use");
+        let config = ServerConfig {
+            root,
+            resumable_uploads: true,
+            ..ServerConfig::default()
+        };
+        let wrq = WriteRequestPacket::new("a.bin", "octet").serialize();
+        let resume = WriteRequestPacket::new("a.bin", "octet").with_option("resume", "1").serialize();
+
+        let first = TFTPServer::new(&wrq, &client(), &config).unwrap();
+        for request in &[&wrq, &resume] {
+            match TFTPServer::new(request, &client(), &config) {
+                Err(ep) => assert_eq!(ep.code(), TFTPError::FileExists.code()),
+                Ok(_) => panic!("Expected the upload to be refused"),
+            }
+        }
+
+        drop(first);
+        assert!(TFTPServer::new(&wrq, &client(), &config).is_ok());
+    }
+
+    #[test]
+    fn append_needs_the_server_to_allow_it() {
+        let local = scratch_dir("append_refused").join("log.txt");
+        let local = local.to_str().unwrap();
+        fs::write(local, "world\n").unwrap();
+
+        let root = scratch_dir("append_refused_remote");
+        let remote = root.join(local);
+        fs::create_dir_all(remote.parent().unwrap()).unwrap();
+        fs::write(&remote, "hello\n").unwrap();
+
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root,
+            ..ServerConfig::default()
+        });
+        thread::spawn(move || listener.serve());
+
+        let config = ClientConfig { append: true, ..ClientConfig::default() };
+        match transfer(&addr.to_string(), local, true, &config) {
+            Err(TransferError::Rejected(_, ep)) => assert_eq!(ep.code(), TFTPError::FileExists.code()),
+            r => panic!("Expected an error got {:?}", r),
+        }
+        assert_eq!(fs::read_to_string(&remote).unwrap(), "hello\n");
+    }
+
+    #[test]
+    fn option_floods_are_dropped() {
+        let dir = scratch_dir("option_flood");
+        fs::write(dir.join("a.txt"), "hello").unwrap();
+        let (addr, listener) = spawn_listener(ServerConfig {
+            root: dir,
+            ..ServerConfig::default()
+        });
+        let metrics = listener.metrics();
+        thread::spawn(move || listener.serve());
+
+        let sock = client_socket();
+        sock.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        let mut flood = ReadRequestPacket::new("a.txt", "octet").serialize();
+        flood.extend_from_slice(b"x\0y\0".repeat(1000).as_slice());
+        sock.send_to(&flood, addr).unwrap();
+        sock.send_to(&flood[..200], addr).unwrap();
+        assert!(sock.recv_from(&mut [0; 16]).is_err());
+        assert_eq!(metrics.dropped(), 2);
+        assert_eq!(metrics.errors(TFTPError::IllegalOperation.code()), 0);
+
+        assert_eq!(download(addr, "a.txt").unwrap(), b"hello");
+    }
 }
@@ -35,15 +35,23 @@ impl Serializable for AckPacket {
     }
     fn serialize(self) -> Vec<u8> {
         let mut buf = Vec::with_capacity(ACK_LEN);
+        self.serialize_into(&mut buf);
+        buf
+    }
+
+    fn serialize_into(self, buf: &mut Vec<u8>) {
+        buf.reserve(ACK_LEN);
         buf.write_u16::<NetworkEndian>(self.op).unwrap();
         buf.write_u16::<NetworkEndian>(self.blk).unwrap();
-
-        buf
     }
 }
 
 impl Deserializable for AckPacket {
     fn deserialize(buf: &[u8]) -> Result<TFTPPacket, TFTPParseError> {
+        if buf.len() < ACK_LEN {
+            return Err(TFTPParseError::new("Packet is too short"));
+        }
+
         let op = NetworkEndian::read_u16(buf);
 
         if op != OP_ACK {
@@ -100,4 +108,14 @@ mod tests {
         let p = AckPacket::deserialize(&mut buf).unwrap_err();
         assert_eq!(p.details, format!("Bad OP code! [{}]", bad_op).as_str())
     }
+
+    #[test]
+    fn serialize_into_appends() {
+        let mut buf = vec![7];
+        AckPacket::new(42).serialize_into(&mut buf);
+
+        let mut expected = vec![7];
+        expected.append(&mut AckPacket::new(42).serialize());
+        assert_eq!(buf, expected);
+    }
 }
@@ -0,0 +1,110 @@
+use std::io;
+use std::io::{Cursor, ErrorKind, Read};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+/// Size of the chunks handed over by the reading thread.
+const CHUNK_SIZE: usize = 8192;
+/// Chunks read ahead before the reading thread waits for the transfer.
+const CHUNKS_AHEAD: usize = 16;
+
+/// Reads a source that may stall (e.g. a pipe) on its own thread. Reads
+/// that find nothing within the patience fail with `WouldBlock` so the
+/// caller can tend to the transfer and try again later.
+pub struct BackgroundReader {
+    chunks: Receiver<io::Result<Vec<u8>>>,
+    chunk: Cursor<Vec<u8>>,
+    patience: Duration,
+}
+
+impl BackgroundReader {
+    pub fn spawn(mut source: Box<dyn Read + Send>, patience: Duration) -> Self {
+        let (tx, rx) = sync_channel(CHUNKS_AHEAD);
+        thread::spawn(move || loop {
+            let mut chunk = vec![0; CHUNK_SIZE];
+            match source.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(n) => {
+                    chunk.truncate(n);
+                    // The transfer is over, nobody wants the rest.
+                    if tx.send(Ok(chunk)).is_err() {
+                        break;
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    let _ = tx.send(Err(e));
+                    break;
+                }
+            }
+        });
+
+        BackgroundReader {
+            chunks: rx,
+            chunk: Cursor::new(Vec::new()),
+            patience,
+        }
+    }
+}
+
+impl Read for BackgroundReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.chunk.position() == self.chunk.get_ref().len() as u64 {
+            match self.chunks.recv_timeout(self.patience) {
+                Ok(chunk) => self.chunk = Cursor::new(chunk?),
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(io::Error::new(ErrorKind::WouldBlock, "The source isn't ready"));
+                }
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
+            }
+        }
+
+        self.chunk.read(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{ErrorKind, Read};
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    use crate::tftp::shared::background_reader::BackgroundReader;
+
+    /// Hands out whatever is sent to it, ends when the sender is dropped.
+    struct Pipe(std::sync::mpsc::Receiver<Vec<u8>>);
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match self.0.recv() {
+                Ok(data) => {
+                    buf[..data.len()].copy_from_slice(&data);
+                    Ok(data.len())
+                }
+                Err(_) => Ok(0),
+            }
+        }
+    }
+
+    #[test]
+    fn stalled_reads_would_block() {
+        let (tx, rx) = channel();
+        let mut reader = BackgroundReader::spawn(Box::new(Pipe(rx)), Duration::from_millis(20));
+        let mut buf = [0; 8];
+
+        assert_eq!(reader.read(&mut buf).unwrap_err().kind(), ErrorKind::WouldBlock);
+        tx.send(b"abc".to_vec()).unwrap();
+        drop(tx);
+
+        let mut read = Vec::new();
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => read.extend_from_slice(&buf[..n]),
+                Err(e) => assert_eq!(e.kind(), ErrorKind::WouldBlock),
+            }
+        }
+        assert_eq!(read, b"abc");
+    }
+}
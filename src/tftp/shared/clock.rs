@@ -0,0 +1,17 @@
+use std::time::Instant;
+
+/// Tells the time for timeouts and retransmissions, tests substitute
+/// a clock they advance themselves for the system one.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// Time as the system tells it.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
@@ -1,11 +1,22 @@
-use std::fs::File;
-use std::io::{Error, ErrorKind, Read, Write};
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::mem;
 use std::path::Path;
+use std::time::Duration;
 
-use crate::tftp::shared::{Serializable, STRIDE_SIZE};
+use crate::tftp::shared::{Serializable, TFTPPacket, STRIDE_SIZE};
 use crate::tftp::shared::ack_packet::AckPacket;
+use crate::tftp::shared::background_reader::BackgroundReader;
 use crate::tftp::shared::data_packet::DataPacket;
 use crate::tftp::shared::err_packet::{ErrorPacket, TFTPError};
+use crate::tftp::shared::oack_packet::OptionAckPacket;
+use crate::tftp::shared::transfer_mode::{NetasciiDecoder, NetasciiEncoder, TransferMode};
+
+/// Packets in a row the peer may repeat without the transfer moving on,
+/// after that it's stalled and given up on.
+pub const STALL_LIMIT: u32 = 16;
 
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
 pub enum DataChannelMode {
@@ -19,12 +30,28 @@ enum DataChannelState {
     SendAck,
     SendLastAck,
     SendData,
+    SendOptionAck,
     WaitAck,
     WaitLastAck,
     Error,
     Done,
 }
 
+/// Where a transfer is in its lifecycle, as seen from the outside.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum TransferState {
+    /// No data was exchanged yet.
+    Idle,
+    /// Data blocks are being sent and acknowledged.
+    Sending,
+    /// Data blocks are being received and acknowledged.
+    Receiving,
+    /// The last block was sent or received and awaits its ACK.
+    Finishing,
+    Done,
+    Errored,
+}
+
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
 pub enum DataChannelOwner {
     Server,
@@ -32,14 +59,75 @@ pub enum DataChannelOwner {
 }
 
 pub struct DataChannel {
+    mode: DataChannelMode,
+    /// File being received.
     fd: Option<File>,
+    /// Data being sent, usually a file.
+    source: Option<Box<dyn Read + Send>>,
+    /// Received blocks are handed to the caller instead of a file.
+    holds_blocks: bool,
+    /// Latest block received, until the caller takes it.
+    held_block: Option<Vec<u8>>,
     file_name: String,
     file_size: u64,
+    /// Whether the size of the source is known, otherwise
+    /// the first short block ends the transfer.
+    size_known: bool,
+    /// Bytes in a full DATA block, `STRIDE_SIZE` unless negotiated.
+    block_size: usize,
+    /// Files whose size is a multiple of the block size are terminated
+    /// by an extra, empty DATA packet.
+    needs_empty_final_block: bool,
+    /// Number of file bytes sent or received so far.
+    transferred_bytes: u64,
+    /// Bytes kept from an interrupted download.
+    resumed_bytes: u64,
+    /// Length of the local file of a resumed download, until it's
+    /// checked against the size of the remote one.
+    unchecked_local_len: Option<u64>,
+    /// Converts received netascii to host text.
+    decoder: Option<NetasciiDecoder>,
     last_transferred_bytes: usize,
     blk: u16,
     error: Option<String>,
     state: DataChannelState,
+    /// Whether the DATA at hand holds the block being sent.
+    block_read: bool,
+    /// Start of the block being sent while the rest isn't ready yet.
+    partial_block: Vec<u8>,
+    /// Block read while the previous one awaited its ACK.
+    next_block: Option<Vec<u8>>,
     packet_at_hand: Option<Vec<u8>>,
+    /// Packets in a row that repeated an older one, see `STALL_LIMIT`.
+    stalled_rounds: u32,
+}
+
+/// Tells the peer why a file couldn't be opened or created.
+fn open_error(e: &io::Error) -> ErrorPacket {
+    match e.kind() {
+        ErrorKind::PermissionDenied => ErrorPacket::new(TFTPError::AccessViolation),
+        _ => ErrorPacket::new_custom(e.to_string()),
+    }
+}
+
+/// Tells errors of a full disk, or of a used up quota, from other
+/// failures to write.
+fn is_out_of_space(e: &io::Error) -> bool {
+    let code = match e.raw_os_error() {
+        Some(code) => code,
+        None => return false,
+    };
+
+    #[cfg(target_os = "linux")]
+    {
+        use rustix::io::Errno;
+        [Errno::NOSPC, Errno::DQUOT].iter().any(|errno| errno.raw_os_error() == code)
+    }
+    // ENOSPC has the same number on the other Unix-likes, EDQUOT doesn't.
+    #[cfg(not(target_os = "linux"))]
+    {
+        cfg!(unix) && code == 28
+    }
 }
 
 impl DataChannel {
@@ -50,51 +138,119 @@ impl DataChannel {
     /// * `file_name` - Specified file name to read data from / write data to.
     /// * `channel_mode` - Tells whether this channel will be receiving or sending data.
     pub fn new(file_name: &str, mode: DataChannelMode, owner: DataChannelOwner) -> Result<Self, ErrorPacket> {
-        let (initial_blk, initial_state) =
-            DataChannel::compute_initial_state(mode, owner);
+        if mode == DataChannelMode::Tx {
+            let (fd, size) = DataChannel::open_file_for_transmission(file_name)?;
+            return Ok(DataChannel::from_source(file_name, Box::new(fd), size, owner));
+        }
 
-        let maybe_fd = if mode == DataChannelMode::Tx {
-            let fd = DataChannel::open_file_for_transmission(file_name, owner);
-            if let Err(ep) = fd {
-                return Err(ep);
-            }
+        DataChannel::validate_file_for_reception(file_name, owner)?;
+        Ok(DataChannel::with_source(file_name, mode, owner, None, 0))
+    }
 
-            Some(fd.unwrap())
-        } else {
-            let fp_valid = DataChannel::validate_file_for_reception(file_name, owner);
-            if let Err(ep) = fp_valid {
-                return Err(ep);
-            }
+    /// Makes a receiving channel that adds the received data to the end of
+    /// the file, which is created if it doesn't exist yet.
+    pub fn append_to(file_name: &str, owner: DataChannelOwner) -> Result<Self, ErrorPacket> {
+        DataChannel::receive_into(file_name, owner, OpenOptions::new().append(true).create(true))
+    }
 
-            None
-        };
+    /// Makes a receiving channel that replaces the content of the file,
+    /// which is created if it doesn't exist yet.
+    pub fn overwrite(file_name: &str, owner: DataChannelOwner) -> Result<Self, ErrorPacket> {
+        DataChannel::receive_into(file_name, owner, OpenOptions::new().write(true).truncate(true).create(true))
+    }
 
-        let (maybe_fd, size) = if maybe_fd.is_some() {
-            let (fd, size) = maybe_fd.unwrap();
-            (Some(fd), size)
-        } else {
-            (None, 0)
-        };
+    fn receive_into(file_name: &str, owner: DataChannelOwner, options: &OpenOptions) -> Result<Self, ErrorPacket> {
+        let path = Path::new(file_name);
+        if Path::file_name(path) == None || path.is_dir() {
+            let err = String::from("Can't write a directory");
+            return Err(ErrorPacket::new_custom(err));
+        }
+
+        let fd = options.open(path).map_err(|e| open_error(&e))?;
+        Ok(DataChannel::receive_file(file_name, fd, owner))
+    }
+
+    /// Makes a receiving channel that writes to a file that's already open.
+    pub fn receive_file(file_name: &str, fd: File, owner: DataChannelOwner) -> Self {
+        let mut channel = DataChannel::with_source(file_name, DataChannelMode::Rx, owner, None, 0);
+        channel.fd = Some(fd);
+        channel
+    }
+
+    /// Makes a receiving channel that holds each new block for
+    /// `take_block` instead of writing it, no file is involved.
+    pub fn receive_blocks(name: &str, owner: DataChannelOwner) -> Self {
+        let mut channel = DataChannel::with_source(name, DataChannelMode::Rx, owner, None, 0);
+        channel.holds_blocks = true;
+        channel
+    }
+
+    /// Makes a sending channel that reads a file that's already open.
+    pub fn send_file(file_name: &str, fd: File, owner: DataChannelOwner) -> Result<Self, ErrorPacket> {
+        let size = fd.metadata().map_err(|e| ErrorPacket::new_custom(e.to_string()))?.len();
+        Ok(DataChannel::from_source(file_name, Box::new(fd), size, owner))
+    }
+
+    /// Refuses to serve an empty file to the client that requested it.
+    pub fn refuse_empty(&self) -> Result<(), ErrorPacket> {
+        if self.mode != DataChannelMode::Tx || !self.size_known || self.file_size > 0 {
+            return Ok(());
+        }
+
+        Err(ErrorPacket::new_custom(String::from("Requested file is empty.")))
+    }
+
+    /// Makes a sending channel that reads its data from `source`
+    /// instead of a file, `size` is the number of bytes it holds.
+    pub fn from_source(name: &str, source: Box<dyn Read + Send>, size: u64, owner: DataChannelOwner) -> Self {
+        DataChannel::with_source(name, DataChannelMode::Tx, owner, Some(source), size)
+    }
+
+    /// Makes a sending channel that reads `source` until it's exhausted,
+    /// for sources whose size isn't known upfront.
+    pub fn from_reader(name: &str, source: Box<dyn Read + Send>, owner: DataChannelOwner) -> Self {
+        let mut channel = DataChannel::with_source(name, DataChannelMode::Tx, owner, Some(source), 0);
+        channel.size_known = false;
+        channel
+    }
+
+    fn with_source(file_name: &str, mode: DataChannelMode, owner: DataChannelOwner, source: Option<Box<dyn Read + Send>>, size: u64) -> Self {
+        let (initial_blk, initial_state) =
+            DataChannel::compute_initial_state(mode, owner);
 
         let mut channel = DataChannel {
-            fd: maybe_fd,
+            mode,
+            fd: None,
+            source,
+            holds_blocks: false,
+            held_block: None,
             file_name: file_name.to_string(),
             file_size: size,
+            size_known: true,
+            block_size: STRIDE_SIZE,
+            needs_empty_final_block: size % STRIDE_SIZE as u64 == 0,
+            transferred_bytes: 0,
+            resumed_bytes: 0,
+            unchecked_local_len: None,
+            decoder: None,
             last_transferred_bytes: 0,
             blk: initial_blk,
             error: None,
             state: initial_state,
+            block_read: false,
+            partial_block: Vec::new(),
+            next_block: None,
             packet_at_hand: None,
+            stalled_rounds: 0,
         };
 
 
-        if channel.state == DataChannelState::SendData {
-            channel.send_data();
-        } else if channel.state == DataChannelState::SendAck {
+        // Data is only read once it's polled by packet_at_hand.
+        if channel.state == DataChannelState::SendAck {
             channel.send_ack();
         }
 
-        Ok(channel)
+        channel
     }
 
     fn compute_initial_state(channel_mode: DataChannelMode, channel_owner: DataChannelOwner) -> (u16, DataChannelState) {
@@ -120,25 +276,12 @@ impl DataChannel {
         }
     }
 
-    fn open_file_for_transmission(file_name: &str, owner: DataChannelOwner) -> Result<(File, u64), ErrorPacket> {
-        use std::fs;
+    fn open_file_for_transmission(file_name: &str) -> Result<(File, u64), ErrorPacket> {
         let fp = Path::new(file_name);
         let fd = File::open(fp)
             .and_then(|fd| {
-                let meta = fs::metadata(fp).unwrap();
-                if meta.len() == 0 {
-                    let direction = if owner == DataChannelOwner::Server {
-                        "Requested"
-                    } else {
-                        "Transmitted"
-                    };
-                    let msg = format!("{} file is empty.", direction);
-                    Err(Error::new(ErrorKind::InvalidData, msg))
-                } else {
-                    let meta = fs::metadata(fp).unwrap();
-
-                    Ok((fd, meta.len()))
-                }
+                let size = fd.metadata()?.len();
+                Ok((fd, size))
             });
 
         if fd.is_err() {
@@ -147,7 +290,7 @@ impl DataChannel {
             return if err.kind() == ErrorKind::NotFound {
                 Err(ErrorPacket::new(TFTPError::FileNotFound))
             } else {
-                Err(ErrorPacket::new_custom(err.to_string()))
+                Err(open_error(&err))
             };
         }
 
@@ -166,37 +309,245 @@ impl DataChannel {
             return Err(ErrorPacket::new_custom(err));
         }
 
-        // Client isn't allowed to traverse the TFTP directory upwards
-        // in any case.
-        if file_name.contains("..") {
-            let err = String::from("Only absolute paths are allowed.");
-            return Err(ErrorPacket::new_custom(err));
+        // A server makes the directories upfront to refuse the upload right
+        // away if it can't, a client waits for DATA #1 so a failed download
+        // doesn't leave them behind.
+        if let (Some(parent), DataChannelOwner::Server) = (path.parent(), owner) {
+            fs::create_dir_all(parent).map_err(|e| open_error(&e))?;
         }
 
-        // Client needn't know anything about the server's host.
-        if path.is_absolute() {
-            let err = String::from("File path must not start with root.");
-            return Err(ErrorPacket::new_custom(err));
+        Ok(())
+    }
+
+    /// Sets the negotiated size of a full DATA block, this
+    /// has to happen before any data is exchanged.
+    pub fn set_block_size(&mut self, block_size: usize) {
+        assert_eq!(self.transferred_bytes, 0);
+
+        self.block_size = block_size;
+        self.needs_empty_final_block = self.file_size % block_size as u64 == 0;
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Converts the data to or from netascii, this has to happen before
+    /// any data is exchanged. A sending channel converts the data as it's
+    /// read, the size of the transfer isn't known until the end then.
+    pub fn set_transfer_mode(&mut self, mode: TransferMode) -> io::Result<()> {
+        if self.transferred_bytes > 0 {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "The transfer mode can't change midway"));
+        }
+        if mode == TransferMode::Octet {
+            return Ok(());
         }
 
-        // File to be added is a decedent of the TFTP server directory.
-        if path.is_relative() && path.parent() != None {
-            use std::fs;
-            if let Err(e) = fs::create_dir_all(path.parent().unwrap()) {
-                return Err(ErrorPacket::new_custom(e.to_string()));
+        match self.mode {
+            DataChannelMode::Tx => {
+                let source = self.source.take()
+                    .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "There's nothing to send"))?;
+                self.source = Some(Box::new(NetasciiEncoder::new(source)));
+                // An empty file stays empty.
+                self.size_known = self.size_known && self.file_size == 0;
             }
+            DataChannelMode::Rx => self.decoder = Some(NetasciiDecoder::default()),
         }
 
         Ok(())
     }
 
+    /// Leaves out the first `bytes` of the source, the peer already has
+    /// them. Sources shorter than that are an error, and so is skipping
+    /// once the transfer started or without a source to skip.
+    pub fn skip(&mut self, bytes: u64) {
+        let source = match self.source.as_mut() {
+            Some(source) if self.transferred_bytes == 0 => source,
+            _ => {
+                self.set_next_err(ErrorPacket::with_message(TFTPError::IllegalOperation, "Can't resume here."));
+                self.set_state(DataChannelState::Error);
+                self.set_err(&format!("Can't resume after {} bytes, nothing is sent from a source yet", bytes));
+                return;
+            }
+        };
+        match io::copy(&mut source.take(bytes), &mut io::sink()) {
+            Ok(skipped) if skipped == bytes => {
+                self.file_size = self.file_size.saturating_sub(bytes);
+                self.needs_empty_final_block = self.file_size % self.block_size as u64 == 0;
+            }
+            Ok(skipped) => {
+                self.set_next_err(ErrorPacket::with_message(TFTPError::IllegalOperation, "Resume offset is past the end of the file."));
+                self.set_state(DataChannelState::Error);
+                self.set_err(&format!("Can't resume after {} bytes, the file has {}", bytes, skipped));
+            }
+            Err(e) => self.set_io_error(&e, "read"),
+        }
+    }
+
+    /// Reads the source on its own thread, a block that isn't ready
+    /// within `patience` is left for a later poll of `packet_at_hand`
+    /// instead of holding up the transfer.
+    pub fn read_in_background(&mut self, patience: Duration) {
+        if let Some(source) = self.source.take() {
+            self.source = Some(Box::new(BackgroundReader::spawn(source, patience)));
+        }
+    }
+
+    /// Answers the request with the accepted options instead of the first
+    /// DATA or ACK #0. A sending server then waits for ACK #0 while a
+    /// receiving one waits for DATA #1.
+    pub fn acknowledge_options(&mut self, oack: OptionAckPacket) {
+        assert!(
+            self.state == DataChannelState::SendData || self.state == DataChannelState::SendAck
+        );
+        assert_eq!(self.transferred_bytes, 0);
+
+        if self.mode == DataChannelMode::Tx {
+            self.blk = 0;
+        }
+
+        self.set_packet(oack);
+        self.set_state(DataChannelState::SendOptionAck);
+    }
+
+    /// Keeps the full blocks of an interrupted download of the same file
+    /// and returns how many were kept. The server still sends these blocks
+    /// since TFTP can't start a transfer midway, they're acknowledged
+    /// without being written again. The local file is only kept once it's
+    /// checked against the `tsize` the server answers with, see
+    /// `check_resumed`.
+    pub fn resume(&mut self) -> io::Result<u64> {
+        if self.state != DataChannelState::WaitData || self.transferred_bytes > 0 {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "The download already started"));
+        }
+
+        let fp = Path::new(&self.file_name);
+        if !fp.exists() {
+            return Ok(0);
+        }
+
+        let fd = OpenOptions::new().write(true).open(fp)?;
+        let local_len = fd.metadata()?.len();
+        let blocks = local_len / self.block_size as u64;
+
+        self.fd = Some(fd);
+        self.resumed_bytes = blocks * self.block_size as u64;
+        self.unchecked_local_len = Some(local_len);
+        Ok(blocks)
+    }
+
+    /// Checks the local file of a resumed download against the size of
+    /// the remote one. A local file that's longer belongs to another
+    /// version of the file, the download is refused instead of mixing the
+    /// two. Without the size nothing can be checked, the download starts
+    /// over.
+    fn check_resumed(&mut self, remote_size: Option<u64>) {
+        let local_len = match self.unchecked_local_len.take() {
+            Some(local_len) => local_len,
+            None => return,
+        };
+
+        let kept = match remote_size {
+            Some(size) if local_len > size => {
+                self.set_next_err(ErrorPacket::with_message(TFTPError::UndefinedError, "File changed since the download was interrupted."));
+                self.set_state(DataChannelState::Error);
+                let err = format!("Can't resume [{}], it has {} bytes and the remote file {}", self.file_name, local_len, size);
+                self.set_err(&err);
+                return;
+            }
+            // Full blocks of the negotiated size, a partial one
+            // might've been cut short and is fetched again.
+            Some(_) => local_len / self.block_size as u64 * self.block_size as u64,
+            None => 0,
+        };
+
+        let fd = self.fd.as_mut().unwrap();
+        let kept_file = fd.set_len(kept).and_then(|_| fd.seek(SeekFrom::End(0)));
+        match kept_file {
+            Ok(_) => self.resumed_bytes = kept,
+            Err(e) => self.set_io_error(&e, "resume"),
+        }
+    }
+
+    /// Acts on any packet from the peer, those that have no place in the
+    /// transfer at this point are answered with an error. `Err` carries the
+    /// error the transfer stopped with, its ERR packet is at hand unless
+    /// the peer is the one that sent it.
+    pub fn handle(&mut self, packet: TFTPPacket) -> Result<(), TFTPError> {
+        match packet {
+            TFTPPacket::DATA(dp) => self.on_data(dp),
+            TFTPPacket::ACK(ap) => self.on_ack(ap),
+            // The server accepted the options of an upload instead of sending ACK #0.
+            TFTPPacket::OACK(_) if self.mode == DataChannelMode::Tx && self.state == DataChannelState::WaitAck && self.blk == 0 && self.transferred_bytes == 0 => {
+                self.on_ack(AckPacket::new(0))
+            }
+            // The server accepted the options of a download, ACK #0 confirms
+            // them. A repeated OACK means the ACK was lost, it's sent again.
+            TFTPPacket::OACK(oack) if self.mode == DataChannelMode::Rx && self.state == DataChannelState::WaitData && self.blk == 1 && self.transferred_bytes == 0 => {
+                self.check_resumed(oack.option("tsize").and_then(|size| size.parse().ok()));
+                if self.state != DataChannelState::Error {
+                    self.set_next_ack(AckPacket::new(0));
+                    self.set_state(DataChannelState::SendAck);
+                }
+            }
+            TFTPPacket::ERR(ep) => {
+                self.packet_at_hand = None;
+                self.set_state(DataChannelState::Error);
+                self.set_err(&format!("Peer error [{}]: {}", ep.code(), ep.err()));
+                return Err(TFTPError::from_code(ep.code()).unwrap_or(TFTPError::UndefinedError));
+            }
+            TFTPPacket::OACK(_) => self.set_unexpected_packet_error("OACK"),
+            TFTPPacket::RRQ(_) => self.set_unexpected_packet_error("RRQ"),
+            TFTPPacket::WRQ(_) => self.set_unexpected_packet_error("WRQ"),
+        }
+
+        if self.state == DataChannelState::Error {
+            Err(TFTPError::IllegalOperation)
+        } else {
+            Ok(())
+        }
+    }
+
     /// Receives a data packet and checks its block number,
     /// if the packets block number is invalid an ErrorPacket is
     /// buffered, otherwise an AckPacket is buffered.
     ///
     /// * `dp` - Data packet received from the other end.
     pub fn on_data(&mut self, dp: DataPacket) {
-        assert_eq!(self.state, DataChannelState::WaitData);
+        if self.state != DataChannelState::WaitData {
+            self.set_unexpected_packet_error("DATA");
+            return;
+        }
+
+        // The server didn't answer with its options, the size is unknown.
+        self.check_resumed(None);
+        if self.state == DataChannelState::Error {
+            return;
+        }
+
+        // Blocks are numbered from 1, block 0 would pass for a retransmission.
+        // Later on it's the block following 65535.
+        if dp.blk() == 0 && self.transferred_bytes == 0 {
+            self.set_next_err(ErrorPacket::with_message(TFTPError::IllegalOperation, "DATA block 0 is invalid."));
+            self.set_state(DataChannelState::Error);
+            self.set_err("Received DATA block 0");
+            return;
+        }
+
+        // Our last ACK was lost, so the peer sent the block again. It's
+        // not a stall, an uploader with a slow source repeats it to keep
+        // the transfer alive for as long as the source takes.
+        if dp.blk() == self.blk.wrapping_sub(1) {
+            self.set_next_ack(AckPacket::new(dp.blk()));
+            self.set_state(DataChannelState::SendAck);
+            return;
+        }
+
+        // A copy the network held back, it was answered long ago.
+        if self.is_stale(dp.blk()) {
+            self.on_no_progress();
+            return;
+        }
 
         // The received blk
         // is the awaited blk number.
@@ -205,17 +556,52 @@ impl DataChannel {
             return;
         }
 
+        self.stalled_rounds = 0;
+
         // To avoid making empty files needlessly.
-        if dp.blk() == 1 {
-            let fp = Path::new(&self.file_name);
-            self.fd = Some(File::create(fp).unwrap());
+        if dp.blk() == 1 && self.fd.is_none() && !self.holds_blocks {
+            let path = Path::new(&self.file_name);
+            let created = path.parent().map_or(Ok(()), fs::create_dir_all).and_then(|_| File::create(path));
+            match created {
+                Ok(fd) => self.fd = Some(fd),
+                Err(e) => {
+                    self.set_io_error(&e, "create");
+                    return;
+                }
+            }
         }
 
+        let offset = self.transferred_bytes;
         let data = &dp.data();
-        self.last_transferred_bytes += data.len();
-        self.fd.as_ref().unwrap().write_all(data).unwrap();
+        self.last_transferred_bytes = data.len();
+        self.transferred_bytes += data.len() as u64;
+        if offset >= self.resumed_bytes {
+            let mut text = Vec::new();
+            let block: &[u8] = match self.decoder.as_mut() {
+                Some(decoder) => {
+                    decoder.decode(data, &mut text);
+                    if data.len() < self.block_size {
+                        decoder.finish(&mut text);
+                    }
+                    &text
+                }
+                None => data,
+            };
+            if self.holds_blocks {
+                self.held_block = Some(block.to_vec());
+            } else if let Err(e) = self.fd.as_ref().unwrap().write_all(block) {
+                self.set_io_error(&e, "write");
+                return;
+            }
+        } else if data.len() < self.block_size {
+            // The remote file is shorter than the one being resumed.
+            if let Err(e) = self.fd.as_ref().unwrap().set_len(self.transferred_bytes) {
+                self.set_io_error(&e, "truncate");
+                return;
+            }
+        }
 
-        if data.len() == STRIDE_SIZE {
+        if data.len() == self.block_size {
             self.set_state(DataChannelState::SendAck);
         } else {
             self.set_state(DataChannelState::SendLastAck);
@@ -230,7 +616,8 @@ impl DataChannel {
         );
 
         self.set_next_ack(AckPacket::new(self.blk as u16));
-        self.blk += 1;
+        // Block numbers wrap around for files of more than 65535 blocks.
+        self.blk = self.blk.wrapping_add(1);
     }
 
     /// Reads the next data packet to be sent,
@@ -239,34 +626,136 @@ impl DataChannel {
     fn send_data(&mut self) {
         assert_eq!(self.state, DataChannelState::SendData);
 
-        let mut buf = [0; STRIDE_SIZE];
-        let bytes_read = self.fd.as_ref().unwrap().read(&mut buf).unwrap();
-        self.last_transferred_bytes = bytes_read;
+        let mut block = match self.next_block.take() {
+            Some(block) => block,
+            None => {
+                let mut block = mem::take(&mut self.partial_block);
+                match self.read_block(&mut block) {
+                    Ok(()) => block,
+                    // What was read so far waits for the rest of the block.
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                        self.partial_block = block;
+                        return;
+                    }
+                    Err(e) => {
+                        self.set_io_error(&e, "read");
+                        return;
+                    }
+                }
+            }
+        };
+
+        // The size was taken when the file was opened, one that another
+        // process truncates or extends meanwhile can't be sent as a whole.
+        if self.size_known {
+            let expected = self.file_size.saturating_sub(self.transferred_bytes).min(self.block_size as u64);
+            if block.len() as u64 != expected {
+                self.set_next_err(ErrorPacket::with_message(TFTPError::UndefinedError, "File changed during the transfer."));
+                self.set_state(DataChannelState::Error);
+                let err = format!("[{}] changed during the transfer, it had {} bytes", self.file_name, self.file_size);
+                self.set_err(&err);
+                return;
+            }
+        }
+
+        self.transferred_bytes += block.len() as u64;
+        self.last_transferred_bytes = block.len();
+
+        // Send the next data packet, the block's buffer is
+        // kept for reading the one after it.
+        self.set_next_data(&block);
+        block.clear();
+        self.partial_block = block;
+        self.block_read = true;
+    }
+
+    /// Reads the next block while the one sent awaits its ACK, so reading
+    /// overlaps with the round trip instead of following it. Does nothing
+    /// unless a block is awaiting its ACK and more are to come.
+    pub fn read_ahead(&mut self) {
+        if self.mode != DataChannelMode::Tx || self.state != DataChannelState::WaitAck || self.next_block.is_some() {
+            return;
+        }
+
+        let mut block = mem::take(&mut self.partial_block);
+        match self.read_block(&mut block) {
+            Ok(()) => self.next_block = Some(block),
+            // Whatever went wrong is met again when the block is sent.
+            Err(_) => self.partial_block = block,
+        }
+    }
+
+    /// Fills the block from the source, a block is only
+    /// short when the end of the source is reached.
+    fn read_block(&mut self, block: &mut Vec<u8>) -> io::Result<()> {
+        let source = self.source.as_mut().unwrap();
+        let mut filled = block.len();
+        block.resize(self.block_size, 0);
+
+        let result = loop {
+            if filled == block.len() {
+                break Ok(());
+            }
+
+            match source.read(&mut block[filled..]) {
+                Ok(0) => break Ok(()),
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => break Err(e),
+            }
+        };
+
+        block.truncate(filled);
+        result
+    }
+
+    /// Tells whether the DATA packet at hand ends the transfer.
+    fn is_final_block(&self) -> bool {
+        if !self.size_known {
+            return self.last_transferred_bytes < self.block_size;
+        }
 
-        // Send the next data packet.
-        let data = Vec::from(&buf[0..bytes_read]);
-        self.set_next_data(DataPacket::new(self.blk as u16, data));
+        if self.transferred_bytes < self.file_size {
+            return false;
+        }
+
+        !self.needs_empty_final_block || self.last_transferred_bytes == 0
     }
 
     /// Receives an ACK packet from the server
     /// validates the block number then sends
     /// the next data block.
     pub fn on_ack(&mut self, ap: AckPacket) {
-        assert!(
-            self.state == DataChannelState::WaitAck || self.state == DataChannelState::WaitLastAck
-        );
+        // The answer to a keepalive, the next block isn't ready yet.
+        if self.state == DataChannelState::SendData && ap.blk() == self.blk.wrapping_sub(1) {
+            return;
+        }
+
+        if self.state != DataChannelState::WaitAck && self.state != DataChannelState::WaitLastAck {
+            self.set_unexpected_packet_error("ACK");
+            return;
+        }
+
+        // The peer retransmitted its previous ACK, answering it with the
+        // DATA at hand would duplicate every packet from now on
+        // (Sorcerer's Apprentice Syndrome). Our own timeout resends it.
+        if ap.blk() == self.blk.wrapping_sub(1) || self.is_stale(ap.blk()) {
+            self.on_no_progress();
+            return;
+        }
 
         if self.blk as u16 != ap.blk() {
             self.set_blk_error(ap.blk());
             return;
         }
 
-        self.blk += 1;
+        self.stalled_rounds = 0;
+        self.blk = self.blk.wrapping_add(1);
 
         match self.state {
             DataChannelState::WaitAck => {
+                self.block_read = false;
                 self.set_state(DataChannelState::SendData);
-                self.send_data();
             }
             DataChannelState::WaitLastAck => {
                 self.set_state(DataChannelState::Done);
@@ -281,8 +770,12 @@ impl DataChannel {
             // now we're done.
             DataChannelState::SendLastAck => self.set_state(DataChannelState::Done),
             DataChannelState::SendAck => self.set_state(DataChannelState::WaitData),
+            DataChannelState::SendOptionAck if self.mode == DataChannelMode::Tx => {
+                self.set_state(DataChannelState::WaitAck)
+            }
+            DataChannelState::SendOptionAck => self.set_state(DataChannelState::WaitData),
             DataChannelState::SendData => {
-                if self.last_transferred_bytes < STRIDE_SIZE {
+                if self.is_final_block() {
                     self.set_state(DataChannelState::WaitLastAck);
                 } else {
                     self.set_state(DataChannelState::WaitAck);
@@ -296,6 +789,44 @@ impl DataChannel {
         self.state = state;
     }
 
+    /// Tells whether the block is older than the previous one, later
+    /// blocks are never stale even when the block number wraps around.
+    fn is_stale(&self, blk: u16) -> bool {
+        let behind = self.blk.wrapping_sub(blk);
+        behind > 1 && behind < u16::MAX / 2
+    }
+
+    /// Counts a packet that repeats an older one. A peer that answers
+    /// but never moves on would keep the transfer going forever, since
+    /// each of its packets holds off our timeout.
+    fn on_no_progress(&mut self) {
+        self.stalled_rounds += 1;
+        if self.stalled_rounds < STALL_LIMIT {
+            return;
+        }
+
+        self.set_next_err(ErrorPacket::with_message(TFTPError::UndefinedError, "transfer stalled"));
+        self.set_state(DataChannelState::Error);
+        let err = format!("Transfer stalled at block #{}, the peer repeated itself {} times", self.blk, self.stalled_rounds);
+        self.set_err(&err);
+    }
+
+    /// Ends the transfer on options the peer had no say in, it's
+    /// told they're refused.
+    pub fn refuse_options(&mut self, msg: &str) {
+        self.set_next_err(ErrorPacket::with_message(TFTPError::OptionNegotiation, msg));
+        self.set_state(DataChannelState::Error);
+        self.set_err(msg);
+    }
+
+    /// The peer sent a packet that has no place in the transfer, like
+    /// an ACK while we're receiving.
+    fn set_unexpected_packet_error(&mut self, kind: &str) {
+        self.set_next_err(ErrorPacket::new(TFTPError::IllegalOperation));
+        self.set_state(DataChannelState::Error);
+        self.set_err(&format!("Unexpected {} packet", kind));
+    }
+
     fn set_blk_error(&mut self, actual: u16) {
         self.set_next_err(ErrorPacket::new(TFTPError::IllegalOperation));
         self.set_state(DataChannelState::Error);
@@ -308,38 +839,111 @@ impl DataChannel {
         self.set_err(&err);
     }
 
+    /// Ends the transfer on a failure of the file or the source, the peer
+    /// is told of an access violation if permission was denied, of a full
+    /// disk if it's out of space and of the error itself otherwise.
+    fn set_io_error(&mut self, e: &io::Error, action: &str) {
+        let packet = match e.kind() {
+            ErrorKind::PermissionDenied => ErrorPacket::new(TFTPError::AccessViolation),
+            _ if is_out_of_space(e) => ErrorPacket::new(TFTPError::DiskFull),
+            _ => ErrorPacket::with_message(TFTPError::UndefinedError, &e.to_string()),
+        };
+        self.set_next_err(packet);
+        self.set_state(DataChannelState::Error);
+        self.set_err(&format!("Failed to {} {}: {}", action, self.file_name, e));
+    }
+
     fn set_err(&mut self, msg: &str) {
         self.error = Some(msg.to_string());
     }
 
-    fn set_next_data(&mut self, packet: DataPacket) {
-        self.set_packet(packet.serialize());
+    fn set_next_data(&mut self, block: &[u8]) {
+        let mut buf = self.packet_at_hand.take().unwrap_or_default();
+        buf.clear();
+        DataPacket::serialize_from_slice(self.blk as u16, block, &mut buf);
+        self.packet_at_hand = Some(buf);
     }
 
     fn set_next_err(&mut self, packet: ErrorPacket) {
-        self.set_packet(packet.serialize());
+        self.set_packet(packet);
     }
 
     fn set_next_ack(&mut self, packet: AckPacket) {
-        self.set_packet(packet.serialize());
+        self.set_packet(packet);
     }
 
-    fn set_packet(&mut self, packet: Vec<u8>) {
-        self.packet_at_hand = Some(packet)
+    /// Serializes the packet into the buffer of the previous one.
+    fn set_packet<P: Serializable>(&mut self, packet: P) {
+        let mut buf = self.packet_at_hand.take().unwrap_or_default();
+        buf.clear();
+        packet.serialize_into(&mut buf);
+        self.packet_at_hand = Some(buf);
     }
 
-    pub fn transfer_size(&self) -> usize {
-        self.last_transferred_bytes
+    /// File bytes moved so far, the same figure on both ends. Headers
+    /// and the empty block ending some transfers aren't counted.
+    pub fn transfer_size(&self) -> u64 {
+        self.transferred_bytes
+    }
+
+    /// Size of the data being sent, if it's known.
+    pub fn size(&self) -> Option<u64> {
+        match self.mode {
+            DataChannelMode::Tx if self.size_known => Some(self.file_size),
+            _ => None,
+        }
+    }
+
+    /// Number of file bytes sent or received so far.
+    pub fn transferred_bytes(&self) -> u64 {
+        self.transferred_bytes
+    }
+
+    pub fn mode(&self) -> DataChannelMode {
+        self.mode
+    }
+
+    pub fn file_name(&self) -> &str {
+        &self.file_name
     }
 
     pub fn is_done(&self) -> bool {
         self.state == DataChannelState::Done
     }
 
+    pub fn state(&self) -> TransferState {
+        match self.state {
+            DataChannelState::SendOptionAck => TransferState::Idle,
+            DataChannelState::WaitAck if self.blk == 0 && self.transferred_bytes == 0 => TransferState::Idle,
+            DataChannelState::SendData | DataChannelState::WaitAck => TransferState::Sending,
+            DataChannelState::WaitData | DataChannelState::SendAck
+                if self.blk == 1 && self.transferred_bytes == 0 => TransferState::Idle,
+            DataChannelState::WaitData | DataChannelState::SendAck => TransferState::Receiving,
+            DataChannelState::SendLastAck | DataChannelState::WaitLastAck => TransferState::Finishing,
+            DataChannelState::Done => TransferState::Done,
+            DataChannelState::Error => TransferState::Errored,
+        }
+    }
+
     pub fn blk(&self) -> u16 {
         self.blk as u16
     }
 
+    /// Hands over the block received last, once. Only channels
+    /// made by `receive_blocks` hold blocks.
+    pub fn take_block(&mut self) -> Option<Vec<u8>> {
+        self.held_block.take()
+    }
+
+    pub fn holds_blocks(&self) -> bool {
+        self.holds_blocks
+    }
+
+    /// Tells whether the next DATA block is still being read from the source.
+    pub fn awaits_source(&self) -> bool {
+        self.state == DataChannelState::SendData && !self.block_read
+    }
+
     pub fn is_err(&self) -> bool {
         self.error.is_some()
     }
@@ -348,14 +952,692 @@ impl DataChannel {
         self.error.unwrap()
     }
 
-    pub fn packet_at_hand(&mut self) -> Option<Vec<u8>> {
-        assert_ne!(self.state, DataChannelState::Done);
+    /// What went wrong with the transfer, if anything did.
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    /// Returns the packet to be sent next, if any. Nothing is
+    /// at hand while waiting for the peer. DATA blocks are read
+    /// from the file on the first poll. The packet is lent, its
+    /// buffer is reused for the next one.
+    pub fn packet_at_hand(&mut self) -> Option<&[u8]> {
+        if self.state == DataChannelState::SendData && !self.block_read {
+            self.send_data();
+        }
+
+        match self.state {
+            DataChannelState::SendData if !self.block_read => None,
+            DataChannelState::SendData
+            | DataChannelState::SendOptionAck
+            | DataChannelState::SendAck
+            | DataChannelState::SendLastAck
+            | DataChannelState::Error => self.packet_at_hand.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Ends a transfer its owner gives up on, returns the ERR that
+    /// spares the peer waiting for a timeout. Nothing is returned
+    /// for a transfer that's over, the peer knows about it already.
+    pub fn take_abort_packet(&mut self) -> Option<&[u8]> {
+        if self.is_done() || self.is_err() {
+            return None;
+        }
+
+        self.set_next_err(ErrorPacket::with_message(TFTPError::UndefinedError, "transfer aborted"));
+        self.set_state(DataChannelState::Error);
+        self.set_err("Transfer aborted");
+        self.packet_at_hand.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io;
+
+    use crate::tftp::shared::{parse_udp_packet, Serializable, TFTPPacket};
+    use crate::tftp::shared::ack_packet::AckPacket;
+    use crate::tftp::shared::data_channel::{DataChannel, DataChannelMode, DataChannelOwner, DataChannelState, TransferState, STALL_LIMIT};
+    use crate::tftp::shared::data_packet::DataPacket;
+    use crate::tftp::shared::err_packet::{ErrorPacket, TFTPError};
+    use crate::tftp::shared::oack_packet::OptionAckPacket;
+    use crate::tftp::shared::request_packet::{ReadRequestPacket, WriteRequestPacket};
+    use crate::tftp::shared::test_util::scratch_dir;
+
+    /// Moves packets between a sending server and a receiving client until
+    /// the transfer completes, returns the number of DATA packets sent.
+    fn pump(tx: &mut DataChannel, rx: &mut DataChannel) -> usize {
+        let mut data_packets = 0;
+
+        while !tx.is_done() {
+            let data = tx.packet_at_hand().unwrap().to_vec();
+            tx.on_packet_sent();
+            data_packets += 1;
+
+            match parse_udp_packet(&data).unwrap() {
+                TFTPPacket::DATA(dp) => rx.on_data(dp),
+                p => panic!("Expected DATA got {:?}", p),
+            }
+
+            let ack = rx.packet_at_hand().unwrap().to_vec();
+            rx.on_packet_sent();
+
+            match parse_udp_packet(&ack).unwrap() {
+                TFTPPacket::ACK(ap) => tx.on_ack(ap),
+                p => panic!("Expected ACK got {:?}", p),
+            }
+        }
+
+        assert!(rx.is_done());
+        data_packets
+    }
+
+    fn content_of_size(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn transfer_file_of_size(name: &str, size: usize) -> usize {
+        let dir = scratch_dir(name);
+        let src = dir.join("src.bin");
+        let dst = dir.join("dst.bin");
+        let content = content_of_size(size);
+        fs::write(&src, &content).unwrap();
+
+        let mut tx = DataChannel::new(src.to_str().unwrap(), DataChannelMode::Tx, DataChannelOwner::Server).unwrap();
+        let mut rx = DataChannel::new(dst.to_str().unwrap(), DataChannelMode::Rx, DataChannelOwner::Client).unwrap();
+        let data_packets = pump(&mut tx, &mut rx);
+        assert_eq!(tx.transfer_size(), size as u64);
+        assert_eq!(rx.transfer_size(), size as u64);
+        drop(rx);
+
+        assert_eq!(fs::read(&dst).unwrap(), content);
+        data_packets
+    }
+
+    #[test]
+    fn transfer_empty_source() {
+        let dst = scratch_dir("empty_source").join("dst.bin");
+        let mut tx = DataChannel::from_source("empty", Box::new(io::empty()), 0, DataChannelOwner::Server);
+        let mut rx = DataChannel::new(dst.to_str().unwrap(), DataChannelMode::Rx, DataChannelOwner::Client).unwrap();
+
+        assert_eq!(pump(&mut tx, &mut rx), 1);
+        assert_eq!(tx.transfer_size(), 0);
+        assert_eq!(rx.transfer_size(), 0);
+        drop(rx);
+        assert!(fs::read(&dst).unwrap().is_empty());
+    }
+
+    #[test]
+    fn transfer_one_byte_short_of_a_block() {
+        assert_eq!(transfer_file_of_size("short_of_a_block", 511), 1);
+    }
+
+    #[test]
+    fn transfer_exactly_one_block() {
+        assert_eq!(transfer_file_of_size("exactly_one_block", 512), 2);
+    }
+
+    #[test]
+    fn transfer_exactly_two_blocks() {
+        assert_eq!(transfer_file_of_size("exactly_two_blocks", 1024), 3);
+    }
+
+    #[test]
+    fn transfer_one_block_and_a_byte() {
+        assert_eq!(transfer_file_of_size("one_block_and_a_byte", 513), 2);
+    }
+
+    #[test]
+    fn transfer_past_4_gib() {
+        // Both ends start 3 blocks short of 4 GiB right before block
+        // numbers wrap, only the last blocks are actually sent.
+        let skipped = (1u64 << 32) - 3 * 512;
+        let size = skipped + 3 * 512 + 100;
+        let dst = scratch_dir("past_4_gib").join("dst.bin");
+        let source = content_of_size(3 * 512 + 100);
+        let mut tx = DataChannel::from_source("huge", Box::new(io::Cursor::new(source)), size, DataChannelOwner::Server);
+        let mut rx = DataChannel::new(dst.to_str().unwrap(), DataChannelMode::Rx, DataChannelOwner::Client).unwrap();
+        for channel in [&mut tx, &mut rx].iter_mut() {
+            channel.transferred_bytes = skipped;
+            channel.blk = u16::MAX - 1;
+        }
+        rx.fd = Some(fs::File::create(&dst).unwrap());
+
+        // Blocks 65534, 65535, 0 and the short block 1.
+        assert_eq!(pump(&mut tx, &mut rx), 4);
+        assert_eq!(tx.transfer_size(), size);
+        assert_eq!(rx.transfer_size(), size);
+        assert_eq!(rx.blk(), 2);
+        drop(rx);
+        assert_eq!(fs::metadata(&dst).unwrap().len(), 3 * 512 + 100);
+    }
+
+    /// Resumes a download of `remote` into `local`, the server answers with
+    /// `tsize` when it's given. Returns the local file afterwards.
+    fn resume_download(name: &str, remote: &[u8], local: &[u8], tsize: Option<u64>) -> (DataChannel, Vec<u8>) {
+        let dir = scratch_dir(name);
+        let src = dir.join("src.bin");
+        let dst = dir.join("dst.bin");
+        fs::write(&src, remote).unwrap();
+        fs::write(&dst, local).unwrap();
+
+        let mut tx = DataChannel::new(src.to_str().unwrap(), DataChannelMode::Tx, DataChannelOwner::Server).unwrap();
+        let mut rx = DataChannel::new(dst.to_str().unwrap(), DataChannelMode::Rx, DataChannelOwner::Client).unwrap();
+        assert_eq!(rx.resume().unwrap(), local.len() as u64 / 512);
+        if let Some(size) = tsize {
+            let oack = OptionAckPacket::new(vec![(String::from("tsize"), size.to_string())]);
+            let _ = rx.handle(TFTPPacket::OACK(oack));
+            if rx.is_err() {
+                return (rx, fs::read(&dst).unwrap());
+            }
+            rx.packet_at_hand().unwrap();
+            rx.on_packet_sent();
+        }
+        pump(&mut tx, &mut rx);
+        (rx, fs::read(&dst).unwrap())
+    }
+
+    #[test]
+    fn resume_half_downloaded_file() {
+        let content = content_of_size(1300);
+        let (rx, downloaded) = resume_download("resume_half", &content, &content[..700], Some(1300));
+        assert_eq!(rx.resumed_bytes, 512);
+        assert_eq!(downloaded, content);
+    }
+
+    #[test]
+    fn resume_file_longer_than_remote() {
+        let content = content_of_size(1300);
+        let mut local = content.clone();
+        local.extend_from_slice(&content);
+        let (rx, kept) = resume_download("resume_longer", &content, &local, Some(1300));
+        assert!(rx.error().unwrap().ends_with("it has 2600 bytes and the remote file 1300"));
+        assert_eq!(kept, local);
+    }
+
+    #[test]
+    fn resume_without_the_remote_size_starts_over() {
+        let content = content_of_size(1300);
+        let (rx, downloaded) = resume_download("resume_unchecked", &content, &[9; 700], None);
+        assert_eq!(rx.resumed_bytes, 0);
+        assert_eq!(downloaded, content);
+    }
+
+    #[test]
+    fn duplicate_ack_is_ignored() {
+        let dir = scratch_dir("duplicate_ack");
+        let src = dir.join("src.bin");
+        fs::write(&src, content_of_size(1300)).unwrap();
+
+        let mut tx = DataChannel::new(src.to_str().unwrap(), DataChannelMode::Tx, DataChannelOwner::Server).unwrap();
+        tx.packet_at_hand().unwrap();
+        tx.on_packet_sent();
+        tx.on_ack(AckPacket::new(1));
+        tx.packet_at_hand().unwrap();
+        tx.on_packet_sent();
+
+        tx.on_ack(AckPacket::new(1));
+        assert_eq!(tx.packet_at_hand(), None);
+        assert!(!tx.is_err());
+
+        tx.on_ack(AckPacket::new(2));
+        match parse_udp_packet(tx.packet_at_hand().unwrap()).unwrap() {
+            TFTPPacket::DATA(dp) => assert_eq!(dp.blk(), 3),
+            p => panic!("Expected DATA got {:?}", p),
+        }
+    }
+
+    #[test]
+    fn repeated_acks_stall_the_transfer() {
+        let dir = scratch_dir("stalled_acks");
+        let src = dir.join("src.bin");
+        fs::write(&src, content_of_size(1300)).unwrap();
+
+        let mut tx = DataChannel::new(src.to_str().unwrap(), DataChannelMode::Tx, DataChannelOwner::Server).unwrap();
+        tx.packet_at_hand().unwrap();
+        tx.on_packet_sent();
+        tx.on_ack(AckPacket::new(1));
+        tx.packet_at_hand().unwrap();
+        tx.on_packet_sent();
+
+        // Moving on starts the count over.
+        for _ in 1..STALL_LIMIT {
+            tx.on_ack(AckPacket::new(1));
+        }
+        tx.on_ack(AckPacket::new(2));
+        tx.packet_at_hand().unwrap();
+        tx.on_packet_sent();
+        for _ in 1..STALL_LIMIT {
+            tx.on_ack(AckPacket::new(2));
+        }
+        assert!(!tx.is_err());
+
+        tx.on_ack(AckPacket::new(2));
+        assert_eq!(tx.error(), Some("Transfer stalled at block #3, the peer repeated itself 16 times"));
+        match parse_udp_packet(tx.packet_at_hand().unwrap()).unwrap() {
+            TFTPPacket::ERR(ep) => assert_eq!(ep.err(), "transfer stalled"),
+            p => panic!("Expected ERR got {:?}", p),
+        }
+    }
+
+    #[test]
+    fn skipping_needs_an_untouched_source() {
+        let src = scratch_dir("skip_started").join("src.bin");
+        fs::write(&src, content_of_size(1300)).unwrap();
+        let mut tx = DataChannel::new(src.to_str().unwrap(), DataChannelMode::Tx, DataChannelOwner::Server).unwrap();
+        tx.packet_at_hand().unwrap();
+        tx.on_packet_sent();
+        tx.skip(512);
+        assert!(tx.is_err());
+
+        let dst = scratch_dir("skip_receiving").join("dst.bin");
+        let mut rx = DataChannel::new(dst.to_str().unwrap(), DataChannelMode::Rx, DataChannelOwner::Client).unwrap();
+        rx.skip(512);
+        assert!(rx.is_err());
+    }
+
+    #[test]
+    fn repeats_of_the_last_block_are_no_stall() {
+        let dst = scratch_dir("repeated_last_block").join("dst.bin");
+        let mut rx = DataChannel::new(dst.to_str().unwrap(), DataChannelMode::Rx, DataChannelOwner::Server).unwrap();
+        rx.packet_at_hand().unwrap();
+        rx.on_packet_sent();
+        rx.on_data(DataPacket::new(1, vec![1; 512]));
+        rx.packet_at_hand().unwrap();
+        rx.on_packet_sent();
+
+        for _ in 0..STALL_LIMIT * 2 {
+            rx.on_data(DataPacket::new(1, vec![1; 512]));
+            assert!(!rx.is_err());
+            assert_eq!(rx.packet_at_hand(), Some(&AckPacket::new(1).serialize()[..]));
+            rx.on_packet_sent();
+        }
+
+        rx.on_data(DataPacket::new(2, vec![2; 10]));
+        assert!(!rx.is_err());
+        assert_eq!(rx.transferred_bytes(), 522);
+    }
+
+    /// Sends the first block of a 1300 byte file, then resizes the file.
+    fn resized_while_sent(name: &str, len: u64) -> DataChannel {
+        let src = scratch_dir(name).join("src.bin");
+        fs::write(&src, content_of_size(1300)).unwrap();
+
+        let mut tx = DataChannel::new(src.to_str().unwrap(), DataChannelMode::Tx, DataChannelOwner::Server).unwrap();
+        tx.packet_at_hand().unwrap();
+        tx.on_packet_sent();
+        fs::OpenOptions::new().write(true).open(&src).unwrap().set_len(len).unwrap();
+        tx.on_ack(AckPacket::new(1));
+        tx
+    }
+
+    #[test]
+    fn files_resized_while_sent_end_the_transfer() {
+        for &(name, len) in &[("shrunk_source", 600), ("grown_source", 4000)] {
+            let mut tx = resized_while_sent(name, len);
+            if len > 1300 {
+                // Growth shows in the last block, the one before is sent as usual.
+                tx.packet_at_hand().unwrap();
+                tx.on_packet_sent();
+                tx.on_ack(AckPacket::new(2));
+            }
+
+            match parse_udp_packet(tx.packet_at_hand().unwrap()).unwrap() {
+                TFTPPacket::ERR(ep) => assert_eq!(ep.err(), "File changed during the transfer."),
+                p => panic!("Expected ERR got {:?}", p),
+            }
+            assert!(tx.error().unwrap().ends_with("changed during the transfer, it had 1300 bytes"));
+        }
+    }
+
+    #[test]
+    fn duplicate_data_is_acked_again() {
+        let dir = scratch_dir("duplicate_data");
+        let dst = dir.join("dst.bin");
+        let block = content_of_size(512);
+
+        let mut rx = DataChannel::new(dst.to_str().unwrap(), DataChannelMode::Rx, DataChannelOwner::Client).unwrap();
+        rx.on_data(DataPacket::new(1, block.clone()));
+        rx.on_packet_sent();
+
+        rx.on_data(DataPacket::new(1, block.clone()));
+        assert!(!rx.is_err());
+        assert_eq!(rx.packet_at_hand().unwrap(), AckPacket::new(1).serialize());
+        rx.on_packet_sent();
+
+        rx.on_data(DataPacket::new(2, Vec::new()));
+        rx.on_packet_sent();
+        assert!(rx.is_done());
+        drop(rx);
+        assert_eq!(fs::read(&dst).unwrap(), block);
+    }
+
+    #[test]
+    fn state_follows_a_download() {
+        let dir = scratch_dir("state_download");
+        let dst = dir.join("dst.bin");
+
+        let mut rx = DataChannel::new(dst.to_str().unwrap(), DataChannelMode::Rx, DataChannelOwner::Client).unwrap();
+        assert_eq!(rx.state(), TransferState::Idle);
+
+        rx.on_data(DataPacket::new(1, content_of_size(512)));
+        assert_eq!(rx.state(), TransferState::Receiving);
+        rx.on_packet_sent();
+        assert_eq!(rx.state(), TransferState::Receiving);
+
+        rx.on_data(DataPacket::new(2, content_of_size(10)));
+        assert_eq!(rx.state(), TransferState::Finishing);
+        rx.on_packet_sent();
+        assert_eq!(rx.state(), TransferState::Done);
+    }
+
+    #[test]
+    fn option_ack_of_a_download_is_confirmed() {
+        let dst = scratch_dir("oack_download").join("dst.bin");
+        let mut rx = DataChannel::new(dst.to_str().unwrap(), DataChannelMode::Rx, DataChannelOwner::Client).unwrap();
+
+        for _ in 0..2 {
+            let oack = OptionAckPacket::new(vec![(String::from("blksize"), String::from("1024"))]);
+            rx.handle(TFTPPacket::OACK(oack)).unwrap();
+            assert_eq!(rx.packet_at_hand().unwrap(), AckPacket::new(0).serialize());
+            rx.on_packet_sent();
+            assert_eq!(rx.state(), TransferState::Idle);
+        }
+
+        rx.handle(TFTPPacket::DATA(DataPacket::new(1, content_of_size(10)))).unwrap();
+        assert_eq!(rx.packet_at_hand().unwrap(), AckPacket::new(1).serialize());
+    }
+
+    #[test]
+    fn state_follows_an_upload() {
+        let dir = scratch_dir("state_upload");
+        let src = dir.join("src.bin");
+        fs::write(&src, content_of_size(600)).unwrap();
+
+        let mut tx = DataChannel::new(src.to_str().unwrap(), DataChannelMode::Tx, DataChannelOwner::Client).unwrap();
+        assert_eq!(tx.state(), TransferState::Idle);
+
+        tx.on_ack(AckPacket::new(0));
+        assert_eq!(tx.state(), TransferState::Sending);
+        tx.packet_at_hand().unwrap();
+        tx.on_packet_sent();
+        tx.on_ack(AckPacket::new(1));
+        tx.packet_at_hand().unwrap();
+        tx.on_packet_sent();
+        assert_eq!(tx.state(), TransferState::Finishing);
+
+        tx.on_ack(AckPacket::new(2));
+        assert_eq!(tx.state(), TransferState::Done);
+    }
+
+    #[test]
+    fn state_on_a_wrong_block() {
+        let dir = scratch_dir("state_error");
+        let dst = dir.join("dst.bin");
+
+        let mut rx = DataChannel::new(dst.to_str().unwrap(), DataChannelMode::Rx, DataChannelOwner::Client).unwrap();
+        rx.on_data(DataPacket::new(5, content_of_size(512)));
+        assert_eq!(rx.state(), TransferState::Errored);
+    }
+
+    fn assert_illegal_operation(channel: &mut DataChannel) {
+        assert_error_code(channel, TFTPError::IllegalOperation);
+    }
+
+    fn assert_error_code(channel: &mut DataChannel, code: TFTPError) {
+        match parse_udp_packet(channel.packet_at_hand().unwrap()).unwrap() {
+            TFTPPacket::ERR(ep) => assert_eq!(ep.code(), code.code()),
+            p => panic!("Expected an error got {:?}", p),
+        }
+        assert_eq!(channel.state(), TransferState::Errored);
+    }
+
+    #[test]
+    fn ack_while_awaiting_data_is_an_error() {
+        let dst = scratch_dir("ack_on_rx").join("dst.bin");
+        let mut rx = DataChannel::new(dst.to_str().unwrap(), DataChannelMode::Rx, DataChannelOwner::Client).unwrap();
+        rx.on_ack(AckPacket::new(1));
+        assert_illegal_operation(&mut rx);
+        assert_eq!(rx.err(), "Unexpected ACK packet");
+    }
+
+    #[test]
+    fn data_block_zero_is_an_error() {
+        let dst = scratch_dir("data_block_zero").join("dst.bin");
+        let mut rx = DataChannel::new(dst.to_str().unwrap(), DataChannelMode::Rx, DataChannelOwner::Client).unwrap();
+        rx.on_data(DataPacket::new(0, content_of_size(10)));
+        assert_illegal_operation(&mut rx);
+        assert_eq!(rx.err(), "Received DATA block 0");
+        assert!(!dst.exists());
+    }
+
+    /// A source whose every read is refused.
+    struct DeniedSource;
+
+    impl io::Read for DeniedSource {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::PermissionDenied, "denied"))
+        }
+    }
+
+    #[test]
+    fn failed_first_read_is_an_access_violation() {
+        let mut tx = DataChannel::from_source("denied.bin", Box::new(DeniedSource), 600, DataChannelOwner::Server);
+        assert_error_code(&mut tx, TFTPError::AccessViolation);
+        assert_eq!(tx.err(), "Failed to read denied.bin: denied");
+    }
+
+    #[test]
+    fn failed_create_is_an_error() {
+        let dst = scratch_dir("failed_create").join("dst.bin");
+        let mut rx = DataChannel::new(dst.to_str().unwrap(), DataChannelMode::Rx, DataChannelOwner::Client).unwrap();
+        // The name is taken by a directory between the request and DATA #1.
+        fs::create_dir(&dst).unwrap();
+
+        rx.on_data(DataPacket::new(1, content_of_size(10)));
+        assert_error_code(&mut rx, TFTPError::UndefinedError);
+        assert!(rx.err().starts_with("Failed to create"));
+    }
+
+    /// The error a receiving channel sends when writing fails with `errno`.
+    #[cfg(target_os = "linux")]
+    fn failing_write_error(errno: i32) -> TFTPError {
+        let dst = scratch_dir(&format!("failed_write_{}", errno)).join("dst.bin");
+        let mut rx = DataChannel::new(dst.to_str().unwrap(), DataChannelMode::Rx, DataChannelOwner::Client).unwrap();
+        let err = io::Error::from_raw_os_error(errno);
+        rx.set_io_error(&err, "write");
+        match parse_udp_packet(rx.packet_at_hand().unwrap()).unwrap() {
+            TFTPPacket::ERR(ep) => TFTPError::from_code(ep.code()).unwrap(),
+            p => panic!("Expected an error got {:?}", p),
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn only_a_full_disk_or_quota_is_disk_full() {
+        // ENOSPC, EDQUOT and EIO.
+        assert_eq!(failing_write_error(28), TFTPError::DiskFull);
+        assert_eq!(failing_write_error(122), TFTPError::DiskFull);
+        assert_eq!(failing_write_error(5), TFTPError::UndefinedError);
+    }
+
+    #[test]
+    fn only_unfinished_transfers_are_aborted() {
+        let dir = scratch_dir("abort_packet");
+        let src = dir.join("src.bin");
+        fs::write(&src, content_of_size(600)).unwrap();
+
+        let mut tx = DataChannel::new(src.to_str().unwrap(), DataChannelMode::Tx, DataChannelOwner::Server).unwrap();
+        tx.packet_at_hand().unwrap();
+        tx.on_packet_sent();
+        match parse_udp_packet(tx.take_abort_packet().unwrap()).unwrap() {
+            TFTPPacket::ERR(ep) => assert_eq!(ep, ErrorPacket::with_message(TFTPError::UndefinedError, "transfer aborted")),
+            p => panic!("Expected an ERR got {:?}", p),
+        }
+        assert_eq!(tx.state(), TransferState::Errored);
+        // The peer is told once.
+        assert_eq!(tx.take_abort_packet(), None);
+
+        let mut tx = DataChannel::new(src.to_str().unwrap(), DataChannelMode::Tx, DataChannelOwner::Server).unwrap();
+        let mut rx = DataChannel::new(dir.join("dst.bin").to_str().unwrap(), DataChannelMode::Rx, DataChannelOwner::Client).unwrap();
+        pump(&mut tx, &mut rx);
+        assert_eq!(tx.take_abort_packet(), None);
+        assert_eq!(rx.take_abort_packet(), None);
+    }
+
+    #[test]
+    fn data_while_awaiting_an_ack_is_an_error() {
+        let src = scratch_dir("data_on_tx").join("src.bin");
+        fs::write(&src, content_of_size(600)).unwrap();
+
+        let mut tx = DataChannel::new(src.to_str().unwrap(), DataChannelMode::Tx, DataChannelOwner::Server).unwrap();
+        tx.packet_at_hand().unwrap();
+        tx.on_packet_sent();
+        tx.on_data(DataPacket::new(1, content_of_size(512)));
+        assert_illegal_operation(&mut tx);
+        assert_eq!(tx.err(), "Unexpected DATA packet");
+    }
+
+    #[test]
+    fn read_ahead_reads_the_next_block_before_the_ack() {
+        let dir = scratch_dir("read_ahead");
+        let src = dir.join("src.bin");
+        let dst = dir.join("dst.bin");
+        let content = content_of_size(1300);
+        fs::write(&src, &content).unwrap();
+
+        let mut tx = DataChannel::new(src.to_str().unwrap(), DataChannelMode::Tx, DataChannelOwner::Server).unwrap();
+        let mut rx = DataChannel::new(dst.to_str().unwrap(), DataChannelMode::Rx, DataChannelOwner::Client).unwrap();
+        while !tx.is_done() {
+            let data = tx.packet_at_hand().unwrap().to_vec();
+            tx.on_packet_sent();
+            tx.read_ahead();
+            // Only the final block has nothing after it.
+            assert_eq!(tx.next_block.is_some(), tx.state == DataChannelState::WaitAck);
 
-        match &self.packet_at_hand {
-            None => None,
-            Some(p) => {
-                Some(p.clone())
+            match parse_udp_packet(&data).unwrap() {
+                TFTPPacket::DATA(dp) => rx.on_data(dp),
+                p => panic!("Expected DATA got {:?}", p),
+            }
+            let ack = rx.packet_at_hand().unwrap().to_vec();
+            rx.on_packet_sent();
+            match parse_udp_packet(&ack).unwrap() {
+                TFTPPacket::ACK(ap) => tx.on_ack(ap),
+                p => panic!("Expected ACK got {:?}", p),
             }
         }
+
+        assert_eq!(tx.transfer_size(), 1300);
+        drop(rx);
+        assert_eq!(fs::read(&dst).unwrap(), content);
+    }
+
+    /// Names of the packet types, in the order `packet_of_type` knows them.
+    const PACKET_TYPES: [&str; 6] = ["RRQ", "WRQ", "DATA", "ACK", "ERR", "OACK"];
+
+    /// A packet of the given type, DATA and ACK carry the given block.
+    fn packet_of_type(name: &str, blk: u16) -> TFTPPacket {
+        match name {
+            "RRQ" => TFTPPacket::RRQ(ReadRequestPacket::new("a.txt", "octet")),
+            "WRQ" => TFTPPacket::WRQ(WriteRequestPacket::new("a.txt", "octet")),
+            "DATA" => TFTPPacket::DATA(DataPacket::new(blk, content_of_size(10))),
+            "ACK" => TFTPPacket::ACK(AckPacket::new(blk)),
+            "ERR" => TFTPPacket::ERR(ErrorPacket::new(TFTPError::DiskFull)),
+            "OACK" => TFTPPacket::OACK(OptionAckPacket::new(Vec::new())),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn handle_takes_only_the_packets_the_state_awaits() {
+        let dir = scratch_dir("handle");
+        let src = dir.join("src.bin");
+        fs::write(&src, content_of_size(600)).unwrap();
+        let src = src.to_str().unwrap().to_string();
+        let dst = dir.join("dst.bin").to_str().unwrap().to_string();
+
+        // How to get a channel in each state, the block it awaits
+        // and the packet types it takes.
+        let awaiting_data = || DataChannel::new(&dst, DataChannelMode::Rx, DataChannelOwner::Client).unwrap();
+        let awaiting_ack = || {
+            let mut tx = DataChannel::new(&src, DataChannelMode::Tx, DataChannelOwner::Server).unwrap();
+            tx.packet_at_hand().unwrap();
+            tx.on_packet_sent();
+            tx
+        };
+        let awaiting_first_ack = || DataChannel::new(&src, DataChannelMode::Tx, DataChannelOwner::Client).unwrap();
+        let done = || {
+            let mut rx = DataChannel::new(&dst, DataChannelMode::Rx, DataChannelOwner::Client).unwrap();
+            rx.on_data(DataPacket::new(1, content_of_size(10)));
+            rx.packet_at_hand().unwrap();
+            rx.on_packet_sent();
+            rx
+        };
+        let states: Vec<(&str, &dyn Fn() -> DataChannel, u16, &[&str])> = vec![
+            ("awaiting DATA #1", &awaiting_data, 1, &["DATA", "OACK"]),
+            ("awaiting ACK", &awaiting_ack, 1, &["ACK"]),
+            ("awaiting ACK #0", &awaiting_first_ack, 0, &["ACK", "OACK"]),
+            ("done", &done, 2, &[]),
+        ];
+
+        for (state, make_channel, blk, taken) in states {
+            for &name in PACKET_TYPES.iter() {
+                let mut channel = make_channel();
+                let result = channel.handle(packet_of_type(name, blk));
+                let _ = fs::remove_file(&dst);
+
+                if taken.contains(&name) {
+                    assert_eq!(result, Ok(()), "{} while {}", name, state);
+                } else if name == "ERR" {
+                    assert_eq!(result, Err(TFTPError::DiskFull), "{} while {}", name, state);
+                    assert_eq!(channel.packet_at_hand(), None, "{} while {}", name, state);
+                } else {
+                    assert_eq!(result, Err(TFTPError::IllegalOperation), "{} while {}", name, state);
+                    assert_illegal_operation(&mut channel);
+                    assert_eq!(channel.err(), format!("Unexpected {} packet", name));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn stale_blocks_are_ignored() {
+        let dir = scratch_dir("stale_blocks");
+        let dst = dir.join("dst.bin");
+
+        let mut rx = DataChannel::new(dst.to_str().unwrap(), DataChannelMode::Rx, DataChannelOwner::Client).unwrap();
+        for blk in 1..=3 {
+            rx.on_data(DataPacket::new(blk, content_of_size(512)));
+            rx.packet_at_hand().unwrap();
+            rx.on_packet_sent();
+        }
+
+        rx.on_data(DataPacket::new(1, content_of_size(512)));
+        assert_eq!(rx.state(), TransferState::Receiving);
+        assert!(rx.packet_at_hand().is_none());
+        assert_eq!(rx.transferred_bytes(), 3 * 512);
+    }
+
+    #[test]
+    fn data_is_read_on_first_poll() {
+        let dir = scratch_dir("lazy_read");
+        let src = dir.join("src.bin");
+        fs::write(&src, content_of_size(600)).unwrap();
+
+        let mut tx = DataChannel::new(src.to_str().unwrap(), DataChannelMode::Tx, DataChannelOwner::Server).unwrap();
+        assert_eq!(tx.transferred_bytes(), 0);
+
+        tx.packet_at_hand().unwrap();
+        assert_eq!(tx.transferred_bytes(), 512);
+        tx.packet_at_hand().unwrap();
+        assert_eq!(tx.transferred_bytes(), 512);
+
+        tx.on_packet_sent();
+        tx.on_ack(AckPacket::new(1));
+        assert_eq!(tx.transferred_bytes(), 512);
+        tx.packet_at_hand().unwrap();
+        assert_eq!(tx.transferred_bytes(), 600);
     }
 }
@@ -7,7 +7,19 @@ use crate::tftp::shared::{
 use super::byteorder::{ByteOrder, NetworkEndian, WriteBytesExt};
 
 const BLK_NUM_LEN: usize = 2;
-const DATA_MAX_LEN: usize = 512;
+/// Largest block size that can be negotiated (RFC 2348).
+pub const DATA_MAX_LEN: usize = 65464;
+
+/// Largest power of two that's no larger than `block_size`, some boot
+/// ROMs misbehave with any other block size.
+pub fn pow2_block_size(block_size: usize) -> usize {
+    let above = block_size.next_power_of_two();
+    if above == block_size {
+        block_size
+    } else {
+        above / 2
+    }
+}
 
 #[derive(Debug, Eq, PartialEq)]
 pub struct DataPacket {
@@ -31,6 +43,19 @@ impl DataPacket {
     pub fn data(self) -> Vec<u8> {
         self.data
     }
+    /// The data carried by the packet, without taking it.
+    pub fn payload(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Appends the DATA packet of `data` to `buf` like `serialize_into`,
+    /// without a packet owning a copy of the data first.
+    pub fn serialize_from_slice(blk: u16, data: &[u8], buf: &mut Vec<u8>) {
+        buf.reserve(OP_LEN + BLK_NUM_LEN + data.len());
+        buf.write_u16::<NetworkEndian>(OP_DATA).unwrap();
+        buf.write_u16::<NetworkEndian>(blk).unwrap();
+        buf.write_all(data).unwrap();
+    }
 }
 
 impl DataPacket {
@@ -47,17 +72,21 @@ impl Serializable for DataPacket {
     fn serialize(self) -> Vec<u8> {
         let buf_len = OP_LEN + BLK_NUM_LEN + self.data_length();
         let mut buf: Vec<u8> = Vec::with_capacity(buf_len);
-        // self.serialize_op(&mut buf);
-        buf.write_u16::<NetworkEndian>(self.op).unwrap();
-        buf.write_u16::<NetworkEndian>(self.blk).unwrap();
-        buf.write_all(self.data.as_slice()).unwrap();
-
+        self.serialize_into(&mut buf);
         buf
     }
+
+    fn serialize_into(self, buf: &mut Vec<u8>) {
+        DataPacket::serialize_from_slice(self.blk, &self.data, buf);
+    }
 }
 
 impl Deserializable for DataPacket {
     fn deserialize(buf: &[u8]) -> Result<TFTPPacket, TFTPParseError> {
+        if buf.len() < OP_LEN + BLK_NUM_LEN {
+            return Err(TFTPParseError::new("Packet is too short"));
+        }
+
         let op: u16 = NetworkEndian::read_u16(&buf[0..2]);
 
         if OP_DATA != op {
@@ -78,12 +107,63 @@ impl Deserializable for DataPacket {
 
 #[cfg(test)]
 mod tests {
+    use crate::tftp::shared::data_packet::{pow2_block_size, DataPacket, DATA_MAX_LEN};
+    use crate::tftp::shared::{Deserializable, Serializable, TFTPPacket, TFTPParseError};
+
     #[test]
-    fn serialize_data_packet() {}
+    fn serialize_data_packet() {
+        let p = DataPacket::new(0x0102, vec![7, 8, 9]);
+        assert_eq!(p.serialize(), vec![0, 3, 1, 2, 7, 8, 9]);
+    }
 
     #[test]
-    fn deserialize_data_packet() {}
+    fn slices_serialize_like_packets() {
+        let data: Vec<u8> = (0..1500).map(|i| i as u8).collect();
+        for len in &[0, 1, 512, 1500] {
+            let mut buf = vec![0xff];
+            DataPacket::serialize_from_slice(0xbeef, &data[..*len], &mut buf);
+            assert_eq!(buf[0], 0xff);
+            assert_eq!(buf[1..], DataPacket::new(0xbeef, data[..*len].to_vec()).serialize()[..]);
+        }
+    }
 
     #[test]
-    fn deserialize_error() {}
+    fn deserialize_data_packet() {
+        let data: Vec<u8> = (0..DATA_MAX_LEN).map(|i| i as u8).collect();
+        let buf = DataPacket::new(42, data.clone()).serialize();
+
+        match DataPacket::deserialize(&buf).unwrap() {
+            TFTPPacket::DATA(p) => {
+                assert_eq!(p.blk(), 42);
+                assert_eq!(p.data(), data);
+            }
+            p => panic!("Expected DATA got {:?}", p),
+        }
+    }
+
+    #[test]
+    fn deserialize_empty_final_block() {
+        let buf = DataPacket::new(3, Vec::new()).serialize();
+        assert_eq!(DataPacket::deserialize(&buf).unwrap(), TFTPPacket::DATA(DataPacket::new(3, Vec::new())));
+    }
+
+    #[test]
+    fn block_sizes_round_down_to_a_power_of_two() {
+        assert_eq!(pow2_block_size(512), 512);
+        assert_eq!(pow2_block_size(1468), 1024);
+        assert_eq!(pow2_block_size(1023), 512);
+        assert_eq!(pow2_block_size(DATA_MAX_LEN), 32768);
+    }
+
+    #[test]
+    fn deserialize_error() {
+        let bad_op = vec![0, 4, 0, 1];
+        assert_eq!(DataPacket::deserialize(&bad_op).unwrap_err(), TFTPParseError::new("Bad OP code!"));
+
+        let too_long = DataPacket::new(1, vec![0; DATA_MAX_LEN + 1]).serialize();
+        assert_eq!(DataPacket::deserialize(&too_long).unwrap_err(), TFTPParseError::new("Invalid data length"));
+
+        let too_short = vec![0, 3, 0];
+        assert_eq!(DataPacket::deserialize(&too_short).unwrap_err(), TFTPParseError::new("Packet is too short"));
+    }
 }
@@ -6,12 +6,15 @@
 /// should be in netascii.  Like all other strings, it is terminated with
 /// a zero byte.
 use std::io::Write;
+use std::net::SocketAddr;
 
 use crate::tftp::shared::{Deserializable, OP_ERR, Serializable, TFTPPacket, TFTPParseError};
 
 use super::byteorder::{ByteOrder, NetworkEndian, WriteBytesExt};
 
 const ERR_LEN: usize = 4;
+/// Starts the message of an error that sends the client to another server.
+const REDIRECT_PREFIX: &str = "REDIRECT ";
 
 #[derive(Debug, Eq, PartialEq)]
 pub struct ErrorPacket {
@@ -20,7 +23,7 @@ pub struct ErrorPacket {
     err: String,
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
 pub enum TFTPError {
     UndefinedError,
     FileNotFound,
@@ -29,36 +32,28 @@ pub enum TFTPError {
     IllegalOperation,
     UnknownTID,
     FileExists,
+    /// The peer's options are refused (RFC 2347).
+    OptionNegotiation,
 }
 
-fn get_err_by_code(code: u16) -> (TFTPError, String) {
-    match code {
-        0 => (
+impl TFTPError {
+    pub fn code(self) -> u16 {
+        get_err_details(self).0
+    }
+
+    /// The standard error with the given code, if any.
+    pub fn from_code(code: u16) -> Option<TFTPError> {
+        let errors = [
             TFTPError::UndefinedError,
-            String::from("Not defined, see error message (if any)."),
-        ),
-        1 => (TFTPError::FileNotFound, String::from("File not found.")),
-        2 => (
+            TFTPError::FileNotFound,
             TFTPError::AccessViolation,
-            String::from("Access violation."),
-        ),
-        3 => (
             TFTPError::DiskFull,
-            String::from("Disk full or allocation exceeded."),
-        ),
-        4 => (
             TFTPError::IllegalOperation,
-            String::from("Illegal TFTP operation."),
-        ),
-        5 => (
             TFTPError::UnknownTID,
-            String::from("Unknown transfer ID."),
-        ),
-        6 => (
             TFTPError::FileExists,
-            String::from("File already exists."),
-        ),
-        _ => (TFTPError::UndefinedError, String::new()),
+            TFTPError::OptionNegotiation,
+        ];
+        errors.iter().copied().find(|err| err.code() == code)
     }
 }
 
@@ -66,18 +61,20 @@ fn get_err_details(err: TFTPError) -> (u16, String) {
     match err {
         TFTPError::UndefinedError => (
             0,
-            String::from("Not defined, see error message (if any).\0"),
+            String::from("Not defined, see error message (if any)."),
         ),
-        TFTPError::FileNotFound => (1, String::from("File not found.\0")),
-        TFTPError::AccessViolation => (2, String::from("Access violation.\0")),
-        TFTPError::DiskFull => (3, String::from("Disk full or allocation exceeded.\0")),
-        TFTPError::IllegalOperation => (4, String::from("Illegal TFTP operation.\0")),
-        TFTPError::UnknownTID => (5, String::from("Unknown transfer ID.\0")),
-        TFTPError::FileExists => (6, String::from("File already exists.\0")),
+        TFTPError::FileNotFound => (1, String::from("File not found.")),
+        TFTPError::AccessViolation => (2, String::from("Access violation.")),
+        TFTPError::DiskFull => (3, String::from("Disk full or allocation exceeded.")),
+        TFTPError::IllegalOperation => (4, String::from("Illegal TFTP operation.")),
+        TFTPError::UnknownTID => (5, String::from("Unknown transfer ID.")),
+        TFTPError::FileExists => (6, String::from("File already exists.")),
+        TFTPError::OptionNegotiation => (8, String::from("Option negotiation failed.")),
     }
 }
 
 impl ErrorPacket {
+    /// Makes an error packet carrying the standard message of the error.
     pub fn new(err: TFTPError) -> Self {
         let (code, msg) = get_err_details(err);
         ErrorPacket {
@@ -87,6 +84,27 @@ impl ErrorPacket {
         }
     }
 
+    /// Makes an error packet with a standard code
+    /// explained by the given message.
+    pub fn with_message(err: TFTPError, msg: &str) -> Self {
+        ErrorPacket {
+            op: OP_ERR,
+            code: err.code(),
+            err: msg.to_string(),
+        }
+    }
+
+    /// Makes an error packet with any code, even one that
+    /// isn't defined, and the given message.
+    pub fn with_code(code: u16, msg: &str) -> Self {
+        ErrorPacket {
+            op: OP_ERR,
+            code,
+            err: msg.to_string(),
+        }
+    }
+
+    /// Makes an error packet with code 0 and the given message.
     pub fn new_custom(err: String) -> Self {
         let (code, _) = get_err_details(TFTPError::UndefinedError);
         ErrorPacket {
@@ -96,6 +114,21 @@ impl ErrorPacket {
         }
     }
 
+    /// Tells the client to make its request to another server instead, it's
+    /// a plain error with a `REDIRECT <addr>` message to other clients.
+    pub fn redirect(addr: SocketAddr) -> Self {
+        ErrorPacket::new_custom(format!("{}{}", REDIRECT_PREFIX, addr))
+    }
+
+    /// The server that a redirect sends the client to.
+    pub fn redirect_target(&self) -> Option<SocketAddr> {
+        if self.code != TFTPError::UndefinedError.code() {
+            return None;
+        }
+
+        self.err.strip_prefix(REDIRECT_PREFIX)?.parse().ok()
+    }
+
     pub fn code(&self) -> u16 {
         self.code
     }
@@ -110,24 +143,30 @@ impl Serializable for ErrorPacket {
     }
 
     fn serialize(self) -> Vec<u8> {
-        let mut buf = Vec::with_capacity(ERR_LEN);
+        let mut buf = Vec::with_capacity(ERR_LEN + self.err.len() + 1);
+        self.serialize_into(&mut buf);
+        buf
+    }
+
+    fn serialize_into(self, buf: &mut Vec<u8>) {
+        buf.reserve(ERR_LEN + self.err.len() + 1);
         buf.write_u16::<NetworkEndian>(self.op).unwrap();
         buf.write_u16::<NetworkEndian>(self.code).unwrap();
-        let terminated_err_msg: String = if !self.err.ends_with("\0") {
-            let mut err_msg = String::from(self.err);
-            err_msg.push('\0');
-            err_msg
-        } else {
-            self.err
-        };
-
-        buf.write_all(terminated_err_msg.as_bytes()).unwrap();
-        buf
+        buf.write_all(self.err.as_bytes()).unwrap();
+        if !self.err.ends_with('\0') {
+            buf.push(0);
+        }
     }
 }
 
 impl Deserializable for ErrorPacket {
     fn deserialize(buf: &[u8]) -> Result<TFTPPacket, TFTPParseError> {
+        // Op code, error code and at least the terminating \0 or a
+        // character of the message.
+        if buf.len() < ERR_LEN + 1 {
+            return Err(TFTPParseError::new("Packet is too short"));
+        }
+
         let op = NetworkEndian::read_u16(buf);
 
         if op != OP_ERR {
@@ -136,19 +175,19 @@ impl Deserializable for ErrorPacket {
             ));
         }
 
+        // The message is kept as sent, peers may explain
+        // standard error codes with their own text.
+        // It ends at the first \0, some peers pad the packet after it.
+        // A message that's missing its \0 is taken whole.
         let code = NetworkEndian::read_u16(&buf[2..]);
-        let (err_type, _) = get_err_by_code(code);
-
-        if err_type == TFTPError::UndefinedError {
-            let buf = &buf[4..];
-            let len = buf.len();
-            let data = Vec::from(&buf[..len - 1]);   // Skip the \0
-            let err = String::from_utf8(data).unwrap();
-            let p = ErrorPacket::new_custom(err);
-            return Ok(TFTPPacket::ERR(p));
-        }
+        let msg = &buf[4..];
+        let msg = match msg.iter().position(|&b| b == 0) {
+            Some(end) => &msg[..end],
+            None => msg,
+        };
+        let err = String::from_utf8(msg.to_vec()).map_err(|_| TFTPParseError::new("Error message isn't valid UTF-8"))?;
 
-        let p = ErrorPacket::new(err_type);
+        let p = ErrorPacket { op: OP_ERR, code, err };
         Ok(TFTPPacket::ERR(p))
     }
 }
@@ -158,7 +197,7 @@ mod tests {
     use std::io::Write;
 
     use crate::tftp::shared::{Deserializable, OP_ERR, Serializable, TFTPPacket};
-    use crate::tftp::shared::err_packet::{ErrorPacket, get_err_details};
+    use crate::tftp::shared::err_packet::{ErrorPacket, get_err_details, TFTPError};
     use crate::tftp::shared::err_packet::TFTPError::IllegalOperation;
 
     use super::super::byteorder::{NetworkEndian, WriteBytesExt};
@@ -170,6 +209,7 @@ mod tests {
         let msg_bytes = &mut Vec::from(err.as_bytes());
         let mut serialized = vec![0, 5, 0, code as u8];
         serialized.append(msg_bytes);
+        serialized.push(0);
 
         assert_eq!(Box::new(p).serialize(), serialized);
     }
@@ -182,6 +222,7 @@ mod tests {
         buf.write_u16::<NetworkEndian>(OP_ERR).unwrap();
         buf.write_u16::<NetworkEndian>(err_code).unwrap();
         buf.write_all(msg_bytes.as_slice()).unwrap();
+        buf.write_u8(0).unwrap();
 
         if let TFTPPacket::ERR(p) = ErrorPacket::deserialize(&mut buf).unwrap() {
             assert_eq!(p.op, OP_ERR);
@@ -207,4 +248,69 @@ mod tests {
         let p = ErrorPacket::deserialize(&mut buf).unwrap_err();
         assert_eq!(p.details, format!("Bad OP code! [{}]", bad_op).as_str())
     }
+
+    #[test]
+    fn custom_message_round_trip() {
+        let p = ErrorPacket::new_custom(String::from("Disk is on fire."));
+
+        if let TFTPPacket::ERR(p) = ErrorPacket::deserialize(&p.serialize()).unwrap() {
+            assert_eq!(p.code(), 0);
+            assert_eq!(p.err(), "Disk is on fire.");
+        } else {
+            panic!("Invalid type")
+        }
+    }
+
+    #[test]
+    fn standard_code_keeps_sent_message() {
+        let mut buf = Vec::new();
+        buf.write_u16::<NetworkEndian>(OP_ERR).unwrap();
+        buf.write_u16::<NetworkEndian>(1).unwrap();
+        buf.write_all(b"No such file: a.txt\0").unwrap();
+
+        if let TFTPPacket::ERR(p) = ErrorPacket::deserialize(&buf).unwrap() {
+            assert_eq!(p.code(), 1);
+            assert_eq!(p.err(), "No such file: a.txt");
+        } else {
+            panic!("Invalid type")
+        }
+    }
+
+    /// Parses an ERR 2 carrying `msg` as it's sent.
+    fn message_of(msg: &[u8]) -> String {
+        let mut buf = vec![0, 5, 0, 2];
+        buf.extend_from_slice(msg);
+        match ErrorPacket::deserialize(&buf).unwrap() {
+            TFTPPacket::ERR(p) => p.err().to_string(),
+            p => panic!("Expected ERR got {:?}", p),
+        }
+    }
+
+    #[test]
+    fn message_ends_at_the_first_nul() {
+        assert_eq!(message_of(b"Access denied\0"), "Access denied");
+        assert_eq!(message_of(b"Access denied\0\0\0garbage"), "Access denied");
+        assert_eq!(message_of(b"\0"), "");
+    }
+
+    #[test]
+    fn message_without_nul_is_taken_whole() {
+        assert_eq!(message_of(b"Access denied"), "Access denied");
+        assert_eq!(message_of(b"x"), "x");
+    }
+
+    #[test]
+    fn redirect_round_trip() {
+        let addr = "10.0.0.2:6969".parse().unwrap();
+
+        if let TFTPPacket::ERR(p) = ErrorPacket::deserialize(&ErrorPacket::redirect(addr).serialize()).unwrap() {
+            assert_eq!(p.err(), "REDIRECT 10.0.0.2:6969");
+            assert_eq!(p.redirect_target(), Some(addr));
+        } else {
+            panic!("Invalid type")
+        }
+
+        assert_eq!(ErrorPacket::new_custom(String::from("REDIRECT nowhere")).redirect_target(), None);
+        assert_eq!(ErrorPacket::with_message(TFTPError::AccessViolation, "REDIRECT 10.0.0.2:6969").redirect_target(), None);
+    }
 }
@@ -7,29 +7,41 @@ use std::fmt::{Debug, Display, Formatter};
 use crate::tftp::shared::ack_packet::AckPacket;
 use crate::tftp::shared::data_packet::DataPacket;
 use crate::tftp::shared::err_packet::ErrorPacket;
+use crate::tftp::shared::oack_packet::OptionAckPacket;
 use crate::tftp::shared::request_packet::*;
 
 use self::byteorder::{ByteOrder, NetworkEndian};
 
 pub mod ack_packet;
+pub mod background_reader;
+pub mod clock;
 pub mod data_channel;
 pub mod data_packet;
 pub mod err_packet;
+pub mod oack_packet;
 pub mod request_packet;
+pub mod rtt;
+pub mod tar_stream;
+pub mod transfer_mode;
+pub mod transport;
+#[cfg(test)]
+pub mod test_util;
 
 const OP_LEN: usize = 2;
 /// Stride size for reading / writing files.
 pub const STRIDE_SIZE: usize = 512;
 /// Op code for Data packet
-const OP_DATA: u16 = 0x003;
+pub const OP_DATA: u16 = 0x003;
 /// Op code for Read Request
-const OP_RRQ: u16 = 0x001;
+pub const OP_RRQ: u16 = 0x001;
 /// Op code for Write Request
-const OP_WRQ: u16 = 0x002;
+pub const OP_WRQ: u16 = 0x002;
 /// Op code for Error packet
-const OP_ERR: u16 = 0x005;
+pub const OP_ERR: u16 = 0x005;
 /// Op code for ACK packet
-const OP_ACK: u16 = 0x004;
+pub const OP_ACK: u16 = 0x004;
+/// Op code for Option ACK packet
+pub const OP_OACK: u16 = 0x006;
 
 #[derive(Debug, Eq, PartialEq)]
 pub enum TFTPPacket {
@@ -38,6 +50,50 @@ pub enum TFTPPacket {
     ACK(AckPacket),
     ERR(ErrorPacket),
     DATA(DataPacket),
+    OACK(OptionAckPacket),
+}
+
+impl TFTPPacket {
+    pub fn opcode(&self) -> u16 {
+        match self {
+            TFTPPacket::RRQ(_) => OP_RRQ,
+            TFTPPacket::WRQ(_) => OP_WRQ,
+            TFTPPacket::ACK(_) => OP_ACK,
+            TFTPPacket::ERR(_) => OP_ERR,
+            TFTPPacket::DATA(_) => OP_DATA,
+            TFTPPacket::OACK(_) => OP_OACK,
+        }
+    }
+
+    /// Name of the requested file, only requests have one.
+    pub fn filename(&self) -> Option<&str> {
+        match self {
+            TFTPPacket::RRQ(p) => Some(p.filename()),
+            TFTPPacket::WRQ(p) => Some(p.filename()),
+            _ => None,
+        }
+    }
+
+    /// Transfer mode of a request as it was sent, e.g. "octet".
+    pub fn mode(&self) -> Option<&str> {
+        match self {
+            TFTPPacket::RRQ(p) => Some(p.mode()),
+            TFTPPacket::WRQ(p) => Some(p.mode()),
+            _ => None,
+        }
+    }
+
+    /// Turns the packet back into the bytes that are sent.
+    pub fn serialize(self) -> Vec<u8> {
+        match self {
+            TFTPPacket::RRQ(p) => p.serialize(),
+            TFTPPacket::WRQ(p) => p.serialize(),
+            TFTPPacket::ACK(p) => p.serialize(),
+            TFTPPacket::ERR(p) => p.serialize(),
+            TFTPPacket::DATA(p) => p.serialize(),
+            TFTPPacket::OACK(p) => p.serialize(),
+        }
+    }
 }
 
 impl Display for TFTPPacket {
@@ -48,6 +104,10 @@ impl Display for TFTPPacket {
             TFTPPacket::ACK(p) => format!("ACK [{}]", p.blk()),
             TFTPPacket::ERR(p) => format!("ERR [{}]: {}", p.code(), p.err()),
             TFTPPacket::DATA(p) => format!("DATA [{}]", p.blk()),
+            TFTPPacket::OACK(p) => {
+                let options: Vec<String> = p.options().iter().map(|(n, v)| format!("{}={}", n, v)).collect();
+                format!("OACK [{}]", options.join(", "))
+            }
         };
 
         write!(f, "{}", desc)
@@ -57,26 +117,44 @@ impl Display for TFTPPacket {
 pub trait Serializable {
     fn box_serialize(self: Box<Self>) -> Vec<u8>;
     fn serialize(self) -> Vec<u8>;
+
+    /// Appends the packet to `buf`, which lets a single buffer
+    /// be reused for all the packets of a transfer.
+    fn serialize_into(self, buf: &mut Vec<u8>)
+    where
+        Self: Sized,
+    {
+        buf.extend_from_slice(&self.serialize());
+    }
 }
 
 pub trait Deserializable {
     fn deserialize(buf: &[u8]) -> Result<TFTPPacket, TFTPParseError>;
 }
 
-pub fn parse_udp_packet(buf: &[u8]) -> TFTPPacket {
-    let p = match NetworkEndian::read_u16(buf) {
+/// Length of a buffer that receives DATA of the given block size, it has
+/// a spare byte so a datagram that fills it is known to be too large.
+pub fn recv_buffer_len(block_size: usize) -> usize {
+    OP_LEN + 2 + block_size + 1
+}
+
+pub fn parse_udp_packet(buf: &[u8]) -> Result<TFTPPacket, TFTPParseError> {
+    if buf.len() < OP_LEN {
+        return Err(TFTPParseError::new("Packet is too short for an OP code"));
+    }
+
+    match NetworkEndian::read_u16(buf) {
         OP_RRQ => ReadRequestPacket::deserialize(buf),
         OP_WRQ => WriteRequestPacket::deserialize(buf),
         OP_ACK => AckPacket::deserialize(buf),
         OP_ERR => ErrorPacket::deserialize(buf),
         OP_DATA => DataPacket::deserialize(buf),
-        val => panic!(format!("Invalid opcode [{}]", val)),
-    };
-
-    p.unwrap()
+        OP_OACK => OptionAckPacket::deserialize(buf),
+        val => Err(TFTPParseError::new(&format!("Invalid opcode [{}]", val))),
+    }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct TFTPParseError {
     details: String,
 }
@@ -100,3 +178,86 @@ impl fmt::Display for TFTPParseError {
         write!(f, "Failed to parse packet: {}", self.details)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use crate::tftp::shared::{parse_udp_packet, Serializable, TFTPPacket, TFTPParseError, OP_ACK, OP_RRQ};
+    use crate::tftp::shared::ack_packet::AckPacket;
+    use crate::tftp::shared::data_packet::DataPacket;
+    use crate::tftp::shared::err_packet::{ErrorPacket, TFTPError};
+    use crate::tftp::shared::oack_packet::OptionAckPacket;
+    use crate::tftp::shared::request_packet::{ReadRequestPacket, WriteRequestPacket};
+
+    #[test]
+    fn short_packets_are_rejected() {
+        let err = TFTPParseError::new("Packet is too short for an OP code");
+        assert_eq!(parse_udp_packet(&[]), Err(err.clone()));
+        assert_eq!(parse_udp_packet(&[4]), Err(err));
+    }
+
+    #[test]
+    fn unknown_opcode_is_rejected() {
+        assert_eq!(parse_udp_packet(&[0, 9, 0, 1]), Err(TFTPParseError::new("Invalid opcode [9]")));
+    }
+
+    #[test]
+    fn packets_round_trip_through_the_raw_api() {
+        let packet = TFTPPacket::ACK(AckPacket::new(7));
+        assert_eq!(packet.opcode(), OP_ACK);
+        assert_eq!(parse_udp_packet(&packet.serialize()).unwrap(), TFTPPacket::ACK(AckPacket::new(7)));
+    }
+
+    #[test]
+    fn requests_tell_their_file_and_mode() {
+        let rrq = parse_udp_packet(&ReadRequestPacket::new("boot/kernel.img", "octet").serialize()).unwrap();
+        assert_eq!(rrq.filename(), Some("boot/kernel.img"));
+        assert_eq!(rrq.mode(), Some("octet"));
+
+        let wrq = parse_udp_packet(&WriteRequestPacket::new("notes.txt", "NetASCII").serialize()).unwrap();
+        assert_eq!(wrq.filename(), Some("notes.txt"));
+        assert_eq!(wrq.mode(), Some("NetASCII"));
+
+        let others = vec![
+            TFTPPacket::ACK(AckPacket::new(1)),
+            TFTPPacket::ERR(ErrorPacket::new(TFTPError::FileNotFound)),
+            TFTPPacket::DATA(DataPacket::new(1, b"data".to_vec())),
+            TFTPPacket::OACK(OptionAckPacket::new(vec![(String::from("blksize"), String::from("1024"))])),
+        ];
+        for packet in others {
+            assert_eq!(packet.filename(), None, "{}", packet);
+            assert_eq!(packet.mode(), None, "{}", packet);
+        }
+    }
+
+    #[test]
+    fn truncated_packets_are_rejected() {
+        // Found by the parse_packet fuzz target, each used to panic.
+        let inputs: &[&[u8]] = &[&[0, 4], &[0, 4, 1], &[0, 5], &[0, 5, 0, 1], &[0, 5, 0]];
+        for input in inputs {
+            assert!(parse_udp_packet(input).is_err(), "{:?} was parsed", input);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn parsing_arbitrary_bytes_never_panics(buf in prop::collection::vec(any::<u8>(), 0..2000)) {
+            let _ = parse_udp_packet(&buf);
+        }
+
+        // Random bytes rarely start with a known op code, these always reach a deserializer.
+        #[test]
+        fn parsing_any_packet_type_never_panics(op in 0u16..8, body in prop::collection::vec(any::<u8>(), 0..2000)) {
+            let mut buf = op.to_be_bytes().to_vec();
+            buf.extend_from_slice(&body);
+            let _ = parse_udp_packet(&buf);
+        }
+    }
+
+    #[test]
+    fn invalid_utf8_is_rejected() {
+        assert!(parse_udp_packet(&[0, OP_RRQ as u8, 0xff, 0, b'o', 0]).is_err());
+        assert!(parse_udp_packet(&[0, 5, 0, 1, 0xff, 0]).is_err());
+    }
+}
@@ -0,0 +1,113 @@
+/// The OACK packet answers a request carrying options (RFC 2347),
+/// it lists the options the server accepted along with their values.
+/// the opcode is 6.
+///
+/// An OACK is acknowledged with an ACK of block number zero when the
+/// server is sending a file, a receiving server gets DATA #1 instead.
+use std::io::Write;
+use std::str;
+
+use crate::tftp::shared::{Deserializable, Serializable, TFTPPacket, TFTPParseError, OP_LEN, OP_OACK};
+
+use super::byteorder::{ByteOrder, NetworkEndian, WriteBytesExt};
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct OptionAckPacket {
+    op: u16,
+    options: Vec<(String, String)>,
+}
+
+impl OptionAckPacket {
+    pub fn new(options: Vec<(String, String)>) -> Self {
+        OptionAckPacket { op: OP_OACK, options }
+    }
+
+    pub fn options(&self) -> &[(String, String)] {
+        &self.options
+    }
+
+    /// Value of the given option, if it was accepted.
+    pub fn option(&self, name: &str) -> Option<&str> {
+        self.options
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+impl Serializable for OptionAckPacket {
+    fn box_serialize(self: Box<Self>) -> Vec<u8> {
+        self.serialize()
+    }
+
+    fn serialize(self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(OP_LEN);
+        self.serialize_into(&mut buf);
+        buf
+    }
+
+    fn serialize_into(self, buf: &mut Vec<u8>) {
+        buf.write_u16::<NetworkEndian>(self.op).unwrap();
+        for (name, value) in self.options {
+            buf.write_all(name.as_bytes()).unwrap();
+            buf.write_u8(0).unwrap();
+            buf.write_all(value.as_bytes()).unwrap();
+            buf.write_u8(0).unwrap();
+        }
+    }
+}
+
+impl Deserializable for OptionAckPacket {
+    fn deserialize(buf: &[u8]) -> Result<TFTPPacket, TFTPParseError> {
+        if buf.len() < OP_LEN {
+            return Err(TFTPParseError::new("Packet is too short"));
+        }
+
+        let op = NetworkEndian::read_u16(buf);
+        if op != OP_OACK {
+            return Err(TFTPParseError::new(format!("Bad OP code! [{}]", op).as_str()));
+        }
+
+        let fields = buf[OP_LEN..]
+            .split(|&byte| byte == 0)
+            .filter(|field| !field.is_empty())
+            .map(|field| str::from_utf8(field).map_err(|_| TFTPParseError::new("Option isn't valid UTF-8")))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if fields.len() % 2 != 0 {
+            return Err(TFTPParseError::new("Option without a value"));
+        }
+
+        let options = fields
+            .chunks_exact(2)
+            .map(|option| (option[0].to_ascii_lowercase(), String::from(option[1])))
+            .collect();
+        Ok(TFTPPacket::OACK(OptionAckPacket::new(options)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tftp::shared::oack_packet::OptionAckPacket;
+    use crate::tftp::shared::{Deserializable, Serializable, TFTPPacket, TFTPParseError};
+
+    #[test]
+    fn serialize_oack_packet() {
+        let p = OptionAckPacket::new(vec![(String::from("blksize"), String::from("1024"))]);
+        assert_eq!(p.serialize(), b"\x00\x06blksize\x001024\x00".to_vec());
+    }
+
+    #[test]
+    fn deserialize_oack_packet() {
+        match OptionAckPacket::deserialize(b"\x00\x06BlkSize\x001468\x00").unwrap() {
+            TFTPPacket::OACK(p) => assert_eq!(p.option("blksize"), Some("1468")),
+            p => panic!("Expected OACK got {:?}", p),
+        }
+    }
+
+    #[test]
+    fn deserialize_error() {
+        let p = OptionAckPacket::deserialize(b"\x00\x06blksize\x00").unwrap_err();
+        assert_eq!(p, TFTPParseError::new("Option without a value"));
+    }
+}
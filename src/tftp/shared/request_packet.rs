@@ -9,10 +9,17 @@ use crate::tftp::shared::{
 
 use super::byteorder::{ByteOrder, WriteBytesExt};
 
+/// Longest request that's parsed, anything longer is refused.
+pub const MAX_REQUEST_LEN: usize = 1400;
+/// Most options a request may carry.
+pub const MAX_OPTIONS: usize = 16;
+
 pub trait Request: Serializable + Deserializable {
     fn op(&self) -> u16;
     fn filename(&self) -> &str;
     fn mode(&self) -> &str;
+    /// Options appended to the request (RFC 2347), names are lowercase.
+    fn options(&self) -> &[(String, String)];
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -26,6 +33,17 @@ impl ReadRequestPacket {
             req: RequestPacket::new(OP_RRQ, filename, mode),
         }
     }
+
+    pub fn with_option(mut self, name: &str, value: &str) -> Self {
+        self.req.add_option(name, value);
+        self
+    }
+
+    /// The plain RFC 1350 request, the options are dropped.
+    pub fn without_options(mut self) -> Self {
+        self.req.options.clear();
+        self
+    }
 }
 
 impl Request for ReadRequestPacket {
@@ -40,6 +58,10 @@ impl Request for ReadRequestPacket {
     fn mode(&self) -> &str {
         &self.req.mode
     }
+
+    fn options(&self) -> &[(String, String)] {
+        &self.req.options
+    }
 }
 
 impl Serializable for ReadRequestPacket {
@@ -69,6 +91,23 @@ impl WriteRequestPacket {
             req: RequestPacket::new(OP_WRQ, filename, mode),
         }
     }
+
+    pub fn with_option(mut self, name: &str, value: &str) -> Self {
+        self.req.add_option(name, value);
+        self
+    }
+
+    /// The plain RFC 1350 request, the options are dropped.
+    pub fn without_options(mut self) -> Self {
+        self.req.options.clear();
+        self
+    }
+
+    /// The same request for a file named `filename`.
+    pub fn renamed(mut self, filename: &str) -> Self {
+        self.req.filename = filename.to_string();
+        self
+    }
 }
 
 impl Request for WriteRequestPacket {
@@ -83,6 +122,10 @@ impl Request for WriteRequestPacket {
     fn mode(&self) -> &str {
         &self.req.mode
     }
+
+    fn options(&self) -> &[(String, String)] {
+        &self.req.options
+    }
 }
 
 impl Serializable for WriteRequestPacket {
@@ -106,6 +149,7 @@ struct RequestPacket {
     op: u16,
     filename: String,
     mode: String,
+    options: Vec<(String, String)>,
 }
 
 impl RequestPacket {
@@ -114,8 +158,13 @@ impl RequestPacket {
             op,
             filename: String::from(filename),
             mode: String::from(mode),
+            options: Vec::new(),
         }
     }
+
+    fn add_option(&mut self, name: &str, value: &str) {
+        self.options.push((name.to_ascii_lowercase(), String::from(value)));
+    }
 }
 
 impl Serializable for RequestPacket {
@@ -133,32 +182,56 @@ impl Serializable for RequestPacket {
         buf.write_u8(0).unwrap();
         buf.write_all(self.mode.as_bytes()).unwrap();
         buf.write_u8(0).unwrap();
+        for (name, value) in self.options {
+            buf.write_all(name.as_bytes()).unwrap();
+            buf.write_u8(0).unwrap();
+            buf.write_all(value.as_bytes()).unwrap();
+            buf.write_u8(0).unwrap();
+        }
         buf
     }
 }
 
 impl Deserializable for RequestPacket {
     fn deserialize(buf: &[u8]) -> Result<TFTPPacket, TFTPParseError> {
-        // TODO: add options
+        if buf.len() < OP_LEN {
+            return Err(TFTPParseError::new("Packet is too short"));
+        }
 
         let op: u16 = NetworkEndian::read_u16(&buf[0..2]);
         if ![OP_RRQ, OP_WRQ].contains(&op) {
             return Err(TFTPParseError::new("Bad OP code!"));
         }
 
+        if buf.len() > MAX_REQUEST_LEN {
+            return Err(TFTPParseError::new("Request is too long"));
+        }
+
         let buf = &buf[2..];
-        let mut data: Vec<&str> = buf
+        let fields = buf
             .split(|&byte| byte == 0)
-            .map(|item| str::from_utf8(item).unwrap())
-            .filter(|s| s.len() != 0)
-            .collect();
+            .filter(|item| !item.is_empty())
+            .map(|item| str::from_utf8(item).map_err(|_| TFTPParseError::new("Request isn't valid UTF-8")))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut fields = fields.into_iter();
+
+        let (filename, mode) = match (fields.next(), fields.next()) {
+            (Some(filename), Some(mode)) => (filename, mode),
+            _ => return Err(TFTPParseError::new("Request without a file name and mode")),
+        };
 
-        let filename = data.remove(0);
-        let mode = data.remove(0);
+        let mut req = RequestPacket::new(op, filename, mode);
+        // An option without a value is dropped.
+        while let (Some(name), Some(value)) = (fields.next(), fields.next()) {
+            if req.options.len() == MAX_OPTIONS {
+                return Err(TFTPParseError::new("Too many options"));
+            }
+            req.add_option(name, value);
+        }
 
         let packet = match op {
-            OP_RRQ => TFTPPacket::RRQ(ReadRequestPacket::new(filename, mode)),
-            OP_WRQ => TFTPPacket::WRQ(WriteRequestPacket::new(filename, mode)),
+            OP_RRQ => TFTPPacket::RRQ(ReadRequestPacket { req }),
+            OP_WRQ => TFTPPacket::WRQ(WriteRequestPacket { req }),
             _ => panic!("Invalid op code."),
         };
 
@@ -168,7 +241,7 @@ impl Deserializable for RequestPacket {
 
 #[cfg(test)]
 mod tests {
-    use crate::tftp::shared::request_packet::{Request, RequestPacket};
+    use crate::tftp::shared::request_packet::{ReadRequestPacket, Request, RequestPacket, MAX_OPTIONS, MAX_REQUEST_LEN};
     use crate::tftp::shared::{
         Deserializable, Serializable, TFTPPacket, TFTPParseError, OP_RRQ, OP_WRQ,
     };
@@ -217,4 +290,49 @@ mod tests {
         let p = RequestPacket::deserialize(&mut bytes).err().unwrap();
         assert_eq!(p, TFTPParseError::new("Bad OP code!"));
     }
+
+    #[test]
+    fn options_round_trip() {
+        let p = ReadRequestPacket::new(FILE_NAME, MODE)
+            .with_option("BLKSIZE", "1024")
+            .with_option("tsize", "0");
+
+        match RequestPacket::deserialize(&p.serialize()).unwrap() {
+            TFTPPacket::RRQ(p) => {
+                assert_eq!(p.filename(), FILE_NAME);
+                let options = vec![
+                    (String::from("blksize"), String::from("1024")),
+                    (String::from("tsize"), String::from("0")),
+                ];
+                assert_eq!(p.options(), options.as_slice());
+            }
+            p => panic!("Expected RRQ got {:?}", p),
+        }
+    }
+
+    #[test]
+    fn option_floods_are_refused() {
+        let mut p = ReadRequestPacket::new(FILE_NAME, MODE);
+        for i in 0..MAX_OPTIONS {
+            p = p.with_option(&format!("o{}", i), "1");
+        }
+        match RequestPacket::deserialize(&p.serialize()).unwrap() {
+            TFTPPacket::RRQ(p) => assert_eq!(p.options().len(), MAX_OPTIONS),
+            p => panic!("Expected RRQ got {:?}", p),
+        }
+
+        let p = ReadRequestPacket::new(FILE_NAME, MODE).with_option("o", "1");
+        let mut bytes = p.serialize();
+        bytes.extend_from_slice(b"x\0y\0".repeat(MAX_OPTIONS).as_slice());
+        let err = RequestPacket::deserialize(&bytes).unwrap_err();
+        assert_eq!(err, TFTPParseError::new("Too many options"));
+    }
+
+    #[test]
+    fn over_long_requests_are_refused() {
+        let mut bytes = ReadRequestPacket::new(FILE_NAME, MODE).serialize();
+        bytes.extend_from_slice(b"x\0y\0".repeat(MAX_REQUEST_LEN).as_slice());
+        let err = RequestPacket::deserialize(&bytes).unwrap_err();
+        assert_eq!(err, TFTPParseError::new("Request is too long"));
+    }
 }
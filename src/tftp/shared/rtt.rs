@@ -0,0 +1,92 @@
+use std::time::Duration;
+
+/// Samples kept of a transfer, older ones are overwritten.
+pub const RTT_SAMPLES: usize = 1024;
+
+/// Round trip times of the blocks of a transfer, the time from sending a
+/// packet to getting the peer's answer. Only the latest samples are kept.
+#[derive(Debug)]
+pub struct RttSamples {
+    samples: Vec<Duration>,
+    capacity: usize,
+    /// Slot the next sample goes to once the buffer is full.
+    next: usize,
+}
+
+/// Where the round trip times of a transfer fell.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct RttSummary {
+    pub min: Duration,
+    pub median: Duration,
+    pub p99: Duration,
+}
+
+impl Default for RttSamples {
+    fn default() -> Self {
+        RttSamples::with_capacity(RTT_SAMPLES)
+    }
+}
+
+impl RttSamples {
+    pub fn with_capacity(capacity: usize) -> Self {
+        RttSamples {
+            samples: Vec::with_capacity(capacity),
+            capacity: capacity.max(1),
+            next: 0,
+        }
+    }
+
+    pub fn record(&mut self, rtt: Duration) {
+        if self.samples.len() < self.capacity {
+            self.samples.push(rtt);
+        } else {
+            self.samples[self.next] = rtt;
+        }
+        self.next = (self.next + 1) % self.capacity;
+    }
+
+    /// Nearest rank percentiles of the samples, if there are any.
+    pub fn summary(&self) -> Option<RttSummary> {
+        let mut sorted = self.samples.clone();
+        sorted.sort_unstable();
+        // The smallest sample with at least `percent` of them at or below it.
+        let at = |percent: usize| sorted[((percent * sorted.len() + 99) / 100).saturating_sub(1)];
+
+        Some(RttSummary {
+            min: *sorted.first()?,
+            median: at(50),
+            p99: at(99),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::tftp::shared::rtt::{RttSamples, RttSummary};
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn percentiles_of_the_samples() {
+        let mut samples = RttSamples::default();
+        assert_eq!(samples.summary(), None);
+
+        for rtt in (1..=100).rev() {
+            samples.record(ms(rtt));
+        }
+        assert_eq!(samples.summary(), Some(RttSummary { min: ms(1), median: ms(50), p99: ms(99) }));
+    }
+
+    #[test]
+    fn only_the_latest_samples_are_kept() {
+        let mut samples = RttSamples::with_capacity(3);
+        for rtt in &[90, 80, 1, 2, 3] {
+            samples.record(ms(*rtt));
+        }
+        assert_eq!(samples.summary(), Some(RttSummary { min: ms(1), median: ms(2), p99: ms(3) }));
+    }
+}
@@ -0,0 +1,146 @@
+use std::fs;
+use std::io;
+use std::io::{Cursor, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread;
+
+use tar::{Archive, Builder};
+
+/// Suffix of the remote file a directory is moved as, `boot/` goes by `boot.tar`.
+pub const TAR_SUFFIX: &str = ".tar";
+/// Chunks packed ahead before the packing thread waits for the transfer.
+const CHUNKS_AHEAD: usize = 16;
+
+/// Name of the remote file that holds `dir` packed.
+pub fn archive_name(dir: &str) -> String {
+    format!("{}{}", dir.trim_end_matches('/'), TAR_SUFFIX)
+}
+
+/// Directory that a request for `path` names by the `.tar` convention, if
+/// there's one. A file of that name takes precedence over the directory.
+pub fn archived_dir(path: &Path) -> Option<PathBuf> {
+    let name = path.to_str()?.strip_suffix(TAR_SUFFIX)?;
+    let dir = PathBuf::from(name);
+    if path.exists() || !dir.is_dir() {
+        return None;
+    }
+
+    Some(dir)
+}
+
+/// Packs `dir` into a tar archive as it's read, on a thread of its own,
+/// so the archive is never put together in memory or on disk. Symbolic
+/// links are stored as links, they aren't followed out of the directory.
+pub fn pack(dir: &Path) -> io::Result<impl Read + Send> {
+    if !dir.is_dir() {
+        return Err(io::Error::new(ErrorKind::NotFound, format!("{} isn't a directory", dir.display())));
+    }
+
+    let dir = dir.to_path_buf();
+    let (tx, rx) = sync_channel(CHUNKS_AHEAD);
+    thread::spawn(move || {
+        let mut builder = Builder::new(ChunkWriter(tx.clone()));
+        builder.follow_symlinks(false);
+        if let Err(e) = builder.append_dir_all(".", &dir).and_then(|_| builder.finish()) {
+            let _ = tx.send(Err(e));
+        }
+    });
+
+    Ok(ChunkReader {
+        chunks: rx,
+        chunk: Cursor::new(Vec::new()),
+    })
+}
+
+/// Unpacks the tar archive `source` yields into `dir`, which is created
+/// if it's missing. Entries that would land outside of `dir` are skipped.
+pub fn unpack<R: Read>(source: R, dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    Archive::new(source).unpack(dir)
+}
+
+/// Hands what the archive builder writes over to the reading side.
+struct ChunkWriter(SyncSender<io::Result<Vec<u8>>>);
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        // The transfer is over, nobody wants the rest.
+        self.0.send(Ok(buf.to_vec()))
+            .map_err(|_| io::Error::new(ErrorKind::BrokenPipe, "The archive isn't read anymore"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct ChunkReader {
+    chunks: Receiver<io::Result<Vec<u8>>>,
+    chunk: Cursor<Vec<u8>>,
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.chunk.position() == self.chunk.get_ref().len() as u64 {
+            match self.chunks.recv() {
+                Ok(chunk) => self.chunk = Cursor::new(chunk?),
+                // The archive is complete.
+                Err(_) => return Ok(0),
+            }
+        }
+
+        self.chunk.read(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use crate::tftp::shared::tar_stream::{archive_name, archived_dir, pack, unpack};
+    use crate::tftp::shared::test_util::scratch_dir;
+
+    #[test]
+    fn directory_trees_survive_a_round_trip() {
+        let root = scratch_dir("tar_round_trip");
+        let tree = root.join("tree");
+        fs::create_dir_all(tree.join("sub").join("empty")).unwrap();
+        fs::write(tree.join("a.txt"), b"alpha").unwrap();
+        fs::write(tree.join("sub").join("b.bin"), vec![7; 1300]).unwrap();
+
+        let copy = root.join("copy");
+        unpack(pack(&tree).unwrap(), &copy).unwrap();
+
+        assert_eq!(fs::read(copy.join("a.txt")).unwrap(), b"alpha");
+        assert_eq!(fs::read(copy.join("sub").join("b.bin")).unwrap(), vec![7; 1300]);
+        assert!(copy.join("sub").join("empty").is_dir());
+    }
+
+    #[test]
+    fn only_directories_are_packed() {
+        let root = scratch_dir("tar_not_a_dir");
+        fs::write(root.join("a.txt"), b"alpha").unwrap();
+        assert!(pack(&root.join("a.txt")).is_err());
+        assert!(pack(&root.join("missing")).is_err());
+    }
+
+    #[test]
+    fn tar_names_lead_to_directories_without_such_a_file() {
+        let root = scratch_dir("tar_names");
+        fs::create_dir_all(root.join("boot")).unwrap();
+        fs::create_dir_all(root.join("kept")).unwrap();
+        fs::write(root.join("kept.tar"), b"").unwrap();
+
+        assert_eq!(archive_name("boot/"), "boot.tar");
+        assert_eq!(archived_dir(&root.join("boot.tar")), Some(root.join("boot")));
+        assert_eq!(archived_dir(&root.join("kept.tar")), None);
+        assert_eq!(archived_dir(&root.join("missing.tar")), None);
+        assert_eq!(archived_dir(Path::new("boot")), None);
+    }
+}
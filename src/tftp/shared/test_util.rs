@@ -0,0 +1,283 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::tftp::shared::clock::Clock;
+use crate::tftp::shared::data_packet::DataPacket;
+use crate::tftp::shared::transport::Transport;
+use crate::tftp::shared::{parse_udp_packet, Serializable, TFTPPacket};
+
+/// Makes a fresh directory for a test under the build directory.
+pub fn scratch_dir(name: &str) -> PathBuf {
+    let dir = PathBuf::from("target").join("scratch").join(name);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// A clock that only moves when it's told to.
+pub struct MockClock {
+    now: Mutex<Instant>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        MockClock { now: Mutex::new(Instant::now()) }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
+
+/// Chances, out of 100, of what may happen to each packet sent over a link.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Faults {
+    pub drop: u32,
+    pub duplicate: u32,
+    pub reorder: u32,
+    pub delay: u32,
+}
+
+/// xorshift64*, so a seed always yields the same faults.
+struct Rng(u64);
+
+impl Rng {
+    fn chance(&mut self, percent: u32) -> bool {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 32) % 100 < u64::from(percent)
+    }
+}
+
+struct Datagram {
+    deliver_at: Instant,
+    from: SocketAddr,
+    payload: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Inbox {
+    queue: Mutex<Vec<Datagram>>,
+    arrived: Condvar,
+}
+
+/// One end of an in-memory link, the faults apply to the packets it sends.
+pub struct MemoryTransport {
+    addr: SocketAddr,
+    inbox: Arc<Inbox>,
+    peer: Arc<Inbox>,
+    faults: Faults,
+    rng: Mutex<Rng>,
+    read_timeout: Duration,
+}
+
+/// How long a delayed packet is held back.
+const DELAY: Duration = Duration::from_millis(30);
+/// Read timeout of both ends until it's changed.
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Links two in-memory transports, `a` may only send to `b` and the other
+/// way around. Each side draws its faults from its own stream of `seed`.
+pub fn memory_link(
+    seed: u64,
+    (a, a_faults): (SocketAddr, Faults),
+    (b, b_faults): (SocketAddr, Faults),
+) -> (MemoryTransport, MemoryTransport) {
+    let a_inbox = Arc::new(Inbox::default());
+    let b_inbox = Arc::new(Inbox::default());
+    let end = |addr, inbox: &Arc<Inbox>, peer: &Arc<Inbox>, faults, seed: u64| MemoryTransport {
+        addr,
+        inbox: inbox.clone(),
+        peer: peer.clone(),
+        faults,
+        rng: Mutex::new(Rng(seed.max(1))),
+        read_timeout: READ_TIMEOUT,
+    };
+
+    (
+        end(a, &a_inbox, &b_inbox, a_faults, seed),
+        end(b, &b_inbox, &a_inbox, b_faults, !seed),
+    )
+}
+
+impl MemoryTransport {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn set_read_timeout(&mut self, timeout: Duration) {
+        self.read_timeout = timeout;
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn send_to(&self, buf: &[u8], _peer: SocketAddr) -> io::Result<usize> {
+        let mut rng = self.rng.lock().unwrap();
+        if rng.chance(self.faults.drop) {
+            return Ok(buf.len());
+        }
+
+        let copies = if rng.chance(self.faults.duplicate) { 2 } else { 1 };
+        let mut deliver_at = Instant::now();
+        if rng.chance(self.faults.delay) {
+            deliver_at += DELAY;
+        }
+
+        let mut queue = self.peer.queue.lock().unwrap();
+        for _ in 0..copies {
+            let datagram = Datagram { deliver_at, from: self.addr, payload: buf.to_vec() };
+            // Jumping ahead of the last queued packet reorders the two.
+            if !queue.is_empty() && rng.chance(self.faults.reorder) {
+                let at = queue.len() - 1;
+                queue.insert(at, datagram);
+            } else {
+                queue.push(datagram);
+            }
+        }
+        self.peer.arrived.notify_all();
+
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let give_up = Instant::now() + self.read_timeout;
+        let mut queue = self.inbox.queue.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            // Delays hold back whatever was sent after, only `reorder` reorders.
+            if queue.first().map_or(false, |d| d.deliver_at <= now) {
+                let datagram = queue.remove(0);
+                // Like UDP, whatever doesn't fit in the buffer is lost.
+                let count = datagram.payload.len().min(buf.len());
+                buf[..count].copy_from_slice(&datagram.payload[..count]);
+                return Ok((count, datagram.from));
+            }
+
+            if now >= give_up {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "read timed out"));
+            }
+
+            let next = queue.first().map_or(give_up, |d| d.deliver_at);
+            let wait = next.min(give_up) - now;
+            queue = self.inbox.arrived.wait_timeout(queue, wait).unwrap().0;
+        }
+    }
+}
+
+/// Serves a single octet download over loopback UDP, doing to chosen
+/// blocks what's scripted for them. It knows just enough of the protocol
+/// to drive a client, so what the client does about misbehaving servers
+/// can be checked without the full server.
+#[derive(Default)]
+pub struct ScriptedServer {
+    content: Vec<u8>,
+    delays: HashMap<u16, Duration>,
+    replays: HashMap<u16, Vec<u16>>,
+}
+
+/// What a scripted server saw of the client.
+#[derive(Debug, Default)]
+pub struct ScriptedRun {
+    /// Block numbers of the ACKs in the order they came.
+    pub acks: Vec<u16>,
+    /// Blocks sent again because the client went quiet.
+    pub timeouts: usize,
+}
+
+/// How long the scripted server waits for an ACK before repeating a block.
+const SCRIPTED_TIMEOUT: Duration = Duration::from_millis(500);
+
+impl ScriptedServer {
+    pub fn new(content: Vec<u8>) -> Self {
+        ScriptedServer { content, ..ScriptedServer::default() }
+    }
+
+    /// Holds DATA `blk` back for `by` once it's due.
+    pub fn delay(mut self, blk: u16, by: Duration) -> Self {
+        self.delays.insert(blk, by);
+        self
+    }
+
+    /// Sends DATA `old` again right after DATA `blk`, so the client gets
+    /// a block it has acknowledged after a newer one.
+    pub fn replay_after(mut self, blk: u16, old: u16) -> Self {
+        self.replays.entry(blk).or_default().push(old);
+        self
+    }
+
+    /// Serves the first request that comes, the run is returned once
+    /// the last block is acknowledged.
+    pub fn spawn(self) -> (SocketAddr, thread::JoinHandle<ScriptedRun>) {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = sock.local_addr().unwrap();
+        (addr, thread::spawn(move || self.serve(sock)))
+    }
+
+    fn serve(self, sock: UdpSocket) -> ScriptedRun {
+        let mut buf = [0; 1024];
+        let (_, client) = sock.recv_from(&mut buf).unwrap();
+        sock.set_read_timeout(Some(SCRIPTED_TIMEOUT)).unwrap();
+
+        // A file of whole blocks ends with an empty one.
+        let mut blocks: Vec<&[u8]> = self.content.chunks(512).collect();
+        if self.content.len() % 512 == 0 {
+            blocks.push(&[]);
+        }
+        let data = |blk: u16| DataPacket::new(blk, blocks[blk as usize - 1].to_vec()).serialize();
+
+        let mut run = ScriptedRun::default();
+        for blk in 1..=blocks.len() as u16 {
+            if let Some(by) = self.delays.get(&blk) {
+                thread::sleep(*by);
+            }
+            sock.send_to(&data(blk), client).unwrap();
+            for &old in self.replays.get(&blk).into_iter().flatten() {
+                sock.send_to(&data(old), client).unwrap();
+            }
+
+            loop {
+                let count = match sock.recv_from(&mut buf) {
+                    Ok((count, _)) => count,
+                    Err(_) => {
+                        run.timeouts += 1;
+                        sock.send_to(&data(blk), client).unwrap();
+                        continue;
+                    }
+                };
+
+                match parse_udp_packet(&buf[..count]).unwrap() {
+                    TFTPPacket::ACK(ap) => {
+                        run.acks.push(ap.blk());
+                        // Repeated ACKs of older blocks aren't answered,
+                        // that's how the Sorcerer's Apprentice starts.
+                        if ap.blk() == blk {
+                            break;
+                        }
+                    }
+                    p => panic!("Unexpected packet {:?}", p),
+                }
+            }
+        }
+
+        run
+    }
+}
@@ -0,0 +1,249 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::Read;
+use std::mem;
+use std::str::FromStr;
+
+/// Line ending of the host, netascii lines are converted to it.
+#[cfg(windows)]
+const LINE_ENDING: &[u8] = b"\r\n";
+#[cfg(not(windows))]
+const LINE_ENDING: &[u8] = b"\n";
+
+const CR: u8 = b'\r';
+const LF: u8 = b'\n';
+const NUL: u8 = 0;
+
+/// How file data is represented on the wire (RFC 1350).
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum TransferMode {
+    /// Raw bytes, the file is sent as is.
+    Octet,
+    /// Text with CR LF line endings, a bare CR is sent as CR NUL.
+    Netascii,
+}
+
+impl Default for TransferMode {
+    fn default() -> Self {
+        TransferMode::Octet
+    }
+}
+
+impl TransferMode {
+    /// Name of the mode in a request packet.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TransferMode::Octet => "octet",
+            TransferMode::Netascii => "netascii",
+        }
+    }
+}
+
+impl Display for TransferMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for TransferMode {
+    type Err = String;
+
+    /// Mode names are case insensitive.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("octet") {
+            Ok(TransferMode::Octet)
+        } else if s.eq_ignore_ascii_case("netascii") {
+            Ok(TransferMode::Netascii)
+        } else {
+            Err(format!("Unsupported transfer mode [{}]", s))
+        }
+    }
+}
+
+/// Converts host text to netascii.
+pub fn encode_netascii(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / 16);
+    let mut i = 0;
+    while i < data.len() {
+        if data[i..].starts_with(LINE_ENDING) {
+            out.extend_from_slice(&[CR, LF]);
+            i += LINE_ENDING.len();
+            continue;
+        }
+
+        match data[i] {
+            CR => out.extend_from_slice(&[CR, NUL]),
+            LF => out.extend_from_slice(&[CR, LF]),
+            byte => out.push(byte),
+        }
+        i += 1;
+    }
+
+    out
+}
+
+/// Converts host text to netascii as it's read, the source is never
+/// held in memory as a whole. The encoded size isn't known until the
+/// source is exhausted.
+pub struct NetasciiEncoder<R> {
+    source: R,
+    /// Encoded bytes that didn't fit the buffer of the last read.
+    encoded: Vec<u8>,
+    /// Text that may start a line ending, it waits for the next read.
+    held: Vec<u8>,
+}
+
+impl<R: Read> NetasciiEncoder<R> {
+    pub fn new(source: R) -> Self {
+        NetasciiEncoder { source, encoded: Vec::new(), held: Vec::new() }
+    }
+}
+
+impl<R: Read> Read for NetasciiEncoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut text = vec![0; buf.len().max(LINE_ENDING.len())];
+        while self.encoded.is_empty() {
+            let count = self.source.read(&mut text)?;
+            if count == 0 && self.held.is_empty() {
+                return Ok(0);
+            }
+
+            let mut data = mem::take(&mut self.held);
+            data.extend_from_slice(&text[..count]);
+            if count > 0 {
+                let keep = (1..LINE_ENDING.len())
+                    .rev()
+                    .find(|&len| data.ends_with(&LINE_ENDING[..len]))
+                    .unwrap_or(0);
+                self.held = data.split_off(data.len() - keep);
+            }
+            self.encoded = encode_netascii(&data);
+        }
+
+        let count = buf.len().min(self.encoded.len());
+        buf[..count].copy_from_slice(&self.encoded[..count]);
+        self.encoded.drain(..count);
+        Ok(count)
+    }
+}
+
+/// Converts netascii to host text block by block, a CR that ends
+/// a block is held until the first byte of the next one is seen.
+#[derive(Debug, Default)]
+pub struct NetasciiDecoder {
+    pending_cr: bool,
+}
+
+impl NetasciiDecoder {
+    pub fn decode(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        for &byte in data {
+            if !self.pending_cr {
+                match byte {
+                    CR => self.pending_cr = true,
+                    byte => out.push(byte),
+                }
+                continue;
+            }
+
+            self.pending_cr = false;
+            match byte {
+                LF => out.extend_from_slice(LINE_ENDING),
+                NUL => out.push(CR),
+                // Not valid netascii, the CR is kept as sent.
+                CR => {
+                    out.push(CR);
+                    self.pending_cr = true;
+                }
+                byte => out.extend_from_slice(&[CR, byte]),
+            }
+        }
+    }
+
+    /// Returns what's held back once the last block was decoded.
+    pub fn finish(&mut self, out: &mut Vec<u8>) {
+        if self.pending_cr {
+            out.push(CR);
+            self.pending_cr = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::io::Read;
+
+    use crate::tftp::shared::transfer_mode::{encode_netascii, NetasciiDecoder, NetasciiEncoder, TransferMode};
+
+    /// Hands out a byte a read.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.split_first() {
+                Some((&byte, rest)) if !buf.is_empty() => {
+                    buf[0] = byte;
+                    self.0 = rest;
+                    Ok(1)
+                }
+                _ => Ok(0),
+            }
+        }
+    }
+
+    fn decode_blocks(blocks: &[&[u8]]) -> Vec<u8> {
+        let mut decoder = NetasciiDecoder::default();
+        let mut out = Vec::new();
+        for block in blocks {
+            decoder.decode(block, &mut out);
+        }
+        decoder.finish(&mut out);
+        out
+    }
+
+    #[test]
+    fn mode_names_are_case_insensitive() {
+        assert_eq!("NetASCII".parse(), Ok(TransferMode::Netascii));
+        assert_eq!("octet".parse(), Ok(TransferMode::Octet));
+        assert_eq!("OCTET".parse(), Ok(TransferMode::Octet));
+        assert_eq!("Octet".parse(), Ok(TransferMode::Octet));
+        assert_eq!("NETASCII".parse(), Ok(TransferMode::Netascii));
+        assert!("mail".parse::<TransferMode>().is_err());
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn netascii_round_trip() {
+        let text = b"a\nb\rc\n";
+        let encoded = encode_netascii(text);
+        assert_eq!(encoded, b"a\r\nb\r\0c\r\n");
+        assert_eq!(decode_blocks(&[&encoded]), text);
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn cr_split_across_blocks() {
+        assert_eq!(decode_blocks(&[b"a\r", b"\nb\r", b"\0"]), b"a\nb\r");
+        assert_eq!(decode_blocks(&[b"a\r"]), b"a\r");
+    }
+
+    #[test]
+    fn encoding_while_reading_matches_encoding_at_once() {
+        let text = b"a\r\nb\rc\n\nd\r".to_vec();
+        let mut streamed = Vec::new();
+        NetasciiEncoder::new(Trickle(&text)).read_to_end(&mut streamed).unwrap();
+        assert_eq!(streamed, encode_netascii(&text));
+
+        let mut buffered = Vec::new();
+        let mut encoder = NetasciiEncoder::new(io::Cursor::new(text.clone()));
+        let mut buf = [0; 3];
+        loop {
+            match encoder.read(&mut buf).unwrap() {
+                0 => break,
+                n => buffered.extend_from_slice(&buf[..n]),
+            }
+        }
+        assert_eq!(buffered, encode_netascii(&text));
+    }
+}
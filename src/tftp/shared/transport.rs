@@ -0,0 +1,351 @@
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, info, log_enabled, Level, Log, Record};
+use socket2::SockRef;
+
+use crate::tftp::shared::{parse_udp_packet, recv_buffer_len, TFTPPacket};
+use crate::tftp::shared::data_packet::DATA_MAX_LEN;
+
+/// Target of the records that trace every packet sent and received.
+pub const PACKET_TARGET: &str = "tftpeer::packets";
+
+/// Largest DSCP, it's a 6-bit field.
+pub const MAX_DSCP: u8 = 63;
+
+/// Attempts at sending a datagram that keeps failing for a passing reason.
+pub const SEND_ATTEMPTS: u32 = 5;
+
+/// Pause before sending again, it doubles with each attempt.
+const SEND_RETRY_PAUSE: Duration = Duration::from_millis(10);
+
+/// Carries the packets of a transfer, the UDP transport is used unless
+/// an embedding application or a test provides another one.
+pub trait Transport {
+    fn send_to(&self, buf: &[u8], peer: SocketAddr) -> io::Result<usize>;
+
+    /// Receives the next packet along with its sender. Reads time out
+    /// with `WouldBlock` or `TimedOut` like those of a UDP socket.
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+
+    /// Caps how long `recv_from` waits, transports with a timeout
+    /// of their own keep it.
+    fn set_recv_timeout(&self, _timeout: Duration) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for &T {
+    fn send_to(&self, buf: &[u8], peer: SocketAddr) -> io::Result<usize> {
+        (**self).send_to(buf, peer)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        (**self).recv_from(buf)
+    }
+
+    fn set_recv_timeout(&self, timeout: Duration) -> io::Result<()> {
+        (**self).set_recv_timeout(timeout)
+    }
+}
+
+pub struct UdpTransport {
+    socket: UdpSocket,
+}
+
+impl UdpTransport {
+    pub fn new(socket: UdpSocket) -> Self {
+        UdpTransport { socket }
+    }
+
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send_to(&self, buf: &[u8], peer: SocketAddr) -> io::Result<usize> {
+        log_packet(true, peer, buf);
+        self.socket.send_to(buf, peer)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (count, peer) = self.socket.recv_from(buf)?;
+        log_packet(false, peer, &buf[..count]);
+        Ok((count, peer))
+    }
+
+    fn set_recv_timeout(&self, timeout: Duration) -> io::Result<()> {
+        self.socket.set_read_timeout(Some(timeout))
+    }
+}
+
+/// Tells send errors that may pass by themselves, like a full buffer of the
+/// network interface or a route that's being changed, from those that won't.
+pub fn is_transient_send_error(e: &io::Error) -> bool {
+    if matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+    ) {
+        return true;
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        use rustix::io::Errno;
+        let passing = [Errno::NOBUFS, Errno::NETUNREACH, Errno::HOSTUNREACH];
+        if let Some(code) = e.raw_os_error() {
+            return passing.iter().any(|errno| errno.raw_os_error() == code);
+        }
+    }
+    false
+}
+
+/// Sends `buf` to `peer`, a send that fails for a passing reason is tried
+/// again up to `SEND_ATTEMPTS` times with a growing pause in between. Any
+/// other failure, or the last one, is returned.
+pub fn send_retrying<T: Transport + ?Sized>(transport: &T, buf: &[u8], peer: SocketAddr) -> io::Result<usize> {
+    let mut pause = SEND_RETRY_PAUSE;
+    for _ in 1..SEND_ATTEMPTS {
+        match transport.send_to(buf, peer) {
+            Err(e) if is_transient_send_error(&e) => {
+                debug!("Sending to [{}] failed, trying again in {:?}: {}", peer, pause, e);
+                thread::sleep(pause);
+                pause *= 2;
+            }
+            result => return result,
+        }
+    }
+    transport.send_to(buf, peer)
+}
+
+/// Keeps answering the peer for `period` after receiving a file, its
+/// retransmissions of the last block get `final_ack` again in case ours
+/// was lost (RFC 1350 calls this dallying). Anything else is ignored.
+pub fn dally<T: Transport>(transport: &T, peer: SocketAddr, final_ack: &[u8], period: Duration) {
+    let last_blk = match parse_udp_packet(final_ack) {
+        Ok(TFTPPacket::ACK(ap)) => ap.blk(),
+        _ => return,
+    };
+
+    let until = Instant::now() + period;
+    let mut buf = vec![0; recv_buffer_len(DATA_MAX_LEN)];
+    loop {
+        let now = Instant::now();
+        if now >= until || transport.set_recv_timeout(until - now).is_err() {
+            return;
+        }
+
+        match transport.recv_from(&mut buf) {
+            Ok((count, addr)) if addr == peer => match parse_udp_packet(&buf[..count]) {
+                Ok(TFTPPacket::DATA(dp)) if dp.blk() == last_blk => {
+                    debug!("[{}] sent block #{} again, acknowledging it again", peer, last_blk);
+                    let _ = send_retrying(transport, final_ack, peer);
+                }
+                _ => {}
+            },
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {}
+            Err(_) => return,
+        }
+    }
+}
+
+/// Traces a packet to or from `peer` in its `Display` form, e.g.
+/// `-> [10.0.0.1:69] RRQ [boot.img] [octet]`. Packets are only
+/// parsed for it when the trace is on.
+pub fn log_packet(sent: bool, peer: SocketAddr, buf: &[u8]) {
+    if log_enabled!(target: PACKET_TARGET, Level::Trace) {
+        log_packet_to(log::logger(), sent, peer, buf);
+    }
+}
+
+/// Traces the packet to `logger` whether or not the trace is on.
+fn log_packet_to(logger: &dyn Log, sent: bool, peer: SocketAddr, buf: &[u8]) {
+    let direction = if sent { "->" } else { "<-" };
+    let line = match parse_udp_packet(buf) {
+        Ok(packet) => format!("{} [{}] {}", direction, peer, packet),
+        Err(e) => format!("{} [{}] {}", direction, peer, e),
+    };
+    logger.log(&Record::builder().args(format_args!("{}", line)).level(Level::Trace).target(PACKET_TARGET).build());
+}
+
+/// Marks the packets sent from the socket with a DSCP for QoS, it's
+/// the upper six bits of the IPv4 ToS byte. Sockets bound to IPv6
+/// addresses aren't supported, and some platforms (e.g. Windows
+/// without a QoS policy) accept the value but don't put it on the wire.
+pub fn set_dscp(socket: &UdpSocket, dscp: u8) -> io::Result<()> {
+    if dscp > MAX_DSCP {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "DSCP must be within 0..=63"));
+    }
+
+    SockRef::from(socket).set_tos(u32::from(dscp) << 2)
+}
+
+/// Only takes the packets that arrive on the network interface named
+/// `interface` and sends through it, whatever address the socket has.
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+pub fn bind_to_interface(socket: &UdpSocket, interface: &str) -> io::Result<()> {
+    SockRef::from(socket)
+        .bind_device(Some(interface.as_bytes()))
+        .map_err(|e| io::Error::new(e.kind(), format!("Cannot bind to interface {}: {}", interface, e)))
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+pub fn bind_to_interface(_socket: &UdpSocket, interface: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        format!("Cannot bind to interface {}: not supported on this platform", interface),
+    ))
+}
+
+/// Asks the kernel for socket buffers of the given sizes in bytes. The
+/// kernel may clamp them (Linux caps them at `net.core.rmem_max` and
+/// `wmem_max`, then doubles them for bookkeeping), so the granted sizes
+/// are logged.
+pub fn set_buffer_sizes(socket: &UdpSocket, recv: Option<usize>, send: Option<usize>) -> io::Result<()> {
+    let socket = SockRef::from(socket);
+    if let Some(size) = recv {
+        socket.set_recv_buffer_size(size)?;
+        info!("Receive buffer: asked for {} bytes, got {}", size, socket.recv_buffer_size()?);
+    }
+    if let Some(size) = send {
+        socket.set_send_buffer_size(size)?;
+        info!("Send buffer: asked for {} bytes, got {}", size, socket.send_buffer_size()?);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::io;
+    use std::net::{SocketAddr, UdpSocket};
+    use std::sync::Mutex;
+
+    use log::{Log, Metadata, Record};
+    use socket2::SockRef;
+
+    use crate::tftp::shared::request_packet::ReadRequestPacket;
+    use crate::tftp::shared::Serializable;
+    use crate::tftp::shared::transport::{
+        is_transient_send_error, log_packet_to, send_retrying, set_buffer_sizes, set_dscp, Transport, SEND_ATTEMPTS,
+        PACKET_TARGET,
+    };
+
+    /// Keeps the packet trace, the other records are dropped.
+    #[derive(Default)]
+    struct PacketCapture(Mutex<Vec<String>>);
+
+    impl Log for PacketCapture {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            metadata.target() == PACKET_TARGET
+        }
+
+        fn log(&self, record: &Record) {
+            if self.enabled(record.metadata()) {
+                self.0.lock().unwrap().push(record.args().to_string());
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn packets_are_traced_both_ways() {
+        let capture = PacketCapture::default();
+        let peer = SocketAddr::from(([10, 0, 0, 1], 69));
+        let rrq = ReadRequestPacket::new("boot.img", "octet").serialize();
+
+        log_packet_to(&capture, true, peer, &rrq);
+        log_packet_to(&capture, false, peer, &rrq);
+        log_packet_to(&capture, false, peer, &[0, 42]);
+
+        let lines = capture.0.lock().unwrap();
+        assert_eq!(lines[..2], ["-> [10.0.0.1:69] RRQ [boot.img] [octet]", "<- [10.0.0.1:69] RRQ [boot.img] [octet]"]);
+        // Malformed packets are traced with what's wrong with them.
+        assert!(lines[2].starts_with("<- [10.0.0.1:69] "));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn dscp_is_set_on_the_socket() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        set_dscp(&socket, 46).unwrap();
+        assert_eq!(SockRef::from(&socket).tos().unwrap(), 46 << 2);
+    }
+
+    #[test]
+    fn dscp_must_fit_six_bits() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(set_dscp(&socket, 64).is_err());
+    }
+
+    #[test]
+    fn buffer_sizes_are_set_on_the_socket() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let size = 32 * 1024;
+        set_buffer_sizes(&socket, Some(size), Some(size)).unwrap();
+
+        // Linux reports double what was granted.
+        let socket = SockRef::from(&socket);
+        assert!(socket.recv_buffer_size().unwrap() >= size);
+        assert!(socket.send_buffer_size().unwrap() >= size);
+    }
+
+    /// Fails the first `failures` sends with the error `fail` makes.
+    struct FailingSends {
+        fail: fn() -> io::Error,
+        failures: u32,
+        attempts: Cell<u32>,
+    }
+
+    impl Transport for FailingSends {
+        fn send_to(&self, buf: &[u8], _peer: SocketAddr) -> io::Result<usize> {
+            self.attempts.set(self.attempts.get() + 1);
+            if self.attempts.get() <= self.failures {
+                return Err((self.fail)());
+            }
+            Ok(buf.len())
+        }
+
+        fn recv_from(&self, _buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+            unreachable!()
+        }
+    }
+
+    #[test]
+    fn passing_send_errors_are_retried() {
+        let peer = "127.0.0.1:69".parse().unwrap();
+        let full_buffer = FailingSends { fail: || io::ErrorKind::WouldBlock.into(), failures: 2, attempts: Cell::new(0) };
+        assert_eq!(send_retrying(&full_buffer, &[0; 4], peer).unwrap(), 4);
+        assert_eq!(full_buffer.attempts.get(), 3);
+
+        let down = FailingSends { fail: || io::ErrorKind::WouldBlock.into(), failures: u32::MAX, attempts: Cell::new(0) };
+        assert!(send_retrying(&down, &[0; 4], peer).is_err());
+        assert_eq!(down.attempts.get(), SEND_ATTEMPTS);
+
+        let denied = FailingSends { fail: || io::ErrorKind::PermissionDenied.into(), failures: 1, attempts: Cell::new(0) };
+        assert_eq!(send_retrying(&denied, &[0; 4], peer).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(denied.attempts.get(), 1);
+    }
+
+    #[test]
+    fn send_errors_are_told_apart() {
+        assert!(is_transient_send_error(&io::ErrorKind::Interrupted.into()));
+        assert!(!is_transient_send_error(&io::ErrorKind::PermissionDenied.into()));
+        assert!(!is_transient_send_error(&io::ErrorKind::InvalidInput.into()));
+        #[cfg(target_os = "linux")]
+        {
+            // ENOBUFS, a full queue of the network interface.
+            assert!(is_transient_send_error(&io::Error::from_raw_os_error(105)));
+        }
+    }
+}